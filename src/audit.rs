@@ -0,0 +1,72 @@
+use std::sync::Arc;
+
+use mongodb::{bson::Timestamp, change_stream::event::OperationType, Namespace};
+use tokio::sync::mpsc;
+
+use crate::{collection_entry::subscriptions_manager::SubscriptionHandle, Handle};
+
+/// Receives a record of every change that matched at least one subscription, see
+/// [`Mercurius::audit_channel`](crate::Mercurius::audit_channel).
+pub type AuditReceiver = mpsc::Receiver<AuditRecord>;
+
+/// A change of a document and the subscriptions it matched. There is one record per change,
+/// however many subscriptions it matched, and whether or not it could be delivered to them.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub namespace: Namespace,
+    pub operation_type: OperationType,
+    /// The key of the document, like in the events; unset for an insert of a document whose
+    /// `_id` is neither a string nor an ObjectId.
+    pub key: Option<Arc<String>>,
+    pub cluster_time: Option<Timestamp>,
+    pub subscriptions: Vec<Handle>,
+}
+
+/// The sending half of the audit channel, bound to a single collection entry.
+#[derive(Debug, Clone)]
+pub(crate) struct Audit {
+    sender: mpsc::Sender<AuditRecord>,
+    namespace: Namespace,
+    entry_id: usize,
+}
+
+impl Audit {
+    pub(crate) fn new(
+        sender: mpsc::Sender<AuditRecord>,
+        namespace: Namespace,
+        entry_id: usize,
+    ) -> Self {
+        Self {
+            sender,
+            namespace,
+            entry_id,
+        }
+    }
+
+    /// Best-effort like the dead-letter channel: if the audit channel is full or closed the
+    /// record is discarded.
+    pub(crate) fn record(
+        &self,
+        operation_type: OperationType,
+        key: Option<Arc<String>>,
+        cluster_time: Option<Timestamp>,
+        subscriptions: Vec<SubscriptionHandle>,
+    ) {
+        let subscriptions = subscriptions
+            .into_iter()
+            .map(|subscription_handle| Handle {
+                collection_name: self.namespace.coll.clone(),
+                entry_id: self.entry_id,
+                subscription_handle,
+            })
+            .collect();
+
+        let _ = self.sender.try_send(AuditRecord {
+            namespace: self.namespace.clone(),
+            operation_type,
+            key,
+            cluster_time,
+            subscriptions,
+        });
+    }
+}
@@ -0,0 +1,555 @@
+//! Evaluates MongoDB query documents client-side, directly on BSON, see
+//! [`MatcherBackend::Bson`](crate::options::MatcherBackend::Bson).
+//!
+//! Besides what the [default matcher](crate::matcher) supports, this implements the array
+//! operators `$elemMatch`, `$all` and `$size`, as well as `$mod`, and `$type` takes both the
+//! names and the numbers of the BSON types, e.g. `"binData"` or `5`. Documents aren't converted
+//! to JSON first, so every BSON type keeps its identity: numbers still compare numerically
+//! regardless of their type, and exactly for a `Decimal128`, while dates, timestamps and
+//! ObjectIds compare by their value. Values of different types never compare as greater or less
+//! than each other.
+//!
+//! Like with the default matcher, a condition on an array field matches if the array itself or
+//! any of its elements matches, and of a [`Collation`] only the case sensitivity is honored.
+
+use std::cmp::Ordering;
+
+use mongodb::{
+    bson::{spec::ElementType, Bson, Document},
+    options::{Collation, CollationStrength},
+};
+use regex::Regex;
+
+use crate::matcher::{regex, Decimal, MatcherError};
+
+/// A compiled query document.
+#[derive(Debug, Clone)]
+pub struct BsonMatcher {
+    query: Query,
+    case_insensitive: bool,
+}
+
+#[derive(Debug, Clone)]
+enum Query {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Nor(Vec<Query>),
+    Field(String, Vec<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Eq(Bson),
+    Ne(Bson),
+    Cmp(Ordering, bool, Bson),
+    /// The values of `$in`, of which regular expressions match like `$regex`.
+    In(Vec<Condition>),
+    Nin(Vec<Condition>),
+    Exists(bool),
+    Type(Vec<TypeSpec>),
+    Regex(Regex),
+    Not(Vec<Condition>),
+    ElemMatch(ElemMatch),
+    /// Each value, or `$elemMatch`, has to match.
+    All(Vec<Condition>),
+    Size(usize),
+    Mod(i64, i64),
+}
+
+/// The operand of `$elemMatch`, which is either a query on the elements, if they are documents,
+/// or conditions on the elements themselves.
+#[derive(Debug, Clone)]
+enum ElemMatch {
+    Query(Box<Query>),
+    Conditions(Vec<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum TypeSpec {
+    /// Any of the numeric types, i.e. double, int, long and decimal.
+    Number,
+    Element(ElementType),
+}
+
+impl BsonMatcher {
+    pub fn new(filter: &Document) -> Result<Self, MatcherError> {
+        Ok(Self {
+            query: Query::parse(filter)?,
+            case_insensitive: false,
+        })
+    }
+
+    /// Like [`BsonMatcher::new`], comparing strings according to `collation`, as far as it's
+    /// supported client-side (see the [module documentation](crate::bson_matcher)).
+    pub fn with_collation(filter: &Document, collation: &Collation) -> Result<Self, MatcherError> {
+        let mut matcher = BsonMatcher::new(filter)?;
+        matcher.set_collation(collation);
+        Ok(matcher)
+    }
+
+    pub(crate) fn set_collation(&mut self, collation: &Collation) {
+        self.case_insensitive = matches!(
+            collation.strength,
+            Some(CollationStrength::Primary | CollationStrength::Secondary)
+        ) && collation.case_level != Some(true);
+    }
+
+    pub fn matches(&self, document: &Document) -> bool {
+        self.query.matches(document, self.case_insensitive)
+    }
+}
+
+impl Query {
+    fn parse(filter: &Document) -> Result<Self, MatcherError> {
+        let mut queries = Vec::with_capacity(filter.len());
+        for (key, value) in filter {
+            queries.push(match key.as_str() {
+                "$and" => Query::And(Query::parse_all(value)?),
+                "$or" => Query::Or(Query::parse_all(value)?),
+                "$nor" => Query::Nor(Query::parse_all(value)?),
+                key if key.starts_with('$') => {
+                    return Err(MatcherError::UnknownOperator(key.to_string()))
+                }
+                key => Query::Field(key.to_string(), Condition::parse(value)?),
+            });
+        }
+
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::And(queries),
+        })
+    }
+
+    fn parse_all(value: &Bson) -> Result<Vec<Self>, MatcherError> {
+        let Bson::Array(queries) = value else {
+            return Err(MatcherError::InvalidOperand("$and, $or and $nor"));
+        };
+
+        queries
+            .iter()
+            .map(|query| match query {
+                Bson::Document(query) => Query::parse(query),
+                _ => Err(MatcherError::InvalidOperand("query")),
+            })
+            .collect()
+    }
+
+    fn matches(&self, document: &Document, case_insensitive: bool) -> bool {
+        match self {
+            Query::And(queries) => queries
+                .iter()
+                .all(|query| query.matches(document, case_insensitive)),
+            Query::Or(queries) => queries
+                .iter()
+                .any(|query| query.matches(document, case_insensitive)),
+            Query::Nor(queries) => !queries
+                .iter()
+                .any(|query| query.matches(document, case_insensitive)),
+            Query::Field(path, conditions) => {
+                let values = resolve(document, path);
+                conditions
+                    .iter()
+                    .all(|condition| condition.matches(&values, case_insensitive))
+            }
+        }
+    }
+}
+
+impl Condition {
+    /// Parses the condition on a field: either an operator expression or a value to compare with.
+    fn parse(value: &Bson) -> Result<Vec<Self>, MatcherError> {
+        let object = match value {
+            Bson::Document(object) if is_operator_expression(object) => object,
+            value => return Ok(vec![Condition::value(value)?]),
+        };
+
+        let mut conditions = Vec::with_capacity(object.len());
+        for (operator, operand) in object {
+            conditions.push(match operator.as_str() {
+                "$eq" => Condition::Eq(operand.clone()),
+                "$ne" => Condition::Ne(operand.clone()),
+                "$gt" => Condition::Cmp(Ordering::Greater, false, operand.clone()),
+                "$gte" => Condition::Cmp(Ordering::Greater, true, operand.clone()),
+                "$lt" => Condition::Cmp(Ordering::Less, false, operand.clone()),
+                "$lte" => Condition::Cmp(Ordering::Less, true, operand.clone()),
+                "$in" => Condition::In(Condition::values(operand, "$in")?),
+                "$nin" => Condition::Nin(Condition::values(operand, "$nin")?),
+                "$exists" => Condition::Exists(match operand {
+                    Bson::Boolean(exists) => *exists,
+                    operand => match Decimal::from_bson(operand) {
+                        Some(number) => number != Decimal::Zero,
+                        None => return Err(MatcherError::InvalidOperand("$exists")),
+                    },
+                }),
+                "$type" => Condition::Type(match operand {
+                    Bson::Array(types) => types
+                        .iter()
+                        .map(TypeSpec::parse)
+                        .collect::<Result<_, _>>()?,
+                    operand => vec![TypeSpec::parse(operand)?],
+                }),
+                "$regex" => {
+                    let (pattern, own_options) = match operand {
+                        Bson::String(pattern) => (pattern.as_str(), ""),
+                        Bson::RegularExpression(regex) => {
+                            (regex.pattern.as_str(), regex.options.as_str())
+                        }
+                        _ => return Err(MatcherError::InvalidOperand("$regex")),
+                    };
+                    // `$options` takes precedence over those of a regular expression
+                    let options = match object.get("$options") {
+                        Some(Bson::String(options)) => options.as_str(),
+                        Some(_) => return Err(MatcherError::InvalidOperand("$options")),
+                        None => own_options,
+                    };
+                    Condition::Regex(regex(pattern, options)?)
+                }
+                "$options" if object.contains_key("$regex") => continue,
+                "$options" => return Err(MatcherError::InvalidOperand("$options")),
+                "$not" => Condition::Not(Condition::parse(operand)?),
+                "$elemMatch" => Condition::ElemMatch(ElemMatch::parse(operand)?),
+                "$all" => {
+                    let Bson::Array(values) = operand else {
+                        return Err(MatcherError::InvalidOperand("$all"));
+                    };
+                    Condition::All(
+                        values
+                            .iter()
+                            .map(|value| match value {
+                                Bson::Document(object) if object.contains_key("$elemMatch") => {
+                                    Ok(Condition::ElemMatch(ElemMatch::parse(
+                                        object.get("$elemMatch").unwrap(),
+                                    )?))
+                                }
+                                value => Condition::value(value),
+                            })
+                            .collect::<Result<_, _>>()?,
+                    )
+                }
+                "$size" => match Decimal::from_bson(operand).and_then(|size| size.to_i64()) {
+                    Some(size) if size >= 0 => Condition::Size(size as usize),
+                    _ => return Err(MatcherError::InvalidOperand("$size")),
+                },
+                "$mod" => {
+                    let operands = match operand {
+                        Bson::Array(operands) if operands.len() == 2 => operands
+                            .iter()
+                            .map(|operand| Decimal::from_bson(operand)?.to_i64())
+                            .collect::<Option<Vec<_>>>(),
+                        _ => None,
+                    };
+                    match operands.as_deref() {
+                        Some([divisor, remainder]) if *divisor != 0 => {
+                            Condition::Mod(*divisor, *remainder)
+                        }
+                        _ => return Err(MatcherError::InvalidOperand("$mod")),
+                    }
+                }
+                operator => return Err(MatcherError::UnknownOperator(operator.to_string())),
+            });
+        }
+
+        Ok(conditions)
+    }
+
+    /// Like in MongoDB, a regular expression as the value matches the strings it matches.
+    fn value(value: &Bson) -> Result<Self, MatcherError> {
+        Ok(match value {
+            Bson::RegularExpression(expression) => {
+                Condition::Regex(regex(&expression.pattern, &expression.options)?)
+            }
+            value => Condition::Eq(value.clone()),
+        })
+    }
+
+    fn values(operand: &Bson, operator: &'static str) -> Result<Vec<Self>, MatcherError> {
+        match operand {
+            Bson::Array(values) => values.iter().map(Condition::value).collect(),
+            _ => Err(MatcherError::InvalidOperand(operator)),
+        }
+    }
+
+    fn matches(&self, values: &[&Bson], case_insensitive: bool) -> bool {
+        match self {
+            Condition::Eq(expected) => matches_eq(values, expected, case_insensitive),
+            Condition::Ne(expected) => !matches_eq(values, expected, case_insensitive),
+            Condition::Cmp(ordering, or_equal, operand) => {
+                any_value(values, |value| {
+                    match compare(value, operand, case_insensitive) {
+                        Some(Ordering::Equal) => *or_equal,
+                        Some(result) => result == *ordering,
+                        None => false,
+                    }
+                })
+            }
+            Condition::In(expected) => expected
+                .iter()
+                .any(|expected| expected.matches(values, case_insensitive)),
+            Condition::Nin(expected) => !expected
+                .iter()
+                .any(|expected| expected.matches(values, case_insensitive)),
+            Condition::Exists(exists) => values.is_empty() != *exists,
+            Condition::Type(types) => {
+                // Only an array matches the array type, not its elements
+                let is_array = |value: &&Bson| matches!(value, Bson::Array(_));
+                types.iter().any(|spec| match spec {
+                    TypeSpec::Element(ElementType::Array) => values.iter().any(is_array),
+                    spec => any_value(values, |value| spec.matches(value)),
+                })
+            }
+            Condition::Regex(regex) => any_value(values, |value| match value {
+                Bson::String(string) | Bson::Symbol(string) => regex.is_match(string),
+                _ => false,
+            }),
+            Condition::Not(conditions) => !conditions
+                .iter()
+                .all(|condition| condition.matches(values, case_insensitive)),
+            Condition::ElemMatch(elem_match) => values.iter().any(|value| match value {
+                Bson::Array(elements) => elements
+                    .iter()
+                    .any(|element| elem_match.matches(element, case_insensitive)),
+                _ => false,
+            }),
+            Condition::All(conditions) => {
+                !conditions.is_empty()
+                    && conditions
+                        .iter()
+                        .all(|condition| condition.matches(values, case_insensitive))
+            }
+            Condition::Size(size) => values
+                .iter()
+                .any(|value| matches!(value, Bson::Array(elements) if elements.len() == *size)),
+            Condition::Mod(divisor, remainder) => any_value(values, |value| {
+                let value = match value {
+                    Bson::Int32(value) => *value as i64,
+                    Bson::Int64(value) => *value,
+                    // Truncated towards zero, like by the server
+                    Bson::Double(value) if value.is_finite() => value.trunc() as i64,
+                    Bson::Decimal128(_) => match Decimal::from_bson(value).and_then(|v| v.to_i64())
+                    {
+                        Some(value) => value,
+                        None => return false,
+                    },
+                    _ => return false,
+                };
+                value.wrapping_rem(*divisor) == *remainder
+            }),
+        }
+    }
+}
+
+impl ElemMatch {
+    fn parse(operand: &Bson) -> Result<Self, MatcherError> {
+        let Bson::Document(operand) = operand else {
+            return Err(MatcherError::InvalidOperand("$elemMatch"));
+        };
+
+        // A logical operator makes it a query, like any field name
+        let logical = ["$and", "$or", "$nor"];
+        let conditions = !operand.is_empty()
+            && operand
+                .keys()
+                .all(|key| key.starts_with('$') && !logical.contains(&key.as_str()));
+        Ok(match conditions {
+            true => ElemMatch::Conditions(Condition::parse(&Bson::Document(operand.clone()))?),
+            false => ElemMatch::Query(Box::new(Query::parse(operand)?)),
+        })
+    }
+
+    fn matches(&self, element: &Bson, case_insensitive: bool) -> bool {
+        match self {
+            ElemMatch::Query(query) => match element {
+                Bson::Document(document) => query.matches(document, case_insensitive),
+                _ => false,
+            },
+            ElemMatch::Conditions(conditions) => conditions
+                .iter()
+                .all(|condition| condition.matches(&[element], case_insensitive)),
+        }
+    }
+}
+
+impl TypeSpec {
+    fn parse(operand: &Bson) -> Result<Self, MatcherError> {
+        let element_type = match operand {
+            Bson::String(name) => match name.as_str() {
+                "number" => return Ok(TypeSpec::Number),
+                "double" => ElementType::Double,
+                "string" => ElementType::String,
+                "object" => ElementType::EmbeddedDocument,
+                "array" => ElementType::Array,
+                "binData" => ElementType::Binary,
+                "undefined" => ElementType::Undefined,
+                "objectId" => ElementType::ObjectId,
+                "bool" => ElementType::Boolean,
+                "date" => ElementType::DateTime,
+                "null" => ElementType::Null,
+                "regex" => ElementType::RegularExpression,
+                "dbPointer" => ElementType::DbPointer,
+                "javascript" => ElementType::JavaScriptCode,
+                "symbol" => ElementType::Symbol,
+                "javascriptWithScope" => ElementType::JavaScriptCodeWithScope,
+                "int" => ElementType::Int32,
+                "timestamp" => ElementType::Timestamp,
+                "long" => ElementType::Int64,
+                "decimal" => ElementType::Decimal128,
+                "minKey" => ElementType::MinKey,
+                "maxKey" => ElementType::MaxKey,
+                _ => return Err(MatcherError::InvalidOperand("$type")),
+            },
+            operand => match Decimal::from_bson(operand).and_then(|code| code.to_i64()) {
+                // The number of `minKey` is -1 in queries, rather than its tag
+                Some(-1) => ElementType::MinKey,
+                Some(code) => u8::try_from(code)
+                    .ok()
+                    .and_then(ElementType::from)
+                    .ok_or(MatcherError::InvalidOperand("$type"))?,
+                None => return Err(MatcherError::InvalidOperand("$type")),
+            },
+        };
+
+        Ok(TypeSpec::Element(element_type))
+    }
+
+    fn matches(&self, value: &Bson) -> bool {
+        match self {
+            TypeSpec::Number => matches!(
+                value,
+                Bson::Double(_) | Bson::Int32(_) | Bson::Int64(_) | Bson::Decimal128(_)
+            ),
+            TypeSpec::Element(element_type) => value.element_type() == *element_type,
+        }
+    }
+}
+
+fn is_operator_expression(object: &Document) -> bool {
+    !object.is_empty() && object.keys().all(|key| key.starts_with('$'))
+}
+
+/// Returns all values at the dot-separated `path`, descending into arrays along the way.
+fn resolve<'a>(document: &'a Document, path: &str) -> Vec<&'a Bson> {
+    let mut segments = path.split('.');
+    let first = segments.next().unwrap_or_default();
+    let mut values: Vec<&Bson> = document.get(first).into_iter().collect();
+
+    for segment in segments {
+        let mut next = Vec::new();
+
+        for value in values {
+            match value {
+                Bson::Document(object) => next.extend(object.get(segment)),
+                Bson::Array(elements) => match segment.parse::<usize>() {
+                    Ok(index) => next.extend(elements.get(index)),
+                    Err(_) => next.extend(elements.iter().filter_map(|element| match element {
+                        Bson::Document(object) => object.get(segment),
+                        _ => None,
+                    })),
+                },
+                _ => {}
+            }
+        }
+
+        values = next;
+    }
+
+    values
+}
+
+/// Whether `predicate` holds for any of the values or, for arrays, any of their elements.
+fn any_value(values: &[&Bson], predicate: impl Fn(&Bson) -> bool) -> bool {
+    values.iter().any(|value| {
+        predicate(value)
+            || match value {
+                Bson::Array(elements) => elements.iter().any(&predicate),
+                _ => false,
+            }
+    })
+}
+
+fn matches_eq(values: &[&Bson], expected: &Bson, case_insensitive: bool) -> bool {
+    // Like in MongoDB, `null` also matches fields that don't exist
+    (values.is_empty() && matches!(expected, Bson::Null))
+        || any_value(values, |value| value_eq(value, expected, case_insensitive))
+}
+
+fn value_eq(a: &Bson, b: &Bson, case_insensitive: bool) -> bool {
+    if let (Some(a), Some(b)) = (Decimal::from_bson(a), Decimal::from_bson(b)) {
+        return a == b;
+    }
+
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) if case_insensitive => {
+            a.to_lowercase() == b.to_lowercase()
+        }
+        (Bson::Array(a), Bson::Array(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(a, b)| value_eq(a, b, case_insensitive))
+        }
+        (Bson::Document(a), Bson::Document(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((ka, va), (kb, vb))| ka == kb && value_eq(va, vb, case_insensitive))
+        }
+        // `undefined` is treated like `null`
+        (Bson::Null | Bson::Undefined, Bson::Null | Bson::Undefined) => true,
+        (a, b) => a == b,
+    }
+}
+
+/// Compares two values of the same type; values of different types are incomparable.
+fn compare(a: &Bson, b: &Bson, case_insensitive: bool) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (Decimal::from_bson(a), Decimal::from_bson(b)) {
+        return Some(a.cmp(&b));
+    }
+
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) if case_insensitive => {
+            Some(a.to_lowercase().cmp(&b.to_lowercase()))
+        }
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        (Bson::Timestamp(a), Bson::Timestamp(b)) => {
+            Some((a.time, a.increment).cmp(&(b.time, b.increment)))
+        }
+        (Bson::ObjectId(a), Bson::ObjectId(b)) => Some(a.bytes().cmp(&b.bytes())),
+        (Bson::MinKey, Bson::MinKey) | (Bson::MaxKey, Bson::MaxKey) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+impl Decimal {
+    fn from_bson(value: &Bson) -> Option<Self> {
+        match value {
+            Bson::Int32(number) => Decimal::from_str(&number.to_string()),
+            Bson::Int64(number) => Decimal::from_str(&number.to_string()),
+            Bson::Double(number) => Decimal::from_str(&number.to_string()),
+            Bson::Decimal128(number) => Decimal::from_str(&number.to_string()),
+            _ => None,
+        }
+    }
+
+    /// The number truncated towards zero; `None` if it doesn't fit into an `i64`.
+    fn to_i64(&self) -> Option<i64> {
+        let (negative, order, digits) = match self {
+            Decimal::Zero => return Some(0),
+            Decimal::Positive { order, digits } => (false, *order, digits),
+            Decimal::Negative { order, digits } => (true, order.0, &digits.0),
+            _ => return None,
+        };
+        if order <= 0 {
+            return Some(0);
+        }
+
+        let order = usize::try_from(order).ok().filter(|order| *order <= 19)?;
+        let mut integer: String = digits.chars().take(order).collect();
+        integer.extend(std::iter::repeat_n('0', order - integer.len()));
+        match negative {
+            true => format!("-{}", integer).parse().ok(),
+            false => integer.parse().ok(),
+        }
+    }
+}
@@ -0,0 +1,76 @@
+//! Creates a [`Mercurius`] with instance-wide defaults for its subscriptions, see
+//! [`Mercurius::builder`].
+
+use std::time::Duration;
+
+use mongodb::{options::FullDocumentType, Client, Database};
+
+use crate::{
+    channel::Overflow,
+    options::{OnTokenExpiry, SubscriptionDefaults},
+    Mercurius,
+};
+
+/// Collects the defaults that every subscription of the instance gets, unless it sets the option
+/// itself, e.g. with [`SubscriptionOptions::full_document`](crate::options::SubscriptionOptions::full_document).
+/// A default therefore can't be unset by a single subscription, e.g. a default capacity can
+/// only be replaced by another one.
+#[derive(Debug)]
+pub struct MercuriusBuilder {
+    db: Database,
+    client: Option<Client>,
+    defaults: SubscriptionDefaults,
+}
+
+impl MercuriusBuilder {
+    pub(crate) fn new(db: Database) -> Self {
+        Self {
+            db,
+            client: None,
+            defaults: SubscriptionDefaults::default(),
+        }
+    }
+
+    /// Keeps `client`, like [`Mercurius::from_client`]. `db` has to be one of its databases.
+    pub fn client(mut self, client: Client) -> Self {
+        self.client = Some(client);
+        self
+    }
+
+    /// See [`SubscriptionOptions::full_document`](crate::options::SubscriptionOptions::full_document).
+    pub fn default_full_document(mut self, full_document: FullDocumentType) -> Self {
+        self.defaults.full_document = Some(full_document);
+        self
+    }
+
+    /// See [`SubscriptionOptions::capacity`](crate::options::SubscriptionOptions::capacity).
+    /// Subscriptions with
+    /// [`SubscriptionOptions::latest_only`](crate::options::SubscriptionOptions::latest_only)
+    /// ignore it.
+    pub fn default_capacity(mut self, capacity: usize, overflow: Overflow) -> Self {
+        self.defaults.capacity = Some((capacity, overflow));
+        self
+    }
+
+    /// How change streams reconnect: they are resumed after a failover for up to
+    /// `failover_timeout`, see
+    /// [`SubscriptionOptions::resume_on_failover`](crate::options::SubscriptionOptions::resume_on_failover),
+    /// and go on according to `on_token_expiry` if their position has left the oplog, see
+    /// [`SubscriptionOptions::on_token_expiry`](crate::options::SubscriptionOptions::on_token_expiry).
+    pub fn reconnect(
+        mut self,
+        failover_timeout: impl Into<Option<Duration>>,
+        on_token_expiry: OnTokenExpiry,
+    ) -> Self {
+        self.defaults.failover_timeout = failover_timeout.into();
+        self.defaults.on_token_expiry = Some(on_token_expiry);
+        self
+    }
+
+    pub fn build(self) -> Mercurius {
+        let mut mercurius = Mercurius::new(self.db);
+        mercurius.client = self.client;
+        mercurius.defaults = self.defaults;
+        mercurius
+    }
+}
@@ -0,0 +1,1009 @@
+//! The channels through which subscriptions receive their events.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex, OnceLock,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use mongodb::{
+    bson::{Bson, DateTime, Timestamp},
+    change_stream::event::UpdateDescription,
+};
+
+use tokio::{
+    sync::{
+        mpsc::{self, error::TrySendError, OwnedPermit, UnboundedSender},
+        Notify,
+    },
+    time::Instant,
+};
+
+#[cfg(feature = "compression")]
+use crate::compression::Compressed;
+use crate::{
+    clock::{self, Clock, TokioClock},
+    receiver::{EventReceiver, Receiver},
+    subscription::{Event, EventMeta},
+    Handle,
+};
+
+/// Called with the handle of a subscription and the number of events it has dropped so far,
+/// whenever one of its events is dropped because its channel is full.
+pub type DropHandler = Arc<dyn Fn(&Handle, u64) + Send + Sync>;
+
+/// Called with the handle of a subscription and how long its consumer hasn't received any of its
+/// queued events, once that exceeds the threshold of
+/// [`Mercurius::on_stuck`](crate::Mercurius::on_stuck).
+pub type StuckHandler = Arc<dyn Fn(&Handle, Duration) + Send + Sync>;
+
+/// What happens to an event that doesn't fit into the channel of its subscription, see
+/// [`SubscriptionOptions::capacity`](crate::options::SubscriptionOptions::capacity). Only
+/// [`Overflow::Block`] waits for the receiver, and holds up the other subscriptions of the
+/// collection meanwhile.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Overflow {
+    /// The event is dropped and sent to the dead-letter channel; the subscription receives the
+    /// events that follow.
+    #[default]
+    Drop,
+    /// The subscription is removed and the event is sent to the dead-letter channel. The receiver
+    /// returns the events that it has buffered, and then `None`.
+    Close,
+    /// The change stream of the collection isn't read any further until the channel has room
+    /// again, so that a slow receiver slows down the reading instead of missing changes. A
+    /// subscription whose channel stays full for longer than the given time is removed, so that
+    /// it doesn't hold up the others indefinitely; the receiver returns the events that it has
+    /// buffered, and then `None`. The change stream waits for room before each change, batch
+    /// boundary and [`Event::CaughtUp`]. Other events don't wait and are dropped like with
+    /// [`Overflow::Drop`] when the channel is full: those that follow a change right away, like
+    /// the [`Event::Closed`] after the last change of
+    /// [`SubscriptionOptions::take`](crate::options::SubscriptionOptions::take), and those that
+    /// don't stem from the change stream, like the documents of
+    /// [`SubscriptionOptions::prime`](crate::options::SubscriptionOptions::prime) or
+    /// [`Event::ConfigurationChanged`].
+    Block(Duration),
+}
+
+/// An event that could not be sent to a subscription.
+#[derive(Debug)]
+pub(crate) enum SendFailure {
+    /// The receiver has been dropped or has fallen behind with [`Overflow::Close`]; the
+    /// subscription has to be removed.
+    Closed(Event),
+    /// The channel is full and the event has been dropped. `dropped` is the number of events the
+    /// subscription has dropped so far.
+    Dropped { event: Event, dropped: u64 },
+}
+
+impl SendFailure {
+    pub(crate) fn into_event(self) -> Event {
+        match self {
+            SendFailure::Closed(event) | SendFailure::Dropped { event, .. } => event,
+        }
+    }
+}
+
+/// The change of a collection that is being dispatched, from which the [`EventMeta`] of the
+/// events that are sent is taken. Shared by all subscriptions on the collection.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Sequence(Arc<SequenceState>);
+
+#[derive(Debug, Default)]
+struct SequenceState {
+    /// The number of changes that have been dispatched, see [`EventMeta::seq`].
+    seq: AtomicU64,
+    /// The cluster time and the wall time of the change that is being dispatched, the latter in
+    /// milliseconds since the epoch.
+    change: StdMutex<(Option<Timestamp>, Option<i64>)>,
+}
+
+impl Sequence {
+    /// Starts dispatching the next change.
+    pub(crate) fn advance(&self, cluster_time: Option<Timestamp>, wall_time: Option<DateTime>) {
+        self.0.seq.fetch_add(1, Ordering::Relaxed);
+        *self.0.change.lock().unwrap() =
+            (cluster_time, wall_time.map(|time| time.timestamp_millis()));
+    }
+
+    /// The change has been dispatched.
+    pub(crate) fn finish(&self) {
+        *self.0.change.lock().unwrap() = (None, None);
+    }
+
+    /// The metadata of an event that is sent now, according to `clock`.
+    fn meta(&self, clock: &dyn Clock) -> EventMeta {
+        let (cluster_time, wall_time) = *self.0.change.lock().unwrap();
+
+        EventMeta {
+            seq: self.0.seq.load(Ordering::Relaxed),
+            cluster_time,
+            delivery_latency: wall_time.map(|wall_time| {
+                let now = DateTime::from_system_time(clock.wall_time());
+                let latency = now.timestamp_millis() - wall_time;
+                // The clocks of the server and the client may differ
+                Duration::from_millis(latency.max(0) as u64)
+            }),
+            synthesized: false,
+        }
+    }
+}
+
+/// Counts the events that are buffered in the channels of all subscriptions of an instance, see
+/// [`Mercurius::max_in_flight`](crate::Mercurius::max_in_flight).
+#[derive(Debug, Clone)]
+pub(crate) struct FlowControl(Arc<FlowState>);
+
+#[derive(Debug)]
+struct FlowState {
+    in_flight: AtomicUsize,
+    max: usize,
+    low_water: usize,
+    /// Notified when the number of events in flight falls to `low_water`.
+    drained: Notify,
+}
+
+impl FlowControl {
+    pub(crate) fn new(max: usize, low_water: usize) -> Self {
+        Self(Arc::new(FlowState {
+            in_flight: AtomicUsize::new(0),
+            max,
+            low_water: low_water.min(max),
+            drained: Notify::new(),
+        }))
+    }
+
+    fn acquire(&self) {
+        self.0.in_flight.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn release(&self, count: usize) {
+        let previous = self.0.in_flight.fetch_sub(count, Ordering::Relaxed);
+        if previous > self.0.low_water && previous - count <= self.0.low_water {
+            self.0.drained.notify_waiters();
+        }
+    }
+
+    /// Waits until more events may be read: right away below the maximum, and otherwise once the
+    /// receivers have drained the channels to the low-water mark.
+    pub(crate) async fn ready(&self) {
+        if self.0.in_flight.load(Ordering::Relaxed) < self.0.max {
+            return;
+        }
+
+        loop {
+            // Created before checking, so that a release in between isn't missed
+            let drained = self.0.drained.notified();
+            if self.0.in_flight.load(Ordering::Relaxed) <= self.0.low_water {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+/// How far the consumer of a subscription has gotten with the events that were sent to it, see
+/// [`SubscriptionStats::idle`](crate::subscription::SubscriptionStats::idle).
+#[derive(Debug, Clone)]
+pub(crate) struct Activity {
+    state: Arc<StdMutex<ActivityState>>,
+    clock: Arc<dyn Clock>,
+}
+
+#[derive(Debug)]
+struct ActivityState {
+    /// The number of events that have been sent but not received yet.
+    pending: usize,
+    /// When the consumer last received an event, or when an event was queued while none were
+    /// pending, whichever was later.
+    since: Instant,
+}
+
+impl Activity {
+    fn new(clock: Arc<dyn Clock>) -> Self {
+        Self {
+            state: Arc::new(StdMutex::new(ActivityState {
+                pending: 0,
+                since: clock.now(),
+            })),
+            clock,
+        }
+    }
+
+    fn sent(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.pending == 0 {
+            state.since = self.clock.now();
+        }
+        state.pending += 1;
+    }
+
+    pub(crate) fn clock(&self) -> &Arc<dyn Clock> {
+        &self.clock
+    }
+
+    pub(crate) fn now(&self) -> Instant {
+        self.clock.now()
+    }
+
+    /// `count` events have been received, or have been replaced before they were.
+    pub(crate) fn received(&self, count: usize, handed_off: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.pending = state.pending.saturating_sub(count);
+        if handed_off {
+            state.since = self.clock.now();
+        }
+    }
+
+    /// The number of events that have been sent but not received yet.
+    #[cfg(feature = "compression")]
+    fn pending(&self) -> usize {
+        self.state.lock().unwrap().pending
+    }
+
+    /// How long the consumer hasn't received any of the events that are pending; `None` if
+    /// there are none.
+    pub(crate) fn idle(&self) -> Option<Duration> {
+        let state = self.state.lock().unwrap();
+        (state.pending > 0).then(|| self.clock.now().saturating_duration_since(state.since))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct EventSender {
+    kind: SenderKind,
+    /// Whether events are compressed while others are waiting, see
+    /// [`SubscriptionOptions::compress_backlog`](crate::options::SubscriptionOptions::compress_backlog).
+    #[cfg(feature = "compression")]
+    compress: bool,
+    /// Set once the subscription has been added to its collection.
+    sequence: Arc<OnceLock<Sequence>>,
+    dropped: Arc<AtomicU64>,
+    flow: Option<FlowControl>,
+    activity: Activity,
+    /// Keeps the events once the receiver has been dropped, see [`GracePeriod`].
+    grace: Option<(GracePeriod, Retained)>,
+}
+
+/// How long a subscription is kept once its receiver has been dropped, and how many events it
+/// holds for the next one meanwhile, see
+/// [`SubscriptionOptions::grace_period`](crate::options::SubscriptionOptions::grace_period).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct GracePeriod {
+    pub(crate) period: Duration,
+    pub(crate) capacity: usize,
+}
+
+/// The events of a subscription whose receiver has been dropped, which the next receiver gets
+/// first, see [`EventSender::reopen`].
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Retained(Arc<StdMutex<RetainedState>>);
+
+#[derive(Debug, Default)]
+struct RetainedState {
+    events: Vec<(Event, EventMeta)>,
+    /// When the receiver was dropped.
+    since: Option<Instant>,
+}
+
+impl Retained {
+    /// Takes the events that were still queued when the receiver was dropped. The receiver is
+    /// closed within `close`, so that no event is held before them.
+    pub(crate) fn close(&self, now: Instant, close: impl FnOnce() -> Vec<(Event, EventMeta)>) {
+        let mut state = self.0.lock().unwrap();
+        state.events = close();
+        state.since = Some(now);
+    }
+}
+
+impl RetainedState {
+    fn expired(&self, grace: GracePeriod, now: Instant) -> bool {
+        self.since
+            .is_some_and(|since| now.saturating_duration_since(since) >= grace.period)
+    }
+}
+
+#[derive(Debug, Clone)]
+enum SenderKind {
+    Unbounded(UnboundedSender<(Buffered, EventMeta)>),
+    Bounded {
+        sender: mpsc::Sender<(Buffered, EventMeta)>,
+        overflow: Overflow,
+        /// The room for the next event with [`Overflow::Block`], see [`EventSender::reserve`].
+        reserved: Arc<StdMutex<Option<Reserved>>>,
+    },
+    Coalescing(CoalescingSender),
+}
+
+/// Room for an event in a bounded channel.
+type Reserved = OwnedPermit<(Buffered, EventMeta)>;
+
+/// An event in the channel of a subscription.
+#[derive(Debug)]
+pub(crate) enum Buffered {
+    Event(Event),
+    #[cfg(feature = "compression")]
+    Compressed(Compressed),
+}
+
+impl Buffered {
+    pub(crate) fn into_event(self) -> Event {
+        match self {
+            Buffered::Event(event) => event,
+            #[cfg(feature = "compression")]
+            Buffered::Compressed(compressed) => compressed.decompress(),
+        }
+    }
+}
+
+/// How the channel of a subscription is set up, see [`channel`]. Defaults to an unbounded
+/// channel without flow control or grace period, whose activity is measured by [`TokioClock`].
+#[derive(Clone)]
+pub(crate) struct ChannelConfig {
+    /// Holds at most this many events if given, handled by `overflow` once it's full.
+    pub(crate) capacity: Option<usize>,
+    pub(crate) overflow: Overflow,
+    /// Coalesces the events of each document, see
+    /// [`SubscriptionOptions::latest_only`](crate::options::SubscriptionOptions::latest_only).
+    pub(crate) latest_only: bool,
+    /// Whether the keys of the collection are its `_id`s, without which coalesced events can't
+    /// become inserts.
+    pub(crate) keyed_by_id: bool,
+    /// Counts the events, see [`FlowControl`].
+    pub(crate) flow: Option<FlowControl>,
+    /// Measures the activity of the channel.
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Keeps the events for the next receiver once the receiver is dropped.
+    pub(crate) grace: Option<GracePeriod>,
+}
+
+impl Default for ChannelConfig {
+    fn default() -> Self {
+        Self {
+            capacity: None,
+            overflow: Overflow::default(),
+            latest_only: false,
+            keyed_by_id: false,
+            flow: None,
+            clock: Arc::new(TokioClock),
+            grace: None,
+        }
+    }
+}
+
+/// Creates the channel of a subscription as described by `config`.
+pub(crate) fn channel(config: ChannelConfig) -> (EventSender, EventReceiver) {
+    let ChannelConfig {
+        capacity,
+        overflow,
+        latest_only,
+        keyed_by_id,
+        flow,
+        clock,
+        grace,
+    } = config;
+    let (kind, receiver) = open(capacity, overflow, latest_only, keyed_by_id);
+    let grace = grace.map(|grace| (grace, Retained::default()));
+
+    let activity = Activity::new(clock);
+    (
+        EventSender {
+            kind,
+            #[cfg(feature = "compression")]
+            compress: false,
+            sequence: Arc::new(OnceLock::new()),
+            dropped: Arc::new(AtomicU64::new(0)),
+            flow: flow.clone(),
+            activity: activity.clone(),
+            grace: grace.clone(),
+        },
+        EventReceiver::new(
+            receiver,
+            flow,
+            activity,
+            grace.map(|(_, retained)| retained),
+        ),
+    )
+}
+
+/// A channel with the [default](ChannelConfig::default) config.
+#[cfg(test)]
+pub(crate) fn test_channel() -> (EventSender, EventReceiver) {
+    channel(ChannelConfig::default())
+}
+
+fn open(
+    capacity: Option<usize>,
+    overflow: Overflow,
+    latest_only: bool,
+    keyed_by_id: bool,
+) -> (SenderKind, Receiver) {
+    match capacity {
+        _ if latest_only => {
+            let buffer = Arc::new(Coalescing {
+                keyed_by_id,
+                state: StdMutex::default(),
+            });
+            (
+                SenderKind::Coalescing(CoalescingSender::new(buffer.clone())),
+                Receiver::Coalescing(buffer),
+            )
+        }
+        Some(capacity) => {
+            let (sender, receiver) = mpsc::channel(capacity.max(1));
+            (
+                SenderKind::Bounded {
+                    sender,
+                    overflow,
+                    reserved: Arc::default(),
+                },
+                Receiver::Bounded(receiver),
+            )
+        }
+        None => {
+            let (sender, receiver) = mpsc::unbounded_channel();
+            (SenderKind::Unbounded(sender), Receiver::Unbounded(receiver))
+        }
+    }
+}
+
+impl EventSender {
+    /// Numbers the events that follow with the sequence of the collection.
+    pub(crate) fn attach(&self, sequence: Sequence) {
+        let _ = self.sequence.set(sequence);
+    }
+
+    /// Compresses the documents of the events that are queued behind others, see
+    /// [`SubscriptionOptions::compress_backlog`](crate::options::SubscriptionOptions::compress_backlog).
+    #[cfg(feature = "compression")]
+    pub(crate) fn compress_backlog(self, compress: bool) -> Self {
+        Self { compress, ..self }
+    }
+
+    /// Compresses the event if it has to wait for others to be received.
+    fn buffer(&self, event: Event) -> Buffered {
+        #[cfg(feature = "compression")]
+        if self.compress && self.activity.pending() > 1 {
+            return match Compressed::new(event) {
+                Ok(compressed) => Buffered::Compressed(compressed),
+                Err(event) => Buffered::Event(event),
+            };
+        }
+
+        Buffered::Event(event)
+    }
+
+    pub(crate) fn send(&self, event: Event) -> Result<(), SendFailure> {
+        self.send_with(event, false)
+    }
+
+    /// Sends an event whose meta is [`EventMeta::synthesized`] if `synthesized` is set.
+    pub(crate) fn send_with(&self, event: Event, synthesized: bool) -> Result<(), SendFailure> {
+        let meta = self.meta(synthesized);
+        match self.detached() {
+            Some((grace, retained)) => self.retain(grace, retained, event, meta),
+            None => self.deliver(event, meta),
+        }
+    }
+
+    fn meta(&self, synthesized: bool) -> EventMeta {
+        let meta = match self.sequence.get() {
+            Some(sequence) => sequence.meta(&*self.activity.clock),
+            None => EventMeta {
+                seq: 0,
+                cluster_time: None,
+                delivery_latency: None,
+                synthesized: false,
+            },
+        };
+        EventMeta {
+            synthesized,
+            ..meta
+        }
+    }
+
+    fn deliver(&self, event: Event, meta: EventMeta) -> Result<(), SendFailure> {
+        // Counted before it's sent, so that the receiver never releases an event that hasn't
+        // been counted yet
+        if let Some(flow) = &self.flow {
+            flow.acquire();
+        }
+        self.activity.sent();
+
+        let result = match &self.kind {
+            SenderKind::Unbounded(sender) => sender.send((self.buffer(event), meta)).map_err(
+                |mpsc::error::SendError((event, _))| SendFailure::Closed(event.into_event()),
+            ),
+            SenderKind::Bounded {
+                sender,
+                overflow,
+                reserved,
+            } => {
+                let reserved = reserved.lock().unwrap().take();
+                match reserved {
+                    Some(reserved) => {
+                        reserved.send((self.buffer(event), meta));
+                        Ok(())
+                    }
+                    None => match sender.try_send((self.buffer(event), meta)) {
+                        Ok(()) => Ok(()),
+                        Err(TrySendError::Closed((event, _))) => {
+                            Err(SendFailure::Closed(event.into_event()))
+                        }
+                        Err(TrySendError::Full((event, _))) => match overflow {
+                            Overflow::Drop | Overflow::Block(_) => {
+                                Err(self.drop_event(event.into_event()))
+                            }
+                            Overflow::Close => Err(SendFailure::Closed(event.into_event())),
+                        },
+                    },
+                }
+            }
+            SenderKind::Coalescing(sender) => match sender.0.send(event, meta) {
+                Ok(coalesced) => {
+                    if coalesced > 0 {
+                        self.activity.received(coalesced, false);
+                        if let Some(flow) = &self.flow {
+                            flow.release(coalesced);
+                        }
+                    }
+                    Ok(())
+                }
+                Err(event) => Err(SendFailure::Closed(event)),
+            },
+        };
+
+        if result.is_err() {
+            self.activity.received(1, false);
+            if let Some(flow) = &self.flow {
+                flow.release(1);
+            }
+        }
+        result
+    }
+
+    /// Whether the channel has [`Overflow::Block`].
+    pub(crate) fn blocks(&self) -> bool {
+        matches!(
+            self.kind,
+            SenderKind::Bounded {
+                overflow: Overflow::Block(_),
+                ..
+            }
+        )
+    }
+
+    /// Waits until the channel has room for the next event with [`Overflow::Block`], which is
+    /// held for it, for at most the time of the policy. Returns `false` if it's still full then.
+    pub(crate) async fn reserve(&self) -> bool {
+        let SenderKind::Bounded {
+            sender,
+            overflow: Overflow::Block(timeout),
+            reserved,
+        } = &self.kind
+        else {
+            return true;
+        };
+        if reserved.lock().unwrap().is_some() {
+            return true;
+        }
+
+        let reserve = sender.clone().reserve_owned();
+        match clock::timeout(&*self.activity.clock, *timeout, reserve).await {
+            Some(Ok(permit)) => {
+                *reserved.lock().unwrap() = Some(permit);
+                true
+            }
+            // A dropped receiver is noticed with the next event
+            Some(Err(_)) => true,
+            None => false,
+        }
+    }
+
+    /// The grace period if the receiver has been dropped during it, see [`GracePeriod`].
+    fn detached(&self) -> Option<(GracePeriod, &Retained)> {
+        let (grace, retained) = self.grace.as_ref()?;
+        self.is_closed().then_some((*grace, retained))
+    }
+
+    /// Whether the receiver has been dropped and its events are held for the next one.
+    pub(crate) fn is_detached(&self) -> bool {
+        self.detached().is_some()
+    }
+
+    /// Holds an event for the next receiver. Fails with [`SendFailure::Closed`] once the grace
+    /// period has elapsed, and drops the event if as many as it allows are held already.
+    fn retain(
+        &self,
+        grace: GracePeriod,
+        retained: &Retained,
+        event: Event,
+        meta: EventMeta,
+    ) -> Result<(), SendFailure> {
+        let mut state = retained.0.lock().unwrap();
+        if state.expired(grace, self.activity.clock.now()) {
+            return Err(SendFailure::Closed(event));
+        }
+        if state.events.len() >= grace.capacity {
+            drop(state);
+            return Err(self.drop_event(event));
+        }
+
+        state.events.push((event, meta));
+        Ok(())
+    }
+
+    /// Replaces the dropped receiver during the grace period with a new one, which first receives
+    /// the events that were held for it. `None` if the receiver hasn't been dropped, or the
+    /// grace period has elapsed.
+    pub(crate) fn reopen(&self) -> Option<(EventSender, EventReceiver)> {
+        let (grace, retained) = self.detached()?;
+        let mut state = retained.0.lock().unwrap();
+        if state.expired(grace, self.activity.clock.now()) {
+            return None;
+        }
+
+        let (kind, receiver) = match &self.kind {
+            SenderKind::Unbounded(_) => open(None, Overflow::default(), false, false),
+            SenderKind::Bounded {
+                sender, overflow, ..
+            } => open(Some(sender.max_capacity()), *overflow, false, false),
+            SenderKind::Coalescing(sender) => {
+                open(None, Overflow::default(), true, sender.0.keyed_by_id)
+            }
+        };
+        let activity = Activity::new(self.activity.clock.clone());
+        let sender = EventSender {
+            kind,
+            activity: activity.clone(),
+            ..self.clone()
+        };
+        let receiver = EventReceiver::new(
+            receiver,
+            self.flow.clone(),
+            activity,
+            Some(retained.clone()),
+        );
+
+        // Counted again like any other event, which could overflow a bounded channel
+        state.since = None;
+        for (event, meta) in state.events.drain(..) {
+            let _ = sender.deliver(event, meta);
+        }
+
+        Some((sender, receiver))
+    }
+
+    pub(crate) fn is_closed(&self) -> bool {
+        match &self.kind {
+            SenderKind::Unbounded(sender) => sender.is_closed(),
+            SenderKind::Bounded { sender, .. } => sender.is_closed(),
+            SenderKind::Coalescing(sender) => sender.0.state.lock().unwrap().closed,
+        }
+    }
+
+    /// Counts an event that isn't sent, e.g. because the channel is full.
+    pub(crate) fn drop_event(&self, event: Event) -> SendFailure {
+        SendFailure::Dropped {
+            event,
+            dropped: self.dropped.fetch_add(1, Ordering::Relaxed) + 1,
+        }
+    }
+
+    /// The number of events that have been dropped, see [`EventSender::drop_event`].
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn idle(&self) -> Option<Duration> {
+        self.activity.idle()
+    }
+}
+
+/// The buffer of a subscription with
+/// [`SubscriptionOptions::latest_only`](crate::options::SubscriptionOptions::latest_only), which
+/// keeps only the latest state of each document that hasn't been received yet.
+#[derive(Debug)]
+pub(crate) struct Coalescing {
+    /// Whether the key of an inserted document is its `_id`, see
+    /// [`Mercurius::key_fn`](crate::Mercurius::key_fn).
+    keyed_by_id: bool,
+    state: StdMutex<CoalescingState>,
+}
+
+#[derive(Debug, Default)]
+struct CoalescingState {
+    /// The pending events of a document, or a single event that isn't about a document, in the
+    /// order in which they were first sent.
+    slots: VecDeque<Slot>,
+    /// The position of the slot of each document that its next events are coalesced into,
+    /// counted from the first slot. Emptied by every event that isn't about a document, so that
+    /// no event is moved before it.
+    keys: HashMap<Arc<String>, u64>,
+    /// The number of slots that have been received.
+    received: u64,
+    senders: usize,
+    /// Whether the receiver has been dropped.
+    closed: bool,
+    /// Woken when an event is sent or the last sender is dropped.
+    waker: Option<Waker>,
+}
+
+impl CoalescingState {
+    fn pop(&mut self) -> Option<(Event, EventMeta)> {
+        let slot = self.slots.front_mut()?;
+        let next = slot.events.pop_front();
+        if slot.events.is_empty() {
+            let slot = self.slots.pop_front().unwrap();
+            let position = self.received;
+            self.received += 1;
+            if let Some(key) = slot.key {
+                if self.keys.get(&key) == Some(&position) {
+                    self.keys.remove(&key);
+                }
+            }
+        }
+        next
+    }
+}
+
+#[derive(Debug)]
+struct Slot {
+    key: Option<Arc<String>>,
+    events: VecDeque<(Event, EventMeta)>,
+}
+
+impl Coalescing {
+    /// Returns the number of pending events that the event has replaced, or the event if the
+    /// receiver has been dropped.
+    fn send(&self, event: Event, meta: EventMeta) -> Result<usize, Event> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            return Err(event);
+        }
+
+        let key = document_key(&event, self.keyed_by_id);
+        let slot = key
+            .as_ref()
+            .and_then(|key| state.keys.get(key))
+            .map(|position| (position - state.received) as usize);
+        let coalesced = match slot {
+            Some(slot) => coalesce(&mut state.slots[slot].events, event, meta),
+            None => {
+                let position = state.received + state.slots.len() as u64;
+                match &key {
+                    Some(key) => state.keys.insert(key.clone(), position),
+                    None => {
+                        state.keys.clear();
+                        None
+                    }
+                };
+                state.slots.push_back(Slot {
+                    key,
+                    events: VecDeque::from([(event, meta)]),
+                });
+                0
+            }
+        };
+
+        if let Some(waker) = state.waker.take() {
+            waker.wake();
+        }
+        Ok(coalesced)
+    }
+
+    pub(crate) fn try_recv(&self) -> Option<(Event, EventMeta)> {
+        self.state.lock().unwrap().pop()
+    }
+
+    pub(crate) fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<(Event, EventMeta)>> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(next) = state.pop() {
+            return Poll::Ready(Some(next));
+        }
+        if state.senders == 0 {
+            return Poll::Ready(None);
+        }
+
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Stops accepting events; returns the events that are still pending.
+    pub(crate) fn close(&self) -> Vec<(Event, EventMeta)> {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.keys.clear();
+        state.slots.drain(..).flat_map(|slot| slot.events).collect()
+    }
+}
+
+/// Counts the senders of a [`Coalescing`] buffer, so that the receiver knows when the
+/// subscription has been removed.
+#[derive(Debug)]
+struct CoalescingSender(Arc<Coalescing>);
+
+impl CoalescingSender {
+    fn new(buffer: Arc<Coalescing>) -> Self {
+        buffer.state.lock().unwrap().senders += 1;
+        Self(buffer)
+    }
+}
+
+impl Clone for CoalescingSender {
+    fn clone(&self) -> Self {
+        Self::new(self.0.clone())
+    }
+}
+
+impl Drop for CoalescingSender {
+    fn drop(&mut self) {
+        let mut state = self.0.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            if let Some(waker) = state.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// The document that an event is about, whose pending events it can be coalesced with.
+fn document_key(event: &Event, keyed_by_id: bool) -> Option<Arc<String>> {
+    match event {
+        Event::Removed(key)
+        | Event::Left(key)
+        | Event::Updated((key, _))
+        | Event::Replaced((key, _))
+        | Event::PreImageUnavailable { key, .. } => Some(key.clone()),
+        Event::Added(document) | Event::Entered(document) if keyed_by_id => {
+            match document.get("_id") {
+                Some(Bson::String(key)) => Some(Arc::new(key.clone())),
+                Some(Bson::ObjectId(key)) => Some(Arc::new(key.to_hex())),
+                _ => None,
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Adds `event` to the pending events of its document, which are either a single event or one
+/// with the document followed by an update. Returns the number of events it replaces.
+fn coalesce(events: &mut VecDeque<(Event, EventMeta)>, event: Event, meta: EventMeta) -> usize {
+    match event {
+        // An update can only replace an update, as it only holds the changed fields
+        Event::Updated((key, update)) => {
+            if let Some((Event::Updated((_, pending)), pending_meta)) = events.back_mut() {
+                if let Some(merged) = merge_updates(pending, &update) {
+                    *pending = Arc::new(merged);
+                    *pending_meta = meta;
+                    return 1;
+                }
+            }
+            events.push_back((Event::Updated((key, update)), meta));
+            0
+        }
+        event => {
+            // The receiver doesn't know a document that it hasn't received yet
+            let event = match (events.front(), event) {
+                (Some((Event::Added(_), _)), Event::Replaced((_, document))) => {
+                    Event::Added(document)
+                }
+                (Some((Event::Entered(_), _)), Event::Replaced((_, document))) => {
+                    Event::Entered(document)
+                }
+                (_, event) => event,
+            };
+            let replaced = events.len();
+            events.clear();
+            events.push_back((event, meta));
+            replaced
+        }
+    }
+}
+
+/// A single update that has the effect of `earlier` followed by `later`. Returns `None` if that
+/// can't be expressed, e.g. when `later` sets a field within one that `earlier` changed, or
+/// either truncates an array.
+fn merge_updates(
+    earlier: &UpdateDescription,
+    later: &UpdateDescription,
+) -> Option<UpdateDescription> {
+    let truncates = |update: &UpdateDescription| {
+        update
+            .truncated_arrays
+            .as_ref()
+            .is_some_and(|arrays| !arrays.is_empty())
+    };
+    if truncates(earlier) || truncates(later) {
+        return None;
+    }
+
+    let within = |field: &str, path: &str| {
+        field
+            .strip_prefix(path)
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+    };
+    let earlier_paths: Vec<&String> = earlier
+        .updated_fields
+        .keys()
+        .chain(&earlier.removed_fields)
+        .collect();
+    let later_paths: Vec<&String> = later
+        .updated_fields
+        .keys()
+        .chain(&later.removed_fields)
+        .collect();
+    // Only a change that replaces an earlier one as a whole can be merged
+    if later_paths.iter().any(|path| {
+        earlier_paths
+            .iter()
+            .any(|earlier| within(path, earlier) && path != earlier)
+    }) {
+        return None;
+    }
+
+    let replaced = |field: &str| later_paths.iter().any(|path| within(field, path));
+    let mut updated_fields = earlier.updated_fields.clone();
+    for field in earlier
+        .updated_fields
+        .keys()
+        .filter(|field| replaced(field))
+    {
+        updated_fields.remove(field);
+    }
+    updated_fields.extend(later.updated_fields.clone());
+    let removed_fields: Vec<String> = earlier
+        .removed_fields
+        .iter()
+        .filter(|field| !replaced(field))
+        .chain(&later.removed_fields)
+        .cloned()
+        .collect();
+
+    let mut merged = mongodb::bson::to_document(later).ok()?;
+    merged.insert("updatedFields", updated_fields);
+    merged.insert("removedFields", removed_fields);
+    mongodb::bson::from_document(merged).ok()
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use mongodb::bson::{DateTime, Timestamp};
+
+    use super::{channel, ChannelConfig, Sequence};
+    use crate::clock::{Clock, TestClock};
+    use crate::subscription::Event;
+
+    #[test]
+    fn delivery_latency_follows_the_clock() {
+        let clock = TestClock::new();
+        let (sender, mut receiver) = channel(ChannelConfig {
+            clock: Arc::new(clock.clone()),
+            ..ChannelConfig::default()
+        });
+        let sequence = Sequence::default();
+        sender.attach(sequence.clone());
+
+        // A change that the server made right now
+        let wall_time = DateTime::from_system_time(clock.wall_time());
+        sequence.advance(
+            Some(Timestamp {
+                time: 1,
+                increment: 0,
+            }),
+            Some(wall_time),
+        );
+        clock.advance(Duration::from_secs(3));
+        sender.send(Event::Count(1)).unwrap();
+
+        let (_, meta) = receiver.try_recv_with_meta().unwrap();
+        assert_eq!(meta.delivery_latency, Some(Duration::from_secs(3)));
+    }
+}
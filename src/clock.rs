@@ -0,0 +1,149 @@
+//! The source of time of an instance, see [`Mercurius::clock`](crate::Mercurius::clock).
+
+use std::{
+    fmt::Debug,
+    future::Future,
+    pin::Pin,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(feature = "test-util")]
+use std::{
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context, Poll, Waker},
+};
+
+use tokio::time::Instant;
+
+/// The future of [`Clock::sleep`].
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Tells the time to everything of an instance that waits or measures time, e.g. the checks of
+/// [`Mercurius::on_stuck`](crate::Mercurius::on_stuck) and the timeouts of subscriptions.
+pub trait Clock: Debug + Send + Sync {
+    fn now(&self) -> Instant;
+
+    /// The wall time, which is compared with that of the server, e.g. for
+    /// [`EventMeta::delivery_latency`](crate::subscription::EventMeta::delivery_latency).
+    /// Defaults to the time of the system.
+    fn wall_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    /// Completes once `duration` has passed according to [`Clock::now`].
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The time of [`tokio::time`], which is the default.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct TokioClock;
+
+impl Clock for TokioClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// Fails with `None` if `future` doesn't complete within `duration` according to `clock`.
+pub(crate) async fn timeout<F: Future>(
+    clock: &dyn Clock,
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    tokio::select! {
+        output = future => Some(output),
+        () = clock.sleep(duration) => None,
+    }
+}
+
+/// A clock that only moves when it's told to with [`TestClock::advance`], so that time-based
+/// behaviour can be tested deterministically. Clones share their time.
+#[cfg(feature = "test-util")]
+#[derive(Debug, Clone)]
+pub struct TestClock(Arc<StdMutex<TestTime>>);
+
+#[cfg(feature = "test-util")]
+#[derive(Debug)]
+struct TestTime {
+    now: Instant,
+    /// The wall time at `now`.
+    wall_time: SystemTime,
+    /// The sleeps that are waiting for the time to move.
+    sleeping: Vec<Waker>,
+}
+
+#[cfg(feature = "test-util")]
+impl TestClock {
+    /// Starts at the current time, and the wall time at that of the system.
+    pub fn new() -> Self {
+        Self(Arc::new(StdMutex::new(TestTime {
+            now: Instant::now(),
+            wall_time: SystemTime::now(),
+            sleeping: Vec::new(),
+        })))
+    }
+
+    /// Moves the time forward, which completes the sleeps that end by then.
+    pub fn advance(&self, duration: Duration) {
+        let sleeping = {
+            let mut time = self.0.lock().unwrap();
+            time.now += duration;
+            time.wall_time += duration;
+            std::mem::take(&mut time.sleeping)
+        };
+        // Each sleep checks its end itself and waits again if it hasn't come yet
+        for waker in sleeping {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Default for TestClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clock for TestClock {
+    fn now(&self) -> Instant {
+        self.0.lock().unwrap().now
+    }
+
+    fn wall_time(&self) -> SystemTime {
+        self.0.lock().unwrap().wall_time
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(TestSleep {
+            time: self.0.clone(),
+            until: self.now() + duration,
+        })
+    }
+}
+
+#[cfg(feature = "test-util")]
+struct TestSleep {
+    time: Arc<StdMutex<TestTime>>,
+    until: Instant,
+}
+
+#[cfg(feature = "test-util")]
+impl Future for TestSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut time = self.time.lock().unwrap();
+        if time.now >= self.until {
+            return Poll::Ready(());
+        }
+
+        time.sleeping.push(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -1,32 +1,87 @@
-use std::sync::Arc;
+use std::{
+    cell::RefCell,
+    collections::{hash_map::RandomState, HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    future::Future,
+    hash::{BuildHasher, Hasher},
+    pin::pin,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex, Weak,
+    },
+    task::Poll,
+    time::Duration,
+};
+
+use futures_util::{
+    future::{join_all, AbortHandle},
+    poll, StreamExt,
+};
 
 use mongodb::{
-    bson::Document,
+    bson::{self, doc, Bson, Document, Timestamp},
     change_stream::{
-        event::{ChangeStreamEvent, OperationType},
+        event::{ChangeStreamEvent, OperationType, ResumeToken, UpdateDescription},
         ChangeStream,
     },
-    options::{ChangeStreamOptions, FullDocumentBeforeChangeType, FullDocumentType},
-    Collection,
-};
-use tokio::{
-    sync::{mpsc::UnboundedSender, Mutex},
-    task::{AbortHandle, JoinSet},
+    options::{
+        AggregateOptions, ChangeStreamOptions, FindOptions, FullDocumentBeforeChangeType,
+        FullDocumentType,
+    },
+    Collection, Cursor, Database,
 };
+use serde::Deserialize;
+use tokio::sync::{watch, Mutex, OnceCell};
 
-use crate::subscription::{Event, Subscription};
-
-use self::subscriptions_manager::{
-    SubscriptionHandle, SubscriptionsManager, SubscriptionsManagerError,
+use crate::{
+    audit::Audit,
+    channel::{DropHandler, FlowControl, SendFailure, Sequence, StuckHandler},
+    clock::Clock,
+    dead_letter::DeadLetter,
+    error::{classify, ErrorHandler, ErrorKind, EventError},
+    options::{CatchUp, MissingKey, OnTokenExpiry, PreImagesDisabled, SubscriptionOptions},
+    receiver::EventReceiver,
+    redaction::Redaction,
+    server_filter::ServerFilter,
+    spawner::{self, Spawner},
+    subscription::{
+        Candidate, DdlEvent, DocumentChange, Event, EventDocument, Primer, RawEvent, Subscription,
+        SubscriptionStats, TeardownReason, Transaction, Transition, TransitionEvent,
+        UnavailableReason,
+    },
+    Handle, KeyFn,
 };
 
+use self::subscriptions_manager::{SubscriptionCount, SubscriptionHandle, SubscriptionsManager};
+
 pub mod subscriptions_manager {
-    use std::{collections::HashMap, fmt::Display};
+    use std::{
+        collections::HashMap,
+        fmt::Display,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use tokio::sync::Notify;
 
     use crate::subscription::Subscription;
 
+    /// Identifies a subscription by its slot and the generation of the slot; a slot is reused
+    /// once its subscription has been removed, but its generation changes, so a handle is never
+    /// issued twice.
     #[derive(Debug, Hash, PartialEq, Eq, Clone, PartialOrd, Ord)]
-    pub struct SubscriptionHandle(usize);
+    pub struct SubscriptionHandle {
+        index: usize,
+        generation: u64,
+    }
+
+    impl SubscriptionHandle {
+        pub(crate) fn id(&self) -> usize {
+            self.index
+        }
+    }
 
     #[derive(Debug)]
     pub enum SubscriptionsManagerError {
@@ -36,16 +91,56 @@ pub mod subscriptions_manager {
     #[derive(Debug)]
     pub(crate) struct SubscriptionsManager {
         subscriptions: HashMap<SubscriptionHandle, Subscription>,
-        has_been_filled: bool,
-        next_index: usize,
+        /// The current generation of every slot that has been used.
+        generations: Vec<u64>,
+        /// The slots whose subscription has been removed.
+        free: Vec<usize>,
+        total: SubscriptionCount,
+    }
+
+    /// The number of subscriptions across all collections of an instance, see
+    /// [`Mercurius::wait_for_empty`](crate::Mercurius::wait_for_empty).
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct SubscriptionCount(Arc<CountState>);
+
+    #[derive(Debug, Default)]
+    struct CountState {
+        count: AtomicUsize,
+        /// Notified when the count falls to zero.
+        empty: Notify,
+    }
+
+    impl SubscriptionCount {
+        fn add(&self, count: usize) {
+            self.0.count.fetch_add(count, Ordering::Relaxed);
+        }
+
+        fn sub(&self, count: usize) {
+            if count > 0 && self.0.count.fetch_sub(count, Ordering::Relaxed) == count {
+                self.0.empty.notify_waiters();
+            }
+        }
+
+        /// Waits until there are no subscriptions.
+        pub(crate) async fn empty(&self) {
+            loop {
+                // Created before checking, so that a removal in between isn't missed
+                let empty = self.0.empty.notified();
+                if self.0.count.load(Ordering::Relaxed) == 0 {
+                    return;
+                }
+                empty.await;
+            }
+        }
     }
 
     impl SubscriptionsManager {
-        pub fn new() -> Self {
+        pub fn new(total: SubscriptionCount) -> Self {
             Self {
                 subscriptions: HashMap::new(),
-                has_been_filled: false,
-                next_index: 0,
+                generations: Vec::new(),
+                free: Vec::new(),
+                total,
             }
         }
 
@@ -53,48 +148,80 @@ pub mod subscriptions_manager {
             self.subscriptions.len()
         }
 
-        pub(crate) fn get_all(&self) -> impl Iterator<Item = &Subscription> {
-            self.subscriptions.values()
+        pub(crate) fn is_empty(&self) -> bool {
+            self.subscriptions.is_empty()
+        }
+
+        /// The handles of the subscriptions, ordered by their slots.
+        pub(crate) fn handles(&self) -> Vec<SubscriptionHandle> {
+            let mut handles: Vec<_> = self.subscriptions.keys().cloned().collect();
+            handles.sort();
+            handles
+        }
+
+        pub(crate) fn iter(&self) -> impl Iterator<Item = (&SubscriptionHandle, &Subscription)> {
+            self.subscriptions.iter()
         }
 
         pub(crate) fn add(
             &mut self,
             subscription: Subscription,
         ) -> Result<SubscriptionHandle, SubscriptionsManagerError> {
-            let handle = SubscriptionHandle(self.next_index);
-            let new_index = if self.has_been_filled {
-                self.find_free_index()?
-            } else {
-                match self.next_index.checked_add(1) {
-                    Some(v) => v,
-                    None => {
-                        self.has_been_filled = true;
-                        self.find_free_index()?
-                    }
+            let index = match self.free.pop() {
+                Some(index) => index,
+                None if self.generations.len() < usize::MAX => {
+                    self.generations.push(0);
+                    self.generations.len() - 1
                 }
+                None => return Err(SubscriptionsManagerError::NoFreeSlot),
             };
 
+            let handle = SubscriptionHandle {
+                index,
+                generation: self.generations[index],
+            };
             self.subscriptions.insert(handle.clone(), subscription);
-            self.next_index = new_index;
+            self.total.add(1);
             Ok(handle)
         }
 
-        pub(crate) fn remove(&mut self, handle: SubscriptionHandle) {
-            self.subscriptions.remove(&handle);
+        pub(crate) fn get(&self, handle: &SubscriptionHandle) -> Option<&Subscription> {
+            self.subscriptions.get(handle)
         }
 
-        fn find_free_index(&self) -> Result<usize, SubscriptionsManagerError> {
-            let mut keys: Vec<_> = self.subscriptions.keys().collect();
-            keys.sort();
+        pub(crate) fn get_mut(&mut self, handle: &SubscriptionHandle) -> Option<&mut Subscription> {
+            self.subscriptions.get_mut(handle)
+        }
 
-            #[allow(clippy::needless_range_loop)]
-            for i in 0..std::cmp::min(keys.len(), usize::MAX) {
-                if keys[i].0 != i {
-                    return Ok(i);
-                }
+        /// The handle of the subscription that currently occupies the slot `index`.
+        pub(crate) fn handle_at(&self, index: usize) -> Option<SubscriptionHandle> {
+            let handle = SubscriptionHandle {
+                index,
+                generation: *self.generations.get(index)?,
+            };
+            self.subscriptions.contains_key(&handle).then_some(handle)
+        }
+
+        /// Returns `None` for a stale handle, whose subscription has already been removed.
+        pub(crate) fn remove(&mut self, handle: &SubscriptionHandle) -> Option<Subscription> {
+            let subscription = self.subscriptions.remove(handle)?;
+            self.total.sub(1);
+
+            // A slot whose generations are exhausted is retired rather than reused
+            let generation = &mut self.generations[handle.index];
+            if let Some(next) = generation.checked_add(1) {
+                *generation = next;
+                self.free.push(handle.index);
             }
 
-            Err(SubscriptionsManagerError::NoFreeSlot)
+            Some(subscription)
+        }
+    }
+
+    impl Drop for SubscriptionsManager {
+        fn drop(&mut self) {
+            // The subscriptions of an entry that is torn down are gone as well
+            self.total.sub(self.subscriptions.len());
         }
     }
 
@@ -119,182 +246,3066 @@ pub mod subscriptions_manager {
             self.source()
         }
     }
+
+    #[cfg(test)]
+    mod tests {
+        use std::collections::HashSet;
+
+        use super::{SubscriptionCount, SubscriptionsManager};
+        use crate::{
+            channel::test_channel,
+            receiver::EventReceiver,
+            subscription::{Event, Subscription},
+        };
+
+        fn subscription() -> (Subscription, EventReceiver) {
+            let (sender, receiver) = test_channel();
+            (Subscription::new(None, sender).unwrap(), receiver)
+        }
+
+        /// A deterministic sequence of pseudo-random numbers.
+        fn numbers(mut state: u64) -> impl Iterator<Item = u64> {
+            std::iter::repeat_with(move || {
+                state = state
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                state >> 33
+            })
+        }
+
+        #[test]
+        fn handles_are_never_reissued() {
+            let total = SubscriptionCount::default();
+            let mut subscriptions = SubscriptionsManager::new(total.clone());
+            let mut issued = HashSet::new();
+            let mut live = Vec::new();
+            let mut removed = Vec::new();
+
+            for number in numbers(7).take(100_000) {
+                // Adds slightly more often than it removes, so that the slots are reused at
+                // every size
+                if live.is_empty() || number % 5 < 3 {
+                    let handle = subscriptions.add(subscription().0).unwrap();
+                    assert!(issued.insert(handle.clone()), "{handle:?} reissued");
+                    live.push(handle);
+                } else {
+                    let handle = live.swap_remove(number as usize % live.len());
+                    assert!(subscriptions.remove(&handle).is_some());
+                    removed.push(handle);
+                }
+            }
+
+            assert_eq!(subscriptions.len(), live.len());
+            assert_eq!(
+                total.0.count.load(std::sync::atomic::Ordering::Relaxed),
+                live.len()
+            );
+            for handle in &removed {
+                assert!(subscriptions.get(handle).is_none(), "{handle:?} is stale");
+                assert!(
+                    subscriptions.remove(handle).is_none(),
+                    "{handle:?} is stale"
+                );
+            }
+            for handle in &live {
+                assert!(subscriptions.get(handle).is_some());
+                assert_eq!(subscriptions.handle_at(handle.id()).as_ref(), Some(handle));
+            }
+            assert_eq!(subscriptions.len(), live.len());
+        }
+
+        #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+        async fn many_events_through_many_subscriptions() {
+            const SUBSCRIPTIONS: usize = 200;
+            const EVENTS: u64 = 2_000;
+
+            let mut subscriptions = SubscriptionsManager::new(SubscriptionCount::default());
+            let receivers: Vec<_> = (0..SUBSCRIPTIONS)
+                .map(|_| {
+                    let (subscription, mut receiver) = subscription();
+                    subscriptions.add(subscription).unwrap();
+                    tokio::spawn(async move {
+                        let mut events = Vec::new();
+                        while let Some(event) = receiver.recv().await {
+                            events.push(event);
+                        }
+                        events
+                    })
+                })
+                .collect();
+
+            for count in 0..EVENTS {
+                for (_, subscription) in subscriptions.iter() {
+                    subscription.send(Event::Count(count)).unwrap();
+                }
+                if count % 100 == 0 {
+                    tokio::task::yield_now().await;
+                }
+            }
+            // Ends the receivers once they have received everything
+            drop(subscriptions);
+
+            let expected: Vec<_> = (0..EVENTS).map(Event::Count).collect();
+            for receiver in receivers {
+                assert_eq!(receiver.await.unwrap(), expected);
+            }
+        }
+    }
 }
 
-#[derive(Debug)]
-pub struct CollectionEntry {
-    // TODO: Convert to RwLock?
-    subscriptions: Arc<Mutex<SubscriptionsManager>>,
-    change_stream_handle: AbortHandle,
+/// The position of a change stream: the last point in time up to which all changes have been
+/// dispatched to the subscriptions.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct StreamPosition {
+    pub(crate) cluster_time: Option<Timestamp>,
+    pub(crate) resume_token: Option<ResumeToken>,
 }
 
-impl CollectionEntry {
-    pub async fn new(
-        collection: Collection<Document>,
-        join_set: &mut JoinSet<()>,
-    ) -> Result<Self, mongodb::error::Error> {
-        // TODO: Consider a single change stream instead of one per collection
-        let change_stream = collection
-            .watch(
-                None,
-                ChangeStreamOptions::builder()
-                    .full_document(Some(FullDocumentType::UpdateLookup))
-                    .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
-                    .build(),
+impl StreamPosition {
+    /// Where a stream that continues from this position starts: after its resume token if there
+    /// is one, and otherwise at its cluster time.
+    fn start(&self) -> StreamStart {
+        match &self.resume_token {
+            Some(token) => StreamStart::ResumeAfter(token.clone()),
+            None => StreamStart::At(self.cluster_time),
+        }
+    }
+}
+
+/// Instance-wide callbacks, channels and settings of a collection entry.
+#[derive(Clone)]
+pub(crate) struct Hooks {
+    pub(crate) dead_letter: Option<DeadLetter>,
+    pub(crate) audit: Option<Audit>,
+    pub(crate) on_error: Option<ErrorHandler>,
+    pub(crate) on_drop: Option<DropHandler>,
+    /// The number of changes that are kept for [`SubscriptionOptions::replay`].
+    pub(crate) replay_capacity: usize,
+    pub(crate) flow: Option<FlowControl>,
+    pub(crate) subscription_count: SubscriptionCount,
+    pub(crate) applied_pre_images: AppliedPreImages,
+    /// See [`Mercurius::redact_fields`](crate::Mercurius::redact_fields).
+    pub(crate) redaction: Option<Redaction>,
+    pub(crate) pre_image_check: Option<PreImageCheck>,
+    pub(crate) stuck_check: Option<StuckCheck>,
+    /// See [`Mercurius::filter_on_server`](crate::Mercurius::filter_on_server).
+    pub(crate) filter_on_server: bool,
+    /// See [`Mercurius::key_fn`](crate::Mercurius::key_fn).
+    pub(crate) key_fn: Option<KeyFn>,
+    /// See [`Mercurius::pause_all`](crate::Mercurius::pause_all).
+    pub(crate) paused: watch::Receiver<bool>,
+    /// See [`Mercurius::clock`](crate::Mercurius::clock).
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Hooks")
+    }
+}
+
+/// Where a change stream starts.
+#[derive(Debug, Clone)]
+pub(crate) enum StreamStart {
+    /// At the given cluster time, or now.
+    At(Option<Timestamp>),
+    ResumeAfter(ResumeToken),
+    StartAfter(ResumeToken),
+}
+
+/// The pipeline and options of the change stream of a collection. They are resolved once from the
+/// options of the subscription that opens the stream and reused whenever it's reopened, e.g.
+/// after a failover; only where it starts differs.
+#[derive(Debug, Clone)]
+pub(crate) struct WatchOptions {
+    /// The `$match` stage of the [`ServerFilter`], which precedes the pipeline.
+    filter: Option<Document>,
+    pipeline: Vec<Document>,
+    options: ChangeStreamOptions,
+    /// See [`SubscriptionOptions::show_expanded_events`].
+    expanded_events: bool,
+}
+
+impl WatchOptions {
+    pub(crate) fn new(options: &SubscriptionOptions) -> Self {
+        let watch_options = ChangeStreamOptions::builder()
+            .full_document(Some(
+                options
+                    .full_document
+                    .clone()
+                    .unwrap_or(FullDocumentType::UpdateLookup),
+            ))
+            .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+            .read_concern(options.read_concern.clone())
+            .selection_criteria(options.selection_criteria.clone())
+            .max_await_time(options.max_await_time)
+            .collation(options.collation.clone())
+            .batch_size(options.batch_size)
+            .build();
+
+        Self {
+            filter: None,
+            pipeline: options.stream_pipeline(),
+            options: watch_options,
+            expanded_events: options.show_expanded_events,
+        }
+    }
+
+    pub(crate) fn filtered(&self, filter: &ServerFilter) -> Self {
+        Self {
+            filter: filter.stage(),
+            ..self.clone()
+        }
+    }
+
+    pub(crate) async fn watch(
+        &self,
+        collection: &Collection<Document>,
+        start: &StreamStart,
+    ) -> Result<Changes, mongodb::error::Error> {
+        let mut options = self.options.clone();
+        match start {
+            StreamStart::At(time) => options.start_at_operation_time = *time,
+            StreamStart::ResumeAfter(token) => options.resume_after = Some(token.clone()),
+            StreamStart::StartAfter(token) => options.start_after = Some(token.clone()),
+        }
+
+        let pipeline = self.filter.iter().chain(&self.pipeline).cloned();
+        if !self.expanded_events {
+            let change_stream = collection.watch(pipeline, options).await?;
+            return Ok(Changes::Stream(Box::new(change_stream.with_type())));
+        }
+
+        // The options that aren't part of the stage are those of the aggregation
+        let mut stage = bson::to_document(&options)?;
+        stage.insert("showExpandedEvents", true);
+        let aggregate_options = AggregateOptions::builder()
+            .batch_size(options.batch_size)
+            .max_await_time(options.max_await_time)
+            .collation(options.collation)
+            .read_concern(options.read_concern)
+            .selection_criteria(options.selection_criteria)
+            .build();
+        let cursor = collection
+            .aggregate(
+                std::iter::once(doc! { "$changeStream": stage }).chain(pipeline),
+                aggregate_options,
             )
             .await?;
 
-        let subscriptions = Arc::new(Mutex::new(SubscriptionsManager::new()));
+        Ok(Changes::Expanded {
+            cursor: Box::new(cursor),
+            resume_token: options.start_after.or(options.resume_after),
+            alive: true,
+        })
+    }
+}
 
-        let event_subscriptions = subscriptions.clone();
-        let change_stream_handle = join_set.spawn(async move {
-            // TODO: Remove `unwrap`
-            CollectionEntry::handle_events(event_subscriptions, change_stream)
-                .await
-                .unwrap();
-        });
+/// The change stream of a collection, see [`WatchOptions::watch`].
+pub(crate) enum Changes {
+    Stream(Box<ChangeStream<Document>>),
+    /// An aggregation with its own `$changeStream` stage, see
+    /// [`SubscriptionOptions::show_expanded_events`]. Its cursor waits for the next change rather
+    /// than returning after an empty batch, and doesn't resume by itself after an error, which is
+    /// left to [`CollectionEntry::handle_events`] as for any error the driver can't resume from.
+    Expanded {
+        cursor: Box<Cursor<Document>>,
+        /// The `_id` of the last change, or where the stream has started.
+        resume_token: Option<ResumeToken>,
+        alive: bool,
+    },
+}
 
-        Ok(Self {
-            subscriptions,
-            change_stream_handle,
+impl Changes {
+    fn is_alive(&self) -> bool {
+        match self {
+            Changes::Stream(change_stream) => change_stream.is_alive(),
+            Changes::Expanded { alive, .. } => *alive,
+        }
+    }
+
+    fn resume_token(&self) -> Option<ResumeToken> {
+        match self {
+            Changes::Stream(change_stream) => change_stream.resume_token(),
+            Changes::Expanded { resume_token, .. } => resume_token.clone(),
+        }
+    }
+
+    /// The next change, or `None` once the batch is used up.
+    async fn next_if_any(&mut self) -> Result<Option<Document>, mongodb::error::Error> {
+        match self {
+            Changes::Stream(change_stream) => change_stream.next_if_any().await,
+            Changes::Expanded {
+                cursor,
+                resume_token,
+                alive,
+            } => match cursor.next().await.transpose()? {
+                Some(document) => {
+                    if let Some(id) = document.get("_id") {
+                        *resume_token = Some(bson::from_bson(id.clone())?);
+                    }
+                    Ok(Some(document))
+                }
+                // The server has closed the cursor, e.g. after an invalidation
+                None => {
+                    *alive = false;
+                    Ok(None)
+                }
+            },
+        }
+    }
+}
+
+/// Everything the change stream task needs to dispatch events.
+struct StreamContext {
+    entry_id: usize,
+    /// The cluster time up to which all changes have been dispatched, see
+    /// [`CollectionEntry::flushed`].
+    dispatched: watch::Sender<Option<Timestamp>>,
+    collection: Collection<Document>,
+    /// The options of the subscription that caused the stream to be opened.
+    options: SubscriptionOptions,
+    watch: WatchOptions,
+    collection_name: String,
+    subscriptions: Arc<Mutex<SubscriptionsManager>>,
+    position: Arc<StdMutex<StreamPosition>>,
+    /// Set while a resumed stream is still reading changes from before it was opened.
+    catch_up: Option<CatchUpState>,
+    /// The fragments of a split event that have been read so far, see
+    /// [`SubscriptionOptions::split_large_events`].
+    fragments: Option<SplitEvent>,
+    /// Set once a change of the current batch has been read, see [`Event::BatchBoundary`].
+    batch_read: bool,
+    /// The transaction whose events are being held back, see
+    /// [`SubscriptionOptions::group_transactions`]. Only used by the stream task itself.
+    transaction: RefCell<Option<PendingTransaction>>,
+    sequence: Sequence,
+    replay: Arc<StdMutex<ReplayBuffer>>,
+    hooks: Hooks,
+}
+
+/// The operation types of the events that change a collection itself rather than its documents.
+/// The server only reports them when the stream is opened with `showExpandedEvents`, see
+/// [`SubscriptionOptions::show_expanded_events`].
+const DDL_OPERATIONS: &[&str] = &[
+    "create",
+    "createIndexes",
+    "dropIndexes",
+    "modify",
+    "shardCollection",
+    "reshardCollection",
+    "refineCollectionShardKey",
+];
+
+/// A change event that the server has split into fragments because it exceeded the maximum
+/// document size. The fragments follow each other in the stream.
+#[derive(Debug)]
+struct SplitEvent {
+    /// The fields of the fragments so far.
+    fields: Document,
+    /// The number of fragments that have been read.
+    received: i32,
+    of: i32,
+}
+
+/// A change event with the fields that the driver's event type lacks.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ChangeEvent {
+    #[serde(flatten)]
+    event: ChangeStreamEvent<Document>,
+    txn_number: Option<i64>,
+    lsid: Option<Document>,
+    /// Only set for DDL events.
+    operation_description: Option<Document>,
+}
+
+impl ChangeEvent {
+    fn transaction(&self) -> Option<TransactionId> {
+        Some(TransactionId {
+            lsid: self.lsid.clone()?,
+            txn_number: self.txn_number?,
         })
     }
+}
 
-    pub async fn add_subscription(
+#[derive(Debug, PartialEq)]
+struct TransactionId {
+    lsid: Document,
+    txn_number: i64,
+}
+
+struct PendingTransaction {
+    id: TransactionId,
+    events: HashMap<SubscriptionHandle, Vec<Event>>,
+}
+
+struct CatchUpState {
+    /// The cluster time at which the stream was opened.
+    target: Option<Timestamp>,
+    pacing: Option<CatchUp>,
+    read: usize,
+}
+
+impl CatchUpState {
+    fn reached(&self, cluster_time: Option<Timestamp>) -> bool {
+        matches!((cluster_time, self.target), (Some(time), Some(target)) if time >= target)
+    }
+}
+
+/// A change to a single document, apart from its old and new versions.
+#[derive(Debug)]
+enum Change {
+    Insert,
+    Delete(Arc<String>),
+    Update(Arc<String>, Arc<UpdateDescription>),
+    Replace(Arc<String>),
+}
+
+impl Change {
+    fn operation_type(&self) -> OperationType {
+        match self {
+            Change::Insert => OperationType::Insert,
+            Change::Delete(_) => OperationType::Delete,
+            Change::Update(..) => OperationType::Update,
+            Change::Replace(_) => OperationType::Replace,
+        }
+    }
+
+    /// How the change affects `subscription`, or `None` if the subscription doesn't receive it.
+    fn transition(
         &self,
-        filter: impl Into<Option<Document>>,
-        channel: UnboundedSender<Event>,
-    ) -> Result<SubscriptionHandle, SubscriptionsManagerError> {
-        let filter = filter.into();
+        subscription: &Subscription,
+        old_doc: Option<&Candidate>,
+        new_doc: Option<&Candidate>,
+    ) -> Option<Transition> {
+        if !subscription.accepts(&self.operation_type()) {
+            return None;
+        }
+        if let (Change::Update(_, update), None) = (self, new_doc) {
+            // The new version isn't available, see `SubscriptionOptions::full_document`, so an
+            // update is matched on the old one and the document is taken to still match
+            let matched = subscription.transition(old_doc, None)? == Transition::Removed;
+            return (matched && subscription.matches_delta(update)).then_some(Transition::Changed);
+        }
+        let transition = subscription.transition(old_doc, new_doc)?;
+        if let (Change::Update(_, update), Transition::Changed) = (self, transition) {
+            if !subscription.matches_delta(update) {
+                return None;
+            }
+        }
 
-        self.subscriptions
-            .lock()
-            .await
-            .add(Subscription::new(filter, channel))
+        // Inserts and deletes always add and remove documents
+        Some(match (self, transition) {
+            (Change::Update(..) | Change::Replace(_), transition)
+                if subscription.reports_raw_transitions() =>
+            {
+                Transition::Raw {
+                    old_matched: !matches!(transition, Transition::Added),
+                    new_matched: !matches!(transition, Transition::Removed),
+                }
+            }
+            (Change::Update(..) | Change::Replace(_), Transition::Added)
+                if subscription.reports_boundaries() =>
+            {
+                Transition::Entered
+            }
+            (Change::Update(..) | Change::Replace(_), Transition::Removed)
+                if subscription.reports_boundaries() =>
+            {
+                Transition::Left
+            }
+            (_, transition) => transition,
+        })
     }
 
-    pub async fn remove_subscription(&self, handle: SubscriptionHandle) {
-        self.subscriptions.lock().await.remove(handle);
+    /// Whether the event of `transition` reports the document entering or leaving the matches
+    /// rather than the operation of the change, see [`EventMeta::synthesized`]. Raw transitions
+    /// are only reduced to one with `set_membership`.
+    ///
+    /// [`EventMeta::synthesized`]: crate::subscription::EventMeta::synthesized
+    fn synthesizes(&self, transition: Transition, set_membership: bool) -> bool {
+        match (self, transition) {
+            (Change::Insert | Change::Delete(_), _) | (_, Transition::Changed) => false,
+            (
+                _,
+                Transition::Added | Transition::Removed | Transition::Entered | Transition::Left,
+            ) => true,
+            (
+                _,
+                Transition::Raw {
+                    old_matched,
+                    new_matched,
+                },
+            ) => set_membership && old_matched != new_matched,
+        }
     }
 
-    pub async fn subscription_count(&self) -> usize {
-        self.subscriptions.lock().await.len()
+    fn needs_document(&self, transition: Transition) -> bool {
+        matches!(
+            (self, transition),
+            (_, Transition::Added | Transition::Entered)
+                | (Change::Replace(_), Transition::Changed)
+        )
     }
 
-    async fn handle_events(
-        subscriptions: Arc<Mutex<SubscriptionsManager>>,
-        mut change_stream: ChangeStream<ChangeStreamEvent<Document>>,
-    ) -> Result<(), Box<dyn std::error::Error>> {
-        fn get_key(document_key: Option<Document>) -> String {
-            // TODO: Do we want to unwrap here?
-            // TODO: Also support ObjectIds
-            document_key
-                .unwrap()
-                .get("_id")
-                .unwrap()
-                .as_str()
-                .unwrap()
-                .to_string()
-        }
-
-        // TODO: Use resume tokens
-        // let mut resume_token = None;
-        // TODO: Don't unwrap here
-        // TODO: Keep looping over the subscriptions when a send fails
-        while change_stream.is_alive() {
-            if let Some(event) = change_stream.next_if_any().await? {
-                // TODO: Use rayon
-                match event.operation_type {
-                    OperationType::Insert => {
-                        let doc = event
-                            .full_document
-                            .expect("the inserted document should be available");
-
-                        let doc = Arc::new(doc);
-
-                        for subscription in subscriptions.lock().await.get_all() {
-                            subscription.handle_insert(&doc)?;
-                        }
-                    }
-                    OperationType::Delete => {
-                        let key = get_key(event.document_key);
+    /// The event of [`Change::event`] as an [`Event::Serialized`].
+    fn serialize(&self, transition: Transition, new_doc: Option<&Document>) -> Arc<str> {
+        const MISSING: &str = "only changes with a new version of the document ask for it";
+
+        match (self, transition) {
+            (_, Transition::Added) => DocumentChange::Added(new_doc.expect(MISSING)),
+            (_, Transition::Entered) => DocumentChange::Entered(new_doc.expect(MISSING)),
+            (
+                Change::Delete(key) | Change::Update(key, _) | Change::Replace(key),
+                Transition::Removed,
+            ) => DocumentChange::Removed(key),
+            (Change::Update(key, _) | Change::Replace(key), Transition::Left) => {
+                DocumentChange::Left(key)
+            }
+            (Change::Update(key, update), Transition::Changed) => {
+                DocumentChange::Updated(key, update)
+            }
+            (Change::Replace(key), Transition::Changed) => {
+                DocumentChange::Replaced(key, new_doc.expect(MISSING))
+            }
+            (_, Transition::Raw { .. }) => {
+                unreachable!("raw transitions are built by `Change::raw_event`")
+            }
+            (Change::Insert, _) | (Change::Delete(_), Transition::Changed | Transition::Left) => {
+                unreachable!("an insert has no old and a delete no new version of the document")
+            }
+        }
+        .serialize()
+    }
 
-                        let doc = event
-                            .full_document_before_change
-                            .expect("the deleted document should be available");
-                        let key = Arc::new(key.to_string());
+    /// The [`Event::Transition`] of an update or replacement.
+    fn raw_event(
+        &self,
+        old_matched: bool,
+        new_matched: bool,
+        old: &Arc<Document>,
+        new: &Arc<Document>,
+    ) -> Event {
+        let key = match self {
+            Change::Update(key, _) | Change::Replace(key) => key.clone(),
+            Change::Insert | Change::Delete(_) => {
+                unreachable!("only updates and replacements are reported as transitions")
+            }
+        };
 
-                        for subscription in subscriptions.lock().await.get_all() {
-                            subscription.handle_delete(&key, &doc)?;
-                        }
-                    }
-                    OperationType::Update => {
-                        let key = get_key(event.document_key);
-
-                        let update = Arc::new(
-                            event
-                                .update_description
-                                .expect("the updated values should be available"),
-                        );
-                        let new_doc = Arc::new(
-                            event
-                                .full_document
-                                .expect("the new document should be available for this update"),
-                        );
-                        let old_doc = event
-                            .full_document_before_change
-                            .expect("the old document should be available for this update");
-                        let key = Arc::new(key.to_string());
-
-                        for subscription in subscriptions.lock().await.get_all() {
-                            subscription.handle_update(&key, &update, &old_doc, &new_doc)?;
-                        }
-                    }
-                    OperationType::Replace => {
-                        let key = get_key(event.document_key);
-
-                        let new_doc =
-                            Arc::new(event.full_document.expect(
-                                "the new document should be available for this replacement",
-                            ));
-                        let old_doc = event
-                            .full_document_before_change
-                            .expect("the old document should be available for this replacement");
-                        let key = Arc::new(key.to_string());
-
-                        for subscription in subscriptions.lock().await.get_all() {
-                            subscription.handle_replace(&key, &old_doc, &new_doc)?;
-                        }
-                    }
-                    OperationType::DropDatabase
-                    | OperationType::Drop
-                    | OperationType::Rename
-                    | OperationType::Invalidate => {
-                        for subscription in subscriptions.lock().await.get_all() {
-                            subscription.handle_drop()?;
-                        }
-                    }
-                    // TODO: Don't panic?
-                    OperationType::Other(event) => panic!(
-                        "Received a change event that we don't know how to handle: {}",
-                        event
-                    ),
-                    _ => panic!("Operation type {:?} not implemented", event.operation_type),
-                }
+        Event::Transition(Box::new(TransitionEvent {
+            key,
+            old: EventDocument::Shared(old.clone()),
+            new: EventDocument::Shared(new.clone()),
+            old_matched,
+            new_matched,
+        }))
+    }
+
+    fn event(&self, transition: Transition, documents: &mut Documents) -> Event {
+        match (self, transition) {
+            (_, Transition::Added) => Event::Added(documents.next()),
+            (_, Transition::Entered) => Event::Entered(documents.next()),
+            (
+                Change::Delete(key) | Change::Update(key, _) | Change::Replace(key),
+                Transition::Removed,
+            ) => Event::Removed(key.clone()),
+            (Change::Update(key, _) | Change::Replace(key), Transition::Left) => {
+                Event::Left(key.clone())
+            }
+            (Change::Update(key, update), Transition::Changed) => {
+                Event::Updated((key.clone(), update.clone()))
+            }
+            (Change::Replace(key), Transition::Changed) => {
+                Event::Replaced((key.clone(), documents.next()))
+            }
+            (_, Transition::Raw { .. }) => {
+                unreachable!("raw transitions are built by `Change::raw_event`")
             }
+            (Change::Insert, _) | (Change::Delete(_), Transition::Changed | Transition::Left) => {
+                unreachable!("an insert has no old and a delete no new version of the document")
+            }
+        }
+    }
+}
 
-            // resume_token = change_stream.resume_token();
+/// A change that has been dispatched, kept for [`SubscriptionOptions::replay`].
+#[derive(Debug)]
+struct RecordedChange {
+    change: Change,
+    old_doc: Option<Document>,
+    new_doc: Option<Document>,
+}
+
+/// The most recent changes of a collection, at most `capacity` of them. Only updated while
+/// holding the `subscriptions` lock, like the [`StreamPosition`].
+#[derive(Debug)]
+struct ReplayBuffer {
+    changes: VecDeque<RecordedChange>,
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    fn new(capacity: usize) -> Self {
+        Self {
+            changes: VecDeque::with_capacity(capacity),
+            capacity,
         }
+    }
 
-        Err(Box::new(mongodb::error::Error::custom(
-            "change stream ended",
-        )))
+    fn record(&mut self, change: RecordedChange) {
+        if self.changes.len() >= self.capacity {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(change);
+    }
+
+    fn clear(&mut self) {
+        self.changes.clear();
+    }
+
+    /// Sends the events of the last `count` changes that concern `subscription` to it, oldest
+    /// first.
+    fn replay(&self, subscription: &Subscription, count: usize) {
+        let start = self.changes.len().saturating_sub(count);
+
+        for recorded in self.changes.range(start..) {
+            let old_candidate = recorded.old_doc.as_ref().map(Candidate::new);
+            let new_candidate = recorded.new_doc.as_ref().map(Candidate::new);
+            let Some(transition) = recorded.change.transition(
+                subscription,
+                old_candidate.as_ref(),
+                new_candidate.as_ref(),
+            ) else {
+                continue;
+            };
+
+            let event = match (transition, &recorded.old_doc, &recorded.new_doc) {
+                (
+                    Transition::Raw {
+                        old_matched,
+                        new_matched,
+                    },
+                    Some(old),
+                    Some(new),
+                ) => recorded.change.raw_event(
+                    old_matched,
+                    new_matched,
+                    &Arc::new(old.clone()),
+                    &Arc::new(new.clone()),
+                ),
+                (Transition::Raw { .. }, _, _) => continue,
+                _ => {
+                    let mut documents = Documents::new(recorded.new_doc.clone(), true, 1);
+                    recorded.change.event(transition, &mut documents)
+                }
+            };
+            // A failure is noticed on the next event, like for `Event::Established`
+            let synthesized = recorded
+                .change
+                .synthesizes(transition, subscription.reports_membership());
+            let _ = subscription.send_with(event, synthesized);
+        }
     }
 }
 
-impl Drop for CollectionEntry {
-    fn drop(&mut self) {
-        // `AbortHandle` does implement `Drop`, but just to be extra safe
-        self.change_stream_handle.abort()
+/// Hands out the new version of a document to the events that contain it.
+enum Documents {
+    Shared(Option<Arc<Document>>),
+    /// The document is cloned for every event but the last, into which it is moved.
+    Owned {
+        document: Option<Document>,
+        remaining: usize,
+    },
+}
+
+impl Documents {
+    fn new(document: Option<Document>, owned: bool, needed: usize) -> Self {
+        match owned {
+            true => Documents::Owned {
+                document,
+                remaining: needed,
+            },
+            false => Documents::Shared(document.map(Arc::new)),
+        }
+    }
+
+    fn next(&mut self) -> EventDocument {
+        const MISSING: &str = "only changes with a new version of the document ask for it";
+
+        match self {
+            Documents::Shared(document) => EventDocument::Shared(document.clone().expect(MISSING)),
+            Documents::Owned {
+                document,
+                remaining,
+            } => {
+                *remaining -= 1;
+                let document = match remaining {
+                    0 => document.take(),
+                    _ => document.clone(),
+                };
+                EventDocument::Owned(document.expect(MISSING))
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CollectionEntry {
+    id: usize,
+    // TODO: Convert to RwLock?
+    subscriptions: Arc<Mutex<SubscriptionsManager>>,
+    /// Only updated while holding the `subscriptions` lock, so that a subscription that is added
+    /// observes exactly the position after which it will receive events.
+    position: Arc<StdMutex<StreamPosition>>,
+    /// The number of change stream tasks of this entry that haven't finished yet.
+    running_streams: Arc<AtomicUsize>,
+    /// Set once the change stream has ended with an error, see [`StreamContext::fail`].
+    failed: Arc<AtomicBool>,
+    dispatched: watch::Receiver<Option<Timestamp>>,
+    /// The number of changes that have been dispatched, see
+    /// [`EventMeta::seq`](crate::subscription::EventMeta::seq).
+    sequence: Sequence,
+    replay: Arc<StdMutex<ReplayBuffer>>,
+    revert_pre_images: Option<RevertPreImages>,
+    /// Set if the stream couldn't be resumed from the token of the subscription that opened it,
+    /// which is told so once it's added.
+    pending_gap: StdMutex<Option<PendingGap>>,
+    /// What the change stream is (re)opened with, see [`CollectionEntry::refresh`].
+    collection: Collection<Document>,
+    options: SubscriptionOptions,
+    watch: WatchOptions,
+    /// Set with [`Hooks::filter_on_server`].
+    server_filter: Option<ServerFilter>,
+    hooks: Hooks,
+    /// Set once the change stream task has been spawned.
+    change_stream_handle: Option<AbortHandle>,
+    /// See [`Mercurius::verify_pre_images`](crate::Mercurius::verify_pre_images).
+    pre_image_check: Option<AbortHandle>,
+    /// See [`Mercurius::on_stuck`](crate::Mercurius::on_stuck).
+    stuck_check: Option<AbortHandle>,
+    /// Set while the collection doesn't exist yet, see [`enable_on_creation`].
+    creation_watch: Option<AbortHandle>,
+}
+
+/// See [`CollectionEntry::pending_gap`].
+#[derive(Debug)]
+struct PendingGap {
+    collection: Collection<Document>,
+    options: SubscriptionOptions,
+}
+
+/// Turns the pre- and post-images of a collection off again once its entry is dropped, see
+/// [`SubscriptionOptions::revert_pre_and_post_images`].
+#[derive(Debug)]
+pub(crate) struct RevertPreImages {
+    pub(crate) db: Database,
+    pub(crate) name: String,
+    /// The entries of the instance; the images stay on while there is an entry for the
+    /// collection again, which takes over reverting them.
+    pub(crate) collections: Weak<Mutex<HashMap<String, CollectionEntry>>>,
+    /// The collections whose images the instance has turned on.
+    pub(crate) enabled: Arc<StdMutex<HashSet<String>>>,
+    pub(crate) applied: AppliedPreImages,
+}
+
+impl RevertPreImages {
+    async fn revert(self) {
+        let collections = self.collections.upgrade();
+        // Held while reverting, so that no new entry relies on the images in the meantime
+        let collections = match &collections {
+            Some(collections) => {
+                let collections = collections.lock().await;
+                if collections.contains_key(&self.name) {
+                    return;
+                }
+                Some(collections)
+            }
+            None => None,
+        };
+
+        let disable = doc! {
+            "collMod": &self.name,
+            "changeStreamPreAndPostImages": { "enabled": false },
+        };
+        match self.db.run_command(disable, None).await {
+            Ok(_) => {
+                self.enabled.lock().unwrap().remove(&self.name);
+                self.applied.remove(&self.name);
+            }
+            Err(err) => eprintln!(
+                "Could not turn off the pre- and post-images of {}: {}",
+                self.name, err
+            ),
+        }
+        drop(collections);
+    }
+}
+
+/// Reports the subscriptions whose consumers have stopped receiving events, see
+/// [`Mercurius::on_stuck`](crate::Mercurius::on_stuck).
+#[derive(Clone)]
+pub(crate) struct StuckCheck {
+    pub(crate) threshold: Duration,
+    pub(crate) handler: StuckHandler,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl StuckCheck {
+    async fn run(
+        self,
+        collection_name: String,
+        entry_id: usize,
+        subscriptions: Arc<Mutex<SubscriptionsManager>>,
+    ) {
+        // The subscriptions that have been reported and haven't received an event since
+        let mut reported = HashSet::new();
+
+        loop {
+            self.clock.sleep(self.threshold / 4).await;
+
+            let stuck: Vec<_> = subscriptions
+                .lock()
+                .await
+                .iter()
+                .filter_map(|(handle, subscription)| {
+                    let idle = subscription.idle()?;
+                    (idle >= self.threshold).then(|| (handle.clone(), idle))
+                })
+                .collect();
+
+            reported.retain(|reported| stuck.iter().any(|(handle, _)| handle == reported));
+            for (handle, idle) in stuck {
+                if reported.insert(handle.clone()) {
+                    let handle = Handle {
+                        collection_name: collection_name.clone(),
+                        entry_id,
+                        subscription_handle: handle,
+                    };
+                    (self.handler)(&handle, idle);
+                }
+            }
+        }
+    }
+}
+
+/// Checks periodically that the pre- and post-images of a collection are still turned on, see
+/// [`Mercurius::verify_pre_images`](crate::Mercurius::verify_pre_images).
+#[derive(Debug, Clone)]
+pub(crate) struct PreImageCheck {
+    pub(crate) db: Database,
+    pub(crate) interval: Duration,
+    pub(crate) on_disabled: PreImagesDisabled,
+    pub(crate) clock: Arc<dyn Clock>,
+}
+
+impl PreImageCheck {
+    async fn run(
+        self,
+        name: String,
+        subscriptions: Arc<Mutex<SubscriptionsManager>>,
+        applied: AppliedPreImages,
+    ) {
+        // Whether the subscriptions have been told that the images are off
+        let mut notified = false;
+
+        loop {
+            self.clock.sleep(jittered(self.interval)).await;
+
+            match pre_and_post_images(&self.db, &name).await {
+                // A collection that doesn't exist (anymore) gets its images once it's created
+                Ok(Some(true) | None) => notified = false,
+                Ok(Some(false)) => {
+                    // An entry that is created later turns them on again
+                    applied.remove(&name);
+
+                    let re_enabled = match self.on_disabled {
+                        PreImagesDisabled::Reenable => self.enable(&name, &applied).await,
+                        PreImagesDisabled::Notify => false,
+                    };
+                    if notified && !re_enabled {
+                        continue;
+                    }
+                    notified = !re_enabled;
+
+                    let subscriptions = subscriptions.lock().await;
+                    for (_, subscription) in subscriptions.iter() {
+                        // A receiver that has been dropped is noticed with the next change
+                        let _ = subscription.send(Event::ConfigurationChanged { re_enabled });
+                    }
+                }
+                Err(err) => eprintln!(
+                    "Could not check the pre- and post-images of {}: {}",
+                    name, err
+                ),
+            }
+        }
+    }
+
+    /// Returns whether the images have been turned on.
+    async fn enable(&self, name: &str, applied: &AppliedPreImages) -> bool {
+        match applied.apply(&self.db, name).await {
+            Ok(()) => true,
+            Err(err) => {
+                eprintln!(
+                    "Could not turn the pre- and post-images of {} on again: {}",
+                    name, err
+                );
+                false
+            }
+        }
+    }
+}
+
+/// Whether the collection `name` has `changeStreamPreAndPostImages` turned on; `None` if there is
+/// no such collection.
+pub(crate) async fn pre_and_post_images(
+    db: &Database,
+    name: &str,
+) -> Result<Option<bool>, mongodb::error::Error> {
+    let mut specifications = db.list_collections(doc! { "name": name }, None).await?;
+
+    Ok(specifications
+        .next()
+        .await
+        .transpose()?
+        .map(|specification| {
+            specification
+                .options
+                .change_stream_pre_and_post_images
+                .is_some_and(|images| images.enabled)
+        }))
+}
+
+/// Turns `changeStreamPreAndPostImages` on for the collection `name`, which has to exist.
+pub(crate) async fn enable_pre_and_post_images(
+    db: &Database,
+    name: &str,
+) -> Result<(), mongodb::error::Error> {
+    let enable = doc! {
+        "collMod": name,
+        "changeStreamPreAndPostImages": { "enabled": true },
+    };
+    db.run_command(enable, None).await.map(drop)
+}
+
+/// The collections whose pre- and post-images the instance has turned on, so that `collMod` is
+/// run once per collection: concurrent callers wait for the first one's rather than running
+/// their own.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct AppliedPreImages(Arc<StdMutex<HashMap<String, Arc<OnceCell<()>>>>>);
+
+impl AppliedPreImages {
+    pub(crate) fn contains(&self, name: &str) -> bool {
+        self.0
+            .lock()
+            .unwrap()
+            .get(name)
+            .is_some_and(|applied| applied.initialized())
+    }
+
+    /// Turns the images of the collection `name`, which has to exist, on unless the instance
+    /// already has. An attempt that fails or is cancelled, e.g. by [`SubscriptionOptions::timeout`],
+    /// leaves it to the next caller.
+    pub(crate) async fn apply(
+        &self,
+        db: &Database,
+        name: &str,
+    ) -> Result<(), mongodb::error::Error> {
+        self.apply_with(name, || enable_pre_and_post_images(db, name))
+            .await
+    }
+
+    /// Like [`AppliedPreImages::apply`], turning the images on with `enable`.
+    async fn apply_with<F, E>(&self, name: &str, enable: impl FnOnce() -> F) -> Result<(), E>
+    where
+        F: Future<Output = Result<(), E>>,
+    {
+        let applied = self
+            .0
+            .lock()
+            .unwrap()
+            .entry(name.to_string())
+            .or_default()
+            .clone();
+        applied.get_or_try_init(enable).await.map(drop)
+    }
+
+    /// The images may be off again, e.g. as the collection was dropped, so the next
+    /// [`AppliedPreImages::apply`] runs `collMod` again. One that is in progress completes for
+    /// the callers that are waiting for it.
+    pub(crate) fn remove(&self, name: &str) {
+        self.0.lock().unwrap().remove(name);
+    }
+}
+
+/// Turns the pre- and post-images of the collection `name` on once it has been created, see
+/// [`SubscriptionOptions::await_creation`]. If it has been created by now, they are turned on
+/// right away and `None` is returned; otherwise the returned task waits for the creation.
+pub(crate) async fn enable_on_creation(
+    db: &Database,
+    name: &str,
+    applied: AppliedPreImages,
+) -> Result<Option<impl Future<Output = ()> + Send + 'static>, mongodb::error::Error> {
+    // Opened before checking again, so that a creation in between isn't missed
+    let pipeline = [
+        doc! { "$match": { "ns.coll": name } },
+        doc! { "$project": { "operationType": 1 } },
+    ];
+    let mut changes = db.watch(pipeline, None).await?.with_type::<Document>();
+
+    if pre_and_post_images(db, name).await?.is_some() {
+        applied.apply(db, name).await?;
+        return Ok(None);
+    }
+
+    let db = db.clone();
+    let name = name.to_string();
+    Ok(Some(async move {
+        // Any change on the namespace means that the collection exists
+        if let Some(Err(err)) = changes.next().await {
+            eprintln!(
+                "Could not wait for the creation of {}, its pre- and post-images stay off: {}",
+                name, err
+            );
+            return;
+        }
+        if let Err(err) = applied.apply(&db, &name).await {
+            eprintln!(
+                "Could not turn the pre- and post-images of {} on after its creation: {}",
+                name, err
+            );
+        }
+    }))
+}
+
+/// `interval`, give or take a random part of up to a quarter of it.
+fn jittered(interval: Duration) -> Duration {
+    // Every `RandomState` is seeded differently, which is random enough to spread the checks
+    let random = RandomState::new().build_hasher().finish();
+    interval.mul_f64(0.75 + (random % 1001) as f64 / 2000.0)
+}
+
+/// Keeps track of a running change stream task; decrements the counter when the task finishes or
+/// is aborted.
+struct RunningStream(Arc<AtomicUsize>);
+
+impl RunningStream {
+    fn new(counter: Arc<AtomicUsize>) -> Self {
+        counter.fetch_add(1, Ordering::Relaxed);
+        Self(counter)
+    }
+}
+
+impl Drop for RunningStream {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl CollectionEntry {
+    /// `filter` is that of the subscription the stream is opened for.
+    pub async fn new(
+        id: usize,
+        collection: Collection<Document>,
+        options: &SubscriptionOptions,
+        filter: Option<&Document>,
+        hooks: Hooks,
+        spawner: &dyn Spawner,
+    ) -> Result<Self, mongodb::error::Error> {
+        // Anchor the stream at a known cluster time, so that subscribers know exactly from which
+        // point onwards they receive changes
+        let now = cluster_time(&collection).await?;
+        let start = match (&options.resume_after, &options.start_after) {
+            (Some(token), _) => StreamStart::ResumeAfter(token.clone()),
+            (_, Some(token)) => StreamStart::StartAfter(token.clone()),
+            _ => StreamStart::At(now),
+        };
+
+        // TODO: Consider a single change stream instead of one per collection
+        let server_filter = hooks.filter_on_server.then(|| ServerFilter::new(filter));
+        let watch = match &server_filter {
+            Some(server_filter) => WatchOptions::new(options).filtered(server_filter),
+            None => WatchOptions::new(options),
+        };
+        let (change_stream, gap) = match watch.watch(&collection, &start).await {
+            Ok(change_stream) => (change_stream, false),
+            Err(err) if !matches!(start, StreamStart::At(_)) && restarts_after(options, &err) => {
+                (watch.watch(&collection, &StreamStart::At(now)).await?, true)
+            }
+            Err(err) => return Err(err),
+        };
+
+        let (start_time, resuming) = match start {
+            StreamStart::At(time) => (time, false),
+            _ if gap => (now, false),
+            _ => (None, true),
+        };
+        let pending_gap = gap.then(|| PendingGap {
+            collection: collection.clone(),
+            options: options.clone(),
+        });
+
+        let subscriptions = Arc::new(Mutex::new(SubscriptionsManager::new(
+            hooks.subscription_count.clone(),
+        )));
+        let position = Arc::new(StdMutex::new(StreamPosition {
+            cluster_time: start_time,
+            resume_token: change_stream.resume_token(),
+        }));
+
+        let (_, dispatched) = watch::channel(start_time);
+        let replay = Arc::new(StdMutex::new(ReplayBuffer::new(hooks.replay_capacity)));
+
+        let pre_image_check = hooks.pre_image_check.clone().map(|check| {
+            spawner::spawn(
+                spawner,
+                check.run(
+                    collection.name().to_string(),
+                    subscriptions.clone(),
+                    hooks.applied_pre_images.clone(),
+                ),
+            )
+        });
+
+        let stuck_check = hooks.stuck_check.clone().map(|check| {
+            spawner::spawn(
+                spawner,
+                check.run(collection.name().to_string(), id, subscriptions.clone()),
+            )
+        });
+
+        let mut entry = Self {
+            id,
+            subscriptions,
+            position,
+            running_streams: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicBool::new(false)),
+            dispatched,
+            sequence: Sequence::default(),
+            replay,
+            revert_pre_images: None,
+            pending_gap: StdMutex::new(pending_gap),
+            collection,
+            options: options.clone(),
+            watch,
+            server_filter,
+            hooks,
+            change_stream_handle: None,
+            pre_image_check,
+            stuck_check,
+            creation_watch: None,
+        };
+        entry.spawn_stream(spawner, change_stream, resuming.then_some(now));
+
+        Ok(entry)
+    }
+
+    /// Spawns the task that dispatches the events of `change_stream`, which is still catching up
+    /// to `catch_up` if it has been resumed.
+    fn spawn_stream(
+        &mut self,
+        spawner: &dyn Spawner,
+        change_stream: Changes,
+        catch_up: Option<Option<Timestamp>>,
+    ) {
+        let start_time = self.position.lock().unwrap().cluster_time;
+        let (dispatched_sender, dispatched) = watch::channel(start_time);
+        self.dispatched = dispatched;
+
+        let mut context = self.stream_context(dispatched_sender, catch_up);
+        let running_stream = RunningStream::new(self.running_streams.clone());
+        let failed = self.failed.clone();
+        self.change_stream_handle = Some(spawner::spawn(spawner, async move {
+            let _running_stream = running_stream;
+            let result = CollectionEntry::handle_events(&mut context, change_stream).await;
+            // Only the message is kept, as the error isn't `Send`
+            if let Err(err) = result.map_err(|err| err.to_string()) {
+                failed.store(true, Ordering::Relaxed);
+                context.fail(err).await;
+            }
+        }));
+    }
+
+    fn stream_context(
+        &self,
+        dispatched: watch::Sender<Option<Timestamp>>,
+        catch_up: Option<Option<Timestamp>>,
+    ) -> StreamContext {
+        StreamContext {
+            entry_id: self.id,
+            dispatched,
+            collection_name: self.collection.name().to_string(),
+            subscriptions: self.subscriptions.clone(),
+            position: self.position.clone(),
+            catch_up: catch_up.map(|target| CatchUpState {
+                target,
+                pacing: self.options.catch_up.clone(),
+                read: 0,
+            }),
+            fragments: None,
+            batch_read: false,
+            transaction: RefCell::new(None),
+            sequence: self.sequence.clone(),
+            replay: self.replay.clone(),
+            collection: self.collection.clone(),
+            options: self.options.clone(),
+            watch: self.watch.clone(),
+            hooks: self.hooks.clone(),
+        }
+    }
+
+    /// Dispatches `event` as if the change stream had read it, see
+    /// [`Mercurius::inject_for_test`](crate::Mercurius::inject_for_test). Futures returned by
+    /// [`CollectionEntry::flushed`] aren't woken by injected changes.
+    #[cfg(feature = "test-util")]
+    pub async fn inject_for_test(
+        &self,
+        event: &ChangeStreamEvent<Document>,
+    ) -> Result<(), mongodb::bson::ser::Error> {
+        let document = mongodb::bson::to_document(event)?;
+        let (dispatched, _) = watch::channel(None);
+        let mut context = self.stream_context(dispatched, None);
+
+        context
+            .dispatch_change(document, Some(event.id.clone()))
+            .await;
+        context.end_transaction(&mut *self.subscriptions.lock().await);
+        Ok(())
+    }
+
+    /// Replaces the change stream with a new one, which resumes after the last change that has
+    /// been dispatched if `resume` is set and otherwise starts at the current time. The
+    /// subscriptions are kept and receive [`Event::Reset`]. If the new stream can't be opened,
+    /// the current one is reopened where it stopped, see [`CollectionEntry::reopen_stream`].
+    pub async fn refresh(
+        &mut self,
+        spawner: &dyn Spawner,
+        resume: bool,
+    ) -> Result<(), mongodb::error::Error> {
+        // The current stream is replaced anyway, and once it's stopped it dispatches nothing
+        // while the new one is opened, without holding up the subscriptions meanwhile
+        let position = self.stop_stream().await;
+        let opened = async {
+            let now = cluster_time(&self.collection).await?;
+            let start = match (resume, &position.resume_token) {
+                (true, Some(token)) => StreamStart::ResumeAfter(token.clone()),
+                _ => StreamStart::At(now),
+            };
+            let change_stream = self.watch.watch(&self.collection, &start).await?;
+            Ok((now, start, change_stream))
+        };
+        let (now, start, change_stream) = match opened.await {
+            Ok(opened) => opened,
+            Err(err) => {
+                self.reopen_stream(spawner, &position).await;
+                return Err(err);
+            }
+        };
+
+        let resuming = matches!(start, StreamStart::ResumeAfter(_));
+        {
+            let subscriptions = self.subscriptions.lock().await;
+            if !resuming {
+                // The changes in between are skipped, so the replayed ones would leave a gap
+                self.replay.lock().unwrap().clear();
+                *self.position.lock().unwrap() = StreamPosition {
+                    cluster_time: now,
+                    resume_token: change_stream.resume_token(),
+                };
+            }
+            for (_, subscription) in subscriptions.iter() {
+                // A receiver that has been dropped is noticed with the next change
+                let _ = subscription.send(Event::Reset);
+            }
+        }
+
+        self.spawn_stream(spawner, change_stream, resuming.then_some(now));
+        Ok(())
+    }
+
+    /// Adds the filter of a subscription that is about to be added to the [`ServerFilter`]. If
+    /// the changes it matches aren't all sent by the server yet, the change stream is replaced
+    /// with one that continues after the last change that has been dispatched, so that no change
+    /// is lost or delivered twice. If the new stream can't be opened, the current one is reopened
+    /// where it stopped, see [`CollectionEntry::reopen_stream`].
+    pub async fn widen_filter(
+        &mut self,
+        spawner: &dyn Spawner,
+        filter: Option<&Document>,
+    ) -> Result<(), mongodb::error::Error> {
+        let Some(server_filter) = &self.server_filter else {
+            return Ok(());
+        };
+        let mut widened = server_filter.clone();
+        if !widened.widen(filter) {
+            return Ok(());
+        }
+
+        // The current stream is replaced anyway, and once it's stopped it dispatches nothing
+        // while the new one is opened, without holding up the subscriptions meanwhile
+        let position = self.stop_stream().await;
+        let watch = self.watch.filtered(&widened);
+        let change_stream = match watch.watch(&self.collection, &position.start()).await {
+            Ok(change_stream) => change_stream,
+            Err(err) => {
+                self.reopen_stream(spawner, &position).await;
+                return Err(err);
+            }
+        };
+        self.watch = watch;
+        self.server_filter = Some(widened);
+
+        self.spawn_stream(spawner, change_stream, None);
+        Ok(())
+    }
+
+    /// Stops the change stream task, e.g. as it's about to be replaced, and returns the position
+    /// up to which it has dispatched changes, which doesn't move anymore.
+    async fn stop_stream(&mut self) -> StreamPosition {
+        if let Some(handle) = self.change_stream_handle.take() {
+            handle.abort();
+        }
+        // Resolves once the task has been dropped along with its end of the channel, as an
+        // aborted task may still be in the middle of dispatching a change
+        let _ = self.dispatched.clone().wait_for(|_| false).await;
+        self.position.lock().unwrap().clone()
+    }
+
+    /// Reopens the change stream that [`CollectionEntry::stop_stream`] has stopped at `position`,
+    /// after the stream that was to replace it couldn't be opened. If that fails as well, the
+    /// stream has failed like one that ends with an error, see [`StreamContext::fail`].
+    async fn reopen_stream(&mut self, spawner: &dyn Spawner, position: &StreamPosition) {
+        match self.watch.watch(&self.collection, &position.start()).await {
+            Ok(change_stream) => self.spawn_stream(spawner, change_stream, None),
+            Err(err) => {
+                self.failed.store(true, Ordering::Relaxed);
+                let (dispatched, _) = watch::channel(None);
+                let mut context = self.stream_context(dispatched, None);
+                context.fail(err.to_string()).await;
+            }
+        }
+    }
+
+    /// Turns the pre- and post-images of the collection off once the entry is dropped.
+    pub(crate) fn revert_pre_images_on_drop(&mut self, revert: RevertPreImages) {
+        self.revert_pre_images = Some(revert);
+    }
+
+    /// Stops `creation_watch` once the entry is dropped, see [`enable_on_creation`].
+    pub(crate) fn stop_on_drop(&mut self, creation_watch: AbortHandle) {
+        self.creation_watch = Some(creation_watch);
+    }
+
+    /// Whether the change stream has ended with an error, after which the entry has no
+    /// subscriptions left and has to be replaced.
+    pub fn has_failed(&self) -> bool {
+        self.failed.load(Ordering::Relaxed)
+    }
+
+    pub fn running_streams(&self) -> usize {
+        self.running_streams.load(Ordering::Relaxed)
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// The resume token up to which the change stream has been read: that of the last event
+    /// that was dispatched, or the post-batch resume token of a later empty batch, which keeps
+    /// moving forward while the collection is quiet.
+    pub fn resume_token(&self) -> Option<ResumeToken> {
+        self.position.lock().unwrap().resume_token.clone()
+    }
+
+    /// The cluster time up to which the change stream has been read, which moves forward like
+    /// [`CollectionEntry::resume_token`].
+    pub fn cluster_time(&self) -> Option<Timestamp> {
+        self.position.lock().unwrap().cluster_time
+    }
+
+    /// Returns a future that resolves once all changes up to `cluster_time` have been
+    /// dispatched, or the change stream has ended. It doesn't borrow the entry, so the collection
+    /// lock needn't be held while waiting.
+    pub fn flushed(&self, cluster_time: Timestamp) -> impl Future<Output = ()> {
+        let mut dispatched = self.dispatched.clone();
+
+        async move {
+            let _ = dispatched
+                .wait_for(|dispatched| matches!(dispatched, Some(time) if *time >= cluster_time))
+                .await;
+        }
+    }
+
+    /// Adds `subscription`, which first receives the events of the last `replay` changes of the
+    /// collection that concern it, see [`SubscriptionOptions::replay`]. Returns the position
+    /// after which it receives changes.
+    pub async fn add_subscription(
+        &self,
+        mut subscription: Subscription,
+        replay: Option<usize>,
+    ) -> Result<(SubscriptionHandle, StreamPosition), Box<dyn std::error::Error + Send + Sync>>
+    {
+        let mut subscriptions = self.subscriptions.lock().await;
+        let position = self.position.lock().unwrap().clone();
+
+        subscription.attach_sequence(self.sequence.clone());
+        // The receiver can't have been dropped yet, and a failure is noticed on the next event
+        let _ = subscription.establish(position.cluster_time, position.resume_token.clone());
+        let gap = self.pending_gap.lock().unwrap().take();
+        let mut snapshot = None;
+        if let Some(gap) = gap {
+            let _ = subscription.report_gap(position.cluster_time);
+            // A subscription that is being primed receives the documents anyway
+            if gap.options.on_token_expiry.unwrap_or_default()
+                == OnTokenExpiry::RestartFromNowWithSnapshot
+                && !subscription.is_priming()
+            {
+                let filter = subscription.filter().cloned();
+                snapshot = Some((gap, filter, subscription.start_priming()));
+            }
+        }
+        if let Some(count) = replay {
+            self.replay.lock().unwrap().replay(&subscription, count);
+        }
+
+        let handle = subscriptions.add(subscription)?;
+        drop(subscriptions);
+
+        // The events that are dispatched meanwhile are held back until the documents are sent
+        if let Some((gap, filter, primer)) = snapshot {
+            let redaction = self.hooks.redaction.as_ref();
+            if let Err(err) =
+                send_snapshot(&gap.collection, &gap.options, redaction, filter, primer).await
+            {
+                self.remove_subscription(&handle).await;
+                return Err(err.into());
+            }
+        }
+
+        Ok((handle, position))
+    }
+
+    pub async fn remove_subscription(&self, handle: &SubscriptionHandle) -> Option<Subscription> {
+        self.subscriptions.lock().await.remove(handle)
+    }
+
+    /// Removes several subscriptions at once; returns whether each of them was still there.
+    pub async fn remove_subscriptions<'a>(
+        &self,
+        handles: impl IntoIterator<Item = &'a SubscriptionHandle>,
+    ) -> Vec<bool> {
+        let mut subscriptions = self.subscriptions.lock().await;
+        handles
+            .into_iter()
+            .map(|handle| match subscriptions.remove(handle) {
+                Some(subscription) => {
+                    subscription.tear_down(TeardownReason::Removed);
+                    true
+                }
+                None => false,
+            })
+            .collect()
+    }
+
+    /// The handle of the subscription with the given [`SubscriptionHandle::id`].
+    pub async fn subscription_handle(&self, id: usize) -> Option<SubscriptionHandle> {
+        self.subscriptions.lock().await.handle_at(id)
+    }
+
+    /// Returns `false` if there is no such subscription or it's already paused.
+    pub async fn pause_subscription(&self, handle: &SubscriptionHandle, capacity: usize) -> bool {
+        let subscriptions = self.subscriptions.lock().await;
+        subscriptions
+            .get(handle)
+            .is_some_and(|subscription| subscription.pause(capacity))
+    }
+
+    /// Returns `None` if there is no such subscription or it can't be reattached, see
+    /// [`Subscription::reattach`].
+    pub async fn reattach_subscription(
+        &self,
+        handle: &SubscriptionHandle,
+    ) -> Option<EventReceiver> {
+        // Events are only dispatched while holding the lock, so none is sent to the old channel
+        // meanwhile
+        let mut subscriptions = self.subscriptions.lock().await;
+        subscriptions.get_mut(handle)?.reattach()
+    }
+
+    /// The filter of the subscription; `None` if there is no such subscription.
+    pub async fn subscription_filter(&self, handle: &SubscriptionHandle) -> Option<Document> {
+        let subscriptions = self.subscriptions.lock().await;
+        let subscription = subscriptions.get(handle)?;
+        Some(subscription.filter().cloned().unwrap_or_default())
+    }
+
+    /// Returns `false` if there is no such subscription or it isn't paused.
+    pub async fn resume_subscription(&self, handle: &SubscriptionHandle) -> bool {
+        // Events are only dispatched while holding the lock, so the held back events are sent
+        // before any new one
+        let subscriptions = self.subscriptions.lock().await;
+        subscriptions
+            .get(handle)
+            .is_some_and(|subscription| subscription.resume())
+    }
+
+    pub async fn subscription_count(&self) -> usize {
+        self.subscriptions.lock().await.len()
+    }
+
+    pub async fn is_empty(&self) -> bool {
+        self.subscriptions.lock().await.is_empty()
+    }
+
+    pub async fn subscription_handles(&self) -> Vec<SubscriptionHandle> {
+        self.subscriptions.lock().await.handles()
+    }
+
+    pub async fn stats(&self) -> Vec<(SubscriptionHandle, SubscriptionStats)> {
+        self.subscriptions
+            .lock()
+            .await
+            .iter()
+            .map(|(handle, subscription)| (handle.clone(), subscription.stats()))
+            .collect()
+    }
+
+    async fn handle_events(
+        context: &mut StreamContext,
+        mut change_stream: Changes,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        loop {
+            // When no buffered events are left, `next_if_any` requests a new batch, for which the
+            // server waits up to `max_await_time` before answering
+            while change_stream.is_alive() {
+                // Fails once the instance is dropped, which aborts the stream anyway
+                let _ = context.hooks.paused.wait_for(|paused| !paused).await;
+                if let Some(flow) = &context.hooks.flow {
+                    flow.ready().await;
+                }
+                context.reserve_room().await;
+
+                let next = {
+                    let resume_token = change_stream.resume_token();
+                    let mut next = pin!(change_stream.next_if_any());
+                    if context.batch_read {
+                        // A read that isn't ready right away has used up the batch and requests
+                        // the next one from the server
+                        match poll!(next.as_mut()) {
+                            Poll::Ready(next) => next,
+                            Poll::Pending => {
+                                context.batch_boundary(resume_token).await;
+                                // The boundary has used up the room reserved for the change
+                                context.reserve_room().await;
+                                next.await
+                            }
+                        }
+                    } else {
+                        next.await
+                    }
+                };
+                let next = match next {
+                    Ok(next) => next,
+                    Err(err) if is_failover(&err) => match context.options.failover_timeout {
+                        Some(timeout) => {
+                            // The position includes the post-batch resume tokens of empty
+                            // batches, so a quiet collection resumes from where the server had
+                            // looked for changes rather than from its last event
+                            let start = context.position.lock().unwrap().start();
+                            change_stream = match resume_after_failover(
+                                &context.collection,
+                                &context.watch,
+                                start,
+                                timeout,
+                                &*context.hooks.clock,
+                            )
+                            .await
+                            {
+                                Ok(change_stream) => change_stream,
+                                Err(err) if restarts_after(&context.options, &err) => {
+                                    context.restart_from_now().await?
+                                }
+                                Err(err) => return Err(err.into()),
+                            };
+                            continue;
+                        }
+                        None => return Err(err.into()),
+                    },
+                    // The driver couldn't resume the stream after an error
+                    Err(err) if restarts_after(&context.options, &err) => {
+                        change_stream = context.restart_from_now().await?;
+                        continue;
+                    }
+                    Err(err) => return Err(err.into()),
+                };
+
+                match next {
+                    Some(document) => {
+                        let document = match context.assemble(document) {
+                            Some(document) => document,
+                            None => continue,
+                        };
+                        let cluster_time = context
+                            .dispatch_change(document, change_stream.resume_token())
+                            .await;
+                        context.batch_read = true;
+
+                        if let Some(catch_up) = &mut context.catch_up {
+                            if catch_up.reached(cluster_time) {
+                                context.caught_up().await;
+                            } else if let Some(pacing) = &catch_up.pacing {
+                                catch_up.read += 1;
+                                if catch_up.read % pacing.batch_size.max(1) == 0 {
+                                    context.hooks.clock.sleep(pacing.interval).await;
+                                }
+                            }
+                        }
+                    }
+                    // An empty batch means there is no backlog left
+                    None => {
+                        // The resume token of an empty batch marks how far the server has looked
+                        // for changes, so everything up to its time has been dispatched
+                        {
+                            let mut subscriptions = context.subscriptions.lock().await;
+                            // All events of a transaction are read before the batch ends
+                            context.end_transaction(&mut subscriptions);
+
+                            let resume_token = change_stream.resume_token();
+                            context.advance(
+                                resume_token.as_ref().and_then(resume_token_time),
+                                resume_token,
+                            );
+                        }
+
+                        if context.catch_up.is_some() {
+                            context.caught_up().await;
+                        }
+                    }
+                }
+            }
+
+            // The stream has been invalidated, its last resume token is that of the invalidation
+            match (
+                context.options.restart_on_invalidate,
+                change_stream.resume_token(),
+            ) {
+                (true, Some(token)) => {
+                    change_stream = context
+                        .watch
+                        .watch(&context.collection, &StreamStart::StartAfter(token))
+                        .await?;
+                }
+                _ => break,
+            }
+        }
+
+        Err(Box::new(mongodb::error::Error::custom(
+            "change stream ended",
+        )))
+    }
+}
+
+impl StreamContext {
+    /// Dispatches a whole change event, as it's received from the server, and moves the position
+    /// to `resume_token`. Returns the cluster time of the change. Takes `&mut self` so that the
+    /// future is `Send`, as the context isn't `Sync`.
+    async fn dispatch_change(
+        &mut self,
+        mut document: Document,
+        resume_token: Option<ResumeToken>,
+    ) -> Option<Timestamp> {
+        if let Some(redaction) = &self.hooks.redaction {
+            redaction.change(&mut document);
+        }
+        let mut subscriptions = self.subscriptions.lock().await;
+
+        // Kept as a whole, once for all subscriptions that receive raw events
+        let raw = subscriptions
+            .iter()
+            .any(|(_, subscription)| subscription.reports_raw_events())
+            .then(|| Arc::new(document.clone()));
+        let event = mongodb::bson::from_document::<ChangeEvent>(document);
+        let (cluster_time, wall_time) = match &event {
+            Ok(event) => (event.event.cluster_time, event.event.wall_time),
+            Err(_) => (None, None),
+        };
+
+        self.sequence.advance(cluster_time, wall_time);
+
+        match event {
+            Ok(event) => {
+                self.begin_transaction(&mut subscriptions, event.transaction());
+
+                if let Err(err) = self.handle_event(&mut subscriptions, event, raw) {
+                    self.report(&err);
+                }
+            }
+            Err(err) => self.report(&EventError::Malformed(err)),
+        }
+
+        self.sequence.finish();
+        self.advance(cluster_time, resume_token);
+        cluster_time
+    }
+
+    fn handle_event(
+        &self,
+        subscriptions: &mut SubscriptionsManager,
+        event: ChangeEvent,
+        raw: Option<Arc<Document>>,
+    ) -> Result<(), EventError> {
+        let ChangeEvent {
+            mut event,
+            operation_description,
+            ..
+        } = event;
+        let cluster_time = event.cluster_time;
+
+        if matches!(
+            event.operation_type,
+            OperationType::DropDatabase | OperationType::Drop | OperationType::Rename
+        ) {
+            // A collection that is created again under the name has no images
+            self.hooks.applied_pre_images.remove(&self.collection_name);
+        }
+
+        // TODO: Use rayon
+        match event.operation_type {
+            OperationType::Insert => {
+                let doc = event
+                    .full_document
+                    .ok_or(EventError::MissingField("fullDocument"))?;
+
+                self.deliver(
+                    subscriptions,
+                    cluster_time,
+                    raw,
+                    Change::Insert,
+                    None,
+                    Some(doc),
+                );
+            }
+            OperationType::Delete => {
+                let key = Arc::new(self.get_key(&mut event)?);
+
+                let doc = match event.full_document_before_change {
+                    Some(doc) => doc,
+                    None => {
+                        self.pre_image_unavailable(subscriptions, key, event.operation_type);
+                        return Ok(());
+                    }
+                };
+
+                self.deliver(
+                    subscriptions,
+                    cluster_time,
+                    raw,
+                    Change::Delete(key),
+                    Some(doc),
+                    None,
+                );
+            }
+            OperationType::Update => {
+                let key = Arc::new(self.get_key(&mut event)?);
+
+                let update = Arc::new(
+                    event
+                        .update_description
+                        .ok_or(EventError::MissingField("updateDescription"))?,
+                );
+                // Without a post-image, e.g. with `FullDocumentType::WhenAvailable`, the update
+                // is matched on the old version alone
+                let new_doc = event.full_document;
+                let old_doc = match event.full_document_before_change {
+                    Some(doc) => doc,
+                    None => {
+                        self.pre_image_unavailable(subscriptions, key, event.operation_type);
+                        return Ok(());
+                    }
+                };
+
+                self.deliver(
+                    subscriptions,
+                    cluster_time,
+                    raw,
+                    Change::Update(key, update),
+                    Some(old_doc),
+                    new_doc,
+                );
+            }
+            OperationType::Replace => {
+                let key = Arc::new(self.get_key(&mut event)?);
+
+                let new_doc = event
+                    .full_document
+                    .ok_or(EventError::MissingField("fullDocument"))?;
+                let old_doc = match event.full_document_before_change {
+                    Some(doc) => doc,
+                    None => {
+                        self.pre_image_unavailable(subscriptions, key, event.operation_type);
+                        return Ok(());
+                    }
+                };
+
+                self.deliver(
+                    subscriptions,
+                    cluster_time,
+                    raw,
+                    Change::Replace(key),
+                    Some(old_doc),
+                    Some(new_doc),
+                );
+            }
+            OperationType::DropDatabase | OperationType::Drop | OperationType::Rename
+                if self.options.restart_on_invalidate =>
+            {
+                // The changes from before the reset are of documents that don't exist anymore
+                self.replay.lock().unwrap().clear();
+                self.dispatch(subscriptions, |subscription| {
+                    subscription.send(Event::Reset)
+                });
+            }
+            // The operation that caused the invalidation has already been handled
+            OperationType::Invalidate if self.options.restart_on_invalidate => {}
+            OperationType::DropDatabase
+            | OperationType::Drop
+            | OperationType::Rename
+            | OperationType::Invalidate => {
+                self.dispatch(subscriptions, |subscription| subscription.handle_drop());
+            }
+            OperationType::Other(operation_type)
+                if DDL_OPERATIONS.contains(&operation_type.as_str()) =>
+            {
+                let ddl = DdlEvent {
+                    operation_type,
+                    description: operation_description,
+                };
+                self.dispatch(subscriptions, |subscription| {
+                    subscription.send(Event::Ddl(Box::new(ddl.clone())))
+                });
+            }
+            operation_type => return Err(EventError::UnsupportedOperation(operation_type)),
+        }
+
+        Ok(())
+    }
+
+    /// Moves the position of the stream forward. Must be called while holding the
+    /// `subscriptions` lock.
+    fn advance(&self, cluster_time: Option<Timestamp>, resume_token: Option<ResumeToken>) {
+        let mut position = self.position.lock().unwrap();
+        if cluster_time > position.cluster_time {
+            position.cluster_time = cluster_time;
+        }
+        if resume_token.is_some() {
+            position.resume_token = resume_token;
+        }
+
+        self.dispatched.send_replace(position.cluster_time);
+    }
+
+    fn get_key(&self, event: &mut ChangeStreamEvent<Document>) -> Result<String, EventError> {
+        if let Some(key_fn) = &self.hooks.key_fn {
+            return [
+                &event.full_document,
+                &event.full_document_before_change,
+                &event.document_key,
+            ]
+            .into_iter()
+            .flatten()
+            .next()
+            .map(|document| key_fn(document))
+            .ok_or(EventError::MissingDocumentKey);
+        }
+
+        let key = match event
+            .document_key
+            .as_mut()
+            .and_then(|key| key.remove("_id"))
+        {
+            Some(key) => key,
+            None => match self.options.missing_key {
+                MissingKey::Skip => return Err(EventError::MissingDocumentKey),
+                MissingKey::FromDocument => {
+                    [&event.full_document, &event.full_document_before_change]
+                        .into_iter()
+                        .flatten()
+                        .find_map(|doc| doc.get("_id").cloned())
+                        .ok_or(EventError::MissingDocumentKey)?
+                }
+            },
+        };
+
+        match key {
+            Bson::String(key) => Ok(key),
+            Bson::ObjectId(key) => Ok(key.to_hex()),
+            key => Err(EventError::UnsupportedDocumentKey(key)),
+        }
+    }
+
+    /// Without the old version of the document it can't be determined which subscriptions it
+    /// matched, so every subscription is told to read the document itself.
+    fn pre_image_unavailable(
+        &self,
+        subscriptions: &mut SubscriptionsManager,
+        key: Arc<String>,
+        operation_type: OperationType,
+    ) {
+        // The instance turns the images on before the stream is opened, unless the collection
+        // doesn't exist yet, and forgets about them once they're off
+        let reason = match self
+            .hooks
+            .applied_pre_images
+            .contains(&self.collection_name)
+        {
+            true => UnavailableReason::Expired,
+            false => UnavailableReason::Disabled,
+        };
+        let failed: Vec<_> = subscriptions
+            .iter()
+            .filter(|(_, subscription)| subscription.accepts(&operation_type))
+            .filter_map(|(handle, subscription)| {
+                let event = Event::PreImageUnavailable {
+                    key: key.clone(),
+                    operation_type: operation_type.clone(),
+                    reason,
+                };
+                Some((
+                    handle.clone(),
+                    self.send(handle, subscription, event, false).err()?,
+                ))
+            })
+            .collect();
+
+        self.handle_failures(subscriptions, failed);
+    }
+
+    /// Waits until every subscription with [`Overflow::Block`] has room for the next event, e.g.
+    /// that of the next change, and removes those that stay full for longer than their policy
+    /// allows.
+    ///
+    /// [`Overflow::Block`]: crate::channel::Overflow::Block
+    async fn reserve_room(&mut self) {
+        let channels: Vec<_> = {
+            let subscriptions = self.subscriptions.lock().await;
+            subscriptions
+                .iter()
+                .filter_map(|(handle, subscription)| {
+                    Some((handle.clone(), subscription.blocking_channel()?))
+                })
+                .collect()
+        };
+        if channels.is_empty() {
+            return;
+        }
+
+        // The channels are waited for together, so that the longest wait is a single timeout
+        let reserved = join_all(channels.iter().map(|(_, channel)| channel.reserve())).await;
+        let full: Vec<_> = channels
+            .into_iter()
+            .zip(reserved)
+            .filter(|(_, reserved)| !reserved)
+            .map(|((handle, _), _)| handle)
+            .collect();
+        if full.is_empty() {
+            return;
+        }
+
+        let mut subscriptions = self.subscriptions.lock().await;
+        for handle in full {
+            if let Some(subscription) = subscriptions.remove(&handle) {
+                subscription.tear_down(TeardownReason::Overflowed);
+            }
+        }
+    }
+
+    /// Sends [`Event::BatchBoundary`] to the subscriptions that ask for it.
+    async fn batch_boundary(&mut self, resume_token: Option<ResumeToken>) {
+        self.batch_read = false;
+        // The token is past the changes that are held back
+        if self.transaction.get_mut().is_some() {
+            return;
+        }
+
+        let mut subscriptions = self.subscriptions.lock().await;
+        self.dispatch(&mut subscriptions, |subscription| {
+            match subscription.reports_batch_boundaries() {
+                true => subscription.send(Event::BatchBoundary {
+                    resume_token: resume_token.clone(),
+                }),
+                false => Ok(()),
+            }
+        });
+    }
+
+    /// Reopens the change stream at the current time once its position has fallen off the oplog,
+    /// see [`SubscriptionOptions::on_token_expiry`].
+    async fn restart_from_now(&mut self) -> Result<Changes, mongodb::error::Error> {
+        let now = cluster_time(&self.collection).await?;
+        let change_stream = self
+            .watch
+            .watch(&self.collection, &StreamStart::At(now))
+            .await?;
+        // The rest of a split event is lost
+        self.fragments = None;
+
+        let mut subscriptions = self.subscriptions.lock().await;
+        self.end_transaction(&mut subscriptions);
+        // Replaying changes from before the gap would hide it
+        self.replay.lock().unwrap().clear();
+        self.advance(now, change_stream.resume_token());
+        self.dispatch(&mut subscriptions, |subscription| {
+            subscription.send(Event::GapDetected { cluster_time: now })
+        });
+
+        let mut snapshots = Vec::new();
+        if self.options.on_token_expiry.unwrap_or_default()
+            == OnTokenExpiry::RestartFromNowWithSnapshot
+        {
+            for handle in subscriptions.handles() {
+                if let Some(subscription) = subscriptions.get_mut(&handle) {
+                    let filter = subscription.filter().cloned();
+                    snapshots.push((filter, snapshot_primer(subscription)));
+                }
+            }
+        }
+        drop(subscriptions);
+
+        // The events that are sent to the subscriptions meanwhile are held back by the primers
+        for (filter, primer) in snapshots {
+            let redaction = self.hooks.redaction.as_ref();
+            send_snapshot(&self.collection, &self.options, redaction, filter, primer).await?;
+        }
+
+        Ok(change_stream)
+    }
+
+    async fn caught_up(&mut self) {
+        self.catch_up = None;
+
+        {
+            let mut subscriptions = self.subscriptions.lock().await;
+            self.end_transaction(&mut subscriptions);
+        }
+        // The change or transaction before it has used up the room reserved for it
+        self.reserve_room().await;
+        let mut subscriptions = self.subscriptions.lock().await;
+        self.dispatch(&mut subscriptions, |subscription| {
+            subscription.send(Event::CaughtUp)
+        });
+    }
+
+    /// Removes all subscriptions once the change stream has ended with `err`, so that their
+    /// receivers return `None` rather than waiting for events that never come. Takes `&mut self`
+    /// so that the future is `Send`.
+    async fn fail(&mut self, err: String) {
+        let err = EventError::StreamFailed(err);
+        match &self.hooks.on_error {
+            Some(on_error) => on_error(&self.collection_name, &err),
+            None => eprintln!(
+                "The change stream of collection {} has ended: {}",
+                self.collection_name, err
+            ),
+        }
+
+        let mut subscriptions = self.subscriptions.lock().await;
+        for handle in subscriptions.handles() {
+            if let Some(subscription) = subscriptions.remove(&handle) {
+                subscription.tear_down(TeardownReason::StreamFailed);
+            }
+        }
+    }
+
+    fn report(&self, err: &EventError) {
+        match &self.hooks.on_error {
+            Some(on_error) => on_error(&self.collection_name, err),
+            None => eprintln!(
+                "Skipped a change event on collection {}: {}",
+                self.collection_name, err
+            ),
+        }
+    }
+
+    /// Delivers a change to the subscriptions it's relevant to. All subscriptions are matched
+    /// first, so that it's known which event is the last to need the new version of the document.
+    fn deliver(
+        &self,
+        subscriptions: &mut SubscriptionsManager,
+        cluster_time: Option<Timestamp>,
+        raw_change: Option<Arc<Document>>,
+        change: Change,
+        old_doc: Option<Document>,
+        new_doc: Option<Document>,
+    ) {
+        let old_candidate = old_doc.as_ref().map(Candidate::new);
+        let new_candidate = new_doc.as_ref().map(Candidate::new);
+        let transitions: Vec<_> = subscriptions
+            .iter()
+            .filter_map(|(handle, subscription)| {
+                let transition = change.transition(
+                    subscription,
+                    old_candidate.as_ref(),
+                    new_candidate.as_ref(),
+                )?;
+                let shared = subscription.shares_serialized()
+                    && !matches!(transition, Transition::Raw { .. });
+                Some((handle.clone(), transition, shared))
+            })
+            .collect();
+
+        if let Some(audit) = &self.hooks.audit {
+            if !transitions.is_empty() {
+                let key = match &change {
+                    Change::Insert => match (&self.hooks.key_fn, new_doc.as_ref()) {
+                        (Some(key_fn), Some(doc)) => Some(Arc::new(key_fn(doc))),
+                        (None, Some(doc)) => match doc.get("_id") {
+                            Some(Bson::String(key)) => Some(Arc::new(key.clone())),
+                            Some(Bson::ObjectId(key)) => Some(Arc::new(key.to_hex())),
+                            _ => None,
+                        },
+                        (_, None) => None,
+                    },
+                    Change::Delete(key) | Change::Update(key, _) | Change::Replace(key) => {
+                        Some(key.clone())
+                    }
+                };
+                let handles = transitions.iter().map(|(handle, ..)| handle.clone());
+                audit.record(
+                    change.operation_type(),
+                    key,
+                    cluster_time,
+                    handles.collect(),
+                );
+            }
+        }
+
+        // Raw transitions share both versions of the document
+        let raw = transitions
+            .iter()
+            .any(|(_, transition, _)| matches!(transition, Transition::Raw { .. }))
+            .then(|| Some((Arc::new(old_doc.clone()?), Arc::new(new_doc.clone()?))))
+            .flatten();
+
+        // Every transition is serialized once for all subscriptions that receive extended JSON
+        let mut serialized: Vec<(Transition, Arc<str>)> = Vec::new();
+        for (_, transition, shared) in &transitions {
+            if *shared && serialized.iter().all(|(other, _)| other != transition) {
+                serialized.push((*transition, change.serialize(*transition, new_doc.as_ref())));
+            }
+        }
+
+        let recorded = (self.hooks.replay_capacity > 0).then(|| new_doc.clone());
+        let needed = transitions
+            .iter()
+            .filter(|(_, transition, shared)| !shared && change.needs_document(*transition))
+            .count();
+        let mut documents = Documents::new(new_doc, self.options.owned_documents, needed);
+
+        let failed: Vec<_> = transitions
+            .into_iter()
+            .filter_map(|(handle, transition, shared)| {
+                let event = match (transition, &raw) {
+                    (
+                        Transition::Raw {
+                            old_matched,
+                            new_matched,
+                        },
+                        Some((old, new)),
+                    ) => change.raw_event(old_matched, new_matched, old, new),
+                    (Transition::Raw { .. }, None) => return None,
+                    _ if shared => serialized
+                        .iter()
+                        .find(|(other, _)| *other == transition)
+                        .map(|(_, json)| Event::Serialized(json.clone()))?,
+                    _ => change.event(transition, &mut documents),
+                };
+                let subscription = subscriptions.get(&handle)?;
+                let event = match &raw_change {
+                    Some(change) if subscription.reports_raw_events() => {
+                        Event::Raw(Box::new(RawEvent {
+                            event,
+                            change: change.clone(),
+                        }))
+                    }
+                    _ => event,
+                };
+                let synthesized = change.synthesizes(transition, subscription.reports_membership());
+                let failure = self.send(&handle, subscription, event, synthesized).err()?;
+                Some((handle, failure))
+            })
+            .collect();
+
+        self.handle_failures(subscriptions, failed);
+
+        if let Some(new_doc) = recorded {
+            self.replay.lock().unwrap().record(RecordedChange {
+                change,
+                old_doc,
+                new_doc,
+            });
+        }
+    }
+
+    /// Sends a change event to a subscription, or holds it back if it is part of the current
+    /// transaction; see [`Subscription::send_with`].
+    fn send(
+        &self,
+        handle: &SubscriptionHandle,
+        subscription: &Subscription,
+        event: Event,
+        synthesized: bool,
+    ) -> Result<(), SendFailure> {
+        match self.transaction.borrow_mut().as_mut() {
+            Some(transaction) => {
+                if let Some(event) = subscription.intercept(event) {
+                    transaction
+                        .events
+                        .entry(handle.clone())
+                        .or_default()
+                        .push(event);
+                }
+                Ok(())
+            }
+            None => subscription.send_with(event, synthesized),
+        }
+    }
+
+    /// Joins the fragments of an event that has been split because it exceeded the maximum
+    /// document size. Returns `None` until the last fragment has been read. An event whose
+    /// fragments weren't all read, e.g. because one lacked its `splitEvent`, is reported and
+    /// skipped.
+    fn assemble(&mut self, mut document: Document) -> Option<Document> {
+        let split = match document.remove("splitEvent") {
+            Some(Bson::Document(split)) => split,
+            _ => {
+                self.abandon_split_event();
+                return Some(document);
+            }
+        };
+        let (fragment, of) = match (split.get_i32("fragment"), split.get_i32("of")) {
+            (Ok(fragment), Ok(of)) => (fragment, of),
+            _ => {
+                self.abandon_split_event();
+                self.report(&EventError::MissingField("splitEvent.fragment"));
+                return None;
+            }
+        };
+
+        let continues = self
+            .fragments
+            .as_ref()
+            .is_some_and(|split| split.received + 1 == fragment && split.of == of);
+        if !continues {
+            self.abandon_split_event();
+            if fragment != 1 {
+                self.report(&EventError::IncompleteSplitEvent { received: 0, of });
+                return None;
+            }
+        }
+
+        // Every fragment holds some of the fields; the resume token of the last one is that of
+        // the whole event
+        let split = self.fragments.get_or_insert_with(|| SplitEvent {
+            fields: Document::new(),
+            received: 0,
+            of,
+        });
+        split.fields.extend(document);
+        split.received = fragment;
+
+        match fragment < of {
+            true => None,
+            false => self.fragments.take().map(|split| split.fields),
+        }
+    }
+
+    /// Reports and discards the fragments of a split event that won't be completed.
+    fn abandon_split_event(&mut self) {
+        if let Some(split) = self.fragments.take() {
+            self.report(&EventError::IncompleteSplitEvent {
+                received: split.received,
+                of: split.of,
+            });
+        }
+    }
+
+    /// Delivers the held back transaction if `transaction` is a different one, and starts holding
+    /// back the events of `transaction`.
+    fn begin_transaction(
+        &self,
+        subscriptions: &mut SubscriptionsManager,
+        transaction: Option<TransactionId>,
+    ) {
+        if !self.options.group_transactions {
+            return;
+        }
+
+        let current = self
+            .transaction
+            .borrow()
+            .as_ref()
+            .map(|pending| &pending.id)
+            == transaction.as_ref();
+        if current {
+            return;
+        }
+
+        self.end_transaction(subscriptions);
+        *self.transaction.borrow_mut() = transaction.map(|id| PendingTransaction {
+            id,
+            events: HashMap::new(),
+        });
+    }
+
+    /// Delivers the events that have been held back, as one [`Event::Transaction`] per
+    /// subscription.
+    fn end_transaction(&self, subscriptions: &mut SubscriptionsManager) {
+        let PendingTransaction { id, events } = match self.transaction.borrow_mut().take() {
+            Some(transaction) => transaction,
+            None => return,
+        };
+
+        let failed: Vec<_> = events
+            .into_iter()
+            .filter_map(|(handle, events)| {
+                let event = Event::Transaction(Box::new(Transaction {
+                    lsid: id.lsid.clone(),
+                    txn_number: id.txn_number,
+                    events,
+                }));
+                let failure = subscriptions.get(&handle)?.forward(event, false).err()?;
+                Some((handle, failure))
+            })
+            .collect();
+
+        self.handle_failures(subscriptions, failed);
+    }
+
+    /// Sends an event to every subscription, see [`StreamContext::handle_failures`].
+    fn dispatch(
+        &self,
+        subscriptions: &mut SubscriptionsManager,
+        send: impl Fn(&Subscription) -> Result<(), SendFailure>,
+    ) {
+        let failed: Vec<_> = subscriptions
+            .iter()
+            .filter_map(|(handle, subscription)| Some((handle.clone(), send(subscription).err()?)))
+            .collect();
+
+        self.handle_failures(subscriptions, failed);
+    }
+
+    /// Subscriptions whose receiver has been dropped, that have been closed because they fell
+    /// behind or that are complete are removed. Every event that wasn't delivered is sent to the dead-letter channel.
+    ///
+    /// Sending never waits: a full channel is resolved right away by its [`Overflow`] policy,
+    /// and the resulting failures are only applied here, once all subscriptions have been sent
+    /// to. [`Overflow::Block`] is resolved before the change is even read instead, by
+    /// [`StreamContext::reserve_room`] waiting until every such channel has room, so that its
+    /// send doesn't have to wait either. The subscriptions are sent to one after the other; there
+    /// is no parallel dispatch.
+    ///
+    /// [`Overflow`]: crate::channel::Overflow
+    /// [`Overflow::Block`]: crate::channel::Overflow::Block
+    fn handle_failures(
+        &self,
+        subscriptions: &mut SubscriptionsManager,
+        failed: Vec<(SubscriptionHandle, SendFailure)>,
+    ) {
+        for (handle, failure) in failed {
+            match &failure {
+                SendFailure::Closed(_) => {
+                    if let Some(subscription) = subscriptions.remove(&handle) {
+                        subscription.tear_down(match subscription.is_closed() {
+                            true => TeardownReason::ReceiverDropped,
+                            false => TeardownReason::Overflowed,
+                        });
+                    }
+                }
+                SendFailure::Dropped { dropped, .. } => self.report_drop(&handle, *dropped),
+            }
+
+            if let Some(dead_letter) = &self.hooks.dead_letter {
+                dead_letter.send(handle, failure.into_event());
+            }
+        }
+
+        let completed: Vec<_> = subscriptions
+            .iter()
+            .filter(|(_, subscription)| subscription.is_complete())
+            .map(|(handle, _)| handle.clone())
+            .collect();
+        for handle in completed {
+            if let Some(subscription) = subscriptions.remove(&handle) {
+                subscription.tear_down(TeardownReason::Completed);
+            }
+        }
+    }
+
+    /// Without a handler, drops are written to stderr whenever their number reaches a power of
+    /// two, so that a subscription that keeps falling behind doesn't flood the log.
+    fn report_drop(&self, handle: &SubscriptionHandle, dropped: u64) {
+        match &self.hooks.on_drop {
+            Some(on_drop) => on_drop(
+                &Handle {
+                    collection_name: self.collection_name.clone(),
+                    entry_id: self.entry_id,
+                    subscription_handle: handle.clone(),
+                },
+                dropped,
+            ),
+            None if dropped.is_power_of_two() => eprintln!(
+                "A subscription on collection {} has dropped {} events because its channel is full",
+                self.collection_name, dropped
+            ),
+            None => {}
+        }
+    }
+}
+
+/// The current cluster time, as reported by the server.
+pub(crate) async fn cluster_time(
+    collection: &Collection<Document>,
+) -> Result<Option<Timestamp>, mongodb::error::Error> {
+    let mut session = collection.client().start_session(None).await?;
+    collection
+        .client()
+        .database(&collection.namespace().db)
+        .run_command_with_session(doc! { "ping": 1 }, None, &mut session)
+        .await?;
+    Ok(session.operation_time())
+}
+
+/// Reopens a change stream after a failover, retrying until a new primary has been elected or
+/// `timeout` has passed.
+async fn resume_after_failover(
+    collection: &Collection<Document>,
+    watch: &WatchOptions,
+    start: StreamStart,
+    timeout: Duration,
+    clock: &dyn Clock,
+) -> Result<Changes, mongodb::error::Error> {
+    let deadline = clock.now() + timeout;
+
+    loop {
+        match watch.watch(collection, &start).await {
+            Ok(change_stream) => return Ok(change_stream),
+            Err(err) if is_failover(&err) && clock.now() < deadline => {
+                clock.sleep(FAILOVER_RETRY_INTERVAL).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+/// Sends the documents that currently match `filter` as [`Event::Added`] with `primer`, see
+/// [`OnTokenExpiry::RestartFromNowWithSnapshot`], and then the events it has held back.
+async fn send_snapshot(
+    collection: &Collection<Document>,
+    options: &SubscriptionOptions,
+    redaction: Option<&Redaction>,
+    filter: Option<Document>,
+    mut primer: Primer,
+) -> Result<(), mongodb::error::Error> {
+    let find_options = FindOptions::builder()
+        .read_concern(options.read_concern.clone())
+        .selection_criteria(options.selection_criteria.clone())
+        .collation(options.collation.clone())
+        .build();
+
+    let mut cursor = collection.find(filter, find_options).await?;
+    while cursor.advance().await? {
+        let mut document = cursor.deserialize_current()?;
+        if let Some(redaction) = redaction {
+            redaction.document(&mut document);
+        }
+        primer.add(document);
+    }
+
+    primer.finish();
+    Ok(())
+}
+
+/// The primer of the snapshot of `subscription`, see [`send_snapshot`], which holds back the
+/// events that are dispatched to it until the documents have been sent. A subscription that is
+/// being primed already holds them back itself.
+fn snapshot_primer(subscription: &mut Subscription) -> Primer {
+    match subscription.is_priming() {
+        true => subscription.snapshot(),
+        false => subscription.start_priming(),
+    }
+}
+
+/// How long to wait between attempts to resume a change stream after a failover.
+const FAILOVER_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Whether `err` is caused by a failover of the replica set, after which the change stream can be
+/// resumed once a new primary has been elected.
+fn is_failover(err: &mongodb::error::Error) -> bool {
+    classify(err).is_failover()
+}
+
+/// Whether the change stream is reopened at the current time after `err`, see
+/// [`SubscriptionOptions::on_token_expiry`].
+fn restarts_after(options: &SubscriptionOptions, err: &mongodb::error::Error) -> bool {
+    options.on_token_expiry.unwrap_or_default() != OnTokenExpiry::Fail
+        && classify(err) == ErrorKind::HistoryLost
+}
+
+/// The cluster time of the change that a resume token points at. Resume tokens aren't meant to be
+/// inspected, but their `_data` starts with a type byte of `0x82` followed by the timestamp.
+fn resume_token_time(token: &ResumeToken) -> Option<Timestamp> {
+    let token = mongodb::bson::to_document(token).ok()?;
+    let data = token.get_str("_data").ok()?;
+
+    match (data.get(..2), data.get(2..10), data.get(10..18)) {
+        (Some("82"), Some(time), Some(increment)) => Some(Timestamp {
+            time: u32::from_str_radix(time, 16).ok()?,
+            increment: u32::from_str_radix(increment, 16).ok()?,
+        }),
+        _ => None,
+    }
+}
+
+impl Drop for CollectionEntry {
+    fn drop(&mut self) {
+        // `AbortHandle` does implement `Drop`, but just to be extra safe
+        if let Some(change_stream_handle) = &self.change_stream_handle {
+            change_stream_handle.abort();
+        }
+        if let Some(pre_image_check) = &self.pre_image_check {
+            pre_image_check.abort();
+        }
+        if let Some(stuck_check) = &self.stuck_check {
+            stuck_check.abort();
+        }
+        if let Some(creation_watch) = &self.creation_watch {
+            creation_watch.abort();
+        }
+
+        // Without a runtime, e.g. when the instance is dropped after it, the images stay on
+        if let (Some(revert), Ok(runtime)) = (
+            self.revert_pre_images.take(),
+            tokio::runtime::Handle::try_current(),
+        ) {
+            runtime.spawn(revert.revert());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        cell::RefCell,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc, Mutex as StdMutex,
+        },
+        time::Duration,
+    };
+
+    use mongodb::{
+        bson::{self, doc, Bson, Document, Timestamp},
+        change_stream::event::OperationType,
+        error::{CommandError, Error, ErrorKind},
+        Client,
+    };
+    use tokio::sync::{watch, Mutex};
+
+    use super::{
+        is_failover, AppliedPreImages, CollectionEntry, Hooks, PendingGap, ReplayBuffer,
+        StreamContext, StreamPosition, SubscriptionCount, SubscriptionsManager, WatchOptions,
+    };
+    use crate::{
+        channel::{channel, test_channel, ChannelConfig, Overflow, Sequence},
+        clock::TokioClock,
+        options::{OnTokenExpiry, SubscriptionOptions},
+        receiver::EventReceiver,
+        redaction::Redaction,
+        server_filter::ServerFilter,
+        spawner::{self, Spawner, TokioSpawner},
+        subscription::{Event, Subscription, UnavailableReason},
+    };
+
+    /// Counts the `collMod`s that [`AppliedPreImages::apply_with`] runs.
+    #[derive(Debug, Clone, Default)]
+    struct CollMods(Arc<AtomicUsize>);
+
+    impl CollMods {
+        async fn run(&self, result: Result<(), ()>) -> Result<(), ()> {
+            self.0.fetch_add(1, Ordering::Relaxed);
+            tokio::task::yield_now().await;
+            result
+        }
+
+        fn count(&self) -> usize {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[tokio::test]
+    async fn coll_mod_runs_once_per_collection() {
+        let applied = AppliedPreImages::default();
+        let coll_mods = CollMods::default();
+
+        for _ in 0..100 {
+            applied
+                .apply_with("orders", || coll_mods.run(Ok(())))
+                .await
+                .unwrap();
+        }
+        assert_eq!(coll_mods.count(), 1);
+        assert!(applied.contains("orders"));
+
+        applied
+            .apply_with("users", || coll_mods.run(Ok(())))
+            .await
+            .unwrap();
+        assert_eq!(coll_mods.count(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_adds_run_one_coll_mod() {
+        let applied = AppliedPreImages::default();
+        let coll_mods = CollMods::default();
+
+        let tasks: Vec<_> = (0..50)
+            .map(|_| {
+                let applied = applied.clone();
+                let coll_mods = coll_mods.clone();
+                tokio::spawn(async move {
+                    applied
+                        .apply_with("orders", || async {
+                            // Long enough for all others to wait for it
+                            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+                            coll_mods.run(Ok(())).await
+                        })
+                        .await
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        assert_eq!(coll_mods.count(), 1);
+        assert!(applied.contains("orders"));
+    }
+
+    #[tokio::test]
+    async fn failed_coll_mod_is_retried() {
+        let applied = AppliedPreImages::default();
+        let coll_mods = CollMods::default();
+
+        assert!(applied
+            .apply_with("orders", || coll_mods.run(Err(())))
+            .await
+            .is_err());
+        assert!(!applied.contains("orders"));
+
+        applied
+            .apply_with("orders", || coll_mods.run(Ok(())))
+            .await
+            .unwrap();
+        applied
+            .apply_with("orders", || coll_mods.run(Ok(())))
+            .await
+            .unwrap();
+        assert_eq!(coll_mods.count(), 2);
+    }
+
+    #[tokio::test]
+    async fn removed_collection_runs_coll_mod_again() {
+        let applied = AppliedPreImages::default();
+        let coll_mods = CollMods::default();
+
+        applied
+            .apply_with("orders", || coll_mods.run(Ok(())))
+            .await
+            .unwrap();
+        applied.remove("orders");
+        assert!(!applied.contains("orders"));
+        applied
+            .apply_with("orders", || coll_mods.run(Ok(())))
+            .await
+            .unwrap();
+        assert_eq!(coll_mods.count(), 2);
+    }
+
+    /// An error as the server reports it for a command that failed with `code`.
+    fn command_error(code: i32) -> Error {
+        let command: CommandError =
+            bson::from_document(doc! { "code": code, "codeName": "", "errmsg": "" }).unwrap();
+        ErrorKind::Command(command).into()
+    }
+
+    #[test]
+    fn failovers_are_recognized() {
+        // `NotWritablePrimary`, `NotPrimaryNoSecondaryOk`, `NotPrimaryOrSecondary`,
+        // `PrimarySteppedDown`, `InterruptedDueToReplStateChange`, `InterruptedAtShutdown` and
+        // `ShutdownInProgress`
+        for code in [10107, 13435, 13436, 189, 11602, 11600, 91] {
+            assert!(is_failover(&command_error(code)), "{code}");
+        }
+        // The connection to the old primary was closed
+        let reset = Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert!(is_failover(&reset));
+    }
+
+    #[test]
+    fn other_errors_are_no_failovers() {
+        // `ChangeStreamHistoryLost`, `CursorNotFound`, `Unauthorized` and `BadValue`
+        for code in [286, 43, 13, 2] {
+            assert!(!is_failover(&command_error(code)), "{code}");
+        }
+        assert!(!is_failover(&Error::custom("failed")));
+    }
+
+    /// A context that dispatches changes to the subscriptions of the collection `orders` like
+    /// the change stream task does; the server is never contacted.
+    async fn context(redaction: Option<Redaction>) -> StreamContext {
+        // No server answers, which the stream only notices once it's opened
+        let client =
+            Client::with_uri_str("mongodb://localhost:27017/?serverSelectionTimeoutMS=100")
+                .await
+                .unwrap();
+        let options = SubscriptionOptions::default();
+        StreamContext {
+            entry_id: 0,
+            dispatched: watch::channel(None).0,
+            collection: client.database("test").collection("orders"),
+            watch: WatchOptions::new(&options),
+            options,
+            collection_name: "orders".to_string(),
+            subscriptions: Arc::new(Mutex::new(SubscriptionsManager::new(
+                SubscriptionCount::default(),
+            ))),
+            position: Arc::new(StdMutex::new(StreamPosition::default())),
+            catch_up: None,
+            fragments: None,
+            batch_read: false,
+            transaction: RefCell::new(None),
+            sequence: Sequence::default(),
+            replay: Arc::new(StdMutex::new(ReplayBuffer::new(0))),
+            hooks: Hooks {
+                dead_letter: None,
+                audit: None,
+                on_error: None,
+                on_drop: None,
+                replay_capacity: 0,
+                flow: None,
+                subscription_count: SubscriptionCount::default(),
+                applied_pre_images: AppliedPreImages::default(),
+                redaction,
+                pre_image_check: None,
+                stuck_check: None,
+                filter_on_server: false,
+                key_fn: None,
+                paused: watch::channel(false).1,
+                clock: Arc::new(TokioClock),
+            },
+        }
+    }
+
+    /// An entry whose change stream task only holds on to `context` until it's aborted.
+    fn entry(
+        context: StreamContext,
+        server_filter: Option<ServerFilter>,
+        spawner: &dyn Spawner,
+    ) -> CollectionEntry {
+        let mut entry = CollectionEntry {
+            id: 0,
+            subscriptions: context.subscriptions.clone(),
+            position: context.position.clone(),
+            running_streams: Arc::new(AtomicUsize::new(0)),
+            failed: Arc::new(AtomicBool::new(false)),
+            dispatched: context.dispatched.subscribe(),
+            sequence: context.sequence.clone(),
+            replay: context.replay.clone(),
+            revert_pre_images: None,
+            pending_gap: StdMutex::new(None),
+            collection: context.collection.clone(),
+            options: context.options.clone(),
+            watch: context.watch.clone(),
+            server_filter,
+            hooks: context.hooks.clone(),
+            change_stream_handle: None,
+            pre_image_check: None,
+            stuck_check: None,
+            creation_watch: None,
+        };
+        entry.change_stream_handle = Some(spawner::spawn(spawner, async move {
+            let _context = context;
+            std::future::pending::<()>().await
+        }));
+        entry
+    }
+
+    /// Adds a subscription to the context, set up by `configure`.
+    async fn subscribe(
+        context: &StreamContext,
+        filter: Option<Document>,
+        configure: impl FnOnce(&mut Subscription),
+    ) -> EventReceiver {
+        let (sender, receiver) = test_channel();
+        let mut subscription = Subscription::new(filter, sender).unwrap();
+        configure(&mut subscription);
+        context
+            .subscriptions
+            .lock()
+            .await
+            .add(subscription)
+            .unwrap();
+        receiver
+    }
+
+    fn change(operation_type: &str, fields: Document) -> Document {
+        let mut change = doc! {
+            "_id": { "_data": "00" },
+            "operationType": operation_type,
+            "clusterTime": Timestamp { time: 1, increment: 1 },
+            "ns": { "db": "test", "coll": "orders" },
+            "documentKey": { "_id": "1" },
+        };
+        change.extend(fields);
+        change
+    }
+
+    #[tokio::test]
+    async fn redacted_fields_reach_no_output_form() {
+        let mut context = context(Some(Redaction::new(["secret", "nested.secret"]))).await;
+        let mut documents = subscribe(&context, None, |_| {}).await;
+        let mut extended_json = subscribe(&context, None, |subscription| {
+            subscription.set_extended_json(true)
+        })
+        .await;
+        let mut raw = subscribe(&context, None, |subscription| {
+            subscription.set_raw_events(true)
+        })
+        .await;
+
+        let document = doc! { "_id": "1", "name": "a", "secret": "x", "nested": { "secret": "y" } };
+        let redacted = doc! { "_id": "1", "name": "a", "nested": {} };
+        context
+            .dispatch_change(
+                change("insert", doc! { "fullDocument": document.clone() }),
+                None,
+            )
+            .await;
+        let updated = doc! { "_id": "1", "name": "b", "secret": "z", "nested": { "secret": "y" } };
+        context
+            .dispatch_change(
+                change(
+                    "update",
+                    doc! {
+                        "fullDocument": updated,
+                        "fullDocumentBeforeChange": document,
+                        "updateDescription": {
+                            "updatedFields": { "name": "b", "secret": "z" },
+                            "removedFields": ["nested.secret"],
+                        },
+                    },
+                ),
+                None,
+            )
+            .await;
+
+        match &documents.drain_available()[..] {
+            [Event::Added(added), Event::Updated((_, description))] => {
+                assert_eq!(**added, redacted);
+                assert_eq!(description.updated_fields, doc! { "name": "b" });
+                assert!(description.removed_fields.is_empty());
+            }
+            events => panic!("unexpected events {events:?}"),
+        }
+
+        let serialized = extended_json.drain_available();
+        assert_eq!(serialized.len(), 2);
+        for event in serialized {
+            let Event::Serialized(json) = event else {
+                panic!("expected a serialized event, got {event:?}");
+            };
+            assert!(!json.contains("secret"), "{json}");
+        }
+
+        let raw = raw.drain_available();
+        assert_eq!(raw.len(), 2);
+        for event in raw {
+            let Event::Raw(raw) = event else {
+                panic!("expected a raw event, got {event:?}");
+            };
+            assert!(!Bson::from(&*raw.change).to_string().contains("secret"));
+            assert!(!format!("{:?}", raw.event).contains("secret"));
+        }
+    }
+
+    #[tokio::test]
+    async fn redacted_fields_are_not_matched() {
+        let mut context = context(Some(Redaction::new(["secret"]))).await;
+        let mut on_secret = subscribe(&context, Some(doc! { "secret": "x" }), |_| {}).await;
+        let mut on_missing_secret = subscribe(
+            &context,
+            Some(doc! { "secret": { "$exists": false } }),
+            |_| {},
+        )
+        .await;
+
+        let document = doc! { "_id": "1", "name": "a", "secret": "x" };
+        context
+            .dispatch_change(change("insert", doc! { "fullDocument": document }), None)
+            .await;
+
+        assert_eq!(on_secret.drain_available(), []);
+        assert!(matches!(
+            &on_missing_secret.drain_available()[..],
+            [Event::Added(added)] if **added == doc! { "_id": "1", "name": "a" }
+        ));
+    }
+
+    #[tokio::test]
+    async fn unavailable_pre_images_tell_expired_from_disabled() {
+        let mut context = context(None).await;
+        let mut receiver = subscribe(&context, None, |_| {}).await;
+        let unavailable = |reason| Event::PreImageUnavailable {
+            key: Arc::new("1".to_string()),
+            operation_type: OperationType::Delete,
+            reason,
+        };
+
+        // The images haven't been turned on, e.g. as the collection didn't exist yet
+        context
+            .dispatch_change(change("delete", doc! {}), None)
+            .await;
+        assert_eq!(
+            receiver.drain_available(),
+            [unavailable(UnavailableReason::Disabled)]
+        );
+
+        context
+            .hooks
+            .applied_pre_images
+            .apply_with("orders", || async { Ok::<_, ()>(()) })
+            .await
+            .unwrap();
+        context
+            .dispatch_change(change("delete", doc! {}), None)
+            .await;
+        assert_eq!(
+            receiver.drain_available(),
+            [unavailable(UnavailableReason::Expired)]
+        );
+
+        // Once the check finds them off
+        context.hooks.applied_pre_images.remove("orders");
+        context
+            .dispatch_change(change("delete", doc! {}), None)
+            .await;
+        assert_eq!(
+            receiver.drain_available(),
+            [unavailable(UnavailableReason::Disabled)]
+        );
+    }
+
+    #[tokio::test]
+    async fn caught_up_waits_for_room_after_the_change() {
+        let mut context = context(None).await;
+        let (sender, mut receiver) = channel(ChannelConfig {
+            capacity: Some(1),
+            overflow: Overflow::Block(Duration::from_secs(5)),
+            ..ChannelConfig::default()
+        });
+        context
+            .subscriptions
+            .lock()
+            .await
+            .add(Subscription::new(None, sender).unwrap())
+            .unwrap();
+
+        context.reserve_room().await;
+        context
+            .dispatch_change(
+                change("insert", doc! { "fullDocument": { "_id": "1" } }),
+                None,
+            )
+            .await;
+        // The change fills the channel, so the event that follows has to wait for the receiver
+        let (_, added) = tokio::join!(context.caught_up(), receiver.recv());
+        assert!(matches!(added, Some(Event::Added(_))));
+        assert_eq!(receiver.try_recv(), Some(Event::CaughtUp));
+    }
+
+    #[tokio::test]
+    async fn widening_the_filter_leaves_the_subscriptions_unlocked() {
+        let context = context(None).await;
+        let mut receiver = subscribe(&context, None, |_| {}).await;
+        let spawner = TokioSpawner::new();
+        let filter = doc! { "status": "open" };
+        let mut entry = entry(context, Some(ServerFilter::new(Some(&filter))), &spawner);
+        let subscriptions = entry.subscriptions.clone();
+
+        let wider = doc! { "status": "closed" };
+        let (widened, ()) = tokio::join!(entry.widen_filter(&spawner, Some(&wider)), async {
+            // While the new stream waits for a server
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let subscriptions = subscriptions.try_lock();
+            assert_eq!(
+                subscriptions.map(|subscriptions| subscriptions.len()).ok(),
+                Some(1)
+            );
+        });
+        // Neither the new stream nor the stopped one can be opened
+        assert!(widened.is_err());
+        assert!(entry.has_failed());
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn refreshing_leaves_the_subscriptions_unlocked() {
+        let context = context(None).await;
+        let mut receiver = subscribe(&context, None, |_| {}).await;
+        let spawner = TokioSpawner::new();
+        let mut entry = entry(context, None, &spawner);
+        let subscriptions = entry.subscriptions.clone();
+
+        let (refreshed, ()) = tokio::join!(entry.refresh(&spawner, false), async {
+            // While asking for the cluster time waits for a server
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let subscriptions = subscriptions.try_lock();
+            assert_eq!(
+                subscriptions.map(|subscriptions| subscriptions.len()).ok(),
+                Some(1)
+            );
+        });
+        // Neither the new stream nor the stopped one can be opened
+        assert!(refreshed.is_err());
+        assert!(entry.has_failed());
+        assert_eq!(receiver.recv().await, None);
+    }
+
+    #[tokio::test]
+    async fn the_snapshot_after_a_gap_leaves_the_subscriptions_unlocked() {
+        let context = context(None).await;
+        let spawner = TokioSpawner::new();
+        let entry = entry(context, None, &spawner);
+        *entry.pending_gap.lock().unwrap() = Some(PendingGap {
+            collection: entry.collection.clone(),
+            options: SubscriptionOptions::default()
+                .on_token_expiry(OnTokenExpiry::RestartFromNowWithSnapshot),
+        });
+        let (sender, _receiver) = test_channel();
+        let subscription = Subscription::new(None, sender).unwrap();
+
+        let (added, ()) = tokio::join!(entry.add_subscription(subscription, None), async {
+            // While the documents are read, the subscription holds back its events
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            let subscriptions = entry.subscriptions.try_lock().unwrap();
+            let (_, subscription) = subscriptions.iter().next().unwrap();
+            assert!(subscription.is_priming());
+        });
+        // The documents can't be read, so the subscription isn't added after all
+        assert!(added.is_err());
+        assert!(entry.is_empty().await);
     }
 }
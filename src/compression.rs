@@ -0,0 +1,106 @@
+//! Compression of the events that wait in the channel of a subscription, see
+//! [`SubscriptionOptions::compress_backlog`](crate::options::SubscriptionOptions::compress_backlog).
+
+use std::{io::Cursor, sync::Arc};
+
+use mongodb::bson::Document;
+
+use crate::subscription::{Event, EventDocument};
+
+/// Favours speed, as events are compressed while the change stream is being read.
+const LEVEL: i32 = 1;
+
+/// An event whose documents are held as a single zstd frame of their BSON.
+#[derive(Debug)]
+pub(crate) struct Compressed {
+    /// The event with empty documents in place of the compressed ones.
+    event: Event,
+    documents: Vec<u8>,
+    count: usize,
+}
+
+impl Compressed {
+    /// Returns the event itself if it holds no documents, or they can't be compressed.
+    pub(crate) fn new(mut event: Event) -> Result<Self, Event> {
+        let mut documents = Vec::new();
+        visit(&mut event, &mut |document| {
+            documents.push(std::mem::take(document))
+        });
+        if documents.is_empty() {
+            return Err(event);
+        }
+
+        let mut raw = Vec::new();
+        let compressed = documents
+            .iter()
+            .try_for_each(|document| document.to_writer(&mut raw).map_err(|_| ()))
+            .and_then(|()| zstd::encode_all(raw.as_slice(), LEVEL).map_err(|_| ()));
+
+        match compressed {
+            Ok(compressed) => Ok(Self {
+                event,
+                documents: compressed,
+                count: documents.len(),
+            }),
+            Err(()) => {
+                let mut documents = documents.into_iter();
+                visit(&mut event, &mut |document| {
+                    *document = documents.next().unwrap_or_default()
+                });
+                Err(event)
+            }
+        }
+    }
+
+    pub(crate) fn decompress(self) -> Event {
+        let Self {
+            mut event,
+            documents,
+            count,
+        } = self;
+
+        // The frame was written by `Compressed::new`, so it can only fail to decode if memory
+        // runs out
+        let raw = zstd::decode_all(documents.as_slice()).expect("invalid compressed event");
+        let mut raw = Cursor::new(raw);
+        let mut documents = (0..count).map(|_| Document::from_reader(&mut raw).unwrap_or_default());
+        visit(&mut event, &mut |document| {
+            *document = documents.next().unwrap_or_default()
+        });
+
+        event
+    }
+}
+
+/// Calls `f` with every document of the event, in the same order for the same kind of event.
+/// Shared documents are made owned, as the event gets its own copy.
+fn visit(event: &mut Event, f: &mut impl FnMut(&mut Document)) {
+    match event {
+        Event::Added(document) | Event::Entered(document) | Event::Replaced((_, document)) => {
+            owned(document, f)
+        }
+        Event::Transition(transition) => {
+            owned(&mut transition.old, f);
+            owned(&mut transition.new, f);
+        }
+        Event::Raw(raw) => {
+            visit(&mut raw.event, f);
+            f(Arc::make_mut(&mut raw.change));
+        }
+        Event::Transaction(transaction) => {
+            for event in &mut transaction.events {
+                visit(event, f);
+            }
+        }
+        _ => {}
+    }
+}
+
+fn owned(document: &mut EventDocument, f: &mut impl FnMut(&mut Document)) {
+    if let EventDocument::Shared(shared) = document {
+        *document = EventDocument::Owned(std::mem::take(Arc::make_mut(shared)));
+    }
+    if let EventDocument::Owned(document) = document {
+        f(document);
+    }
+}
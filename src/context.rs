@@ -0,0 +1,66 @@
+//! Subscriptions whose events carry a value of the caller's choosing, see
+//! [`Mercurius::add_with_context`](crate::Mercurius::add_with_context).
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+
+use crate::{
+    receiver::EventReceiver,
+    subscription::{Event, EventMeta},
+};
+
+/// Receives the events of a subscription along with the context it was added with, e.g. the id
+/// of the client session it belongs to. The receivers of many subscriptions can be merged into a
+/// single stream, e.g. with `futures::stream::select_all`, whose events can still be routed
+/// without looking up their subscription.
+#[derive(Debug)]
+pub struct ContextReceiver<C> {
+    receiver: EventReceiver,
+    context: C,
+}
+
+impl<C: Clone> ContextReceiver<C> {
+    pub(crate) fn new(receiver: EventReceiver, context: C) -> Self {
+        Self { receiver, context }
+    }
+
+    pub fn context(&self) -> &C {
+        &self.context
+    }
+
+    /// Waits for the next event, see [`EventReceiver::recv`].
+    pub async fn recv(&mut self) -> Option<(C, Event)> {
+        let event = self.receiver.recv().await?;
+        Some((self.context.clone(), event))
+    }
+
+    /// Like [`ContextReceiver::recv`], along with the metadata of the event.
+    pub async fn recv_with_meta(&mut self) -> Option<(C, Event, EventMeta)> {
+        let (event, meta) = self.receiver.recv_with_meta().await?;
+        Some((self.context.clone(), event, meta))
+    }
+
+    /// Returns the next event if one is immediately available, without waiting.
+    pub fn try_recv(&mut self) -> Option<(C, Event)> {
+        let event = self.receiver.try_recv()?;
+        Some((self.context.clone(), event))
+    }
+
+    pub fn into_inner(self) -> (EventReceiver, C) {
+        (self.receiver, self.context)
+    }
+}
+
+impl<C: Clone + Unpin> Stream for ContextReceiver<C> {
+    type Item = (C, Event);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver)
+            .poll_next(cx)
+            .map(|next| next.map(|event| (self.context.clone(), event)))
+    }
+}
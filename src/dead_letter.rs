@@ -0,0 +1,43 @@
+use tokio::sync::mpsc;
+
+use crate::{
+    collection_entry::subscriptions_manager::SubscriptionHandle, subscription::Event, Handle,
+};
+
+/// Receives the events that could not be delivered to their subscription, together with the
+/// handle of that subscription.
+pub type DeadLetterReceiver = mpsc::Receiver<(Handle, Event)>;
+
+/// The sending half of the dead-letter channel, bound to a single collection entry.
+#[derive(Debug, Clone)]
+pub(crate) struct DeadLetter {
+    sender: mpsc::Sender<(Handle, Event)>,
+    collection_name: String,
+    entry_id: usize,
+}
+
+impl DeadLetter {
+    pub(crate) fn new(
+        sender: mpsc::Sender<(Handle, Event)>,
+        collection_name: String,
+        entry_id: usize,
+    ) -> Self {
+        Self {
+            sender,
+            collection_name,
+            entry_id,
+        }
+    }
+
+    /// Best-effort: if the dead-letter channel is full or closed the event is discarded.
+    pub(crate) fn send(&self, handle: SubscriptionHandle, event: Event) {
+        let _ = self.sender.try_send((
+            Handle {
+                collection_name: self.collection_name.clone(),
+                entry_id: self.entry_id,
+                subscription_handle: handle,
+            },
+            event,
+        ));
+    }
+}
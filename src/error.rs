@@ -0,0 +1,321 @@
+use std::{fmt::Display, sync::Arc};
+
+use mongodb::{
+    bson::Bson, change_stream::event::OperationType, error::ErrorKind as DriverErrorKind,
+};
+
+use crate::{matcher::MatcherError, sink::SinkError};
+
+/// Called with the name of the collection and the error whenever a change event could not be
+/// processed, or something else has failed in the background, see
+/// [`Mercurius::on_error`](crate::Mercurius::on_error).
+pub type ErrorHandler = Arc<dyn Fn(&str, &EventError) + Send + Sync>;
+
+/// A change event that could not be processed. The event is skipped; the change stream and
+/// the other events are unaffected, except after [`EventError::StreamFailed`].
+#[derive(Debug)]
+pub enum EventError {
+    MissingDocumentKey,
+    UnsupportedDocumentKey(Bson),
+    /// The change event lacks a field that is required for its operation type.
+    MissingField(&'static str),
+    UnsupportedOperation(OperationType),
+    /// The change event has an unexpected shape.
+    Malformed(mongodb::bson::de::Error),
+    /// Only `received` of the `of` fragments of a split change event were read, see
+    /// [`SubscriptionOptions::split_large_events`](crate::options::SubscriptionOptions::split_large_events).
+    IncompleteSplitEvent {
+        received: i32,
+        of: i32,
+    },
+    /// The event couldn't be delivered to the sink of its subscription, see
+    /// [`Mercurius::add_to_sink`](crate::Mercurius::add_to_sink).
+    Undeliverable(SinkError),
+    /// The change stream of the collection ended with the given error, e.g. because it couldn't
+    /// be resumed. Its subscriptions have been removed with
+    /// [`TeardownReason::StreamFailed`](crate::subscription::TeardownReason::StreamFailed), and
+    /// the next subscription on the collection opens a new stream.
+    StreamFailed(String),
+    /// A collection that [`Mercurius::watch_matching`](crate::Mercurius::watch_matching) has
+    /// discovered couldn't be subscribed to; the group receives no events of it.
+    SubscribeFailed(String),
+}
+
+impl Display for EventError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EventError::MissingDocumentKey => f.write_str("The document key is missing"),
+            EventError::UnsupportedDocumentKey(key) => {
+                write!(f, "Unsupported document key: {}", key)
+            }
+            EventError::MissingField(field) => write!(f, "The field `{}` is missing", field),
+            EventError::UnsupportedOperation(operation_type) => {
+                write!(f, "Unsupported operation type: {:?}", operation_type)
+            }
+            EventError::Malformed(err) => write!(f, "Malformed change event: {}", err),
+            EventError::IncompleteSplitEvent { received, of } => write!(
+                f,
+                "Only {} of the {} fragments of a split change event were read",
+                received, of
+            ),
+            EventError::Undeliverable(err) => write!(f, "{}", err),
+            EventError::StreamFailed(err) => write!(f, "The change stream failed: {}", err),
+            EventError::SubscribeFailed(err) => write!(f, "Could not subscribe: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for EventError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EventError::Malformed(err) => Some(err),
+            EventError::Undeliverable(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        self.source()
+    }
+}
+
+/// An event of a typed subscription that doesn't match the types it was requested with, see
+/// [`Mercurius::add_typed`](crate::Mercurius::add_typed).
+#[derive(Debug)]
+pub enum TypedError {
+    /// The document can't be deserialized into the document type. Typed receivers deliver such
+    /// documents as [`TypedEvent::DeserializeError`](crate::typed::TypedEvent::DeserializeError)
+    /// instead.
+    Document(mongodb::bson::de::Error),
+    /// The `_id` of the document can't be deserialized into the key type.
+    Key {
+        key: Arc<String>,
+        error: mongodb::bson::de::Error,
+    },
+}
+
+impl Display for TypedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TypedError::Document(err) => write!(f, "Unexpected document: {}", err),
+            TypedError::Key { key, error } => {
+                write!(f, "Unexpected document key {}: {}", key, error)
+            }
+        }
+    }
+}
+
+impl std::error::Error for TypedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TypedError::Document(err) | TypedError::Key { error: err, .. } => Some(err),
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        self.source()
+    }
+}
+
+/// An error of [`Mercurius`](crate::Mercurius) itself, rather than of the database.
+#[derive(Debug)]
+pub enum MercuriusError {
+    /// The subscription couldn't be added within its
+    /// [`timeout`](crate::options::SubscriptionOptions::timeout), or
+    /// [`Mercurius::next_matching`](crate::Mercurius::next_matching) didn't receive an event in
+    /// time.
+    Timeout,
+    /// The subscription was removed before it delivered an event.
+    Ended,
+    /// The filter of the subscription can't be evaluated client-side, see [`crate::matcher`].
+    MatcherParse(MatcherError),
+    /// [`Mercurius::run_in_background`](crate::Mercurius::run_in_background) was called while
+    /// its task from an earlier call is still running.
+    AlreadyRunning,
+    /// The subscription sets two options that can't be combined, e.g.
+    /// [`show_expanded_events`](crate::options::SubscriptionOptions::show_expanded_events) and
+    /// [`group_transactions`](crate::options::SubscriptionOptions::group_transactions).
+    ConflictingOptions(&'static str, &'static str),
+}
+
+impl Display for MercuriusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MercuriusError::Timeout => f.write_str("The operation timed out"),
+            MercuriusError::Ended => f.write_str("The subscription has ended"),
+            MercuriusError::MatcherParse(err) => write!(f, "Invalid filter: {}", err),
+            MercuriusError::AlreadyRunning => f.write_str("The instance is already running"),
+            MercuriusError::ConflictingOptions(first, second) => {
+                write!(f, "The options {} and {} can't be combined", first, second)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MercuriusError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MercuriusError::MatcherParse(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        self.source()
+    }
+}
+
+/// What a database error means for a change stream, see [`classify`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The server isn't (or no longer) the primary, e.g. during an election. The change stream
+    /// can be resumed once a new primary has been elected.
+    NotPrimary,
+    /// No server could be reached, or the connection to it was closed.
+    Network,
+    /// The credentials were rejected, or aren't allowed to run the operation.
+    Auth,
+    /// The position the change stream was to be resumed from is no longer in the oplog, see
+    /// [`SubscriptionOptions::on_token_expiry`](crate::options::SubscriptionOptions::on_token_expiry).
+    HistoryLost,
+    /// Another error after which the server considers the change stream resumable.
+    Resumable,
+    Other,
+}
+
+impl ErrorKind {
+    /// Whether the error is caused by a failover of the replica set, see
+    /// [`SubscriptionOptions::resume_on_failover`](crate::options::SubscriptionOptions::resume_on_failover).
+    pub fn is_failover(self) -> bool {
+        matches!(self, ErrorKind::NotPrimary | ErrorKind::Network)
+    }
+}
+
+/// The codes of the errors with which a server rejects an operation because it isn't (or no
+/// longer) the primary: `NotWritablePrimary`, `NotPrimaryNoSecondaryOk`, `NotPrimaryOrSecondary`,
+/// `PrimarySteppedDown`, `InterruptedDueToReplStateChange`, `InterruptedAtShutdown` and
+/// `ShutdownInProgress`.
+const NOT_PRIMARY_CODES: &[i32] = &[10107, 13435, 13436, 189, 11602, 11600, 91];
+
+/// `Unauthorized` and `AuthenticationFailed`.
+const AUTH_CODES: &[i32] = &[13, 18];
+
+/// The code of the error with which a server rejects resuming a change stream whose position is
+/// no longer in the oplog.
+const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+
+/// `CursorNotFound`, which the change streams specification always treats as resumable.
+const CURSOR_NOT_FOUND: i32 = 43;
+
+/// The label with which servers since 4.4 mark the errors after which a change stream can be
+/// resumed.
+const RESUMABLE_CHANGE_STREAM_ERROR: &str = "ResumableChangeStreamError";
+
+/// Classifies a database error by its kind, code and labels, the way the crate itself does when
+/// deciding whether to resume a change stream.
+pub fn classify(err: &mongodb::error::Error) -> ErrorKind {
+    classify_kind(&err.kind, |label| err.contains_label(label))
+}
+
+/// Classifies an error of the given kind that has the labels that `has_label` reports; only the
+/// driver can label its errors.
+fn classify_kind(kind: &DriverErrorKind, has_label: impl Fn(&str) -> bool) -> ErrorKind {
+    match kind {
+        DriverErrorKind::Command(command) => match command.code {
+            CHANGE_STREAM_HISTORY_LOST => ErrorKind::HistoryLost,
+            code if NOT_PRIMARY_CODES.contains(&code) => ErrorKind::NotPrimary,
+            code if AUTH_CODES.contains(&code) => ErrorKind::Auth,
+            CURSOR_NOT_FOUND => ErrorKind::Resumable,
+            _ if has_label(RESUMABLE_CHANGE_STREAM_ERROR) => ErrorKind::Resumable,
+            _ => ErrorKind::Other,
+        },
+        DriverErrorKind::Authentication { .. } => ErrorKind::Auth,
+        // No primary is available yet, or the connection to the old one was closed
+        DriverErrorKind::ServerSelection { .. }
+        | DriverErrorKind::Io(_)
+        | DriverErrorKind::ConnectionPoolCleared { .. } => ErrorKind::Network,
+        _ if has_label(RESUMABLE_CHANGE_STREAM_ERROR) => ErrorKind::Resumable,
+        _ => ErrorKind::Other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::{
+        bson::{self, doc},
+        error::{CommandError, Error, ErrorKind as DriverErrorKind},
+    };
+
+    use super::{classify, classify_kind, ErrorKind, RESUMABLE_CHANGE_STREAM_ERROR};
+
+    fn command(code: i32) -> DriverErrorKind {
+        let command: CommandError =
+            bson::from_document(doc! { "code": code, "codeName": "", "errmsg": "" }).unwrap();
+        DriverErrorKind::Command(command)
+    }
+
+    /// Classifies an error of the given kind, with or without the `ResumableChangeStreamError`
+    /// label.
+    fn labeled(kind: &DriverErrorKind, resumable: bool) -> ErrorKind {
+        classify_kind(kind, |label| {
+            resumable && label == RESUMABLE_CHANGE_STREAM_ERROR
+        })
+    }
+
+    #[test]
+    fn command_errors_by_code() {
+        for code in [10107, 13435, 13436, 189, 11602, 11600, 91] {
+            assert_eq!(
+                classify(&command(code).into()),
+                ErrorKind::NotPrimary,
+                "{code}"
+            );
+        }
+        for code in [13, 18] {
+            assert_eq!(classify(&command(code).into()), ErrorKind::Auth, "{code}");
+        }
+        assert_eq!(classify(&command(286).into()), ErrorKind::HistoryLost);
+        assert_eq!(classify(&command(43).into()), ErrorKind::Resumable);
+        assert_eq!(classify(&command(2).into()), ErrorKind::Other);
+    }
+
+    #[test]
+    fn network_errors() {
+        let reset = Error::from(std::io::Error::from(std::io::ErrorKind::ConnectionReset));
+        assert_eq!(classify(&reset), ErrorKind::Network);
+        assert!(classify(&reset).is_failover());
+    }
+
+    #[test]
+    fn resumable_label() {
+        assert_eq!(labeled(&command(2), true), ErrorKind::Resumable);
+        assert_eq!(labeled(&command(2), false), ErrorKind::Other);
+
+        let io = DriverErrorKind::from(std::io::ErrorKind::BrokenPipe);
+        assert_eq!(labeled(&io, true), ErrorKind::Network);
+
+        // The code takes precedence over the label
+        assert_eq!(labeled(&command(286), true), ErrorKind::HistoryLost);
+        assert_eq!(labeled(&command(189), true), ErrorKind::NotPrimary);
+    }
+
+    #[test]
+    fn other_errors() {
+        assert_eq!(classify(&Error::custom("failed")), ErrorKind::Other);
+        assert!(!ErrorKind::Other.is_failover());
+        assert!(!ErrorKind::Resumable.is_failover());
+        assert!(!ErrorKind::HistoryLost.is_failover());
+    }
+}
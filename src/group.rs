@@ -0,0 +1,118 @@
+//! Subscriptions on every collection whose name matches a pattern, see
+//! [`Mercurius::watch_matching`](crate::Mercurius::watch_matching).
+
+use std::{
+    collections::HashSet,
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use mongodb::bson::Document;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::sync::CancellationToken;
+
+use crate::{options::SubscriptionOptions, subscription::Event, Handle, Mercurius};
+
+/// Identifies a group of subscriptions, see [`crate::Mercurius::remove_group`].
+#[derive(Debug, Clone)]
+pub struct GroupHandle {
+    token: CancellationToken,
+    handles: Arc<StdMutex<HashSet<Handle>>>,
+}
+
+impl GroupHandle {
+    /// The subscriptions of the group so far, one per collection.
+    pub fn handles(&self) -> Vec<Handle> {
+        self.handles.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub(crate) fn cancel(&self) -> Vec<Handle> {
+        self.token.cancel();
+        self.handles.lock().unwrap().drain().collect()
+    }
+}
+
+/// Receives the events of all subscriptions of a group, along with the name of their collection.
+#[derive(Debug)]
+pub struct GroupReceiver {
+    receiver: UnboundedReceiver<(String, Event)>,
+}
+
+impl GroupReceiver {
+    /// Waits for the next event. Returns `None` once the group has been removed and all buffered
+    /// events have been received.
+    pub async fn recv(&mut self) -> Option<(String, Event)> {
+        self.receiver.recv().await
+    }
+}
+
+impl Stream for GroupReceiver {
+    type Item = (String, Event);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+/// What every subscription of a group shares.
+#[derive(Clone)]
+pub(crate) struct Group {
+    filter: Option<Document>,
+    sender: UnboundedSender<(String, Event)>,
+    handle: GroupHandle,
+}
+
+impl Group {
+    pub(crate) fn new(filter: Option<Document>) -> (Self, GroupReceiver) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let group = Self {
+            filter,
+            sender,
+            handle: GroupHandle {
+                token: CancellationToken::new(),
+                handles: Arc::new(StdMutex::new(HashSet::new())),
+            },
+        };
+
+        (group, GroupReceiver { receiver })
+    }
+
+    pub(crate) fn handle(&self) -> &GroupHandle {
+        &self.handle
+    }
+
+    pub(crate) fn token(&self) -> &CancellationToken {
+        &self.handle.token
+    }
+
+    /// Subscribes to the collection `name` and forwards its events to the receiver of the group.
+    /// A collection that is discovered after it was created is primed, so that the documents
+    /// inserted before are delivered as well.
+    pub(crate) async fn join(
+        &self,
+        mercurius: &Mercurius,
+        name: String,
+        prime: bool,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let options = SubscriptionOptions::default()
+            .cancellation_token(self.handle.token.child_token())
+            .prime(prime);
+        let (mut receiver, handle) = mercurius
+            .add_with_options(name.clone(), self.filter.clone(), options)
+            .await?;
+        self.handle.handles.lock().unwrap().insert(handle);
+
+        let sender = self.sender.clone();
+        mercurius.spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if sender.send((name.clone(), event)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
@@ -0,0 +1,6 @@
+//! Adapters for frameworks that consume Mercurius events.
+
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "stream-map")]
+pub mod stream_map;
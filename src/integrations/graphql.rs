@@ -0,0 +1,232 @@
+//! Bridges subscriptions into [`async-graphql`](https://docs.rs/async-graphql) subscription
+//! streams.
+//!
+//! ```ignore
+//! #[Subscription]
+//! impl SubscriptionRoot {
+//!     async fn tasks(&self, ctx: &Context<'_>) -> async_graphql::Result<LiveStream> {
+//!         let mercurius = ctx.data::<Arc<Mercurius>>()?;
+//!         graphql::subscribe(mercurius, "tasks", None, SubscriptionOptions::default())
+//!             .await
+//!             .map_err(|err| err.to_string().into())
+//!     }
+//! }
+//! ```
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_graphql::{Enum, Json, SimpleObject};
+use futures_util::Stream;
+use mongodb::bson::{Bson, Document};
+use serde_json::Value;
+use tokio_util::sync::{CancellationToken, DropGuard};
+
+use crate::{
+    options::SubscriptionOptions, receiver::EventReceiver, subscription::Event, Handle, Mercurius,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+pub enum LiveEventKind {
+    Added,
+    Removed,
+    Updated,
+    Replaced,
+    /// See [`Event::Entered`].
+    Entered,
+    /// See [`Event::Left`].
+    Left,
+    /// See [`Event::PreImageUnavailable`].
+    PreImageUnavailable,
+    /// See [`Event::Reset`].
+    Reset,
+    /// See [`Event::GapDetected`].
+    GapDetected,
+    /// See [`Event::ConfigurationChanged`].
+    ConfigurationChanged,
+    /// See [`Event::Count`]; the number is in the `description`.
+    Count,
+    /// The subscription has ended, see [`Event::Drop`].
+    Dropped,
+    /// See [`Event::Transaction`].
+    Transaction,
+    /// See [`Event::Ddl`]. The description holds the operation type and its description.
+    Ddl,
+    /// See [`Event::Transition`]. The document is the new version, the description holds the old
+    /// one and which of them match.
+    Transition,
+}
+
+/// An [`Event`] in a shape that can be returned from a GraphQL subscription. Documents are
+/// represented as relaxed extended JSON.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct LiveEvent {
+    pub kind: LiveEventKind,
+    /// The key of the document; unset for [`LiveEventKind::Added`] and [`LiveEventKind::Entered`],
+    /// whose document contains it.
+    pub id: Option<String>,
+    /// The new version of the document, for [`LiveEventKind::Added`],
+    /// [`LiveEventKind::Replaced`], [`LiveEventKind::Entered`] and
+    /// [`LiveEventKind::Transition`].
+    pub document: Option<Json<Value>>,
+    /// The changed fields, for [`LiveEventKind::Updated`], the change of a
+    /// [`LiveEventKind::Ddl`], or the old version of a [`LiveEventKind::Transition`].
+    pub description: Option<Json<Value>>,
+    /// The events of a [`LiveEventKind::Transaction`].
+    pub events: Option<Vec<LiveEvent>>,
+}
+
+impl LiveEvent {
+    /// Returns `None` for events that only concern the subscription itself, like
+    /// [`Event::Established`].
+    pub fn from_event(event: Event) -> Option<Self> {
+        let event = match event {
+            Event::Added(document) => {
+                LiveEvent::new(LiveEventKind::Added, None).with_document(&document)
+            }
+            Event::Removed(key) => LiveEvent::new(LiveEventKind::Removed, Some(&key)),
+            Event::Updated((key, description)) => LiveEvent {
+                description: serde_json::to_value(&*description).ok().map(Json),
+                ..LiveEvent::new(LiveEventKind::Updated, Some(&key))
+            },
+            Event::Replaced((key, document)) => {
+                LiveEvent::new(LiveEventKind::Replaced, Some(&key)).with_document(&document)
+            }
+            Event::Entered(document) => {
+                LiveEvent::new(LiveEventKind::Entered, None).with_document(&document)
+            }
+            Event::Left(key) => LiveEvent::new(LiveEventKind::Left, Some(&key)),
+            Event::PreImageUnavailable { key, .. } => {
+                LiveEvent::new(LiveEventKind::PreImageUnavailable, Some(&key))
+            }
+            Event::Reset => LiveEvent::new(LiveEventKind::Reset, None),
+            Event::GapDetected { .. } => LiveEvent::new(LiveEventKind::GapDetected, None),
+            Event::ConfigurationChanged { .. } => {
+                LiveEvent::new(LiveEventKind::ConfigurationChanged, None)
+            }
+            Event::Count(count) => LiveEvent {
+                description: Some(Json(serde_json::json!({ "count": count }))),
+                ..LiveEvent::new(LiveEventKind::Count, None)
+            },
+            Event::Drop => LiveEvent::new(LiveEventKind::Dropped, None),
+            Event::Transaction(transaction) => LiveEvent {
+                events: Some(
+                    transaction
+                        .events
+                        .into_iter()
+                        .filter_map(LiveEvent::from_event)
+                        .collect(),
+                ),
+                ..LiveEvent::new(LiveEventKind::Transaction, None)
+            },
+            Event::Ddl(ddl) => LiveEvent {
+                description: Some(Json(serde_json::json!({
+                    "operationType": ddl.operation_type,
+                    "description": ddl
+                        .description
+                        .map(|description| Bson::from(description).into_relaxed_extjson()),
+                }))),
+                ..LiveEvent::new(LiveEventKind::Ddl, None)
+            },
+            Event::Transition(transition) => LiveEvent {
+                description: Some(Json(serde_json::json!({
+                    "old": Bson::from(&*transition.old).into_relaxed_extjson(),
+                    "oldMatched": transition.old_matched,
+                    "newMatched": transition.new_matched,
+                }))),
+                ..LiveEvent::new(LiveEventKind::Transition, Some(&transition.key))
+                    .with_document(&transition.new)
+            },
+            // `subscribe` turns off `extended_json`, which builds these
+            Event::Serialized(_) => return None,
+            Event::Raw(raw) => return LiveEvent::from_event(raw.event),
+            Event::Established { .. }
+            | Event::BatchBoundary { .. }
+            | Event::CaughtUp
+            | Event::Closed => return None,
+        };
+
+        Some(event)
+    }
+
+    fn new(kind: LiveEventKind, id: Option<&str>) -> Self {
+        Self {
+            kind,
+            id: id.map(str::to_string),
+            document: None,
+            description: None,
+            events: None,
+        }
+    }
+
+    fn with_document(mut self, document: &Document) -> Self {
+        self.document = Some(Json(Bson::from(document).into_relaxed_extjson()));
+        self
+    }
+}
+
+/// The events of a subscription as [`LiveEvent`]s. The subscription is removed once the stream is
+/// dropped, i.e. when the GraphQL client unsubscribes.
+#[derive(Debug)]
+pub struct LiveStream {
+    receiver: EventReceiver,
+    handle: Handle,
+    _unsubscribe: DropGuard,
+}
+
+impl LiveStream {
+    pub fn handle(&self) -> &Handle {
+        &self.handle
+    }
+}
+
+impl Stream for LiveStream {
+    type Item = LiveEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            match Pin::new(&mut self.receiver).poll_next(cx) {
+                Poll::Ready(Some(event)) => {
+                    if let Some(event) = LiveEvent::from_event(event) {
+                        return Poll::Ready(Some(event));
+                    }
+                }
+                Poll::Ready(None) => return Poll::Ready(None),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+/// Subscribes to the collection `name` like [`Mercurius::add_with_options`], for use as the
+/// result of a GraphQL subscription field. A cancellation token in `options` still removes the
+/// subscription as well, while [`SubscriptionOptions::extended_json`] is ignored.
+pub async fn subscribe(
+    mercurius: &Mercurius,
+    name: impl Into<String>,
+    filter: impl Into<Option<Document>>,
+    options: SubscriptionOptions,
+) -> Result<LiveStream, Box<dyn std::error::Error>> {
+    let unsubscribe = match &options.cancellation_token {
+        Some(token) => token.child_token(),
+        None => CancellationToken::new(),
+    };
+
+    let (receiver, handle) = mercurius
+        .add_with_options(
+            name,
+            filter,
+            options
+                .cancellation_token(unsubscribe.clone())
+                .extended_json(false),
+        )
+        .await?;
+
+    Ok(LiveStream {
+        receiver,
+        handle,
+        _unsubscribe: unsubscribe.drop_guard(),
+    })
+}
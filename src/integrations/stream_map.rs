@@ -0,0 +1,104 @@
+//! Consumes the subscriptions of several collections as one
+//! [`tokio_stream::StreamMap`](https://docs.rs/tokio-stream/latest/tokio_stream/struct.StreamMap.html),
+//! which yields the events along with the name of their collection.
+//!
+//! ```ignore
+//! let mut streams = CollectionStreams::new();
+//! streams.insert(&mercurius, "tasks", None, SubscriptionOptions::default()).await?;
+//! streams.insert(&mercurius, "users", None, SubscriptionOptions::default()).await?;
+//!
+//! while let Some((collection, event)) = streams.next().await {
+//!     // ...
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use mongodb::bson::Document;
+use tokio_stream::StreamMap;
+
+use crate::{
+    options::SubscriptionOptions, receiver::EventReceiver, subscription::Event, Handle, Mercurius,
+};
+
+/// A subscription per collection, keyed by the name of the collection.
+#[derive(Debug, Default)]
+pub struct CollectionStreams {
+    streams: StreamMap<String, EventReceiver>,
+    handles: HashMap<String, Handle>,
+}
+
+impl CollectionStreams {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Subscribes to the collection `name` like [`Mercurius::add_with_options`]. A previous
+    /// subscription on the collection is replaced and removed.
+    pub async fn insert(
+        &mut self,
+        mercurius: &Mercurius,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        options: SubscriptionOptions,
+    ) -> Result<Handle, Box<dyn std::error::Error>> {
+        let name = name.into();
+        let (receiver, handle) = mercurius
+            .add_with_options(name.clone(), filter, options)
+            .await?;
+
+        self.streams.insert(name.clone(), receiver);
+        if let Some(previous) = self.handles.insert(name, handle.clone()) {
+            mercurius.remove(&previous).await;
+        }
+
+        Ok(handle)
+    }
+
+    /// Removes the subscription on the collection `name`. Returns `false` if there is none.
+    pub async fn remove(&mut self, mercurius: &Mercurius, name: &str) -> bool {
+        self.streams.remove(name);
+
+        match self.handles.remove(name) {
+            Some(handle) => mercurius.remove(&handle).await,
+            None => false,
+        }
+    }
+
+    pub fn handle(&self, name: &str) -> Option<&Handle> {
+        self.handles.get(name)
+    }
+
+    pub fn len(&self) -> usize {
+        self.streams.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.streams.is_empty()
+    }
+
+    /// The underlying map, e.g. to combine it with other streams. Subscriptions whose receiver
+    /// is dropped are removed once their collection changes.
+    pub fn into_inner(self) -> StreamMap<String, EventReceiver> {
+        self.streams
+    }
+}
+
+impl Stream for CollectionStreams {
+    type Item = (String, Event);
+
+    /// A subscription whose receiver has ended, e.g. after [`Event::Drop`], is left out from then
+    /// on; the stream ends once every subscription has.
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.streams).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.streams.size_hint()
+    }
+}
@@ -0,0 +1,41 @@
+use std::{fmt::Debug, sync::Arc};
+
+use crate::subscription::Event;
+
+/// What should happen with an event after an interceptor has seen it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Intercept {
+    Deliver,
+    /// Don't deliver the event; the remaining interceptors are skipped.
+    Discard,
+}
+
+/// Observes and possibly modifies events right before they are sent to a subscription.
+///
+/// Interceptors run once per subscription for every event it receives, so an expensive
+/// interceptor slows down the dispatching of all events of the collection.
+#[derive(Clone)]
+pub struct Interceptor(Arc<dyn Fn(&mut Event) -> Intercept + Send + Sync>);
+
+impl Interceptor {
+    pub fn new(interceptor: impl Fn(&mut Event) -> Intercept + Send + Sync + 'static) -> Self {
+        Self(Arc::new(interceptor))
+    }
+}
+
+impl Debug for Interceptor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Interceptor")
+    }
+}
+
+/// Runs `interceptors` in order. Returns `None` if one of them discarded the event.
+pub(crate) fn intercept(interceptors: &[Interceptor], mut event: Event) -> Option<Event> {
+    for Interceptor(interceptor) in interceptors {
+        if interceptor(&mut event) == Intercept::Discard {
+            return None;
+        }
+    }
+
+    Some(event)
+}
@@ -1,99 +1,1586 @@
-use std::collections::HashMap;
+use std::{
+    collections::{hash_map, HashMap, HashSet},
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
 
-use collection_entry::{subscriptions_manager::SubscriptionHandle, CollectionEntry};
+use audit::{Audit, AuditReceiver, AuditRecord};
+use builder::MercuriusBuilder;
+use channel::ChannelConfig;
+use clock::{Clock, TokioClock};
+use collection_entry::{
+    enable_on_creation, pre_and_post_images,
+    subscriptions_manager::{SubscriptionCount, SubscriptionHandle},
+    AppliedPreImages, CollectionEntry, Hooks, PreImageCheck, RevertPreImages, StuckCheck,
+};
+use context::ContextReceiver;
+use dead_letter::{DeadLetter, DeadLetterReceiver};
+use error::{EventError, MercuriusError};
+use futures_util::StreamExt;
+use group::{Group, GroupHandle, GroupReceiver};
+use interceptor::{Intercept, Interceptor};
 use mongodb::{
-    bson::{doc, Document},
-    Database,
+    bson::{doc, oid::ObjectId, Bson, Document, Timestamp},
+    options::{FindOptions, RunCursorCommandOptions},
+    Client, Collection, Database,
 };
-use subscription::Event;
+use options::{PreImagesDisabled, SubscriptionConfig, SubscriptionDefaults, SubscriptionOptions};
+use outputs::Representation;
+use persistence::{PersistentStore, SubscriptionDefinition};
+use projection::Projection;
+use receiver::EventReceiver;
+use redaction::Redaction;
+use regex::Regex;
+use serde::de::DeserializeOwned;
+use sink::{EventSink, Forwarder, SinkRetries};
+use spawner::{Spawner, TokioSpawner};
+use subscription::{Event, Predicate, Primer, Subscription, SubscriptionStats, TeardownReason};
 use tokio::{
-    sync::{
-        mpsc::{self, UnboundedReceiver},
-        Mutex,
-    },
-    task::JoinSet,
+    sync::{broadcast, mpsc, watch, Mutex},
+    task::JoinHandle,
+    time::Instant,
 };
+use tokio_util::sync::CancellationToken;
+use typed::TypedReceiver;
 
+pub mod audit;
+#[cfg(feature = "bson-matcher")]
+pub mod bson_matcher;
+pub mod builder;
+pub mod channel;
+pub mod clock;
 mod collection_entry;
+#[cfg(feature = "compression")]
+mod compression;
+pub mod context;
+pub mod dead_letter;
+pub mod error;
+pub mod group;
+#[cfg(any(feature = "graphql", feature = "stream-map"))]
+pub mod integrations;
+pub mod interceptor;
+pub mod matcher;
+pub mod merge;
+pub mod options;
+pub mod outputs;
+pub mod persistence;
+mod projection;
+pub mod receiver;
+mod redaction;
+mod server_filter;
+pub mod sink;
+pub mod spawner;
 pub mod subscription;
+pub mod typed;
 
+/// Identifies a single subscription.
+///
+/// Handles can be cloned freely; removing a subscription through one clone makes every other clone
+/// stale, and removing a stale handle is a no-op. A handle never refers to a subscription on a
+/// collection entry that was created after the one it was issued for.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Handle {
     collection_name: String,
+    entry_id: usize,
     subscription_handle: SubscriptionHandle,
 }
 
+impl Handle {
+    pub fn collection_name(&self) -> &str {
+        &self.collection_name
+    }
+
+    /// The id of the subscription among those on its collection, see
+    /// [`Mercurius::remove_by_id`]. Ids are reused once a subscription has been removed.
+    pub fn id(&self) -> usize {
+        self.subscription_handle.id()
+    }
+}
+
+pub type HandleSet = HashSet<Handle>;
+
+/// Computes the key of a document of a collection, see [`Mercurius::key_fn`].
+pub type KeyFn = Arc<dyn Fn(&Document) -> String + Send + Sync>;
+
+type Collections = Arc<Mutex<HashMap<String, CollectionEntry>>>;
+
+/// Multiplexes the change streams of a database to any number of subscriptions.
+///
+/// The change streams run as tasks on the Tokio runtime that subscribes, which may be a
+/// `current_thread` runtime, e.g. one that also drives a `LocalSet` with `!Send` work. The tasks
+/// only make progress while the runtime is driven, and never block it. Events are sent without
+/// waiting, except with [`Overflow::Block`](channel::Overflow::Block), which holds up the stream
+/// task of the collection until the channel has room; the runtime keeps running other tasks
+/// meanwhile.
 pub struct Mercurius {
-    collections: Mutex<HashMap<String, CollectionEntry>>,
-    join_set: Mutex<JoinSet<()>>,
+    collections: Collections,
+    spawner: Arc<dyn Spawner>,
+    /// The default spawner, whose tasks [`Mercurius::run`] waits for; unset with a custom one.
+    tokio_spawner: Option<Arc<TokioSpawner>>,
+    /// Cancelled by [`Mercurius::shutdown`] to stop the tasks spawned so far.
+    stop: StdMutex<CancellationToken>,
+    /// Set while the task of [`Mercurius::run_in_background`] runs.
+    supervised: AtomicBool,
+    next_entry_id: AtomicUsize,
+    dead_letter: Option<mpsc::Sender<(Handle, Event)>>,
+    audit: Option<mpsc::Sender<AuditRecord>>,
+    on_error: Option<error::ErrorHandler>,
+    on_drop: Option<channel::DropHandler>,
+    replay_capacity: usize,
+    flow: Option<channel::FlowControl>,
+    subscription_count: SubscriptionCount,
+    /// The collections whose pre- and post-images have been turned on by this instance and are
+    /// turned off again, see [`SubscriptionOptions::revert_pre_and_post_images`].
+    enabled_pre_images: Arc<StdMutex<HashSet<String>>>,
+    /// The collections whose pre- and post-images have been turned on by this instance, whether
+    /// or not they are turned off again, so that `collMod` is only run once per collection.
+    applied_pre_images: AppliedPreImages,
+    /// See [`Mercurius::verify_pre_images`].
+    pre_image_check: Option<(Duration, PreImagesDisabled)>,
+    on_stuck: Option<(Duration, channel::StuckHandler)>,
+    filter_on_server: bool,
+    /// See [`Mercurius::pause_all`].
+    paused: watch::Sender<bool>,
+    #[cfg(feature = "bson-matcher")]
+    matcher: options::MatcherBackend,
+    redactions: HashMap<String, Redaction>,
+    sink_retries: SinkRetries,
+    clock: Arc<dyn Clock>,
+    key_fns: HashMap<String, KeyFn>,
+    interceptors: Vec<Interceptor>,
+    defaults: SubscriptionDefaults,
+    store: Option<PersistentStore>,
+    client: Option<Client>,
     db: Database,
 }
 
 impl Mercurius {
     pub fn new(db: Database) -> Self {
+        let tokio_spawner = Arc::new(TokioSpawner::new());
+
         Self {
-            collections: Mutex::new(HashMap::new()),
-            join_set: Mutex::new(JoinSet::new()),
+            tokio_spawner: Some(tokio_spawner.clone()),
+            ..Self::with_spawner(db, tokio_spawner)
+        }
+    }
+
+    /// Spawns the background tasks of the instance with `spawner` rather than onto the current
+    /// tokio runtime, e.g. the change stream of every collection. The tasks still need a tokio
+    /// runtime to be driven by. [`Mercurius::run`] doesn't wait for them; that's up to whoever
+    /// runs them.
+    pub fn with_spawner(db: Database, spawner: impl Spawner + 'static) -> Self {
+        Self {
+            collections: Arc::new(Mutex::new(HashMap::new())),
+            spawner: Arc::new(spawner),
+            tokio_spawner: None,
+            stop: StdMutex::new(CancellationToken::new()),
+            supervised: AtomicBool::new(false),
+            next_entry_id: AtomicUsize::new(0),
+            dead_letter: None,
+            audit: None,
+            on_error: None,
+            on_drop: None,
+            replay_capacity: 0,
+            flow: None,
+            subscription_count: SubscriptionCount::default(),
+            enabled_pre_images: Arc::new(StdMutex::new(HashSet::new())),
+            applied_pre_images: AppliedPreImages::default(),
+            pre_image_check: None,
+            on_stuck: None,
+            filter_on_server: false,
+            paused: watch::channel(false).0,
+            #[cfg(feature = "bson-matcher")]
+            matcher: options::MatcherBackend::default(),
+            redactions: HashMap::new(),
+            sink_retries: SinkRetries::default(),
+            clock: Arc::new(TokioClock),
+            key_fns: HashMap::new(),
+            interceptors: Vec::new(),
+            defaults: SubscriptionDefaults::default(),
+            store: None,
+            client: None,
             db,
         }
     }
 
+    /// Creates an instance with defaults for the options of its subscriptions, e.g. a channel
+    /// capacity, which each subscription can override.
+    pub fn builder(db: Database) -> MercuriusBuilder {
+        MercuriusBuilder::new(db)
+    }
+
+    /// Watches the database `default_db` of `client`, keeping the client. Every change stream
+    /// and query runs on the connection pool of the client, which is shared with the rest of the
+    /// application rather than opened per collection.
+    pub fn from_client(client: Client, default_db: &str) -> Self {
+        Self {
+            client: Some(client.clone()),
+            ..Self::new(client.database(default_db))
+        }
+    }
+
+    /// Evaluates the filters of subscriptions with `backend`, unless they ask for a matcher with
+    /// [`SubscriptionOptions::matcher`]. Defaults to [`MatcherBackend::Json`].
+    ///
+    /// [`MatcherBackend::Json`]: options::MatcherBackend::Json
+    #[cfg(feature = "bson-matcher")]
+    pub fn matcher(&mut self, backend: options::MatcherBackend) {
+        self.matcher = backend;
+    }
+
+    /// Called whenever a change event could not be processed, e.g. because it lacks a document
+    /// key. The event is skipped either way; without a handler the error is written to stderr.
+    /// Failures in the background that aren't about a single event are reported as well, e.g.
+    /// [`EventError::StreamFailed`]. Only applies to collections that are subscribed to
+    /// afterwards.
+    pub fn on_error(&mut self, handler: impl Fn(&str, &EventError) + Send + Sync + 'static) {
+        self.on_error = Some(Arc::new(handler));
+    }
+
+    /// Called whenever an event is dropped because the channel of its subscription is full, with
+    /// the number of events the subscription has dropped so far. Without a handler, drops are
+    /// written to stderr each time their number reaches a power of two. Only applies to
+    /// collections that are subscribed to afterwards.
+    pub fn on_drop(&mut self, handler: impl Fn(&Handle, u64) + Send + Sync + 'static) {
+        self.on_drop = Some(Arc::new(handler));
+    }
+
+    /// Called once a subscription has events queued that its consumer hasn't received for
+    /// `threshold`, e.g. because it's deadlocked, with how long that has been the case. It's
+    /// called again only after the consumer has received an event. Subscriptions are checked
+    /// every quarter of `threshold`. Only applies to collections that are subscribed to
+    /// afterwards. See [`Mercurius::stuck_subscriptions`] to query them instead.
+    pub fn on_stuck(
+        &mut self,
+        threshold: Duration,
+        handler: impl Fn(&Handle, Duration) + Send + Sync + 'static,
+    ) {
+        self.on_stuck = Some((threshold, Arc::new(handler)));
+    }
+
+    /// Adds an interceptor that sees every event right before it is sent to a subscription, e.g.
+    /// to redact fields or collect metrics. Returning [`Intercept::Discard`] drops the event.
+    ///
+    /// Interceptors run in the order they were added, once per subscription for every event it
+    /// receives. Only applies to subscriptions that are added afterwards.
+    pub fn add_interceptor(
+        &mut self,
+        interceptor: impl Fn(&mut Event) -> Intercept + Send + Sync + 'static,
+    ) {
+        self.interceptors.push(Interceptor::new(interceptor));
+    }
+
+    /// Stores the definitions of persistent subscriptions in `store`, see
+    /// [`SubscriptionOptions::persistent`].
+    pub fn set_store(&mut self, store: PersistentStore) {
+        self.store = Some(store);
+    }
+
+    pub fn database(&self) -> &Database {
+        &self.db
+    }
+
+    /// The client the instance was created with, see [`Mercurius::from_client`].
+    pub fn client(&self) -> Option<&Client> {
+        self.client.as_ref()
+    }
+
+    /// Returns a handle to the collection `name` of the watched database, sharing its connection
+    /// pool.
+    pub fn collection(&self, name: &str) -> Collection<Document> {
+        self.db.collection(name)
+    }
+
+    /// Measures time with `clock` instead of [`tokio::time`], e.g. with a
+    /// [`TestClock`](clock::TestClock) to test time-based behaviour deterministically. It applies
+    /// to the timeouts of subscriptions, [`Mercurius::on_stuck`],
+    /// [`Mercurius::verify_pre_images`], the retries of failovers and sinks, the pacing of
+    /// [`SubscriptionOptions::catch_up`], the window of [`merge::merge_ordered`] and the
+    /// [`EventMeta::delivery_latency`](subscription::EventMeta::delivery_latency) of events.
+    /// Only applies to collections that are subscribed to afterwards.
+    pub fn clock(&mut self, clock: impl Clock + 'static) {
+        self.clock = Arc::new(clock);
+    }
+
+    /// Retries a delivery to an [`EventSink`] that fails with a transient [`SinkError`](sink::SinkError) up to
+    /// `attempts` times, waiting `backoff` before the first retry and twice as long before each
+    /// further one. Defaults to 3 attempts after 100 milliseconds.
+    pub fn sink_retries(&mut self, attempts: u32, backoff: Duration) {
+        self.sink_retries = SinkRetries { attempts, backoff };
+    }
+
+    /// Routes events that could not be delivered to this channel, which holds at most `capacity`
+    /// events. Only applies to collections that are subscribed to afterwards.
+    ///
+    /// An event is undeliverable when the receiver of its subscription has been dropped. The
+    /// subscription is removed in that case.
+    pub fn dead_letter_channel(&mut self, capacity: usize) -> DeadLetterReceiver {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.dead_letter = Some(sender);
+        receiver
+    }
+
+    /// Keeps the last `capacity` changes of every collection in memory, so that a subscription
+    /// can ask for them with [`SubscriptionOptions::replay`]. Only applies to collections that
+    /// are subscribed to afterwards; the changes are kept from then on, until the change stream of
+    /// the collection ends. Defaults to none.
+    pub fn replay_buffer(&mut self, capacity: usize) {
+        self.replay_capacity = capacity;
+    }
+
+    /// Limits the number of events that are buffered in the channels of all subscriptions
+    /// together, i.e. sent but not received yet. Once `max` events are in flight, the change
+    /// streams stop reading from the server until the receivers have brought the number down to
+    /// `low_water`, so that slow consumers slow down the streams instead of exhausting memory.
+    /// The events already read are still delivered, so the limit may be exceeded by a batch.
+    ///
+    /// Only applies to subscriptions added afterwards. A consumer that stops receiving halts all
+    /// the collections, so drop receivers that aren't read anymore, or give them a
+    /// [`SubscriptionOptions::capacity`].
+    pub fn max_in_flight(&mut self, max: usize, low_water: usize) {
+        self.flow = Some(channel::FlowControl::new(max, low_water));
+    }
+
+    /// Removes the fields at `paths`, which are dot-separated, from every change of the collection
+    /// `name` as soon as it's received, so that neither filters nor subscribers ever see them: a
+    /// filter on a redacted field doesn't match, and the documents are delivered without it.
+    /// This includes the documents before a change and the fields of an update. The documents
+    /// read for [`SubscriptionOptions::prime`] are redacted as well, but their filter already
+    /// runs on the server, where it still sees the fields. Only applies to collections that are
+    /// subscribed to afterwards.
+    pub fn redact_fields(&mut self, name: &str, paths: &[&str]) {
+        self.redactions
+            .insert(name.to_string(), Redaction::new(paths.iter().copied()));
+    }
+
+    /// Computes the keys of the documents of the collection `name` with `key_fn` instead of taking
+    /// their `_id`, e.g. to render a composite key like `"tenant:123#order:456"`. The keys of
+    /// events like [`Event::Updated`] and [`Event::Removed`] are the result. It's called with the
+    /// new version of the document, or else the old one, after [`Mercurius::redact_fields`]; a
+    /// change that holds neither, like an update without [`SubscriptionOptions::full_document`] or
+    /// a delete without pre-images, only provides its document key, i.e. the `_id` and the shard
+    /// key. [`SubscriptionOptions::missing_key`] doesn't apply. With
+    /// [`SubscriptionOptions::latest_only`], inserts aren't coalesced with the changes that
+    /// follow them, as their projected documents may lack the fields of the key. Only applies
+    /// to collections that are subscribed to afterwards.
+    pub fn key_fn(
+        &mut self,
+        name: &str,
+        key_fn: impl Fn(&Document) -> String + Send + Sync + 'static,
+    ) {
+        self.key_fns.insert(name.to_string(), Arc::new(key_fn));
+    }
+
+    /// Has the server filter the changes of every collection by the filters of its subscriptions,
+    /// so that it only sends the changes that may concern one of them. As all subscriptions on a
+    /// collection share one change stream, it's filtered by the union of their filters: a change
+    /// is sent if either version of its document matches any of them, or if it has none, like a
+    /// delete without a pre-image. A subscription whose filter isn't covered yet reopens the
+    /// stream with the wider union, continuing after the last change that has been dispatched;
+    /// the union doesn't shrink when subscriptions are removed. Subscriptions without a filter,
+    /// or with one that can only be evaluated on whole documents like `$expr`, turn the filtering
+    /// off for the collection.
+    ///
+    /// [`SubscriptionOptions::replay`] only replays the changes that have passed the filter of
+    /// the stream. Only applies to collections that are subscribed to afterwards.
+    pub fn filter_on_server(&mut self, enabled: bool) {
+        self.filter_on_server = enabled;
+    }
+
+    /// Checks every `interval` whether the pre- and post-images of each watched collection are
+    /// still turned on, as someone may turn them off with `collMod` while the collection is
+    /// watched, after which updates, replacements and deletes arrive without the version from
+    /// before the change. Once they are found off, the subscriptions of the collection receive
+    /// [`Event::ConfigurationChanged`], and with [`PreImagesDisabled::Reenable`] they're turned on
+    /// again first. Each check is delayed by a random part of up to a quarter of `interval` in
+    /// either direction, so that the collections aren't all checked at once. Only applies to
+    /// collections that are subscribed to afterwards.
+    pub fn verify_pre_images(&mut self, interval: Duration, on_disabled: PreImagesDisabled) {
+        self.pre_image_check = Some((interval, on_disabled));
+    }
+
+    /// Records every change of a document that matched at least one subscription in this
+    /// channel, which holds at most `capacity` records, e.g. for an audit log. Unlike the
+    /// dead-letter channel, it's a record of all matched changes, made once per change whether
+    /// or not it could be delivered. Only applies to collections that are subscribed to
+    /// afterwards; records that don't fit into the channel are discarded.
+    pub fn audit_channel(&mut self, capacity: usize) -> AuditReceiver {
+        let (sender, receiver) = mpsc::channel(capacity);
+        self.audit = Some(sender);
+        receiver
+    }
+
     pub async fn add(
         &self,
-        name: String,
+        name: impl Into<String>,
         filter: impl Into<Option<Document>>,
-    ) -> Result<(UnboundedReceiver<Event>, Handle), Box<dyn std::error::Error>> {
-        self.db
-            .run_command(
-                doc! { "collMod": name.clone(), "changeStreamPreAndPostImages": { "enabled": true } },
-                None,
-            )
-            .await?;
-
-        let entry = {
-            let mut join_set = self.join_set.lock().await;
-
-            CollectionEntry::new(self.db.collection::<Document>(&name), &mut join_set).await?
+    ) -> Result<(EventReceiver, Handle), Box<dyn std::error::Error>> {
+        self.add_with_options(name, filter, SubscriptionOptions::default())
+            .await
+    }
+
+    /// Subscribes to all changes of documents for which `predicate` returns `true`.
+    ///
+    /// Unlike a filter document, the predicate can't be evaluated by the server; it runs for every
+    /// change of the collection.
+    pub async fn add_with_predicate(
+        &self,
+        name: impl Into<String>,
+        predicate: impl Fn(&Document) -> bool + Send + Sync + 'static,
+    ) -> Result<(EventReceiver, Handle), Box<dyn std::error::Error>> {
+        self.add_with_options(
+            name,
+            None,
+            SubscriptionOptions::default().predicate(Predicate::new(predicate)),
+        )
+        .await
+    }
+
+    /// Subscribes to all documents of the collection, of which only `fields` and `_id` are
+    /// delivered, see [`SubscriptionOptions::fields`].
+    ///
+    /// If this subscription opens the change stream of the collection, the stream is projected
+    /// to these fields as well, so the server doesn't send the others. Subscriptions that are
+    /// added later share that stream, and so only see these fields.
+    pub async fn watch_fields(
+        &self,
+        name: impl Into<String>,
+        fields: &[&str],
+    ) -> Result<(EventReceiver, Handle), Box<dyn std::error::Error>> {
+        let projection = Projection::new(fields.iter().copied());
+        let options = SubscriptionOptions::default()
+            .pipeline([projection.stage()])
+            .fields(fields.iter().copied());
+
+        self.add_with_options(name, None, options).await
+    }
+
+    pub async fn add_with_options(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        options: SubscriptionOptions,
+    ) -> Result<(EventReceiver, Handle), Box<dyn std::error::Error>> {
+        self.subscribe(name.into(), filter.into(), options, None)
+            .await
+    }
+
+    /// Waits for the next change of a document in the collection `name` that matches `filter`,
+    /// e.g. for an order to reach a status, and returns its event. The change stream is opened
+    /// anew if there is no subscription on the collection yet, so only changes after the call are
+    /// seen. [`Event::Drop`] is returned if the collection is dropped in the meantime; events that
+    /// are sent regardless of `filter`, like [`Event::PreImageUnavailable`] and [`Event::Ddl`],
+    /// are skipped.
+    ///
+    /// Fails with [`MercuriusError::Timeout`] if no event arrives within `timeout`. The
+    /// subscription is removed once the future completes or is dropped.
+    pub async fn next_matching(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        timeout: impl Into<Option<Duration>>,
+    ) -> Result<Event, Box<dyn std::error::Error>> {
+        let timeout = timeout.into();
+        let deadline = timeout.map(|timeout| self.clock.now() + timeout);
+        let token = CancellationToken::new();
+        let _unsubscribe = token.clone().drop_guard();
+
+        let options = SubscriptionOptions::default()
+            .take(1)
+            .timeout(timeout)
+            .cancellation_token(token);
+        let (mut receiver, _) = self.add_with_options(name, filter, options).await?;
+
+        let next = next_match(&mut receiver);
+        let next = match deadline {
+            Some(deadline) => {
+                let timeout = deadline.saturating_duration_since(self.clock.now());
+                clock::timeout(&*self.clock, timeout, next)
+                    .await
+                    .ok_or(MercuriusError::Timeout)?
+            }
+            None => next.await,
         };
 
-        let (sender, receiver) = mpsc::unbounded_channel();
+        Ok(next.ok_or(MercuriusError::Ended)?)
+    }
 
-        let handle = entry.add_subscription(filter, sender).await?;
+    /// Subscribes to the collection `name` as described by `config`, which can be cloned to add
+    /// the same subscription to several collections.
+    pub async fn add_with_config(
+        &self,
+        name: impl Into<String>,
+        config: SubscriptionConfig,
+    ) -> Result<(EventReceiver, Handle), Box<dyn std::error::Error>> {
+        let (filter, options) = config.into_parts();
+        self.add_with_options(name, filter, options).await
+    }
 
-        {
-            let mut collections = self.collections.lock().await;
-            collections.insert(name.clone(), entry);
-        }
+    /// Subscribes like [`Mercurius::add_with_options`], but delivers the documents deserialized
+    /// into `T` and the document keys into `K`, e.g. an `ObjectId` or a newtype around a string.
+    /// Keys reach `K` as strings, an ObjectId `_id` as its hex representation, so `K` has to
+    /// deserialize from a string; an event whose key it rejects is an error. The receiver is a
+    /// `Stream` as well, for use with stream combinators.
+    ///
+    /// ```ignore
+    /// let (mut receiver, handle) = mercurius
+    ///     .add_typed::<Task, ObjectId>("tasks", None, SubscriptionOptions::default())
+    ///     .await?;
+    /// while let Some(event) = receiver.next().await {
+    ///     // ...
+    /// }
+    /// ```
+    pub async fn add_typed<T: DeserializeOwned, K: DeserializeOwned>(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        options: SubscriptionOptions,
+    ) -> Result<(TypedReceiver<T, K>, Handle), Box<dyn std::error::Error>> {
+        let (receiver, handle) = self.add_with_options(name, filter, options).await?;
+        Ok((TypedReceiver::new(receiver), handle))
+    }
+
+    /// Subscribes like [`Mercurius::add_with_options`], but delivers every event along with
+    /// `context`, so that the events of many subscriptions can be consumed together and still be
+    /// told apart, e.g. by the client session they belong to.
+    pub async fn add_with_context<C: Clone>(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        options: SubscriptionOptions,
+        context: C,
+    ) -> Result<(ContextReceiver<C>, Handle), Box<dyn std::error::Error>> {
+        let (receiver, handle) = self.add_with_options(name, filter, options).await?;
+        Ok((ContextReceiver::new(receiver, context), handle))
+    }
+
+    /// Subscribes like [`Mercurius::add`], but feeds the events into a `broadcast` channel, so
+    /// that any number of consumers share the one subscription. Further consumers call
+    /// [`broadcast::Receiver::resubscribe`] on the returned receiver, and receive the events from
+    /// then on.
+    ///
+    /// The channel holds the last `capacity` events and never waits for its consumers: one that
+    /// falls further behind gets [`broadcast::error::RecvError::Lagged`] with the number of
+    /// events it missed, and continues with the oldest event that is still held. Once the
+    /// change stream ends the consumers receive [`broadcast::error::RecvError::Closed`]. The
+    /// subscription is removed with the first event after all consumers have been dropped.
+    pub async fn add_broadcast(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        capacity: usize,
+    ) -> Result<(broadcast::Receiver<Event>, Handle), Box<dyn std::error::Error>> {
+        let (mut receiver, handle) = self.add(name, filter).await?;
+        let (sender, consumer) = broadcast::channel(capacity);
+
+        self.spawn(async move {
+            while let Some(event) = receiver.recv().await {
+                if sender.send(event).is_err() {
+                    break;
+                }
+            }
+        });
 
-        Ok((
+        Ok((consumer, handle))
+    }
+
+    /// Subscribes like [`Mercurius::add_with_options`], but delivers the events to `sink` rather
+    /// than through a receiver, one at a time; events wait in the channel of the subscription
+    /// meanwhile, so its capacity and [`Overflow`](channel::Overflow) policy apply to a slow sink. A delivery that
+    /// fails with a transient [`SinkError`](sink::SinkError) is retried according to [`Mercurius::sink_retries`].
+    /// An event that can't be delivered is reported to [`Mercurius::on_error`] as
+    /// [`EventError::Undeliverable`] and sent to the [`Mercurius::dead_letter_channel`], and the
+    /// sink goes on with the next one. The subscription is removed with [`Mercurius::remove`].
+    pub async fn add_to_sink(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        options: SubscriptionOptions,
+        sink: Arc<dyn EventSink>,
+    ) -> Result<Handle, Box<dyn std::error::Error>> {
+        let (receiver, handle) = self.add_with_options(name, filter, options).await?;
+
+        let forwarder = Forwarder {
             receiver,
-            Handle {
+            sink,
+            handle: handle.clone(),
+            retries: self.sink_retries,
+            clock: self.clock.clone(),
+            on_error: self.on_error.clone(),
+            dead_letter: self.dead_letter.clone(),
+        };
+        self.spawn(forwarder.run());
+
+        Ok(handle)
+    }
+
+    /// Subscribes like [`Mercurius::add_with_options`], but delivers the events to one receiver
+    /// per entry of `outputs`, each in the [`Representation`] it asks for, e.g. as events to a
+    /// consumer and as JSON to a logger. The changes are matched once, and serialized once for
+    /// all JSON outputs. Each receiver holds up to `capacity` events; while one is full the
+    /// others wait as well, and the events back up in the channel of the subscription, to which
+    /// [`SubscriptionOptions::capacity`] applies. An output stops receiving events once its
+    /// receiver is dropped, and the subscription is removed with its first event after all of
+    /// them have been.
+    pub async fn add_with_outputs(
+        &self,
+        name: impl Into<String>,
+        filter: impl Into<Option<Document>>,
+        options: SubscriptionOptions,
+        outputs: &[Representation],
+        capacity: usize,
+    ) -> Result<(Vec<mpsc::Receiver<Event>>, Handle), Box<dyn std::error::Error>> {
+        let (receiver, handle) = self.add_with_options(name, filter, options).await?;
+
+        let (senders, receivers) = outputs
+            .iter()
+            .map(|representation| {
+                let (sender, receiver) = mpsc::channel(capacity.max(1));
+                ((*representation, sender), receiver)
+            })
+            .unzip();
+        self.spawn(outputs::fan_out(receiver, senders));
+
+        Ok((receivers, handle))
+    }
+
+    /// Subscribes to every collection whose name matches the regex `pattern`, including the ones
+    /// that are created later on, and delivers their events through a single receiver.
+    ///
+    /// New collections are discovered through a change stream on the database, which reports a
+    /// collection once its first document has been written. Its subscription is primed, so that
+    /// documents written before it was opened are delivered as [`Event::Added`].
+    ///
+    /// A collection that is discovered later on but can't be subscribed to is reported to
+    /// [`Mercurius::on_error`] as [`EventError::SubscribeFailed`]. The subscriptions are removed
+    /// with [`Mercurius::remove_group`].
+    pub async fn watch_matching(
+        self: &Arc<Self>,
+        pattern: &str,
+        filter: impl Into<Option<Document>>,
+    ) -> Result<(GroupReceiver, GroupHandle), Box<dyn std::error::Error>> {
+        let regex = Regex::new(pattern)?;
+        let (group, receiver) = Group::new(filter.into());
+
+        // Opened before the collections are listed, so that none is missed in between
+        let pipeline = [
+            doc! { "$match": { "ns.coll": { "$regex": pattern } } },
+            doc! { "$project": { "ns": 1 } },
+        ];
+        let mut discovered = self.db.watch(pipeline, None).await?.with_type::<Document>();
+
+        let mut known = HashSet::new();
+        for name in self.db.list_collection_names(None).await? {
+            if regex.is_match(&name) && known.insert(name.clone()) {
+                group.join(self, name, false).await?;
+            }
+        }
+
+        let mercurius = Arc::downgrade(self);
+        let handle = group.handle().clone();
+        self.spawn(async move {
+            loop {
+                let event = tokio::select! {
+                    _ = group.token().cancelled() => break,
+                    event = discovered.next() => event,
+                };
+                let name = match event {
+                    Some(Ok(event)) => {
+                        match event.get_document("ns").and_then(|ns| ns.get_str("coll")) {
+                            Ok(name) => name.to_string(),
+                            Err(_) => continue,
+                        }
+                    }
+                    _ => break,
+                };
+                if !known.insert(name.clone()) {
+                    continue;
+                }
+
+                let mercurius = match mercurius.upgrade() {
+                    Some(mercurius) => mercurius,
+                    None => break,
+                };
+                if let Err(err) = group.join(&mercurius, name.clone(), true).await {
+                    let err = EventError::SubscribeFailed(err.to_string());
+                    match &mercurius.on_error {
+                        Some(on_error) => on_error(&name, &err),
+                        None => eprintln!("Skipped collection {}: {}", name, err),
+                    }
+                }
+            }
+        });
+
+        Ok((receiver, handle))
+    }
+
+    /// Removes all subscriptions of a group, see [`Mercurius::watch_matching`], and stops
+    /// discovering collections for it.
+    pub async fn remove_group(&self, group: &GroupHandle) {
+        for handle in group.cancel() {
+            self.remove(&handle).await;
+        }
+    }
+
+    /// Subscribes again to every subscription stored in the instance's store, resuming each one
+    /// from its last checkpoint (see [`Mercurius::checkpoint`]).
+    ///
+    /// Subscriptions on the same collection share one change stream, which is resumed from the
+    /// token of the first restored subscription of that collection.
+    pub async fn restore(
+        &self,
+    ) -> Result<Vec<(SubscriptionDefinition, EventReceiver, Handle)>, Box<dyn std::error::Error>>
+    {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(Vec::new()),
+        };
+
+        let mut restored = Vec::new();
+        for definition in store.load_subscriptions().await? {
+            let options = SubscriptionOptions::default()
+                .persistent(true)
+                .resume_after(definition.resume_token.clone());
+
+            let (receiver, handle) = self
+                .subscribe(
+                    definition.collection.clone(),
+                    definition.filter.clone(),
+                    options,
+                    Some(definition.id),
+                )
+                .await?;
+            restored.push((definition, receiver, handle));
+        }
+
+        Ok(restored)
+    }
+
+    /// Stores the current position of every persistent subscription of this instance, so that
+    /// they are resumed from there when restored. On a quiet collection the position is the
+    /// post-batch resume token of the last empty batch, so it keeps moving forward between
+    /// changes and the stream isn't resumed from an old change that may have left the oplog.
+    pub async fn checkpoint(&self) -> Result<(), mongodb::error::Error> {
+        let store = match &self.store {
+            Some(store) => store,
+            None => return Ok(()),
+        };
+
+        for (handle, id) in store.tracked() {
+            let resume_token = match self.collections.lock().await.get(&handle.collection_name) {
+                Some(collection) if collection.id() == handle.entry_id => collection.resume_token(),
+                _ => None,
+            };
+
+            if let Some(resume_token) = resume_token {
+                store.save_resume_token(id, &resume_token).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// `persisted` is the id of the stored definition if the subscription is restored.
+    async fn subscribe(
+        &self,
+        name: String,
+        filter: Option<Document>,
+        options: SubscriptionOptions,
+        persisted: Option<ObjectId>,
+    ) -> Result<(EventReceiver, Handle), Box<dyn std::error::Error>> {
+        let options = self.defaults.apply(options);
+        if let Some((first, second)) = options.conflict() {
+            return Err(MercuriusError::ConflictingOptions(first, second).into());
+        }
+        let store = self.store.as_ref().filter(|_| options.persistent);
+        let definition_filter = store.and(filter.clone());
+
+        let priming_filter = (options.prime || options.initial_count).then(|| filter.clone());
+
+        let (sender, receiver) = channel::channel(ChannelConfig {
+            capacity: options.capacity,
+            overflow: options.overflow,
+            latest_only: options.latest_only,
+            keyed_by_id: !self.key_fns.contains_key(&name),
+            flow: self.flow.clone(),
+            clock: self.clock.clone(),
+            grace: options.grace_period,
+        });
+        #[cfg(feature = "compression")]
+        let sender = sender.compress_backlog(options.compress_backlog);
+        #[cfg(feature = "bson-matcher")]
+        let subscription = match options.matcher.unwrap_or(self.matcher) {
+            options::MatcherBackend::Json => Subscription::new(filter, sender),
+            options::MatcherBackend::Bson => Subscription::with_bson_matcher(filter, sender),
+        };
+        #[cfg(not(feature = "bson-matcher"))]
+        let subscription = Subscription::new(filter, sender);
+        let mut subscription = subscription.map_err(MercuriusError::MatcherParse)?;
+        subscription.set_predicate(options.predicate.clone());
+        // Members are delivered with nothing but their key
+        subscription.set_projection(match options.set_membership {
+            true => Some(Projection::new(std::iter::empty::<String>())),
+            false => options.projection.clone(),
+        });
+        subscription.set_extended_json(options.extended_json);
+        subscription.set_boundary_events(options.boundary_events);
+        subscription.set_raw_transitions(options.raw_transitions);
+        subscription.set_set_membership(options.set_membership);
+        subscription.set_batch_boundaries(options.batch_boundaries);
+        subscription.set_raw_events(options.raw_events);
+        subscription.set_interceptors(self.interceptors.clone().into());
+        subscription.set_limit(options.take);
+        subscription.set_on_teardown(options.on_teardown.clone());
+        subscription.set_delta_predicate(options.delta_predicate.clone());
+        subscription.set_operations(options.operations.clone());
+        if let Some(collation) = &options.collation {
+            subscription.set_collation(collation);
+        }
+        let primer = priming_filter.map(|filter| (subscription.start_priming(), filter));
+
+        // Removes the subscription once the token is cancelled. The task stops as soon as the
+        // subscription is dropped, so it doesn't outlive the subscription when it's removed
+        // another way.
+        let cancellation = options.cancellation_token.clone().map(|token| {
+            let removed = CancellationToken::new();
+            subscription.set_drop_guard(removed.clone().drop_guard());
+            (token, removed)
+        });
+
+        let deadline = options.timeout.map(|timeout| self.clock.now() + timeout);
+
+        // All subscriptions on a collection share a single change stream, which is only opened
+        // for the first one
+        let added = until(&*self.clock, deadline, async {
+            let mut collections = self.collections.lock().await;
+            // The subscriptions of a failed stream have all been removed
+            if collections
+                .get(&name)
+                .is_some_and(|entry| entry.has_failed())
+            {
+                collections.remove(&name);
+            }
+            let entry = match collections.entry(name.clone()) {
+                hash_map::Entry::Occupied(entry) => {
+                    let entry = entry.into_mut();
+                    entry
+                        .widen_filter(&*self.spawner, subscription.filter())
+                        .await?;
+                    entry
+                }
+                hash_map::Entry::Vacant(entry) => {
+                    let owned = self.enabled_pre_images.lock().unwrap().contains(&name);
+                    let applied = self.applied_pre_images.contains(&name);
+                    let images = match !applied
+                        && (options.revert_pre_and_post_images || options.await_creation)
+                    {
+                        true => Some(pre_and_post_images(&self.db, &name).await?),
+                        false => None,
+                    };
+                    // The images of a collection that doesn't exist yet are turned on once it's
+                    // created
+                    let missing = options.await_creation && images == Some(None);
+                    // Whether the images were on before is only known until the instance has
+                    // turned them on itself
+                    let revert = owned
+                        || (!applied
+                            && options.revert_pre_and_post_images
+                            && images != Some(Some(true)));
+
+                    let creation = match missing {
+                        true => {
+                            enable_on_creation(&self.db, &name, self.applied_pre_images.clone())
+                                .await?
+                        }
+                        false => None,
+                    };
+                    if !applied && !missing {
+                        self.applied_pre_images.apply(&self.db, &name).await?;
+                    }
+                    if revert {
+                        self.enabled_pre_images.lock().unwrap().insert(name.clone());
+                    }
+
+                    let id = self.next_entry_id.fetch_add(1, Ordering::Relaxed);
+                    let collection = self.db.collection::<Document>(&name);
+
+                    let entry = entry.insert(
+                        CollectionEntry::new(
+                            id,
+                            collection.clone(),
+                            &options,
+                            subscription.filter(),
+                            Hooks {
+                                dead_letter: self
+                                    .dead_letter
+                                    .clone()
+                                    .map(|sender| DeadLetter::new(sender, name.clone(), id)),
+                                audit: self
+                                    .audit
+                                    .clone()
+                                    .map(|sender| Audit::new(sender, collection.namespace(), id)),
+                                on_error: self.on_error.clone(),
+                                on_drop: self.on_drop.clone(),
+                                replay_capacity: self.replay_capacity,
+                                flow: self.flow.clone(),
+                                subscription_count: self.subscription_count.clone(),
+                                applied_pre_images: self.applied_pre_images.clone(),
+                                redaction: self.redactions.get(&name).cloned(),
+                                pre_image_check: self.pre_image_check.map(
+                                    |(interval, on_disabled)| PreImageCheck {
+                                        db: self.db.clone(),
+                                        interval,
+                                        on_disabled,
+                                        clock: self.clock.clone(),
+                                    },
+                                ),
+                                stuck_check: self.on_stuck.clone().map(|(threshold, handler)| {
+                                    StuckCheck {
+                                        threshold,
+                                        handler,
+                                        clock: self.clock.clone(),
+                                    }
+                                }),
+                                filter_on_server: self.filter_on_server,
+                                key_fn: self.key_fns.get(&name).cloned(),
+                                paused: self.paused.subscribe(),
+                                clock: self.clock.clone(),
+                            },
+                            &*self.spawner,
+                        )
+                        .await?,
+                    );
+                    if revert {
+                        entry.revert_pre_images_on_drop(RevertPreImages {
+                            db: self.db.clone(),
+                            name: name.clone(),
+                            collections: Arc::downgrade(&self.collections),
+                            enabled: self.enabled_pre_images.clone(),
+                            applied: self.applied_pre_images.clone(),
+                        });
+                    }
+                    if let Some(creation) = creation {
+                        entry.stop_on_drop(spawner::spawn(&*self.spawner, creation));
+                    }
+                    entry
+                }
+            };
+
+            let (handle, position) =
+                match entry.add_subscription(subscription, options.replay).await {
+                    Ok(added) => added,
+                    Err(err) => {
+                        if entry.is_empty().await {
+                            collections.remove(&name);
+                        }
+                        return Err(err);
+                    }
+                };
+            let handle = Handle {
                 collection_name: name.clone(),
+                entry_id: entry.id(),
                 subscription_handle: handle,
-            },
-        ))
+            };
+
+            Ok::<_, BoxError>((handle, position))
+        })
+        .await;
+        let (handle, position) = match added {
+            Ok(added) => added,
+            Err(err) => {
+                // After a timeout, the stream may have been opened without the subscription being
+                // added
+                let mut collections = self.collections.lock().await;
+                if let Some(entry) = collections.get(&name) {
+                    if entry.is_empty().await {
+                        collections.remove(&name);
+                    }
+                }
+                return Err(err);
+            }
+        };
+
+        if let Some((mut primer, filter)) = primer {
+            let primed = async {
+                if options.initial_count {
+                    let count = self
+                        .count(
+                            &name,
+                            filter.clone(),
+                            &options,
+                            position.cluster_time,
+                            &primer,
+                        )
+                        .await?;
+                    primer.count(count);
+                }
+                if options.prime {
+                    self.prime(&name, filter, &options, &mut primer).await?;
+                }
+                primer.finish();
+                Ok::<_, mongodb::error::Error>(())
+            };
+            if let Err(err) = until(&*self.clock, deadline, primed).await {
+                Mercurius::remove_from(&self.collections, &handle, false).await;
+                return Err(err);
+            }
+        }
+
+        if let Some(store) = store {
+            let id = match persisted {
+                Some(id) => id,
+                None => {
+                    let definition = SubscriptionDefinition {
+                        id: ObjectId::new(),
+                        collection: name.clone(),
+                        filter: definition_filter,
+                        resume_token: position.resume_token,
+                    };
+
+                    if let Err(err) =
+                        until(&*self.clock, deadline, store.save_subscription(&definition)).await
+                    {
+                        Mercurius::remove_from(&self.collections, &handle, false).await;
+                        return Err(err);
+                    }
+                    definition.id
+                }
+            };
+
+            store.track(handle.clone(), id);
+        }
+
+        if let Some((token, removed)) = cancellation {
+            let collections = self.collections.clone();
+            let store = self.store.clone();
+            let handle = handle.clone();
+
+            self.spawn(async move {
+                tokio::select! {
+                    _ = token.cancelled() => {
+                        if Mercurius::remove_from(&collections, &handle, false).await {
+                            if let Some(store) = store {
+                                store.forget(&handle).await;
+                            }
+                        }
+                    }
+                    _ = removed.cancelled() => {}
+                }
+            });
+        }
+
+        if let Some(on_teardown) = &options.on_teardown {
+            on_teardown.arm();
+        }
+        Ok((receiver, handle))
+    }
+
+    /// Spawns a task that is stopped by [`Mercurius::shutdown`].
+    pub(crate) fn spawn(&self, task: impl Future<Output = ()> + Send + 'static) {
+        let stop = self.stop.lock().unwrap().clone();
+        self.spawner.spawn(Box::pin(async move {
+            tokio::select! {
+                _ = stop.cancelled() => {}
+                _ = task => {}
+            }
+        }));
+    }
+
+    /// Reads the documents that currently match the subscription. Changes that happen meanwhile
+    /// are held back by the subscription until the primer is finished.
+    async fn prime(
+        &self,
+        name: &str,
+        filter: Option<Document>,
+        options: &SubscriptionOptions,
+        primer: &mut Primer,
+    ) -> Result<(), mongodb::error::Error> {
+        let find_options = FindOptions::builder()
+            .read_concern(options.read_concern.clone())
+            .selection_criteria(options.selection_criteria.clone())
+            .collation(options.collation.clone())
+            .build();
+
+        let mut cursor = self.collection(name).find(filter, find_options).await?;
+        while cursor.advance().await? {
+            let mut document = cursor.deserialize_current()?;
+            if let Some(redaction) = self.redactions.get(name) {
+                redaction.document(&mut document);
+            }
+            primer.add(document);
+        }
+
+        Ok(())
+    }
+
+    /// Counts the documents that match the subscription at `cluster_time`, after which it
+    /// receives changes, see [`SubscriptionOptions::initial_count`]. Documents are only read
+    /// if the subscription has a predicate; otherwise the server counts them.
+    async fn count(
+        &self,
+        name: &str,
+        filter: Option<Document>,
+        options: &SubscriptionOptions,
+        cluster_time: Option<Timestamp>,
+        primer: &Primer,
+    ) -> Result<u64, mongodb::error::Error> {
+        let mut pipeline = vec![doc! { "$match": filter.unwrap_or_default() }];
+        if !primer.has_predicate() {
+            pipeline.push(doc! { "$count": "count" });
+        }
+
+        let mut command = doc! { "aggregate": name, "pipeline": pipeline, "cursor": {} };
+        match (cluster_time, &options.read_concern) {
+            (Some(time), _) => {
+                command.insert(
+                    "readConcern",
+                    doc! { "level": "snapshot", "atClusterTime": time },
+                );
+            }
+            (None, Some(read_concern)) => {
+                command.insert("readConcern", mongodb::bson::to_document(read_concern)?);
+            }
+            (None, None) => {}
+        }
+        if let Some(collation) = &options.collation {
+            command.insert("collation", mongodb::bson::to_document(collation)?);
+        }
+
+        let cursor_options = RunCursorCommandOptions::builder()
+            .selection_criteria(options.selection_criteria.clone())
+            .build();
+        let mut cursor = self.db.run_cursor_command(command, cursor_options).await?;
+
+        let mut count = 0;
+        while cursor.advance().await? {
+            let document: Document = cursor.deserialize_current()?;
+            match primer.has_predicate() {
+                true => count += u64::from(primer.matches(&document)),
+                false => {
+                    count = match document.get("count") {
+                        Some(Bson::Int32(count)) => *count as u64,
+                        Some(Bson::Int64(count)) => *count as u64,
+                        _ => 0,
+                    }
+                }
+            }
+        }
+
+        Ok(count)
+    }
+
+    /// Waits until every change that happened before the call has been dispatched to the
+    /// subscriptions, e.g. to read the events of a write deterministically in a test. Can take as
+    /// long as the [`max_await_time`](SubscriptionOptions::max_await_time) of a stream that has
+    /// no new changes, and on a stream that
+    /// [shows expanded events](SubscriptionOptions::show_expanded_events) until its collection
+    /// changes.
+    pub async fn flush(&self) -> Result<(), mongodb::error::Error> {
+        // Any collection of the database will do to ask for the cluster time
+        let name = match self.collections.lock().await.keys().next() {
+            Some(name) => name.clone(),
+            None => return Ok(()),
+        };
+        let now = match collection_entry::cluster_time(&self.collection(&name)).await? {
+            Some(now) => now,
+            None => return Ok(()),
+        };
+
+        let flushed: Vec<_> = self
+            .collections
+            .lock()
+            .await
+            .values()
+            .map(|collection| collection.flushed(now))
+            .collect();
+
+        for flushed in flushed {
+            flushed.await;
+        }
+
+        Ok(())
+    }
+
+    /// Waits until there are no subscriptions left, e.g. to tear down a test deterministically or
+    /// to shut down once every consumer has unsubscribed. Subscriptions are gone once they are
+    /// removed, including when their receiver is dropped and the change stream notices it with
+    /// the next event, or when their collection is torn down.
+    pub async fn wait_for_empty(&self) {
+        self.subscription_count.empty().await
     }
 
-    pub async fn remove(&self, handle: Handle) {
+    /// The number of subscriptions across all collections.
+    pub async fn total_subscription_count(&self) -> usize {
+        let collections = self.collections.lock().await;
+
+        let mut count = 0;
+        for collection in collections.values() {
+            count += collection.subscription_count().await;
+        }
+
+        count
+    }
+
+    /// The names of the collections that have at least one subscription.
+    pub async fn collection_names(&self) -> Vec<String> {
+        self.collections.lock().await.keys().cloned().collect()
+    }
+
+    /// The handles of the subscriptions on every collection that has any, ordered by their
+    /// [`Handle::id`].
+    pub async fn subscriptions(&self) -> HashMap<String, Vec<Handle>> {
+        let collections = self.collections.lock().await;
+
+        let mut subscriptions = HashMap::new();
+        for (name, collection) in collections.iter() {
+            let handles: Vec<_> = collection
+                .subscription_handles()
+                .await
+                .into_iter()
+                .map(|subscription_handle| Handle {
+                    collection_name: name.clone(),
+                    entry_id: collection.id(),
+                    subscription_handle,
+                })
+                .collect();
+            if !handles.is_empty() {
+                subscriptions.insert(name.clone(), handles);
+            }
+        }
+
+        subscriptions
+    }
+
+    /// The statistics of every subscription, e.g. to size the capacities of their channels.
+    pub async fn stats(&self) -> HashMap<Handle, SubscriptionStats> {
+        let collections = self.collections.lock().await;
+
+        let mut stats = HashMap::new();
+        for (name, collection) in collections.iter() {
+            for (subscription_handle, subscription_stats) in collection.stats().await {
+                let handle = Handle {
+                    collection_name: name.clone(),
+                    entry_id: collection.id(),
+                    subscription_handle,
+                };
+                stats.insert(handle, subscription_stats);
+            }
+        }
+
+        stats
+    }
+
+    /// The subscriptions that have events queued which their consumers haven't received for at
+    /// least `threshold`, along with how long that has been the case, see
+    /// [`SubscriptionStats::idle`].
+    pub async fn stuck_subscriptions(&self, threshold: Duration) -> Vec<(Handle, Duration)> {
+        self.stats()
+            .await
+            .into_iter()
+            .filter_map(|(handle, stats)| {
+                Some((handle, stats.idle.filter(|idle| *idle >= threshold)?))
+            })
+            .collect()
+    }
+
+    /// The filter that the subscription was added with, as it was given, e.g. to show what a
+    /// subscription is watching. A subscription without a filter has an empty one, which matches
+    /// every document like it. Predicates and other options aren't part of it.
+    ///
+    /// Returns `None` if the subscription was removed.
+    pub async fn filter(&self, handle: &Handle) -> Option<Document> {
+        match self.collections.lock().await.get(&handle.collection_name) {
+            Some(collection) if collection.id() == handle.entry_id => {
+                collection
+                    .subscription_filter(&handle.subscription_handle)
+                    .await
+            }
+            _ => None,
+        }
+    }
+
+    /// The number of change streams that are running for the collection `name`. All
+    /// subscriptions on a collection share one stream, so this is at most one.
+    #[doc(hidden)]
+    pub async fn stream_count_for(&self, name: &str) -> usize {
+        match self.collections.lock().await.get(name) {
+            Some(collection) => collection.running_streams(),
+            None => 0,
+        }
+    }
+
+    /// How far the change stream of the collection `name` is behind the server: the difference
+    /// between the current cluster time, which is fetched with a `ping`, and the time of the last
+    /// change that has been dispatched. While the collection is quiet the position keeps moving
+    /// forward, so the lag stays small unless the stream or its subscribers can't keep up.
+    ///
+    /// Cluster times have a resolution of one second. Returns `None` if the collection isn't
+    /// watched or no position is known yet.
+    pub async fn collection_lag(
+        &self,
+        name: &str,
+    ) -> Result<Option<Duration>, mongodb::error::Error> {
+        let dispatched = match self.collections.lock().await.get(name) {
+            Some(collection) => collection.cluster_time(),
+            None => return Ok(None),
+        };
+        let Some(dispatched) = dispatched else {
+            return Ok(None);
+        };
+
+        let now = collection_entry::cluster_time(&self.collection(name)).await?;
+        Ok(now.map(|now| Duration::from_secs(now.time.saturating_sub(dispatched.time).into())))
+    }
+
+    /// Dispatches `event` to the subscriptions on the collection `name` as if its change stream
+    /// had read it, so that tests can drive the matching and delivery of events with exact
+    /// changes, e.g. with pre-images or within a transaction, and assert on what the receivers
+    /// get. The events of a grouped transaction are delivered right away, as if the batch had
+    /// ended, and the position of the stream moves past the change.
+    ///
+    /// The collection must already be subscribed to; returns `false` if it isn't.
+    #[cfg(feature = "test-util")]
+    pub async fn inject_for_test(
+        &self,
+        name: &str,
+        event: &mongodb::change_stream::event::ChangeStreamEvent<Document>,
+    ) -> Result<bool, mongodb::bson::ser::Error> {
+        match self.collections.lock().await.get(name) {
+            Some(collection) => collection.inject_for_test(event).await.map(|()| true),
+            None => Ok(false),
+        }
+    }
+
+    /// Replaces the change stream of the collection `name` with a new one, e.g. when it seems
+    /// stuck or the configuration of the server has changed. With `resume`, the new stream
+    /// continues after the last change that has been dispatched; otherwise it starts at the
+    /// current time and the changes in between are skipped. The subscriptions are kept and
+    /// receive [`Event::Reset`]; the events of a grouped transaction that are being held back
+    /// are discarded.
+    ///
+    /// Returns `false` if the collection isn't watched. If the new stream can't be opened, the
+    /// current one continues where it was stopped for it; if that can't be reopened either, its
+    /// subscriptions are removed like those of a stream that ends with an error.
+    pub async fn refresh_collection(
+        &self,
+        name: &str,
+        resume: bool,
+    ) -> Result<bool, mongodb::error::Error> {
         let mut collections = self.collections.lock().await;
+        let Some(entry) = collections.get_mut(name) else {
+            return Ok(false);
+        };
+
+        entry.refresh(&*self.spawner, resume).await?;
+        Ok(true)
+    }
+
+    /// Stops reading the change streams of all collections until [`Mercurius::resume_all`], e.g.
+    /// to reduce the load on the server during a migration. Nothing is torn down: each stream
+    /// stops after the change it's reading and continues from there on resume, so no change is
+    /// missed, as long as the oplog still holds it then; otherwise
+    /// [`SubscriptionOptions::on_token_expiry`] applies. Events that are already queued
+    /// are still delivered, and collections that are subscribed to while paused wait as well.
+    ///
+    /// Returns `false` if the instance is already paused.
+    pub fn pause_all(&self) -> bool {
+        !self.paused.send_replace(true)
+    }
+
+    /// Continues reading the change streams that were stopped by [`Mercurius::pause_all`].
+    ///
+    /// Returns `false` if the instance isn't paused.
+    pub fn resume_all(&self) -> bool {
+        self.paused.send_replace(false)
+    }
+
+    /// Stops delivering events to the subscription identified by `handle` until it's resumed
+    /// with [`Mercurius::resume`], without losing its place: up to `capacity` events are held
+    /// back and delivered on resume, any further ones are dropped and counted in
+    /// [`SubscriptionStats::dropped`], as well as sent to the dead-letter channel.
+    ///
+    /// Returns `false` if the subscription was removed or is already paused.
+    pub async fn pause(&self, handle: &Handle, capacity: usize) -> bool {
+        match self.collections.lock().await.get(&handle.collection_name) {
+            Some(collection) if collection.id() == handle.entry_id => {
+                collection
+                    .pause_subscription(&handle.subscription_handle, capacity)
+                    .await
+            }
+            _ => false,
+        }
+    }
+
+    /// A new receiver for a subscription whose receiver has been dropped during its
+    /// [`SubscriptionOptions::grace_period`], which first receives the events that were held
+    /// for it.
+    ///
+    /// Returns `None` if the subscription was removed, its receiver hasn't been dropped, or its
+    /// grace period has elapsed.
+    pub async fn reattach(&self, handle: &Handle) -> Option<EventReceiver> {
+        match self.collections.lock().await.get(&handle.collection_name) {
+            Some(collection) if collection.id() == handle.entry_id => {
+                collection
+                    .reattach_subscription(&handle.subscription_handle)
+                    .await
+            }
+            _ => None,
+        }
+    }
+
+    /// Delivers the events that were held back while the subscription was paused, followed by
+    /// all new ones.
+    ///
+    /// Returns `false` if the subscription was removed or isn't paused.
+    pub async fn resume(&self, handle: &Handle) -> bool {
+        match self.collections.lock().await.get(&handle.collection_name) {
+            Some(collection) if collection.id() == handle.entry_id => {
+                collection
+                    .resume_subscription(&handle.subscription_handle)
+                    .await
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the subscription identified by `handle`.
+    ///
+    /// Returns `false` if the subscription was already removed (e.g. through a clone of the handle).
+    ///
+    /// The stored definition of a persistent subscription is removed as well.
+    pub async fn remove(&self, handle: &Handle) -> bool {
+        self.remove_and_forget(handle, false).await
+    }
+
+    /// Removes all subscriptions of `handles`, like [`Mercurius::remove`], but takes the lock of
+    /// each collection only once and tears down the collections that are left without
+    /// subscriptions at the end, so that many subscriptions are removed at once cheaply.
+    ///
+    /// Returns the handles of the subscriptions that were removed; the others had already been
+    /// removed.
+    pub async fn remove_all(&self, handles: Vec<Handle>) -> Vec<Handle> {
+        let mut by_collection: HashMap<(String, usize), Vec<Handle>> = HashMap::new();
+        for handle in handles {
+            by_collection
+                .entry((handle.collection_name.clone(), handle.entry_id))
+                .or_default()
+                .push(handle);
+        }
+
+        let mut removed = Vec::new();
+        {
+            let mut collections = self.collections.lock().await;
+
+            for ((name, entry_id), handles) in by_collection {
+                let collection = match collections.get(&name) {
+                    Some(collection) if collection.id() == entry_id => collection,
+                    _ => continue,
+                };
+
+                let results = collection
+                    .remove_subscriptions(handles.iter().map(|handle| &handle.subscription_handle))
+                    .await;
+                if collection.is_empty().await {
+                    collections.remove(&name);
+                }
+
+                removed.extend(
+                    handles
+                        .into_iter()
+                        .zip(results)
+                        .filter_map(|(handle, removed)| removed.then_some(handle)),
+                );
+            }
+        }
+
+        if let Some(store) = &self.store {
+            store.forget_all(&removed).await;
+        }
+
+        removed
+    }
+
+    /// Removes the subscription with the given [`Handle::id`] on the collection `name`, like
+    /// [`Mercurius::remove`].
+    ///
+    /// Returns `false` if there is no such subscription. Unlike a handle, the id may refer to
+    /// another subscription than the one it was issued for, if that one has been removed and
+    /// the id has been reused since.
+    pub async fn remove_by_id(&self, name: &str, id: usize) -> bool {
+        let handle = match self.collections.lock().await.get(name) {
+            Some(collection) => match collection.subscription_handle(id).await {
+                Some(subscription_handle) => Handle {
+                    collection_name: name.to_string(),
+                    entry_id: collection.id(),
+                    subscription_handle,
+                },
+                None => return false,
+            },
+            None => return false,
+        };
+        self.remove_and_forget(&handle, false).await
+    }
+
+    /// Like [`Mercurius::remove`], but sends [`Event::Closed`] after the last event of the
+    /// subscription, so the consumer knows it has received everything and can stop.
+    pub async fn drain_and_close(&self, handle: &Handle) -> bool {
+        self.remove_and_forget(handle, true).await
+    }
+
+    async fn remove_and_forget(&self, handle: &Handle, close: bool) -> bool {
+        let removed = Mercurius::remove_from(&self.collections, handle, close).await;
+
+        if let (true, Some(store)) = (removed, &self.store) {
+            store.forget(handle).await;
+        }
+
+        removed
+    }
+
+    async fn remove_from(collections: &Collections, handle: &Handle, close: bool) -> bool {
+        let mut collections = collections.lock().await;
 
         let collection = match collections.get(&handle.collection_name) {
-            Some(collection) => collection,
-            None => return,
+            Some(collection) if collection.id() == handle.entry_id => collection,
+            _ => return false,
         };
 
-        collection
-            .remove_subscription(handle.subscription_handle)
+        let removed = collection
+            .remove_subscription(&handle.subscription_handle)
             .await;
 
-        if collection.subscription_count().await == 0 {
+        if collection.is_empty().await {
             collections.remove(&handle.collection_name);
         }
+
+        match removed {
+            Some(subscription) => {
+                // No events can be dispatched to the subscription anymore, so this is the last one
+                if close {
+                    // A paused subscription still delivers the events it has held back
+                    subscription.resume();
+                    let _ = subscription.send(Event::Closed);
+                }
+                subscription.tear_down(TeardownReason::Removed);
+                true
+            }
+            None => false,
+        }
     }
 
+    /// Waits for the background tasks of the instance and fails once one of them panics. Returns
+    /// right away with a custom spawner, see [`Mercurius::with_spawner`].
     pub async fn run(&self) -> Result<(), Box<tokio::task::JoinError>> {
-        let mut join_set = self.join_set.lock().await;
+        let tokio_spawner = match &self.tokio_spawner {
+            Some(tokio_spawner) => tokio_spawner,
+            None => return Ok(()),
+        };
 
-        while let Some(res) = join_set.join_next().await {
+        while let Some(res) = tokio_spawner.join_next().await {
             if let Err(e) = res {
                 if e.is_panic() {
                     Err(Box::new(e))?;
@@ -103,4 +1590,163 @@ impl Mercurius {
 
         Ok(())
     }
+
+    /// Runs [`Mercurius::run`] in a task of its own and returns its handle, which can be awaited
+    /// or aborted. Aborting it stops the instance like [`Mercurius::shutdown`]: the subscriptions
+    /// are removed and the change streams and all other background tasks are aborted.
+    ///
+    /// Fails with [`MercuriusError::AlreadyRunning`] while the task of an earlier call is still
+    /// running, as only one task may wait for the background tasks.
+    pub fn run_in_background(
+        self: Arc<Self>,
+    ) -> Result<JoinHandle<Result<(), Box<tokio::task::JoinError>>>, MercuriusError> {
+        if self.supervised.swap(true, Ordering::AcqRel) {
+            return Err(MercuriusError::AlreadyRunning);
+        }
+
+        // Created outside of the task, so that it also stops the instance if the task is aborted
+        // before it starts
+        let mut supervisor = Supervisor {
+            mercurius: self,
+            finished: false,
+        };
+        Ok(tokio::spawn(async move { supervisor.run().await }))
+    }
+
+    /// Like [`Mercurius::run`], but stops all change streams and returns once `token` is
+    /// cancelled.
+    pub async fn run_with_cancel(
+        &self,
+        token: CancellationToken,
+    ) -> Result<(), Box<tokio::task::JoinError>> {
+        tokio::select! {
+            res = self.run() => res,
+            _ = token.cancelled() => {
+                self.shutdown().await;
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes all subscriptions and stops all change streams.
+    ///
+    /// The receivers of the subscriptions are closed after the events that were already sent.
+    /// Persistent subscriptions stay stored, so they can be restored later on.
+    pub async fn shutdown(&self) {
+        self.collections.lock().await.clear();
+        let stop = std::mem::take(&mut *self.stop.lock().unwrap());
+        stop.cancel();
+        if let Some(tokio_spawner) = &self.tokio_spawner {
+            tokio_spawner.shutdown().await;
+        }
+    }
+}
+
+/// The task of [`Mercurius::run_in_background`], which stops the instance if it's aborted before
+/// it finishes.
+struct Supervisor {
+    mercurius: Arc<Mercurius>,
+    finished: bool,
+}
+
+impl Supervisor {
+    async fn run(&mut self) -> Result<(), Box<tokio::task::JoinError>> {
+        let result = self.mercurius.run().await;
+        self.finished = true;
+        result
+    }
+}
+
+impl Drop for Supervisor {
+    fn drop(&mut self) {
+        let mercurius = &self.mercurius;
+        if !self.finished {
+            // The subscriptions are only removed if no one holds the lock, as dropping can't wait
+            if let Ok(mut collections) = mercurius.collections.try_lock() {
+                collections.clear();
+            }
+            std::mem::take(&mut *mercurius.stop.lock().unwrap()).cancel();
+            if let Some(tokio_spawner) = &mercurius.tokio_spawner {
+                tokio_spawner.abort_all();
+            }
+        }
+        mercurius.supervised.store(false, Ordering::Release);
+    }
+}
+
+/// The event that [`Mercurius::next_matching`] waits for: a change that matches the filter of
+/// the subscription, or [`Event::Drop`]. Events that are sent regardless of the filter, like
+/// [`Event::PreImageUnavailable`], are skipped.
+async fn next_match(receiver: &mut EventReceiver) -> Option<Event> {
+    while let Some(event) = receiver.recv().await {
+        if event.is_match() || matches!(event, Event::Drop) {
+            return Some(event);
+        }
+    }
+    None
+}
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Fails with [`MercuriusError::Timeout`] if `future` doesn't complete before `deadline`
+/// according to `clock`.
+async fn until<T, E: Into<BoxError>>(
+    clock: &dyn Clock,
+    deadline: Option<Instant>,
+    future: impl Future<Output = Result<T, E>>,
+) -> Result<T, BoxError> {
+    let result = match deadline {
+        Some(deadline) => {
+            let timeout = deadline.saturating_duration_since(clock.now());
+            clock::timeout(clock, timeout, future)
+                .await
+                .ok_or(MercuriusError::Timeout)?
+        }
+        None => future.await,
+    };
+
+    result.map_err(Into::into)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mongodb::{bson::doc, change_stream::event::OperationType};
+
+    use super::next_match;
+    use crate::{
+        channel::test_channel,
+        subscription::{DdlEvent, Event, EventDocument, UnavailableReason},
+    };
+
+    #[tokio::test]
+    async fn next_match_skips_events_regardless_of_the_filter() {
+        let (sender, mut receiver) = test_channel();
+        let added = Event::Added(EventDocument::Owned(doc! { "_id": "1" }));
+        for event in [
+            Event::Established {
+                cluster_time: None,
+                resume_token: None,
+            },
+            Event::PreImageUnavailable {
+                key: Arc::new("2".to_string()),
+                operation_type: OperationType::Delete,
+                reason: UnavailableReason::Expired,
+            },
+            Event::Ddl(Box::new(DdlEvent {
+                operation_type: "createIndexes".to_string(),
+                description: None,
+            })),
+            added.clone(),
+            Event::Drop,
+        ] {
+            sender.send(event).unwrap();
+        }
+
+        assert_eq!(next_match(&mut receiver).await, Some(added));
+        assert_eq!(next_match(&mut receiver).await, Some(Event::Drop));
+        drop(sender);
+        assert_eq!(next_match(&mut receiver).await, None);
+    }
 }
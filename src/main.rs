@@ -30,11 +30,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         let mercurius = mercurius.clone();
         tokio::spawn(async move {
             tokio::time::sleep(Duration::from_secs(2)).await;
-            mercurius.remove(handle).await;
+            mercurius.remove(&handle).await;
         });
     }
 
-    mercurius.run().await?;
+    mercurius.run_in_background()?.await??;
 
     Ok(())
 }
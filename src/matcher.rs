@@ -0,0 +1,1088 @@
+//! Evaluates MongoDB query documents client-side.
+//!
+//! Supported are the comparison operators (`$eq`, `$ne`, `$gt`, `$gte`, `$lt`, `$lte`, `$in` and
+//! `$nin`), `$exists`, `$type`, `$regex` (with the `$options` `i`, `m`, `s` and `x`), `$not` and
+//! the logical operators `$and`, `$or` and `$nor`; a regular expression as the value of a field
+//! works like a `$regex`. Regular expressions are compiled once, when the matcher is created.
+//! Fields can be addressed with dot notation, and like in MongoDB a condition on an array field
+//! matches if the array itself or any of its elements matches.
+//!
+//! Documents are compared in their relaxed extended JSON form, so numbers compare numerically
+//! regardless of their BSON type; a `Decimal128` is compared exactly, with other decimals as well
+//! as with other numbers, and dates compare chronologically. Values of different types never
+//! compare as greater or less than each other.
+//!
+//! Strings are compared by their code points, unless a [`Collation`] is given with
+//! [`Matcher::with_collation`]. Of a collation only the case sensitivity is honored: a `strength`
+//! of 1 or 2 without `caseLevel` compares strings case-insensitively; `$regex` is unaffected, as by
+//! the server. The locale and all other options only apply server-side, i.e. to the stages of the
+//! change stream and to priming.
+//!
+//! The `bson-matcher` feature adds a matcher that evaluates filters on BSON directly and supports
+//! more operators, e.g. `$elemMatch`, which subscriptions can opt into.
+
+use std::{cmp::Ordering, fmt::Display};
+
+use mongodb::{
+    bson::{Bson, DateTime, Document},
+    options::{Collation, CollationStrength},
+};
+use regex::{Regex, RegexBuilder};
+use serde_json::{Map, Value};
+
+/// A compiled query document.
+#[derive(Debug, Clone)]
+pub struct Matcher {
+    query: Query,
+    case_insensitive: bool,
+}
+
+#[derive(Debug)]
+pub enum MatcherError {
+    UnknownOperator(String),
+    /// The operand of an operator has an unexpected type.
+    InvalidOperand(&'static str),
+    InvalidRegex(regex::Error),
+}
+
+#[derive(Debug, Clone)]
+enum Query {
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Nor(Vec<Query>),
+    Field(String, Vec<Condition>),
+}
+
+#[derive(Debug, Clone)]
+enum Condition {
+    Eq(Value),
+    Ne(Value),
+    Cmp(Ordering, bool, Value),
+    In(Vec<Value>),
+    Nin(Vec<Value>),
+    Exists(bool),
+    Type(Vec<String>),
+    Regex(Regex),
+    Not(Vec<Condition>),
+}
+
+impl Matcher {
+    pub fn new(filter: &Document) -> Result<Self, MatcherError> {
+        let query = Query::parse(&Matcher::document_to_value(filter))?;
+        Ok(Self {
+            query,
+            case_insensitive: false,
+        })
+    }
+
+    /// Like [`Matcher::new`], comparing strings according to `collation`, as far as it's
+    /// supported client-side (see the [module documentation](crate::matcher)).
+    pub fn with_collation(filter: &Document, collation: &Collation) -> Result<Self, MatcherError> {
+        let mut matcher = Matcher::new(filter)?;
+        matcher.set_collation(collation);
+        Ok(matcher)
+    }
+
+    pub(crate) fn set_collation(&mut self, collation: &Collation) {
+        self.case_insensitive = matches!(
+            collation.strength,
+            Some(CollationStrength::Primary | CollationStrength::Secondary)
+        ) && collation.case_level != Some(true);
+    }
+
+    pub fn matches(&self, document: &Document) -> bool {
+        self.matches_value(&Matcher::document_to_value(document))
+    }
+
+    /// Like [`Matcher::matches`], for a document that has already been converted to relaxed
+    /// extended JSON.
+    pub fn matches_value(&self, document: &Value) -> bool {
+        self.query.matches(document, self.case_insensitive)
+    }
+
+    pub(crate) fn document_to_value(document: &Document) -> Value {
+        Bson::from(document).into_relaxed_extjson()
+    }
+}
+
+impl Query {
+    fn parse(value: &Value) -> Result<Self, MatcherError> {
+        let object = value
+            .as_object()
+            .ok_or(MatcherError::InvalidOperand("query"))?;
+
+        let mut queries = Vec::with_capacity(object.len());
+        for (key, value) in object {
+            queries.push(match key.as_str() {
+                "$and" => Query::And(Query::parse_all(value)?),
+                "$or" => Query::Or(Query::parse_all(value)?),
+                "$nor" => Query::Nor(Query::parse_all(value)?),
+                key if key.starts_with('$') => {
+                    return Err(MatcherError::UnknownOperator(key.to_string()))
+                }
+                key => Query::Field(key.to_string(), Condition::parse(value)?),
+            });
+        }
+
+        Ok(match queries.len() {
+            1 => queries.remove(0),
+            _ => Query::And(queries),
+        })
+    }
+
+    fn parse_all(value: &Value) -> Result<Vec<Self>, MatcherError> {
+        value
+            .as_array()
+            .ok_or(MatcherError::InvalidOperand("$and, $or and $nor"))?
+            .iter()
+            .map(Query::parse)
+            .collect()
+    }
+
+    fn matches(&self, document: &Value, case_insensitive: bool) -> bool {
+        match self {
+            Query::And(queries) => queries
+                .iter()
+                .all(|query| query.matches(document, case_insensitive)),
+            Query::Or(queries) => queries
+                .iter()
+                .any(|query| query.matches(document, case_insensitive)),
+            Query::Nor(queries) => !queries
+                .iter()
+                .any(|query| query.matches(document, case_insensitive)),
+            Query::Field(path, conditions) => {
+                let values = resolve(document, path);
+                conditions
+                    .iter()
+                    .all(|condition| condition.matches(&values, case_insensitive))
+            }
+        }
+    }
+}
+
+impl Condition {
+    /// Parses the condition on a field: either an operator expression or a value to compare with.
+    fn parse(value: &Value) -> Result<Vec<Self>, MatcherError> {
+        let object = match value {
+            Value::Object(object) if is_operator_expression(object) => object,
+            // Like in MongoDB, a regular expression as the value matches the strings it matches
+            value => {
+                return Ok(vec![match regular_expression(value) {
+                    Some((pattern, options)) => Condition::Regex(regex(pattern, options)?),
+                    None => Condition::Eq(value.clone()),
+                }])
+            }
+        };
+
+        let mut conditions = Vec::with_capacity(object.len());
+        for (operator, operand) in object {
+            conditions.push(match operator.as_str() {
+                "$eq" => Condition::Eq(operand.clone()),
+                "$ne" => Condition::Ne(operand.clone()),
+                "$gt" => Condition::Cmp(Ordering::Greater, false, operand.clone()),
+                "$gte" => Condition::Cmp(Ordering::Greater, true, operand.clone()),
+                "$lt" => Condition::Cmp(Ordering::Less, false, operand.clone()),
+                "$lte" => Condition::Cmp(Ordering::Less, true, operand.clone()),
+                "$in" => Condition::In(array_operand(operand, "$in")?),
+                "$nin" => Condition::Nin(array_operand(operand, "$nin")?),
+                "$exists" => Condition::Exists(match operand {
+                    Value::Bool(exists) => *exists,
+                    Value::Number(n) => n.as_f64() != Some(0.0),
+                    _ => return Err(MatcherError::InvalidOperand("$exists")),
+                }),
+                "$type" => Condition::Type(match operand {
+                    Value::String(name) => vec![name.clone()],
+                    Value::Array(names) => names
+                        .iter()
+                        .map(|name| name.as_str().map(str::to_string))
+                        .collect::<Option<_>>()
+                        .ok_or(MatcherError::InvalidOperand("$type"))?,
+                    _ => return Err(MatcherError::InvalidOperand("$type")),
+                }),
+                "$regex" => {
+                    let (pattern, own_options) = match operand {
+                        Value::String(pattern) => (pattern.as_str(), ""),
+                        operand => regular_expression(operand)
+                            .ok_or(MatcherError::InvalidOperand("$regex"))?,
+                    };
+                    // `$options` takes precedence over those of a regular expression
+                    let options = match object.get("$options") {
+                        Some(Value::String(options)) => options.as_str(),
+                        Some(_) => return Err(MatcherError::InvalidOperand("$options")),
+                        None => own_options,
+                    };
+                    Condition::Regex(regex(pattern, options)?)
+                }
+                "$options" if object.contains_key("$regex") => continue,
+                "$options" => return Err(MatcherError::InvalidOperand("$options")),
+                "$not" => Condition::Not(Condition::parse(operand)?),
+                operator => return Err(MatcherError::UnknownOperator(operator.to_string())),
+            });
+        }
+
+        Ok(conditions)
+    }
+
+    fn matches(&self, values: &[&Value], case_insensitive: bool) -> bool {
+        match self {
+            Condition::Eq(expected) => matches_eq(values, expected, case_insensitive),
+            Condition::Ne(expected) => !matches_eq(values, expected, case_insensitive),
+            Condition::Cmp(ordering, or_equal, operand) => {
+                any_value(values, |value| {
+                    match compare(value, operand, case_insensitive) {
+                        Some(Ordering::Equal) => *or_equal,
+                        Some(result) => result == *ordering,
+                        None => false,
+                    }
+                })
+            }
+            Condition::In(expected) => expected
+                .iter()
+                .any(|expected| matches_eq(values, expected, case_insensitive)),
+            Condition::Nin(expected) => !expected
+                .iter()
+                .any(|expected| matches_eq(values, expected, case_insensitive)),
+            Condition::Exists(exists) => values.is_empty() != *exists,
+            Condition::Type(names) => any_value(values, |value| {
+                names.iter().any(|name| is_type(value, name))
+            }),
+            Condition::Regex(regex) => any_value(
+                values,
+                |value| matches!(value, Value::String(s) if regex.is_match(s)),
+            ),
+            Condition::Not(conditions) => !conditions
+                .iter()
+                .all(|condition| condition.matches(values, case_insensitive)),
+        }
+    }
+}
+
+/// The keys with which extended JSON represents BSON values that have no JSON equivalent.
+const EXTJSON_KEYS: &[&str] = &[
+    "$oid",
+    "$date",
+    "$numberDecimal",
+    "$numberDouble",
+    "$numberLong",
+    "$numberInt",
+    "$binary",
+    "$timestamp",
+    "$regularExpression",
+    "$symbol",
+    "$code",
+    "$dbPointer",
+    "$minKey",
+    "$maxKey",
+    "$undefined",
+];
+
+fn is_extjson_value(object: &Map<String, Value>) -> bool {
+    matches!(object.keys().next(), Some(key) if EXTJSON_KEYS.contains(&key.as_str()))
+}
+
+/// The pattern and options of a BSON regular expression in extended JSON.
+fn regular_expression(value: &Value) -> Option<(&str, &str)> {
+    let expression = value.get("$regularExpression")?;
+    let options = match expression.get("options") {
+        Some(options) => options.as_str()?,
+        None => "",
+    };
+    Some((expression.get("pattern")?.as_str()?, options))
+}
+
+/// Compiles a regular expression with the options of `$options`, once for the whole matcher.
+pub(crate) fn regex(pattern: &str, options: &str) -> Result<Regex, MatcherError> {
+    let mut builder = RegexBuilder::new(pattern);
+    for option in options.chars() {
+        match option {
+            'i' => builder.case_insensitive(true),
+            'm' => builder.multi_line(true),
+            's' => builder.dot_matches_new_line(true),
+            'x' => builder.ignore_whitespace(true),
+            // Patterns are always matched as Unicode
+            'u' => &mut builder,
+            _ => return Err(MatcherError::InvalidOperand("$options")),
+        };
+    }
+
+    builder.build().map_err(MatcherError::InvalidRegex)
+}
+
+fn is_operator_expression(object: &Map<String, Value>) -> bool {
+    !object.is_empty() && !is_extjson_value(object) && object.keys().all(|key| key.starts_with('$'))
+}
+
+fn array_operand(operand: &Value, operator: &'static str) -> Result<Vec<Value>, MatcherError> {
+    operand
+        .as_array()
+        .cloned()
+        .ok_or(MatcherError::InvalidOperand(operator))
+}
+
+/// Returns all values at the dot-separated `path`, descending into arrays along the way.
+fn resolve<'a>(document: &'a Value, path: &str) -> Vec<&'a Value> {
+    let mut values = vec![document];
+
+    for segment in path.split('.') {
+        let mut next = Vec::new();
+
+        for value in values {
+            match value {
+                Value::Object(object) => next.extend(object.get(segment)),
+                Value::Array(elements) => match segment.parse::<usize>() {
+                    Ok(index) => next.extend(elements.get(index)),
+                    Err(_) => next.extend(
+                        elements
+                            .iter()
+                            .filter_map(|element| element.as_object()?.get(segment)),
+                    ),
+                },
+                _ => {}
+            }
+        }
+
+        values = next;
+    }
+
+    values
+}
+
+/// Whether `predicate` holds for any of the values or, for arrays, any of their elements.
+fn any_value(values: &[&Value], predicate: impl Fn(&Value) -> bool) -> bool {
+    values.iter().any(|value| {
+        predicate(value)
+            || match value {
+                Value::Array(elements) => elements.iter().any(&predicate),
+                _ => false,
+            }
+    })
+}
+
+fn matches_eq(values: &[&Value], expected: &Value, case_insensitive: bool) -> bool {
+    // Like in MongoDB, `null` also matches fields that don't exist
+    (values.is_empty() && expected.is_null())
+        || any_value(values, |value| value_eq(value, expected, case_insensitive))
+}
+
+fn value_eq(a: &Value, b: &Value, case_insensitive: bool) -> bool {
+    if let Some(ordering) = compare_extjson(a, b) {
+        return ordering == Ordering::Equal;
+    }
+
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::String(a), Value::String(b)) if case_insensitive => {
+            a.to_lowercase() == b.to_lowercase()
+        }
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|(a, b)| value_eq(a, b, case_insensitive))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b)
+                    .all(|((ka, va), (kb, vb))| ka == kb && value_eq(va, vb, case_insensitive))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Compares two values of the same type; values of different types are incomparable.
+fn compare(a: &Value, b: &Value, case_insensitive: bool) -> Option<Ordering> {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        (Value::String(a), Value::String(b)) if case_insensitive => {
+            Some(a.to_lowercase().cmp(&b.to_lowercase()))
+        }
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        _ => compare_extjson(a, b),
+    }
+}
+
+/// Compares dates, and numbers of which at least one is in extended JSON, e.g. a `Decimal128`;
+/// `None` for any other values.
+fn compare_extjson(a: &Value, b: &Value) -> Option<Ordering> {
+    if !a.is_object() && !b.is_object() {
+        return None;
+    }
+
+    if let (Some(a), Some(b)) = (date(a), date(b)) {
+        return Some(a.cmp(&b));
+    }
+    Some(Decimal::parse(a)?.cmp(&Decimal::parse(b)?))
+}
+
+/// The milliseconds since the epoch of a date in relaxed extended JSON.
+fn date(value: &Value) -> Option<i64> {
+    match value.as_object()?.get("$date")? {
+        Value::String(date) => Some(DateTime::parse_rfc3339_str(date).ok()?.timestamp_millis()),
+        Value::Object(date) => date.get("$numberLong")?.as_str()?.parse().ok(),
+        Value::Number(millis) => millis.as_i64(),
+        _ => None,
+    }
+}
+
+/// A number of any BSON type, compared exactly by its decimal digits rather than as a double, so
+/// that a `Decimal128` keeps its precision. Like in MongoDB, `NaN` is less than any other number.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Decimal {
+    NaN,
+    NegativeInfinity,
+    /// The digits of the magnitude are compared the other way around.
+    Negative {
+        order: std::cmp::Reverse<i64>,
+        digits: std::cmp::Reverse<String>,
+    },
+    Zero,
+    /// `digits` without leading or trailing zeros, the first of which is at position `order`,
+    /// i.e. the value is `0.digits × 10^order`.
+    Positive {
+        order: i64,
+        digits: String,
+    },
+    Infinity,
+}
+
+impl Decimal {
+    fn parse(value: &Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => Decimal::from_str(&number.to_string()),
+            Value::Object(object) => match object.iter().next()? {
+                (key, Value::String(number))
+                    if object.len() == 1 && (key == "$numberDecimal" || key == "$numberDouble") =>
+                {
+                    Decimal::from_str(number)
+                }
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    pub(crate) fn from_str(number: &str) -> Option<Self> {
+        let (negative, number) = match number.strip_prefix('-') {
+            Some(number) => (true, number),
+            None => (false, number.strip_prefix('+').unwrap_or(number)),
+        };
+
+        if number.eq_ignore_ascii_case("nan") {
+            return Some(Decimal::NaN);
+        }
+        if number.eq_ignore_ascii_case("infinity") || number.eq_ignore_ascii_case("inf") {
+            return Some(match negative {
+                true => Decimal::NegativeInfinity,
+                false => Decimal::Infinity,
+            });
+        }
+
+        let (mantissa, exponent) = match number.find(['e', 'E']) {
+            Some(index) => (&number[..index], number[index + 1..].parse::<i64>().ok()?),
+            None => (number, 0),
+        };
+        let (integer, fraction) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if integer.is_empty() && fraction.is_empty() {
+            return None;
+        }
+        if !integer
+            .chars()
+            .chain(fraction.chars())
+            .all(|c| c.is_ascii_digit())
+        {
+            return None;
+        }
+
+        let integer = integer.trim_start_matches('0');
+        let mut order = exponent + integer.len() as i64;
+        let digits = match integer.is_empty() {
+            true => {
+                let significant = fraction.trim_start_matches('0');
+                order -= (fraction.len() - significant.len()) as i64;
+                significant.to_string()
+            }
+            false => format!("{}{}", integer, fraction),
+        };
+        let digits = digits.trim_end_matches('0').to_string();
+
+        Some(match (digits.is_empty(), negative) {
+            (true, _) => Decimal::Zero,
+            (false, true) => Decimal::Negative {
+                order: std::cmp::Reverse(order),
+                digits: std::cmp::Reverse(digits),
+            },
+            (false, false) => Decimal::Positive { order, digits },
+        })
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match (name, value) {
+        ("null", Value::Null) => true,
+        ("bool", Value::Bool(_)) => true,
+        ("string", Value::String(_)) => true,
+        ("array", Value::Array(_)) => true,
+        ("number", Value::Number(_)) => true,
+        ("int" | "long", Value::Number(n)) => n.is_i64() || n.is_u64(),
+        ("double", Value::Number(n)) => n.is_f64(),
+        ("object", Value::Object(object)) => !is_extjson_value(object),
+        ("objectId", Value::Object(object)) => object.contains_key("$oid"),
+        ("date", Value::Object(object)) => object.contains_key("$date"),
+        ("decimal", Value::Object(object)) => object.contains_key("$numberDecimal"),
+        _ => false,
+    }
+}
+
+impl Display for MatcherError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MatcherError::UnknownOperator(operator) => write!(f, "Unknown operator {}", operator),
+            MatcherError::InvalidOperand(operator) => {
+                write!(f, "Invalid operand for {}", operator)
+            }
+            MatcherError::InvalidRegex(err) => write!(f, "Invalid regex: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for MatcherError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MatcherError::InvalidRegex(err) => Some(err),
+            _ => None,
+        }
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        self.source()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, DateTime, Document};
+    use serde_json::{json, Value};
+
+    use super::{Matcher, MatcherError};
+
+    fn matches(filter: Document, document: Document) -> bool {
+        Matcher::new(&filter).unwrap().matches(&document)
+    }
+
+    /// Asserts that `filter` matches `matching` but not `non_matching`.
+    fn check(filter: Document, matching: Document, non_matching: Document) {
+        assert!(
+            matches(filter.clone(), matching.clone()),
+            "{} should match {}",
+            filter,
+            matching
+        );
+        assert!(
+            !matches(filter.clone(), non_matching.clone()),
+            "{} should not match {}",
+            filter,
+            non_matching
+        );
+    }
+
+    #[test]
+    fn eq() {
+        check(doc! { "a": 1 }, doc! { "a": 1 }, doc! { "a": 2 });
+        check(
+            doc! { "a": { "$eq": "x" } },
+            doc! { "a": "x" },
+            doc! { "a": "y" },
+        );
+        // Numbers compare regardless of their BSON type
+        check(doc! { "a": 1 }, doc! { "a": 1.0 }, doc! { "a": 1.5 });
+        check(doc! { "a": 5_i64 }, doc! { "a": 5_i32 }, doc! { "a": "5" });
+        check(
+            doc! { "a": { "b": 1 } },
+            doc! { "a": { "b": 1 } },
+            doc! { "a": { "b": 1, "c": 2 } },
+        );
+    }
+
+    #[test]
+    fn ne() {
+        check(doc! { "a": { "$ne": 1 } }, doc! { "a": 2 }, doc! { "a": 1 });
+        check(doc! { "a": { "$ne": 1 } }, doc! {}, doc! { "a": [2, 1] });
+    }
+
+    #[test]
+    fn comparisons() {
+        check(doc! { "a": { "$gt": 1 } }, doc! { "a": 2 }, doc! { "a": 1 });
+        check(
+            doc! { "a": { "$gte": 1 } },
+            doc! { "a": 1 },
+            doc! { "a": 0 },
+        );
+        check(doc! { "a": { "$lt": 1 } }, doc! { "a": 0 }, doc! { "a": 1 });
+        check(
+            doc! { "a": { "$lte": 1 } },
+            doc! { "a": 1 },
+            doc! { "a": 2 },
+        );
+        check(
+            doc! { "a": { "$gt": "b" } },
+            doc! { "a": "c" },
+            doc! { "a": "a" },
+        );
+        check(
+            doc! { "a": { "$gt": 1, "$lt": 3 } },
+            doc! { "a": 2 },
+            doc! { "a": 3 },
+        );
+        // Values of different types never compare as greater or less than each other
+        check(
+            doc! { "a": { "$gt": 1 } },
+            doc! { "a": 2 },
+            doc! { "a": "2" },
+        );
+        check(doc! { "a": { "$lt": 1 } }, doc! { "a": 0 }, doc! {});
+    }
+
+    #[test]
+    fn in_and_nin() {
+        check(
+            doc! { "a": { "$in": [1, 2] } },
+            doc! { "a": 2 },
+            doc! { "a": 3 },
+        );
+        check(
+            doc! { "a": { "$in": [1, 2] } },
+            doc! { "a": [3, 1] },
+            doc! { "a": [] },
+        );
+        check(
+            doc! { "a": { "$nin": [1, 2] } },
+            doc! { "a": 3 },
+            doc! { "a": 1 },
+        );
+        check(
+            doc! { "a": { "$nin": [1, 2] } },
+            doc! {},
+            doc! { "a": [3, 2] },
+        );
+        check(doc! { "a": { "$in": [null] } }, doc! {}, doc! { "a": 1 });
+    }
+
+    #[test]
+    fn exists() {
+        check(
+            doc! { "a": { "$exists": true } },
+            doc! { "a": null },
+            doc! { "b": 1 },
+        );
+        check(
+            doc! { "a": { "$exists": false } },
+            doc! { "b": 1 },
+            doc! { "a": 1 },
+        );
+        check(doc! { "a": { "$exists": 1 } }, doc! { "a": 0 }, doc! {});
+    }
+
+    #[test]
+    fn type_() {
+        check(
+            doc! { "a": { "$type": "string" } },
+            doc! { "a": "x" },
+            doc! { "a": 1 },
+        );
+        check(
+            doc! { "a": { "$type": "number" } },
+            doc! { "a": 1.5 },
+            doc! { "a": "1" },
+        );
+        check(
+            doc! { "a": { "$type": "int" } },
+            doc! { "a": 1 },
+            doc! { "a": 1.5 },
+        );
+        check(
+            doc! { "a": { "$type": "double" } },
+            doc! { "a": 1.5 },
+            doc! { "a": 1 },
+        );
+        check(
+            doc! { "a": { "$type": "null" } },
+            doc! { "a": null },
+            doc! {},
+        );
+        check(
+            doc! { "a": { "$type": "objectId" } },
+            doc! { "a": mongodb::bson::oid::ObjectId::new() },
+            doc! { "a": { "b": 1 } },
+        );
+        check(
+            doc! { "a": { "$type": "object" } },
+            doc! { "a": { "b": 1 } },
+            doc! { "a": mongodb::bson::DateTime::now() },
+        );
+        check(
+            doc! { "a": { "$type": ["bool", "array"] } },
+            doc! { "a": true },
+            doc! { "a": "true" },
+        );
+        check(
+            doc! { "a": { "$type": "array" } },
+            doc! { "a": [] },
+            doc! { "a": {} },
+        );
+    }
+
+    #[test]
+    fn regex() {
+        check(
+            doc! { "a": { "$regex": "^x" } },
+            doc! { "a": "xy" },
+            doc! { "a": "yx" },
+        );
+        check(
+            doc! { "a": mongodb::bson::Regex { pattern: "y$".into(), options: String::new() } },
+            doc! { "a": "xy" },
+            doc! { "a": "yx" },
+        );
+        // Only strings match
+        check(
+            doc! { "a": { "$regex": "1" } },
+            doc! { "a": "1" },
+            doc! { "a": 1 },
+        );
+    }
+
+    #[test]
+    fn anchored_regex() {
+        check(
+            doc! { "a": { "$regex": "^ab$" } },
+            doc! { "a": "ab" },
+            doc! { "a": "abc" },
+        );
+        check(
+            doc! { "a": { "$regex": "ab" } },
+            doc! { "a": "cabc" },
+            doc! { "a": "ba" },
+        );
+        // Anchors only match at the ends of the string without `m`
+        check(
+            doc! { "a": { "$regex": "^b" } },
+            doc! { "a": "bc" },
+            doc! { "a": "a\nb" },
+        );
+    }
+
+    #[test]
+    fn case_insensitive_regex() {
+        check(
+            doc! { "a": { "$regex": "^ab", "$options": "i" } },
+            doc! { "a": "ABc" },
+            doc! { "a": "cAB" },
+        );
+        check(
+            doc! { "a": { "$regex": "^ab" } },
+            doc! { "a": "abc" },
+            doc! { "a": "ABc" },
+        );
+        check(
+            doc! { "a": mongodb::bson::Regex { pattern: "^ab".into(), options: "i".into() } },
+            doc! { "a": "Ab" },
+            doc! { "a": "b" },
+        );
+        // `$options` takes precedence over those of a regular expression
+        check(
+            doc! {
+                "a": {
+                    "$regex": mongodb::bson::Regex { pattern: "^ab".into(), options: "i".into() },
+                    "$options": "",
+                }
+            },
+            doc! { "a": "ab" },
+            doc! { "a": "AB" },
+        );
+    }
+
+    #[test]
+    fn multiline_regex() {
+        check(
+            doc! { "a": { "$regex": "^b$", "$options": "m" } },
+            doc! { "a": "a\nb\nc" },
+            doc! { "a": "a\nbc" },
+        );
+        check(
+            doc! { "a": { "$regex": "a.b", "$options": "s" } },
+            doc! { "a": "a\nb" },
+            doc! { "a": "a\n\nb" },
+        );
+        check(
+            doc! { "a": { "$regex": "a b # comment", "$options": "x" } },
+            doc! { "a": "ab" },
+            doc! { "a": "a b" },
+        );
+    }
+
+    #[test]
+    fn invalid_regex_options() {
+        let err = Matcher::new(&doc! { "a": { "$regex": "a", "$options": "q" } }).unwrap_err();
+        assert!(matches!(err, MatcherError::InvalidOperand("$options")));
+
+        let err = Matcher::new(&doc! { "a": { "$options": "i" } }).unwrap_err();
+        assert!(matches!(err, MatcherError::InvalidOperand("$options")));
+    }
+
+    #[test]
+    fn invalid_regex_pattern() {
+        let err = Matcher::new(&doc! { "a": { "$regex": "(a" } }).unwrap_err();
+        assert!(matches!(err, MatcherError::InvalidRegex(_)));
+    }
+
+    #[test]
+    fn not() {
+        check(
+            doc! { "a": { "$not": { "$gt": 1 } } },
+            doc! { "a": 1 },
+            doc! { "a": 2 },
+        );
+        check(
+            doc! { "a": { "$not": { "$gt": 1 } } },
+            doc! {},
+            doc! { "a": [0, 2] },
+        );
+        check(
+            doc! { "a": { "$not": { "$regex": "^x" } } },
+            doc! { "a": "yx" },
+            doc! { "a": "xy" },
+        );
+    }
+
+    #[test]
+    fn and() {
+        check(
+            doc! { "$and": [{ "a": 1 }, { "b": 2 }] },
+            doc! { "a": 1, "b": 2 },
+            doc! { "a": 1, "b": 3 },
+        );
+        // Several fields are combined with `$and` as well
+        check(
+            doc! { "a": 1, "b": 2 },
+            doc! { "a": 1, "b": 2 },
+            doc! { "a": 1 },
+        );
+    }
+
+    #[test]
+    fn or() {
+        check(
+            doc! { "$or": [{ "a": 1 }, { "b": 2 }] },
+            doc! { "b": 2 },
+            doc! { "a": 2, "b": 1 },
+        );
+    }
+
+    #[test]
+    fn nor() {
+        check(
+            doc! { "$nor": [{ "a": 1 }, { "b": 2 }] },
+            doc! { "a": 2, "b": 1 },
+            doc! { "b": 2 },
+        );
+    }
+
+    #[test]
+    fn dot_paths() {
+        check(
+            doc! { "a.b": 1 },
+            doc! { "a": { "b": 1 } },
+            doc! { "a": { "b": 2 } },
+        );
+        check(
+            doc! { "a.b.c": 1 },
+            doc! { "a": { "b": { "c": 1 } } },
+            doc! { "a": 1 },
+        );
+        // Into the elements of an array
+        check(
+            doc! { "a.b": 1 },
+            doc! { "a": [{ "b": 2 }, { "b": 1 }] },
+            doc! { "a": [{ "b": 2 }, { "c": 1 }] },
+        );
+        // By index
+        check(
+            doc! { "a.1": 1 },
+            doc! { "a": [0, 1] },
+            doc! { "a": [1, 0] },
+        );
+        check(
+            doc! { "a.0.b": { "$gt": 1 } },
+            doc! { "a": [{ "b": 2 }] },
+            doc! { "a": [{ "b": 1 }, { "b": 2 }] },
+        );
+    }
+
+    #[test]
+    fn arrays() {
+        // The array itself or any of its elements
+        check(doc! { "a": 1 }, doc! { "a": [2, 1] }, doc! { "a": [2, 3] });
+        check(
+            doc! { "a": [1, 2] },
+            doc! { "a": [1, 2] },
+            doc! { "a": [2, 1] },
+        );
+        check(
+            doc! { "a": { "$gt": 2 } },
+            doc! { "a": [1, 3] },
+            doc! { "a": [1, 2] },
+        );
+    }
+
+    #[test]
+    fn null_matches_missing() {
+        check(doc! { "a": null }, doc! {}, doc! { "a": 1 });
+        check(doc! { "a": null }, doc! { "a": null }, doc! { "a": false });
+        check(
+            doc! { "a.b": null },
+            doc! { "a": { "c": 1 } },
+            doc! { "a": { "b": 1 } },
+        );
+        check(doc! { "a": { "$ne": null } }, doc! { "a": 1 }, doc! {});
+    }
+
+    fn decimal(number: &str) -> mongodb::bson::Decimal128 {
+        number.parse().unwrap()
+    }
+
+    #[test]
+    fn decimals_beyond_double_precision() {
+        // Both are the same double
+        let low = decimal("0.10000000000000000000000000000001");
+        let high = decimal("0.10000000000000000000000000000002");
+        check(
+            doc! { "a": { "$gt": low } },
+            doc! { "a": high },
+            doc! { "a": low },
+        );
+        check(
+            doc! { "a": { "$lt": high } },
+            doc! { "a": low },
+            doc! { "a": high },
+        );
+        check(
+            doc! { "a": { "$gte": high } },
+            doc! { "a": high },
+            doc! { "a": low },
+        );
+        check(doc! { "a": low }, doc! { "a": low }, doc! { "a": high });
+        check(
+            doc! { "a": { "$gt": 9_007_199_254_740_992_i64 } },
+            doc! { "a": decimal("9007199254740993") },
+            doc! { "a": decimal("9007199254740992") },
+        );
+        // With other numbers
+        check(
+            doc! { "a": decimal("1.50") },
+            doc! { "a": 1.5 },
+            doc! { "a": 1 },
+        );
+        check(
+            doc! { "a": { "$lt": decimal("2") } },
+            doc! { "a": 1 },
+            doc! { "a": 2_i64 },
+        );
+    }
+
+    #[test]
+    fn negative_decimals() {
+        check(
+            doc! { "a": { "$lt": decimal("-1.5") } },
+            doc! { "a": decimal("-1.50000000000000000000000000001") },
+            doc! { "a": decimal("-1.49999999999999999999999999999") },
+        );
+        check(
+            doc! { "a": { "$gt": decimal("-10") } },
+            doc! { "a": decimal("-9.99") },
+            doc! { "a": decimal("-100") },
+        );
+        check(
+            doc! { "a": { "$gte": decimal("-0.001") } },
+            doc! { "a": decimal("0") },
+            doc! { "a": -1 },
+        );
+        check(
+            doc! { "a": decimal("-2E+1") },
+            doc! { "a": -20 },
+            doc! { "a": 20 },
+        );
+    }
+
+    #[test]
+    fn date_boundaries() {
+        let boundary = DateTime::from_millis(1_700_000_000_000);
+        let before = DateTime::from_millis(1_699_999_999_999);
+        let after = DateTime::from_millis(1_700_000_000_001);
+
+        check(
+            doc! { "a": { "$gte": boundary } },
+            doc! { "a": boundary },
+            doc! { "a": before },
+        );
+        check(
+            doc! { "a": { "$lt": boundary } },
+            doc! { "a": before },
+            doc! { "a": boundary },
+        );
+        check(
+            doc! { "a": { "$gte": boundary, "$lt": after } },
+            doc! { "a": boundary },
+            doc! { "a": after },
+        );
+        // Dates before 1970 are represented as `$numberLong`
+        let old = DateTime::from_millis(-1);
+        check(
+            doc! { "a": { "$lt": boundary } },
+            doc! { "a": old },
+            doc! { "a": after },
+        );
+        check(
+            doc! { "a": { "$gte": old } },
+            doc! { "a": old },
+            doc! { "a": DateTime::from_millis(-2) },
+        );
+    }
+
+    #[test]
+    fn date_encodings() {
+        let matcher = Matcher::new(&doc! {
+            "a": { "$gte": DateTime::from_millis(1_700_000_000_000) }
+        })
+        .unwrap();
+        let at = |date: Value| matcher.matches_value(&json!({ "a": { "$date": date } }));
+
+        assert!(at(json!("2023-11-14T22:13:20Z")));
+        assert!(!at(json!("2023-11-14T22:13:19.999Z")));
+        assert!(at(json!({ "$numberLong": "1700000000000" })));
+        assert!(!at(json!({ "$numberLong": "1699999999999" })));
+        assert!(at(json!(1_700_000_000_000_i64)));
+        assert!(!at(json!(1_699_999_999_999_i64)));
+
+        // The filter can be in either encoding as well
+        let matcher =
+            Matcher::new(&doc! { "a": { "$lt": { "$date": { "$numberLong": "1700000000000" } } } })
+                .unwrap();
+        assert!(matcher.matches(&doc! { "a": DateTime::from_millis(1_699_999_999_999) }));
+        assert!(!matcher.matches(&doc! { "a": DateTime::from_millis(1_700_000_000_000) }));
+    }
+
+    #[test]
+    fn invalid_filters() {
+        assert!(Matcher::new(&doc! { "a": { "$foo": 1 } }).is_err());
+        assert!(Matcher::new(&doc! { "$foo": [] }).is_err());
+        assert!(Matcher::new(&doc! { "a": { "$in": 1 } }).is_err());
+        assert!(Matcher::new(&doc! { "$or": { "a": 1 } }).is_err());
+        assert!(Matcher::new(&doc! { "a": { "$exists": "yes" } }).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+//! The events of several subscriptions, e.g. on different collections, merged in the order in
+//! which their changes were committed, see [`merge_ordered`].
+
+use std::{
+    collections::VecDeque,
+    fmt::Debug,
+    future::poll_fn,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use tokio::time::{Duration, Instant};
+
+use crate::{
+    clock::{Clock, Sleep, TokioClock},
+    receiver::EventReceiver,
+    subscription::{Event, EventMeta},
+};
+
+/// Merges the events of `receivers` in the order of the cluster time of their changes, see
+/// [`EventMeta::cluster_time`]. Each receiver is identified by its key, e.g. the name of its
+/// collection, which is returned with every event.
+///
+/// The change streams of different collections are read independently, so a change can arrive
+/// after a later change of another collection. An event is therefore held back until every
+/// receiver has delivered an event after it, which proves that none of them has an earlier
+/// change still to come, or until the event that has been waiting longest has waited for
+/// `window`. A receiver that sees few changes thus delays the others by up to `window`, and
+/// changes that arrive more than `window` late are delivered out of order. A longer window
+/// orders more reliably at the cost of latency.
+///
+/// The events of each receiver stay in the order they were sent. Events that don't stem from a
+/// change, like [`Event::Established`], are passed on once the events before them have been.
+/// The window is measured with the clock of the instance the receivers belong to, see
+/// [`Mercurius::clock`](crate::Mercurius::clock).
+pub fn merge_ordered<K>(
+    receivers: impl IntoIterator<Item = (K, EventReceiver)>,
+    window: Duration,
+) -> OrderedReceiver<K> {
+    let sources: Vec<_> = receivers
+        .into_iter()
+        .map(|(key, receiver)| Source {
+            key,
+            receiver,
+            buffered: VecDeque::new(),
+            ended: false,
+        })
+        .collect();
+    let clock = match sources.first() {
+        Some(source) => source.receiver.clock(),
+        None => Arc::new(TokioClock),
+    };
+
+    OrderedReceiver {
+        sources,
+        window,
+        clock,
+        sleep: None,
+    }
+}
+
+/// Receives the events of several subscriptions in the order of their changes, see
+/// [`merge_ordered`].
+pub struct OrderedReceiver<K> {
+    sources: Vec<Source<K>>,
+    window: Duration,
+    clock: Arc<dyn Clock>,
+    /// Wakes the receiver once the event that has been waiting longest is due, at the given time.
+    sleep: Option<(Instant, Sleep)>,
+}
+
+impl<K: Debug> Debug for OrderedReceiver<K> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OrderedReceiver")
+            .field("sources", &self.sources)
+            .field("window", &self.window)
+            .field("clock", &self.clock)
+            .finish_non_exhaustive()
+    }
+}
+
+#[derive(Debug)]
+struct Source<K> {
+    key: K,
+    receiver: EventReceiver,
+    buffered: VecDeque<Buffered>,
+    /// Whether the receiver has returned `None`.
+    ended: bool,
+}
+
+#[derive(Debug)]
+struct Buffered {
+    event: Event,
+    meta: EventMeta,
+    arrived: Instant,
+}
+
+impl<K: Clone> OrderedReceiver<K> {
+    /// Waits for the next event. Returns `None` once all subscriptions have been removed and all
+    /// buffered events have been received.
+    pub async fn recv(&mut self) -> Option<(K, Event)> {
+        self.recv_with_meta()
+            .await
+            .map(|(key, event, _)| (key, event))
+    }
+
+    /// Like [`OrderedReceiver::recv`], along with the metadata of the event.
+    pub async fn recv_with_meta(&mut self) -> Option<(K, Event, EventMeta)> {
+        poll_fn(|cx| self.poll_recv_with_meta(cx)).await
+    }
+
+    fn poll_recv_with_meta(&mut self, cx: &mut Context<'_>) -> Poll<Option<(K, Event, EventMeta)>> {
+        for source in &mut self.sources {
+            while !source.ended {
+                match source.receiver.poll_recv_with_meta(cx) {
+                    Poll::Ready(Some((event, meta))) => source.buffered.push_back(Buffered {
+                        event,
+                        meta,
+                        arrived: self.clock.now(),
+                    }),
+                    Poll::Ready(None) => source.ended = true,
+                    Poll::Pending => break,
+                }
+            }
+        }
+
+        let Some(next) = self.next_source() else {
+            return match self.sources.iter().all(|source| source.ended) {
+                true => Poll::Ready(None),
+                false => Poll::Pending,
+            };
+        };
+
+        // Every receiver has either ended or delivered an event from after the next one
+        let complete = self
+            .sources
+            .iter()
+            .all(|source| source.ended || !source.buffered.is_empty());
+        let due = self
+            .sources
+            .iter()
+            .filter_map(|source| source.buffered.front())
+            .map(|buffered| buffered.arrived + self.window)
+            .min();
+        let released = complete || next.1 || due.is_some_and(|due| due <= self.clock.now());
+
+        if !released {
+            // Polled once the event that has been waiting longest is due
+            let due = due.unwrap();
+            let sleep = match &mut self.sleep {
+                Some((until, sleep)) if *until == due => sleep,
+                sleep => {
+                    let duration = due.saturating_duration_since(self.clock.now());
+                    &mut sleep.insert((due, self.clock.sleep(duration))).1
+                }
+            };
+            if sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+
+        self.sleep = None;
+        let source = &mut self.sources[next.0];
+        let buffered = source.buffered.pop_front().unwrap();
+        Poll::Ready(Some((source.key.clone(), buffered.event, buffered.meta)))
+    }
+
+    /// The index of the source whose first buffered event is next, and whether it can be passed
+    /// on right away because it doesn't stem from a change.
+    fn next_source(&self) -> Option<(usize, bool)> {
+        let fronts = self
+            .sources
+            .iter()
+            .enumerate()
+            .filter_map(|(index, source)| Some((index, source.buffered.front()?)));
+
+        let mut next = None;
+        for (index, buffered) in fronts {
+            match (buffered.meta.cluster_time, next) {
+                (None, _) => return Some((index, true)),
+                (Some(time), Some((_, earliest))) if time >= earliest => {}
+                (Some(time), _) => next = Some((index, time)),
+            }
+        }
+
+        next.map(|(index, _)| (index, false))
+    }
+}
+
+impl<K: Clone + Unpin> Stream for OrderedReceiver<K> {
+    type Item = (K, Event);
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_recv_with_meta(cx)
+            .map(|next| next.map(|(key, event, _)| (key, event)))
+    }
+}
+
+#[cfg(all(test, feature = "test-util"))]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::FutureExt;
+    use mongodb::bson::Timestamp;
+    use tokio::time::Duration;
+
+    use super::merge_ordered;
+    use crate::{
+        channel::{channel, ChannelConfig, EventSender, Sequence},
+        clock::TestClock,
+        receiver::EventReceiver,
+        subscription::Event,
+    };
+
+    fn receiver(clock: &TestClock) -> (EventSender, Sequence, EventReceiver) {
+        let (sender, receiver) = channel(ChannelConfig {
+            clock: Arc::new(clock.clone()),
+            ..ChannelConfig::default()
+        });
+        let sequence = Sequence::default();
+        sender.attach(sequence.clone());
+        (sender, sequence, receiver)
+    }
+
+    fn send(sender: &EventSender, sequence: &Sequence, time: u32) {
+        sequence.advance(Some(Timestamp { time, increment: 0 }), None);
+        sender.send(Event::Count(time.into())).unwrap();
+        sequence.finish();
+    }
+
+    #[tokio::test]
+    async fn window_is_measured_with_the_clock() {
+        let clock = TestClock::new();
+        let (a, a_sequence, a_receiver) = receiver(&clock);
+        let (_b, _, b_receiver) = receiver(&clock);
+        let mut merged = merge_ordered(
+            [("a", a_receiver), ("b", b_receiver)],
+            Duration::from_secs(5),
+        );
+
+        // `b` might still deliver an earlier change until the window has passed
+        send(&a, &a_sequence, 2);
+        assert!(merged.recv().now_or_never().is_none());
+        clock.advance(Duration::from_millis(4_999));
+        assert!(merged.recv().now_or_never().is_none());
+
+        clock.advance(Duration::from_millis(1));
+        assert_eq!(
+            merged.recv().now_or_never(),
+            Some(Some(("a", Event::Count(2))))
+        );
+    }
+
+    #[tokio::test]
+    async fn events_are_ordered_within_the_window() {
+        let clock = TestClock::new();
+        let (a, a_sequence, a_receiver) = receiver(&clock);
+        let (b, b_sequence, b_receiver) = receiver(&clock);
+        let mut merged = merge_ordered(
+            [("a", a_receiver), ("b", b_receiver)],
+            Duration::from_secs(5),
+        );
+
+        send(&a, &a_sequence, 2);
+        clock.advance(Duration::from_secs(1));
+        send(&b, &b_sequence, 1);
+        send(&b, &b_sequence, 3);
+
+        assert_eq!(merged.recv().await, Some(("b", Event::Count(1))));
+        assert_eq!(merged.recv().await, Some(("a", Event::Count(2))));
+        // Only `b` has events left, which can't be preceded by one of `a` before the window ends
+        assert!(merged.recv().now_or_never().is_none());
+        clock.advance(Duration::from_secs(4));
+        assert!(merged.recv().now_or_never().is_none());
+        clock.advance(Duration::from_secs(1));
+        assert_eq!(
+            merged.recv().now_or_never(),
+            Some(Some(("b", Event::Count(3))))
+        );
+    }
+}
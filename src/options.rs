@@ -0,0 +1,728 @@
+use std::time::Duration;
+
+use mongodb::{
+    bson::{doc, Document},
+    change_stream::event::{OperationType, ResumeToken},
+    options::{Collation, FullDocumentType, ReadConcern, SelectionCriteria},
+};
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    channel::{GracePeriod, Overflow},
+    projection::Projection,
+    subscription::{DeltaPredicate, OnTeardown, Predicate, TeardownReason},
+};
+
+/// Options used when subscribing to a collection.
+///
+/// Mercurius opens a single change stream per collection, so options that configure the change
+/// stream itself only take effect for the subscription that causes the stream to be opened.
+/// Subsequent subscriptions on the same collection share the existing stream.
+///
+/// ## Resume tokens
+///
+/// Change streams only ever return changes that have been majority-committed, so a resume token
+/// is always durable, no matter which node it was read from. Choosing a read preference that
+/// targets secondaries only affects latency: a secondary may lag behind the primary, but resuming
+/// from one of its tokens on any other node of the replica set is safe.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionOptions {
+    pub(crate) read_concern: Option<ReadConcern>,
+    pub(crate) selection_criteria: Option<SelectionCriteria>,
+    pub(crate) cancellation_token: Option<CancellationToken>,
+    pub(crate) on_teardown: Option<OnTeardown>,
+    pub(crate) max_await_time: Option<Duration>,
+    pub(crate) predicate: Option<Predicate>,
+    pub(crate) delta_predicate: Option<DeltaPredicate>,
+    pub(crate) resume_after: Option<ResumeToken>,
+    pub(crate) start_after: Option<ResumeToken>,
+    pub(crate) restart_on_invalidate: bool,
+    pub(crate) catch_up: Option<CatchUp>,
+    pub(crate) persistent: bool,
+    pub(crate) owned_documents: bool,
+    pub(crate) prime: bool,
+    pub(crate) initial_count: bool,
+    pub(crate) capacity: Option<usize>,
+    pub(crate) overflow: Overflow,
+    pub(crate) latest_only: bool,
+    #[cfg(feature = "compression")]
+    pub(crate) compress_backlog: bool,
+    pub(crate) grace_period: Option<GracePeriod>,
+    pub(crate) missing_key: MissingKey,
+    pub(crate) group_transactions: bool,
+    pub(crate) pipeline: Vec<Document>,
+    pub(crate) projection: Option<Projection>,
+    pub(crate) failover_timeout: Option<Duration>,
+    pub(crate) on_token_expiry: Option<OnTokenExpiry>,
+    pub(crate) take: Option<usize>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) collation: Option<Collation>,
+    #[cfg(feature = "bson-matcher")]
+    pub(crate) matcher: Option<MatcherBackend>,
+    pub(crate) operations: Option<Vec<OperationType>>,
+    pub(crate) full_document: Option<FullDocumentType>,
+    pub(crate) replay: Option<usize>,
+    pub(crate) extended_json: bool,
+    pub(crate) revert_pre_and_post_images: bool,
+    pub(crate) await_creation: bool,
+    pub(crate) split_large_events: bool,
+    pub(crate) boundary_events: bool,
+    pub(crate) raw_transitions: bool,
+    pub(crate) set_membership: bool,
+    pub(crate) batch_size: Option<u32>,
+    pub(crate) batch_boundaries: bool,
+    pub(crate) raw_events: bool,
+    pub(crate) show_expanded_events: bool,
+}
+
+/// Paces reading the backlog of a resumed change stream: after every `batch_size` events the
+/// stream waits for `interval` before reading on, until it has caught up.
+#[derive(Debug, Clone)]
+pub struct CatchUp {
+    pub batch_size: usize,
+    pub interval: Duration,
+}
+
+/// What happens to a change event that lacks a document key.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MissingKey {
+    /// The event is skipped and reported as
+    /// [`EventError::MissingDocumentKey`](crate::error::EventError::MissingDocumentKey).
+    #[default]
+    Skip,
+    /// The key is taken from the `_id` of the new or else the old version of the document; the
+    /// event is only skipped if neither is available.
+    FromDocument,
+}
+
+/// What happens when a change stream can't be resumed because its resume token has fallen off
+/// the oplog, see [`SubscriptionOptions::on_token_expiry`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnTokenExpiry {
+    /// The change stream ends.
+    #[default]
+    Fail,
+    /// The change stream is reopened at the current time and the subscriptions receive
+    /// [`Event::GapDetected`](crate::subscription::Event::GapDetected); the changes in between
+    /// are lost.
+    RestartFromNow,
+    /// Like [`OnTokenExpiry::RestartFromNow`], but [`Event::GapDetected`](crate::subscription::Event::GapDetected)
+    /// is followed by an [`Event::Added`](crate::subscription::Event::Added) for every document
+    /// that currently matches, like with [`SubscriptionOptions::prime`].
+    RestartFromNowWithSnapshot,
+}
+
+/// What evaluates the filter of a subscription, see [`SubscriptionOptions::matcher`].
+#[cfg(feature = "bson-matcher")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatcherBackend {
+    /// The [default matcher](crate::matcher), which evaluates filters on the relaxed extended
+    /// JSON of the documents.
+    #[default]
+    Json,
+    /// The [BSON matcher](crate::bson_matcher), which evaluates filters on the documents
+    /// themselves and supports more of MongoDB's query language, e.g. `$elemMatch`.
+    Bson,
+}
+
+/// What happens when the pre- and post-images of a watched collection turn out to have been
+/// turned off by someone else, see
+/// [`Mercurius::verify_pre_images`](crate::Mercurius::verify_pre_images).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PreImagesDisabled {
+    /// The subscriptions receive
+    /// [`Event::ConfigurationChanged`](crate::subscription::Event::ConfigurationChanged); the
+    /// images stay off.
+    #[default]
+    Notify,
+    /// The images are turned on again before the subscriptions are notified.
+    Reenable,
+}
+
+impl SubscriptionOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The read concern of the change stream. Defaults to the read concern of the database.
+    pub fn read_concern(mut self, read_concern: impl Into<Option<ReadConcern>>) -> Self {
+        self.read_concern = read_concern.into();
+        self
+    }
+
+    /// Which nodes the change stream reads from, e.g. `secondaryPreferred`. Defaults to the
+    /// selection criteria of the database.
+    pub fn selection_criteria(
+        mut self,
+        selection_criteria: impl Into<Option<SelectionCriteria>>,
+    ) -> Self {
+        self.selection_criteria = selection_criteria.into();
+        self
+    }
+
+    /// Removes the subscription once `token` is cancelled.
+    pub fn cancellation_token(mut self, token: impl Into<Option<CancellationToken>>) -> Self {
+        self.cancellation_token = token.into();
+        self
+    }
+
+    /// How long the server waits for new changes before answering an empty batch. Lower values
+    /// deliver events with less latency at the cost of more round trips. Defaults to the server
+    /// default.
+    pub fn max_await_time(mut self, max_await_time: impl Into<Option<Duration>>) -> Self {
+        self.max_await_time = max_await_time.into();
+        self
+    }
+
+    /// Only delivers changes to documents for which `predicate` returns `true`, in addition to
+    /// the filter. The predicate runs client-side on every change of the collection.
+    pub fn predicate(mut self, predicate: impl Into<Option<Predicate>>) -> Self {
+        self.predicate = predicate.into();
+        self
+    }
+
+    /// Only delivers updates of matching documents as
+    /// [`Event::Updated`](crate::subscription::Event::Updated) if `predicate` returns `true` for
+    /// their change, in addition to the filter and the predicate. Other operations are unaffected,
+    /// and so is an update by which a document starts or stops matching, which is still delivered
+    /// as [`Event::Added`](crate::subscription::Event::Added) or
+    /// [`Event::Removed`](crate::subscription::Event::Removed).
+    pub fn delta_predicate(mut self, predicate: impl Into<Option<DeltaPredicate>>) -> Self {
+        self.delta_predicate = predicate.into();
+        self
+    }
+
+    /// Resumes the change stream after the change identified by `token`, instead of starting at
+    /// the current time. Once the stream has delivered all changes that happened before it was
+    /// opened, [`Event::CaughtUp`](crate::subscription::Event::CaughtUp) is sent.
+    ///
+    /// Resuming fails if the stream was invalidated after `token`, e.g. because the collection
+    /// was dropped; use [`SubscriptionOptions::start_after`] to resume across invalidations.
+    /// Overrides `start_after`.
+    pub fn resume_after(mut self, token: impl Into<Option<ResumeToken>>) -> Self {
+        self.resume_after = token.into();
+        self.start_after = None;
+        self
+    }
+
+    /// Like [`SubscriptionOptions::resume_after`], but `token` may also be the token of an
+    /// invalidate event, so the stream can pick up changes of a collection that was dropped and
+    /// recreated or renamed. Overrides `resume_after`.
+    pub fn start_after(mut self, token: impl Into<Option<ResumeToken>>) -> Self {
+        self.start_after = token.into();
+        self.resume_after = None;
+        self
+    }
+
+    /// Instead of ending the change stream when it's invalidated (the collection was dropped or
+    /// renamed), open a new one that starts after the invalidation and send
+    /// [`Event::Reset`](crate::subscription::Event::Reset) instead of
+    /// [`Event::Drop`](crate::subscription::Event::Drop).
+    pub fn restart_on_invalidate(mut self, restart: bool) -> Self {
+        self.restart_on_invalidate = restart;
+        self
+    }
+
+    /// Throttles the delivery of the backlog when resuming a change stream that is far behind,
+    /// so that the consumer is not overwhelmed before it has caught up.
+    pub fn catch_up(mut self, catch_up: impl Into<Option<CatchUp>>) -> Self {
+        self.catch_up = catch_up.into();
+        self
+    }
+
+    /// Stores the definition of the subscription in the instance's
+    /// [`PersistentStore`](crate::persistence::PersistentStore), if it has one. The predicate and
+    /// the other options are not stored.
+    pub fn persistent(mut self, persistent: bool) -> Self {
+        self.persistent = persistent;
+        self
+    }
+
+    /// Delivers documents as [`EventDocument::Owned`](crate::subscription::EventDocument::Owned)
+    /// instead of sharing them between the subscriptions of the collection. The document is moved
+    /// into the event of the last subscription that receives it and cloned for all others, so
+    /// single-consumer collections never copy it. Like the other stream options, this applies to
+    /// every subscription on the collection.
+    pub fn owned_documents(mut self, owned: bool) -> Self {
+        self.owned_documents = owned;
+        self
+    }
+
+    /// Starts the subscription with an [`Event::Added`](crate::subscription::Event::Added) for
+    /// every document that currently matches it, before any change is delivered. Subscribing
+    /// returns once all of them have been read, so with a
+    /// [`capacity`](SubscriptionOptions::capacity) documents beyond it overflow.
+    pub fn prime(mut self, prime: bool) -> Self {
+        self.prime = prime;
+        self
+    }
+
+    /// Starts the subscription with an [`Event::Count`](crate::subscription::Event::Count) of the
+    /// documents that currently match it, before any change is delivered, e.g. to keep a live
+    /// counter with the [`Event::Added`](crate::subscription::Event::Added) and
+    /// [`Event::Removed`](crate::subscription::Event::Removed) that follow. The documents are
+    /// counted at the cluster time of [`Event::Established`](crate::subscription::Event::Established)
+    /// with a snapshot read, so every change is either part of the count or delivered after it,
+    /// never both or neither; this requires MongoDB 5.0, and the stream may not be more than
+    /// `minSnapshotHistoryWindowInSeconds` (5 minutes by default) behind. Without a cluster
+    /// time the count is taken at the current time instead. Subscribing returns once the
+    /// documents have been counted. With [`SubscriptionOptions::prime`], the count comes first.
+    pub fn initial_count(mut self, initial_count: bool) -> Self {
+        self.initial_count = initial_count;
+        self
+    }
+
+    /// Limits the channel of the subscription to `capacity` events. Events that don't fit are
+    /// handled according to `overflow`. Only with [`Overflow::Block`] are they waited for, which
+    /// lets a slow consumer hold up the other subscriptions on the collection; with the other
+    /// policies it can't. Defaults to an unbounded channel.
+    pub fn capacity(mut self, capacity: impl Into<Option<usize>>, overflow: Overflow) -> Self {
+        self.capacity = capacity.into();
+        self.overflow = overflow;
+        self
+    }
+
+    /// Keeps only the latest state of each document while the consumer is behind, rather than
+    /// every event: an event about a document that is still waiting to be received replaces the
+    /// earlier one, so a burst of updates to a document is received as a single event once the
+    /// consumer gets to it. Consecutive updates are merged into one, and a document that hasn't
+    /// been received yet stays [`Event::Added`](crate::subscription::Event::Added) when it's
+    /// replaced; an update that can't be merged, e.g. because it truncates an array, follows the
+    /// pending one. Events that aren't about a document, like
+    /// [`Event::Reset`](crate::subscription::Event::Reset), are received in order with the rest,
+    /// and no event is moved before them. Events are coalesced rather than dropped, so this takes
+    /// the place of [`SubscriptionOptions::capacity`]; the buffer grows with the number of
+    /// documents that have pending events, rather than with the number of events.
+    pub fn latest_only(mut self, latest_only: bool) -> Self {
+        self.latest_only = latest_only;
+        self
+    }
+
+    /// Compresses the documents of the events that wait in the channel while the consumer is
+    /// behind, and decompresses them as they're received, trading CPU for memory during a
+    /// backlog. An event that is received right away isn't compressed. Documents that are shared
+    /// with other subscriptions are copied before they're compressed, and are received as
+    /// [`EventDocument::Owned`](crate::subscription::EventDocument::Owned). Has no effect with
+    /// [`SubscriptionOptions::latest_only`], whose buffer holds one event per document.
+    #[cfg(feature = "compression")]
+    pub fn compress_backlog(mut self, compress_backlog: bool) -> Self {
+        self.compress_backlog = compress_backlog;
+        self
+    }
+
+    /// Keeps the subscription for `period` once its receiver has been dropped, e.g. while a web
+    /// client reconnects, rather than removing it with the next event. Meanwhile up to
+    /// `capacity` events are held, starting with the ones that the receiver hadn't received
+    /// yet, and any further ones are dropped like with [`Overflow::Drop`].
+    /// [`Mercurius::reattach`](crate::Mercurius::reattach) hands them to a new receiver, which
+    /// then receives the subscription's events from where the old one left off. Once `period`
+    /// has elapsed, the subscription is removed with its next event, and the held events are
+    /// lost. Defaults to no grace period.
+    pub fn grace_period(mut self, period: impl Into<Option<Duration>>, capacity: usize) -> Self {
+        self.grace_period = period.into().map(|period| GracePeriod { period, capacity });
+        self
+    }
+
+    /// How change events without a document key are handled. Defaults to [`MissingKey::Skip`].
+    pub fn missing_key(mut self, missing_key: MissingKey) -> Self {
+        self.missing_key = missing_key;
+        self
+    }
+
+    /// Delivers the changes of a multi-document transaction together as one
+    /// [`Event::Transaction`](crate::subscription::Event::Transaction) per subscription, instead
+    /// of one event per change. The events of a transaction are held back until an event outside
+    /// of it is read or the server has no more events to return, so a consumer never observes a
+    /// partially applied transaction.
+    pub fn group_transactions(mut self, group: bool) -> Self {
+        self.group_transactions = group;
+        self
+    }
+
+    /// Aggregation stages that the change stream applies to its events before they are
+    /// dispatched, e.g. a `$match` on `operationType`. Mercurius needs the fields of the change
+    /// events, so stages that remove or reshape them break dispatching; and the stages affect
+    /// every subscription on the collection, including their filters.
+    pub fn pipeline(mut self, pipeline: impl IntoIterator<Item = Document>) -> Self {
+        self.pipeline = pipeline.into_iter().collect();
+        self
+    }
+
+    /// Reports documents that start or stop matching the filter because of an update or
+    /// replacement as [`Event::Entered`](crate::subscription::Event::Entered) and
+    /// [`Event::Left`](crate::subscription::Event::Left), rather than as
+    /// [`Event::Added`](crate::subscription::Event::Added) and
+    /// [`Event::Removed`](crate::subscription::Event::Removed), which are then only sent for
+    /// inserts and deletes. For consumers that keep a map of the matching documents both are the
+    /// same; this distinguishes new documents from those that came into view.
+    pub fn boundary_events(mut self, boundary_events: bool) -> Self {
+        self.boundary_events = boundary_events;
+        self
+    }
+
+    /// Reports every update and replacement for which either version of the document matches the
+    /// filter as an [`Event::Transition`](crate::subscription::Event::Transition), with both
+    /// versions and whether each of them matches, so that the consumer can decide what it means
+    /// for its view. Inserts and deletes are still reported as
+    /// [`Event::Added`](crate::subscription::Event::Added) and
+    /// [`Event::Removed`](crate::subscription::Event::Removed). Takes precedence over
+    /// [`SubscriptionOptions::boundary_events`].
+    pub fn raw_transitions(mut self, raw_transitions: bool) -> Self {
+        self.raw_transitions = raw_transitions;
+        self
+    }
+
+    /// Only reports which documents match the filter, e.g. to keep a set of their keys: a
+    /// document that starts matching, by an insert, update or replacement, is delivered as an
+    /// [`Event::Added`](crate::subscription::Event::Added) that only holds its `_id`, and one that
+    /// stops matching as an [`Event::Removed`](crate::subscription::Event::Removed). Changes of
+    /// documents that keep matching aren't delivered. Takes precedence over
+    /// [`SubscriptionOptions::fields`], [`SubscriptionOptions::boundary_events`] and
+    /// [`SubscriptionOptions::raw_transitions`].
+    pub fn set_membership(mut self, set_membership: bool) -> Self {
+        self.set_membership = set_membership;
+        self
+    }
+
+    /// The maximum number of changes per batch that the change stream reads from the server.
+    /// Larger batches take fewer round trips for streams with many changes. Defaults to the server
+    /// default.
+    pub fn batch_size(mut self, batch_size: impl Into<Option<u32>>) -> Self {
+        self.batch_size = batch_size.into();
+        self
+    }
+
+    /// Sends [`Event::BatchBoundary`](crate::subscription::Event::BatchBoundary) once all changes
+    /// of a batch have been dispatched, e.g. to checkpoint once per batch rather than per event;
+    /// see [`SubscriptionOptions::batch_size`].
+    pub fn batch_boundaries(mut self, batch_boundaries: bool) -> Self {
+        self.batch_boundaries = batch_boundaries;
+        self
+    }
+
+    /// Wraps every change of a document in an [`Event::Raw`](crate::subscription::Event::Raw),
+    /// along with the change stream event it stems from, e.g. to re-emit the change to another
+    /// system with its resume token, cluster time and namespace. The change stream event is
+    /// copied once per change for all subscriptions with this option. Changes that are replayed,
+    /// see [`SubscriptionOptions::replay`], are delivered without it.
+    pub fn raw_events(mut self, raw_events: bool) -> Self {
+        self.raw_events = raw_events;
+        self
+    }
+
+    /// Opens the change stream with `showExpandedEvents`, so that the server also reports changes
+    /// to the collection itself, e.g. created or dropped indexes, as
+    /// [`Event::Ddl`](crate::subscription::Event::Ddl); requires MongoDB 6.0. The driver can't
+    /// request them, so the stream is opened as an aggregation with its own `$changeStream`
+    /// stage instead. Such a stream only learns where the server has looked for changes from the
+    /// changes themselves: it doesn't resume from after a quiet period, and a resumed stream only
+    /// reports that it has caught up at its first change after the backlog. Neither does it see
+    /// that the server has no more changes to return, so it can't be combined with
+    /// [`SubscriptionOptions::group_transactions`] or [`SubscriptionOptions::batch_boundaries`],
+    /// and [`Mercurius::flush`](crate::Mercurius::flush) only returns once the collection has
+    /// changed after the call. This applies to every subscription on the collection while the
+    /// stream was opened with this option.
+    pub fn show_expanded_events(mut self, show: bool) -> Self {
+        self.show_expanded_events = show;
+        self
+    }
+
+    /// Lets the server split change events that exceed the maximum document size of 16 MB, e.g.
+    /// because of large pre- and post-images, instead of failing the change stream. Mercurius
+    /// joins the fragments back into one event before dispatching it. Appends a
+    /// `$changeStreamSplitLargeEvent` stage to the [`SubscriptionOptions::pipeline`], which
+    /// requires MongoDB 7.0 or 6.0.9.
+    pub fn split_large_events(mut self, split: bool) -> Self {
+        self.split_large_events = split;
+        self
+    }
+
+    /// The first two options that are set but can't be combined, see
+    /// [`MercuriusError::ConflictingOptions`](crate::error::MercuriusError::ConflictingOptions).
+    pub(crate) fn conflict(&self) -> Option<(&'static str, &'static str)> {
+        // Both rely on the empty batches that an expanded stream doesn't report
+        if self.show_expanded_events {
+            if self.group_transactions {
+                return Some(("show_expanded_events", "group_transactions"));
+            }
+            if self.batch_boundaries {
+                return Some(("show_expanded_events", "batch_boundaries"));
+            }
+        }
+        None
+    }
+
+    /// The pipeline that the change stream is opened with.
+    pub(crate) fn stream_pipeline(&self) -> Vec<Document> {
+        let mut pipeline = self.pipeline.clone();
+        // It has to be the last stage
+        if self.split_large_events {
+            pipeline.push(doc! { "$changeStreamSplitLargeEvent": {} });
+        }
+        pipeline
+    }
+
+    /// Only delivers the given fields (dot-separated paths) of documents, plus `_id`. Updates
+    /// that don't change any of them are skipped. The filter and the predicate still see the full
+    /// documents. See [`Mercurius::watch_fields`](crate::Mercurius::watch_fields) to also keep the
+    /// server from sending the other fields.
+    pub fn fields(mut self, fields: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.projection = Some(Projection::new(fields));
+        self
+    }
+
+    /// When the change stream fails because the primary has stepped down or is unreachable, e.g.
+    /// during an election, keep trying to resume it from the last resume token for up to
+    /// `timeout`, instead of ending it. The subscriptions receive no event for this; they
+    /// continue with the next change once a new primary has been elected.
+    pub fn resume_on_failover(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.failover_timeout = timeout.into();
+        self
+    }
+
+    /// How to go on when the change stream can't be resumed because its position is no longer in
+    /// the oplog (`ChangeStreamHistoryLost`), be it the token of
+    /// [`SubscriptionOptions::resume_after`] or the last token of a stream that is resumed after
+    /// an error. Defaults to [`OnTokenExpiry::Fail`].
+    pub fn on_token_expiry(mut self, policy: OnTokenExpiry) -> Self {
+        self.on_token_expiry = Some(policy);
+        self
+    }
+
+    /// Calls `on_teardown` once the subscription ends, with the reason why, e.g. to clean up state
+    /// that belongs to it: when it's removed, its receiver has been dropped, it has received
+    /// [`Event::Drop`](crate::subscription::Event::Drop) or it has been completed, or when the
+    /// instance is shut down. It isn't called if the subscription can't be added. The callback is
+    /// shared by clones of the options, so only the first of their subscriptions to end calls it.
+    pub fn on_teardown(
+        mut self,
+        on_teardown: impl FnOnce(TeardownReason) + Send + 'static,
+    ) -> Self {
+        self.on_teardown = Some(OnTeardown::new(on_teardown));
+        self
+    }
+
+    /// Removes the subscription once it has delivered `count` changes that match its filter, i.e.
+    /// events other than [`Event::Established`](crate::subscription::Event::Established) and the
+    /// like, or than [`Event::PreImageUnavailable`](crate::subscription::Event::PreImageUnavailable)
+    /// and [`Event::Ddl`](crate::subscription::Event::Ddl), which are sent regardless of it. The last
+    /// change is followed by [`Event::Closed`](crate::subscription::Event::Closed), after which
+    /// the receiver returns `None`. The documents sent by [`SubscriptionOptions::prime`] are not
+    /// counted.
+    pub fn take(mut self, count: impl Into<Option<usize>>) -> Self {
+        self.take = count.into();
+        self
+    }
+
+    /// How long adding the subscription may take, including opening the change stream, priming
+    /// and storing it, before it fails with
+    /// [`MercuriusError::Timeout`](crate::error::MercuriusError::Timeout). The subscription and a
+    /// change stream that was opened for it are removed again. Defaults to no timeout, i.e. the
+    /// timeouts of the MongoDB client.
+    pub fn timeout(mut self, timeout: impl Into<Option<Duration>>) -> Self {
+        self.timeout = timeout.into();
+        self
+    }
+
+    /// Compares strings according to `collation`. It's passed on to the server for the
+    /// [`pipeline`](SubscriptionOptions::pipeline) of the change stream and for priming, while
+    /// the filter only honors its case sensitivity, see [`crate::matcher`]. A change stream that
+    /// is shared uses the collation of the subscription that opened it.
+    pub fn collation(mut self, collation: impl Into<Option<Collation>>) -> Self {
+        self.collation = collation.into();
+        self
+    }
+
+    /// Evaluates the filter with `backend` rather than with the matcher of the instance, see
+    /// [`Mercurius::matcher`](crate::Mercurius::matcher).
+    #[cfg(feature = "bson-matcher")]
+    pub fn matcher(mut self, backend: MatcherBackend) -> Self {
+        self.matcher = Some(backend);
+        self
+    }
+
+    /// Only delivers the changes of the given operations, i.e. of inserts, updates, replacements
+    /// and deletes. An update that isn't delivered is also not delivered as an
+    /// [`Event::Added`](crate::subscription::Event::Added) or
+    /// [`Event::Removed`](crate::subscription::Event::Removed) if the document starts or stops
+    /// matching. Defaults to all operations.
+    pub fn operations(mut self, operations: impl IntoIterator<Item = OperationType>) -> Self {
+        self.operations = Some(operations.into_iter().collect());
+        self
+    }
+
+    /// Which version of a document the change stream reports for updates. Mercurius matches
+    /// updates against it; [`FullDocumentType::WhenAvailable`] and [`FullDocumentType::Required`]
+    /// report the version right after the update rather than the current one.
+    ///
+    /// With [`FullDocumentType::WhenAvailable`], an update whose new version isn't available is
+    /// matched on the old version alone: a subscription that the old version matches receives it
+    /// as [`Event::Updated`](crate::subscription::Event::Updated), even if the document has
+    /// stopped matching, and the others don't receive it, even if it has started to. Defaults to
+    /// [`FullDocumentType::UpdateLookup`].
+    pub fn full_document(mut self, full_document: impl Into<Option<FullDocumentType>>) -> Self {
+        self.full_document = full_document.into();
+        self
+    }
+
+    /// Replays the last `count` changes of the collection that concern the subscription, i.e.
+    /// that it would have received, right after
+    /// [`Event::Established`](crate::subscription::Event::Established) and before the live events.
+    /// Lets a consumer that re-subscribes catch up on what it missed in the meantime. Only the
+    /// changes kept by [`Mercurius::replay_buffer`](crate::Mercurius::replay_buffer) can be
+    /// replayed; they are matched against the current filter, not the one of an earlier
+    /// subscription.
+    pub fn replay(mut self, count: impl Into<Option<usize>>) -> Self {
+        self.replay = count.into();
+        self
+    }
+
+    /// Delivers the changes of documents as
+    /// [`Event::Serialized`](crate::subscription::Event::Serialized), i.e. already serialized to
+    /// canonical extended JSON, e.g. to forward them to a socket as they are. The filter still
+    /// runs on the documents. A change is serialized once for all subscriptions on the
+    /// collection that receive it this way, unless they have [`SubscriptionOptions::fields`];
+    /// interceptors see the serialized event.
+    pub fn extended_json(mut self, extended_json: bool) -> Self {
+        self.extended_json = extended_json;
+        self
+    }
+
+    /// Turns `changeStreamPreAndPostImages` off again once the last subscription on the
+    /// collection has been removed, if it was Mercurius that turned it on, so that the server
+    /// stops keeping pre- and post-images for collections that are only watched for a while.
+    /// Like the other stream options, this is taken from the subscription that opens the stream;
+    /// once a subscription asked for it, the images are turned off after every later stream on
+    /// the collection as well, until that has succeeded.
+    pub fn revert_pre_and_post_images(mut self, revert: bool) -> Self {
+        self.revert_pre_and_post_images = revert;
+        self
+    }
+
+    /// Allows subscribing to a collection that doesn't exist yet. `changeStreamPreAndPostImages`
+    /// can only be turned on for an existing collection, so without this, subscribing to a
+    /// missing collection fails. With it, the subscription is added right away and receives the
+    /// changes from the creation of the collection on; the images are turned on as soon as it
+    /// has been created. Updates and deletes in between may therefore arrive as
+    /// [`Event::PreImageUnavailable`](crate::subscription::Event::PreImageUnavailable).
+    pub fn await_creation(mut self, await_creation: bool) -> Self {
+        self.await_creation = await_creation;
+        self
+    }
+}
+
+/// Where the change stream of a subscription starts, see [`SubscriptionConfig::start`].
+#[derive(Debug, Clone, Default)]
+pub enum StartPosition {
+    /// At the time of subscribing.
+    #[default]
+    Now,
+    /// See [`SubscriptionOptions::resume_after`].
+    ResumeAfter(ResumeToken),
+    /// See [`SubscriptionOptions::start_after`].
+    StartAfter(ResumeToken),
+}
+
+/// Instance-wide defaults of subscription options, see
+/// [`MercuriusBuilder`](crate::builder::MercuriusBuilder).
+#[derive(Debug, Clone, Default)]
+pub(crate) struct SubscriptionDefaults {
+    pub(crate) full_document: Option<FullDocumentType>,
+    pub(crate) capacity: Option<(usize, Overflow)>,
+    pub(crate) failover_timeout: Option<Duration>,
+    pub(crate) on_token_expiry: Option<OnTokenExpiry>,
+}
+
+impl SubscriptionDefaults {
+    /// Fills in the options that `options` leaves unset.
+    pub(crate) fn apply(&self, mut options: SubscriptionOptions) -> SubscriptionOptions {
+        if options.full_document.is_none() {
+            options.full_document = self.full_document.clone();
+        }
+        if let (None, false, Some((capacity, overflow))) =
+            (options.capacity, options.latest_only, self.capacity)
+        {
+            options.capacity = Some(capacity);
+            options.overflow = overflow;
+        }
+        if options.failover_timeout.is_none() {
+            options.failover_timeout = self.failover_timeout;
+        }
+        if options.on_token_expiry.is_none() {
+            options.on_token_expiry = self.on_token_expiry;
+        }
+        options
+    }
+}
+
+/// Everything that describes a subscription in one value, which can be reused for several
+/// collections with [`Mercurius::add_with_config`](crate::Mercurius::add_with_config). The
+/// fields correspond to [`SubscriptionOptions`], which `options` holds any others of.
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionConfig {
+    pub filter: Option<Document>,
+    /// See [`SubscriptionOptions::operations`].
+    pub operations: Option<Vec<OperationType>>,
+    /// See [`SubscriptionOptions::fields`].
+    pub fields: Option<Vec<String>>,
+    /// See [`SubscriptionOptions::full_document`].
+    pub full_document: Option<FullDocumentType>,
+    pub start: StartPosition,
+    /// See [`SubscriptionOptions::capacity`]; unbounded if unset.
+    pub capacity: Option<(usize, Overflow)>,
+    pub options: SubscriptionOptions,
+}
+
+impl SubscriptionConfig {
+    /// The filter and the options to subscribe with. The fields of the config take precedence
+    /// over `options`, unless they are unset.
+    pub fn into_parts(self) -> (Option<Document>, SubscriptionOptions) {
+        let mut options = self.options;
+
+        if let Some(operations) = self.operations {
+            options = options.operations(operations);
+        }
+        if let Some(fields) = self.fields {
+            options = options.fields(fields);
+        }
+        if let Some(full_document) = self.full_document {
+            options = options.full_document(full_document);
+        }
+        options = match self.start {
+            StartPosition::Now => options,
+            StartPosition::ResumeAfter(token) => options.resume_after(token),
+            StartPosition::StartAfter(token) => options.start_after(token),
+        };
+        if let Some((capacity, overflow)) = self.capacity {
+            options = options.capacity(capacity, overflow);
+        }
+
+        (self.filter, options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SubscriptionOptions;
+
+    #[test]
+    fn expanded_events_conflict_with_empty_batches() {
+        let expanded = SubscriptionOptions::new().show_expanded_events(true);
+        assert_eq!(expanded.clone().conflict(), None);
+        assert_eq!(
+            expanded.clone().group_transactions(true).conflict(),
+            Some(("show_expanded_events", "group_transactions"))
+        );
+        assert_eq!(
+            expanded.batch_boundaries(true).conflict(),
+            Some(("show_expanded_events", "batch_boundaries"))
+        );
+        assert_eq!(
+            SubscriptionOptions::new()
+                .group_transactions(true)
+                .batch_boundaries(true)
+                .conflict(),
+            None
+        );
+    }
+}
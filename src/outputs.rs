@@ -0,0 +1,49 @@
+//! Subscriptions whose events are delivered to several receivers in different shapes, see
+//! [`Mercurius::add_with_outputs`](crate::Mercurius::add_with_outputs).
+
+use tokio::sync::mpsc;
+
+use crate::{receiver::EventReceiver, subscription::Event};
+
+/// The shape in which an output receives the events of its subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Representation {
+    /// The events as they are.
+    Event,
+    /// The changes of documents as [`Event::Serialized`], like with
+    /// [`SubscriptionOptions::extended_json`](crate::options::SubscriptionOptions::extended_json),
+    /// and all other events as they are.
+    Json,
+}
+
+/// Hands the events of a subscription to its outputs until all of them have been dropped.
+pub(crate) async fn fan_out(
+    mut receiver: EventReceiver,
+    mut outputs: Vec<(Representation, mpsc::Sender<Event>)>,
+) {
+    while let Some(event) = receiver.recv().await {
+        // Serialized once for all outputs that ask for it
+        let serialized = outputs
+            .iter()
+            .any(|(representation, _)| *representation == Representation::Json)
+            .then(|| event.clone().serialize());
+
+        let mut closed = Vec::new();
+        for (index, (representation, sender)) in outputs.iter().enumerate() {
+            let event = match (representation, &serialized) {
+                (Representation::Json, Some(serialized)) => serialized.clone(),
+                _ => event.clone(),
+            };
+            // A full output holds up the others, and the channel of the subscription fills up
+            if sender.send(event).await.is_err() {
+                closed.push(index);
+            }
+        }
+        for index in closed.into_iter().rev() {
+            outputs.remove(index);
+        }
+        if outputs.is_empty() {
+            break;
+        }
+    }
+}
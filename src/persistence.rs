@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use futures_util::TryStreamExt;
+use mongodb::{
+    bson::{doc, oid::ObjectId, to_bson, Document},
+    change_stream::event::ResumeToken,
+    options::ReplaceOptions,
+    Collection,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::Handle;
+
+/// A subscription as it is stored by a [`PersistentStore`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SubscriptionDefinition {
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    pub collection: String,
+    pub filter: Option<Document>,
+    /// The position from which the subscription is resumed when it is restored.
+    pub resume_token: Option<ResumeToken>,
+}
+
+/// Stores subscription definitions in a MongoDB collection, so that another (or a restarted)
+/// instance can restore them with [`crate::Mercurius::restore`].
+///
+/// Only subscriptions created with
+/// [`SubscriptionOptions::persistent`](crate::options::SubscriptionOptions::persistent) are
+/// stored. Their definitions are removed again when the subscription is removed.
+#[derive(Debug, Clone)]
+pub struct PersistentStore {
+    collection: Collection<SubscriptionDefinition>,
+    /// The stored definitions of the subscriptions of this instance.
+    ids: Arc<Mutex<HashMap<Handle, ObjectId>>>,
+}
+
+impl PersistentStore {
+    pub fn new(collection: Collection<Document>) -> Self {
+        Self {
+            collection: collection.clone_with_type(),
+            ids: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub async fn save_subscription(
+        &self,
+        definition: &SubscriptionDefinition,
+    ) -> Result<(), mongodb::error::Error> {
+        self.collection
+            .replace_one(
+                doc! { "_id": definition.id },
+                definition,
+                ReplaceOptions::builder().upsert(true).build(),
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn load_subscriptions(
+        &self,
+    ) -> Result<Vec<SubscriptionDefinition>, mongodb::error::Error> {
+        self.collection.find(None, None).await?.try_collect().await
+    }
+
+    pub async fn remove_subscription(&self, id: ObjectId) -> Result<(), mongodb::error::Error> {
+        self.collection.delete_one(doc! { "_id": id }, None).await?;
+        Ok(())
+    }
+
+    pub async fn save_resume_token(
+        &self,
+        id: ObjectId,
+        resume_token: &ResumeToken,
+    ) -> Result<(), mongodb::error::Error> {
+        let resume_token = to_bson(resume_token).map_err(mongodb::error::Error::custom)?;
+
+        self.collection
+            .update_one(
+                doc! { "_id": id },
+                doc! { "$set": { "resume_token": resume_token } },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    pub(crate) fn track(&self, handle: Handle, id: ObjectId) {
+        self.ids.lock().unwrap().insert(handle, id);
+    }
+
+    pub(crate) fn untrack(&self, handle: &Handle) -> Option<ObjectId> {
+        self.ids.lock().unwrap().remove(handle)
+    }
+
+    pub(crate) fn tracked(&self) -> Vec<(Handle, ObjectId)> {
+        self.ids
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(handle, id)| (handle.clone(), *id))
+            .collect()
+    }
+
+    /// Removes the stored definition of the subscription `handle`. Best-effort: if this fails
+    /// the definition is restored later on, which only costs an unused subscription.
+    pub(crate) async fn forget(&self, handle: &Handle) {
+        if let Some(id) = self.untrack(handle) {
+            let _ = self.remove_subscription(id).await;
+        }
+    }
+
+    /// Like [`PersistentStore::forget`], for several subscriptions at once.
+    pub(crate) async fn forget_all(&self, handles: &[Handle]) {
+        let ids: Vec<ObjectId> = handles
+            .iter()
+            .filter_map(|handle| self.untrack(handle))
+            .collect();
+
+        if !ids.is_empty() {
+            let _ = self
+                .collection
+                .delete_many(doc! { "_id": { "$in": ids } }, None)
+                .await;
+        }
+    }
+}
@@ -0,0 +1,204 @@
+use std::sync::Arc;
+
+use mongodb::{
+    bson::{doc, Bson, Document},
+    change_stream::event::UpdateDescription,
+};
+
+use crate::subscription::{Event, EventDocument};
+
+/// The fields of the documents that a subscription receives, see
+/// [`SubscriptionOptions::fields`](crate::options::SubscriptionOptions::fields).
+#[derive(Debug, Clone)]
+pub(crate) struct Projection {
+    /// Dot-separated paths; `_id` is always included.
+    fields: Vec<String>,
+}
+
+/// The fields of a change event that Mercurius relies on.
+const EVENT_FIELDS: &[&str] = &[
+    "operationType",
+    "clusterTime",
+    "ns",
+    "to",
+    "documentKey",
+    "txnNumber",
+    "lsid",
+    // The keys of `updatedFields` are paths themselves, which can't be projected
+    "updateDescription",
+];
+
+impl Projection {
+    pub(crate) fn new(fields: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        let mut fields: Vec<String> = fields.into_iter().map(Into::into).collect();
+        fields.push("_id".to_string());
+
+        // A projection can't contain both a field and one of its subfields
+        fields.sort();
+        fields.dedup();
+        let fields = fields
+            .iter()
+            .filter(|field| {
+                !fields.iter().any(|other| {
+                    field
+                        .strip_prefix(other.as_str())
+                        .is_some_and(|rest| rest.starts_with('.'))
+                })
+            })
+            .cloned()
+            .collect();
+
+        Self { fields }
+    }
+
+    /// A `$project` stage that reduces the change events to the projected fields, so that the
+    /// server doesn't send the others.
+    pub(crate) fn stage(&self) -> Document {
+        let mut projection: Document = EVENT_FIELDS
+            .iter()
+            .map(|field| (field.to_string(), Bson::Int32(1)))
+            .collect();
+
+        for field in &self.fields {
+            for prefix in ["fullDocument", "fullDocumentBeforeChange"] {
+                projection.insert(format!("{}.{}", prefix, field), 1);
+            }
+        }
+
+        doc! { "$project": projection }
+    }
+
+    /// Returns `None` for an update that doesn't change any of the projected fields.
+    pub(crate) fn event(&self, event: Event) -> Option<Event> {
+        Some(match event {
+            Event::Added(document) => Event::Added(EventDocument::Owned(self.document(&document))),
+            Event::Entered(document) => {
+                Event::Entered(EventDocument::Owned(self.document(&document)))
+            }
+            Event::Replaced((key, document)) => {
+                Event::Replaced((key, EventDocument::Owned(self.document(&document))))
+            }
+            Event::Updated((key, update)) => Event::Updated((key, Arc::new(self.update(&update)?))),
+            Event::Transition(mut transition) => {
+                transition.old = EventDocument::Owned(self.document(&transition.old));
+                transition.new = EventDocument::Owned(self.document(&transition.new));
+                Event::Transition(transition)
+            }
+            event => event,
+        })
+    }
+
+    pub(crate) fn document(&self, document: &Document) -> Document {
+        let mut projected = Document::new();
+
+        for field in &self.fields {
+            if let Some(value) = get_path(document, field) {
+                insert_path(&mut projected, field, value.clone());
+            }
+        }
+
+        projected
+    }
+
+    /// Returns `None` if none of the projected fields has changed.
+    pub(crate) fn update(&self, update: &UpdateDescription) -> Option<UpdateDescription> {
+        let mut update = mongodb::bson::to_document(update).ok()?;
+
+        let updated_fields: Document = update
+            .get_document("updatedFields")
+            .ok()?
+            .iter()
+            .filter_map(|(path, value)| Some((path.clone(), self.narrow(path, value)?)))
+            .collect();
+        let removed_fields: Vec<Bson> = update
+            .get_array("removedFields")
+            .ok()?
+            .iter()
+            .filter(|path| matches!(path, Bson::String(path) if self.includes(path)))
+            .cloned()
+            .collect();
+        let truncated_arrays: Vec<Bson> = match update.get_array("truncatedArrays") {
+            Ok(arrays) => arrays
+                .iter()
+                .filter(|array| {
+                    matches!(array, Bson::Document(array) if array.get_str("field").is_ok_and(|path| self.includes(path)))
+                })
+                .cloned()
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+
+        if updated_fields.is_empty() && removed_fields.is_empty() && truncated_arrays.is_empty() {
+            return None;
+        }
+
+        update.insert("updatedFields", updated_fields);
+        update.insert("removedFields", removed_fields);
+        update.insert("truncatedArrays", truncated_arrays);
+        mongodb::bson::from_document(update).ok()
+    }
+
+    /// Reduces the new value of the field at `path` to the projected fields, or returns `None` if
+    /// it contains none.
+    fn narrow(&self, path: &str, value: &Bson) -> Option<Bson> {
+        if !self.includes(path) {
+            return None;
+        }
+
+        // The value is a parent of projected fields, e.g. `a` with `a.b` projected
+        let subfields: Vec<_> = self
+            .fields
+            .iter()
+            .filter_map(|field| field.strip_prefix(path)?.strip_prefix('.'))
+            .collect();
+
+        match value {
+            Bson::Document(document) if !subfields.is_empty() => {
+                let mut narrowed = Document::new();
+                for subfield in subfields {
+                    if let Some(value) = get_path(document, subfield) {
+                        insert_path(&mut narrowed, subfield, value.clone());
+                    }
+                }
+                Some(Bson::Document(narrowed))
+            }
+            value => Some(value.clone()),
+        }
+    }
+
+    /// Whether a change of the field at `path` changes a projected field.
+    fn includes(&self, path: &str) -> bool {
+        self.fields.iter().any(|field| {
+            // One of the paths is a prefix of the other, e.g. `a` of `a.b`
+            let (short, long) = match field.len() <= path.len() {
+                true => (field.as_str(), path),
+                false => (path, field.as_str()),
+            };
+            long.strip_prefix(short)
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    }
+}
+
+fn get_path<'a>(document: &'a Document, path: &str) -> Option<&'a Bson> {
+    match path.split_once('.') {
+        Some((field, rest)) => get_path(document.get_document(field).ok()?, rest),
+        None => document.get(path),
+    }
+}
+
+fn insert_path(document: &mut Document, path: &str, value: Bson) {
+    match path.split_once('.') {
+        Some((field, rest)) => {
+            if !matches!(document.get(field), Some(Bson::Document(_))) {
+                document.insert(field, Document::new());
+            }
+            if let Some(Bson::Document(nested)) = document.get_mut(field) {
+                insert_path(nested, rest, value);
+            }
+        }
+        None => {
+            document.insert(path, value);
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use std::{
+    future::poll_fn,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::{
+    channel::{Activity, Buffered, Coalescing, FlowControl, Retained},
+    clock::Clock,
+    subscription::{Event, EventMeta},
+};
+
+/// Receives the events of a single subscription.
+#[derive(Debug)]
+pub struct EventReceiver {
+    receiver: Receiver,
+    /// Released for every event that is received, see
+    /// [`Mercurius::max_in_flight`](crate::Mercurius::max_in_flight).
+    flow: Option<FlowControl>,
+    activity: Activity,
+    /// Takes the events that are still queued once this is dropped, see
+    /// [`SubscriptionOptions::grace_period`](crate::options::SubscriptionOptions::grace_period).
+    retained: Option<Retained>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Receiver {
+    Unbounded(UnboundedReceiver<(Buffered, EventMeta)>),
+    Bounded(mpsc::Receiver<(Buffered, EventMeta)>),
+    Coalescing(Arc<Coalescing>),
+}
+
+impl EventReceiver {
+    pub(crate) fn new(
+        receiver: Receiver,
+        flow: Option<FlowControl>,
+        activity: Activity,
+        retained: Option<Retained>,
+    ) -> Self {
+        Self {
+            receiver,
+            flow,
+            activity,
+            retained,
+        }
+    }
+
+    /// The clock of the instance, see [`Mercurius::clock`](crate::Mercurius::clock).
+    pub(crate) fn clock(&self) -> Arc<dyn Clock> {
+        self.activity.clock().clone()
+    }
+
+    /// Closes the channel and returns the events that are still in it.
+    fn close(&mut self) -> Vec<(Event, EventMeta)> {
+        match &mut self.receiver {
+            Receiver::Unbounded(receiver) => {
+                receiver.close();
+                std::iter::from_fn(|| receiver.try_recv().ok())
+                    .map(unbuffer)
+                    .collect()
+            }
+            Receiver::Bounded(receiver) => {
+                receiver.close();
+                std::iter::from_fn(|| receiver.try_recv().ok())
+                    .map(unbuffer)
+                    .collect()
+            }
+            Receiver::Coalescing(buffer) => buffer.close(),
+        }
+    }
+
+    fn received<T>(&self, next: Option<T>) -> Option<T> {
+        if next.is_some() {
+            self.activity.received(1, true);
+            if let Some(flow) = &self.flow {
+                flow.release(1);
+            }
+        }
+        next
+    }
+
+    /// Waits for the next event. Returns `None` once the subscription has been removed and all
+    /// buffered events have been received.
+    pub async fn recv(&mut self) -> Option<Event> {
+        self.recv_with_meta().await.map(|(event, _)| event)
+    }
+
+    /// Like [`EventReceiver::recv`], along with the metadata of the event.
+    pub async fn recv_with_meta(&mut self) -> Option<(Event, EventMeta)> {
+        let next = match &mut self.receiver {
+            Receiver::Unbounded(receiver) => receiver.recv().await.map(unbuffer),
+            Receiver::Bounded(receiver) => receiver.recv().await.map(unbuffer),
+            Receiver::Coalescing(buffer) => poll_fn(|cx| buffer.poll_recv(cx)).await,
+        };
+        self.received(next)
+    }
+
+    /// Returns the next event if one is immediately available, without waiting.
+    pub fn try_recv(&mut self) -> Option<Event> {
+        self.try_recv_with_meta().map(|(event, _)| event)
+    }
+
+    /// Like [`EventReceiver::try_recv`], along with the metadata of the event.
+    pub fn try_recv_with_meta(&mut self) -> Option<(Event, EventMeta)> {
+        let next = match &mut self.receiver {
+            Receiver::Unbounded(receiver) => receiver.try_recv().ok().map(unbuffer),
+            Receiver::Bounded(receiver) => receiver.try_recv().ok().map(unbuffer),
+            Receiver::Coalescing(buffer) => buffer.try_recv(),
+        };
+        self.received(next)
+    }
+
+    pub(crate) fn poll_recv_with_meta(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<(Event, EventMeta)>> {
+        let next = match &mut self.receiver {
+            Receiver::Unbounded(receiver) => receiver.poll_recv(cx).map(|next| next.map(unbuffer)),
+            Receiver::Bounded(receiver) => receiver.poll_recv(cx).map(|next| next.map(unbuffer)),
+            Receiver::Coalescing(buffer) => buffer.poll_recv(cx),
+        };
+
+        next.map(|next| self.received(next))
+    }
+
+    /// Returns all events that are immediately available, without waiting.
+    pub fn drain_available(&mut self) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        while let Some(event) = self.try_recv() {
+            events.push(event);
+        }
+
+        events
+    }
+}
+
+fn unbuffer((event, meta): (Buffered, EventMeta)) -> (Event, EventMeta) {
+    (event.into_event(), meta)
+}
+
+impl Stream for EventReceiver {
+    type Item = Event;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.poll_recv_with_meta(cx)
+            .map(|next| next.map(|(event, _)| event))
+    }
+}
+
+impl Drop for EventReceiver {
+    fn drop(&mut self) {
+        if let Some(retained) = self.retained.take() {
+            let mut remaining = 0;
+            retained.close(self.activity.now(), || {
+                let events = self.close();
+                remaining = events.len();
+                events
+            });
+            // Counted again once they're sent to the next receiver
+            self.activity.received(remaining, false);
+            if let Some(flow) = self.flow.as_ref().filter(|_| remaining > 0) {
+                flow.release(remaining);
+            }
+            return;
+        }
+
+        let Some(flow) = &self.flow else {
+            // Unlike a channel, the buffer is shared with the senders and isn't closed with it
+            if let Receiver::Coalescing(buffer) = &self.receiver {
+                buffer.close();
+            }
+            return;
+        };
+
+        // No event can be sent once the channel is closed, so the remaining ones are all that
+        // are still counted
+        let mut remaining = 0;
+        match &mut self.receiver {
+            Receiver::Unbounded(receiver) => {
+                receiver.close();
+                while receiver.try_recv().is_ok() {
+                    remaining += 1;
+                }
+            }
+            Receiver::Bounded(receiver) => {
+                receiver.close();
+                while receiver.try_recv().is_ok() {
+                    remaining += 1;
+                }
+            }
+            Receiver::Coalescing(buffer) => remaining = buffer.close().len(),
+        }
+        if remaining > 0 {
+            flow.release(remaining);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::EventReceiver;
+    use crate::{
+        channel::{channel, ChannelConfig, EventSender},
+        subscription::Event,
+    };
+
+    /// An unbounded, a bounded and a coalescing channel.
+    fn channels() -> [(EventSender, EventReceiver); 3] {
+        [(None, false), (Some(8), false), (None, true)].map(|(capacity, latest_only)| {
+            channel(ChannelConfig {
+                capacity,
+                latest_only,
+                ..ChannelConfig::default()
+            })
+        })
+    }
+
+    #[test]
+    fn try_recv_returns_what_is_buffered() {
+        for (sender, mut receiver) in channels() {
+            assert_eq!(receiver.try_recv(), None);
+
+            sender.send(Event::Count(1)).unwrap();
+            assert_eq!(receiver.try_recv(), Some(Event::Count(1)));
+            assert_eq!(receiver.try_recv(), None);
+
+            sender.send(Event::Count(2)).unwrap();
+            drop(sender);
+            assert_eq!(receiver.try_recv(), Some(Event::Count(2)));
+            assert_eq!(receiver.try_recv(), None);
+        }
+    }
+
+    #[test]
+    fn drain_available_returns_what_is_buffered() {
+        for (sender, mut receiver) in channels() {
+            assert_eq!(receiver.drain_available(), []);
+
+            sender.send(Event::Count(1)).unwrap();
+            sender.send(Event::Reset).unwrap();
+            assert_eq!(receiver.drain_available(), [Event::Count(1), Event::Reset]);
+            assert_eq!(receiver.drain_available(), []);
+
+            sender.send(Event::Count(2)).unwrap();
+            drop(sender);
+            assert_eq!(receiver.drain_available(), [Event::Count(2)]);
+            assert_eq!(receiver.drain_available(), []);
+        }
+    }
+
+    #[tokio::test]
+    async fn recv_ends_once_the_sender_is_dropped() {
+        for (sender, mut receiver) in channels() {
+            sender.send(Event::Count(1)).unwrap();
+            sender.send(Event::Reset).unwrap();
+            assert_eq!(receiver.recv().await, Some(Event::Count(1)));
+
+            // The buffered events are still received
+            drop(sender);
+            assert_eq!(receiver.recv().await, Some(Event::Reset));
+            assert_eq!(receiver.recv().await, None);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::sync::Arc;
+
+use mongodb::bson::{Bson, Document};
+
+/// The fields that are removed from the documents of a collection before they're matched, see
+/// [`Mercurius::redact_fields`](crate::Mercurius::redact_fields).
+#[derive(Debug, Clone)]
+pub(crate) struct Redaction {
+    /// Dot-separated paths.
+    paths: Arc<[String]>,
+}
+
+impl Redaction {
+    pub(crate) fn new(paths: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            paths: paths.into_iter().map(Into::into).collect(),
+        }
+    }
+
+    /// Removes the fields from the documents of a change event, as it's received from the server.
+    pub(crate) fn change(&self, change: &mut Document) {
+        for key in ["fullDocument", "fullDocumentBeforeChange"] {
+            if let Ok(document) = change.get_document_mut(key) {
+                self.document(document);
+            }
+        }
+
+        let Ok(description) = change.get_document_mut("updateDescription") else {
+            return;
+        };
+        if let Ok(updated) = description.get_document_mut("updatedFields") {
+            self.updated_fields(updated);
+        }
+        if let Ok(removed) = description.get_array_mut("removedFields") {
+            removed.retain(|field| !field.as_str().is_some_and(|field| self.covers(field)));
+        }
+        if let Ok(truncated) = description.get_array_mut("truncatedArrays") {
+            truncated.retain(|array| {
+                !array
+                    .as_document()
+                    .and_then(|array| array.get_str("field").ok())
+                    .is_some_and(|field| self.covers(field))
+            });
+        }
+    }
+
+    pub(crate) fn document(&self, document: &mut Document) {
+        for path in self.paths.iter() {
+            remove(document, path);
+        }
+    }
+
+    /// The keys of `updatedFields` are paths themselves, whose values may contain a redacted field.
+    fn updated_fields(&self, updated: &mut Document) {
+        let covered: Vec<String> = updated
+            .keys()
+            .filter(|field| self.covers(field))
+            .cloned()
+            .collect();
+        for field in covered {
+            updated.remove(&field);
+        }
+
+        for (field, value) in updated.iter_mut() {
+            let field = without_indices(field);
+            for path in self.paths.iter() {
+                if let Some(rest) = path
+                    .strip_prefix(field.as_str())
+                    .and_then(|rest| rest.strip_prefix('.'))
+                {
+                    remove_within(value, rest);
+                }
+            }
+        }
+    }
+
+    /// Whether `field` is one of the redacted fields or lies within one.
+    fn covers(&self, field: &str) -> bool {
+        let field = without_indices(field);
+        self.paths.iter().any(|path| {
+            field
+                .strip_prefix(path.as_str())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with('.'))
+        })
+    }
+}
+
+/// Removes the field at `path`, within every element of the arrays along it.
+fn remove(document: &mut Document, path: &str) {
+    match path.split_once('.') {
+        None => {
+            document.remove(path);
+        }
+        Some((field, rest)) => {
+            if let Some(value) = document.get_mut(field) {
+                remove_within(value, rest);
+            }
+        }
+    }
+}
+
+fn remove_within(value: &mut Bson, path: &str) {
+    match value {
+        Bson::Document(document) => remove(document, path),
+        Bson::Array(values) => {
+            for value in values {
+                remove_within(value, path);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// `field` without the array indices in it, e.g. `items.secret` for `items.0.secret`, as the
+/// redacted fields apply to every element of an array.
+fn without_indices(field: &str) -> String {
+    field
+        .split('.')
+        .filter(|segment| !segment.bytes().all(|byte| byte.is_ascii_digit()))
+        .collect::<Vec<_>>()
+        .join(".")
+}
@@ -0,0 +1,85 @@
+use mongodb::bson::{doc, Bson, Document};
+
+/// The union of the filters of the subscriptions on a collection, with which the server only
+/// sends the changes that may concern one of them, see
+/// [`Mercurius::filter_on_server`](crate::Mercurius::filter_on_server).
+#[derive(Debug, Clone)]
+pub(crate) struct ServerFilter {
+    /// The distinct filters of the subscriptions; `None` once one of them matches every document
+    /// or can't be evaluated on change events, so that the stream isn't filtered.
+    filters: Option<Vec<Document>>,
+}
+
+impl ServerFilter {
+    pub(crate) fn new(filter: Option<&Document>) -> Self {
+        let mut server_filter = Self {
+            filters: Some(Vec::new()),
+        };
+        server_filter.widen(filter);
+        server_filter
+    }
+
+    /// Adds the filter of a subscription. Returns whether the union has grown, in which case the
+    /// stream has to be reopened with the new [`ServerFilter::stage`].
+    pub(crate) fn widen(&mut self, filter: Option<&Document>) -> bool {
+        let Some(filters) = &mut self.filters else {
+            return false;
+        };
+
+        match filter {
+            Some(filter) if !filter.is_empty() && rewrite(filter, "fullDocument").is_some() => {
+                if filters.contains(filter) {
+                    return false;
+                }
+                filters.push(filter.clone());
+            }
+            _ => self.filters = None,
+        }
+        true
+    }
+
+    /// The `$match` stage that precedes the pipeline of the stream; `None` if it isn't filtered.
+    ///
+    /// A change passes if either version of its document matches one of the filters, so that
+    /// documents that stop matching are still seen. Changes without any version of the document
+    /// always pass, e.g. deletes without a pre-image and drops, as they can't be matched.
+    pub(crate) fn stage(&self) -> Option<Document> {
+        let filters = self.filters.as_ref()?;
+
+        let mut clauses = vec![Bson::Document(
+            doc! { "fullDocument": null, "fullDocumentBeforeChange": null },
+        )];
+        for filter in filters {
+            for prefix in ["fullDocument", "fullDocumentBeforeChange"] {
+                clauses.push(Bson::Document(rewrite(filter, prefix)?));
+            }
+        }
+
+        Some(doc! { "$match": { "$or": clauses } })
+    }
+}
+
+/// Rewrites a filter on documents into one on the field `prefix` of change events; `None` if it
+/// uses an operator that doesn't apply to a single field, like `$expr` or `$text`.
+fn rewrite(filter: &Document, prefix: &str) -> Option<Document> {
+    filter
+        .iter()
+        .map(|(key, value)| match key.as_str() {
+            "$and" | "$or" | "$nor" => {
+                let Bson::Array(clauses) = value else {
+                    return None;
+                };
+                let clauses = clauses
+                    .iter()
+                    .map(|clause| match clause {
+                        Bson::Document(clause) => rewrite(clause, prefix).map(Bson::Document),
+                        _ => None,
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                Some((key.clone(), Bson::Array(clauses)))
+            }
+            key if key.starts_with('$') => None,
+            key => Some((format!("{}.{}", prefix, key), value.clone())),
+        })
+        .collect()
+}
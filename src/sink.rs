@@ -0,0 +1,153 @@
+//! Delivery of events into external systems like Kafka or Redis, see
+//! [`Mercurius::add_to_sink`](crate::Mercurius::add_to_sink).
+
+use std::{fmt::Display, future::Future, pin::Pin, sync::Arc, time::Duration};
+
+use tokio::sync::mpsc;
+
+use crate::{
+    clock::Clock,
+    error::{ErrorHandler, EventError},
+    receiver::EventReceiver,
+    subscription::Event,
+    Handle,
+};
+
+/// The future of [`EventSink::deliver`].
+pub type SinkFuture<'a> = Pin<Box<dyn Future<Output = Result<(), SinkError>> + Send + 'a>>;
+
+/// Receives the events of a subscription in place of a channel, see
+/// [`Mercurius::add_to_sink`](crate::Mercurius::add_to_sink).
+pub trait EventSink: Send + Sync {
+    /// Delivers an event, e.g. by producing it to a topic. The next event of the subscription is
+    /// only delivered once the returned future has completed.
+    fn deliver(&self, event: Event) -> SinkFuture<'_>;
+}
+
+/// An event that couldn't be delivered to an [`EventSink`].
+#[derive(Debug)]
+pub struct SinkError {
+    error: Box<dyn std::error::Error + Send + Sync>,
+    transient: bool,
+}
+
+impl SinkError {
+    /// Delivery may succeed when it's retried, e.g. after a timeout; see
+    /// [`Mercurius::sink_retries`](crate::Mercurius::sink_retries).
+    pub fn transient(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            error: error.into(),
+            transient: true,
+        }
+    }
+
+    /// The event can't be delivered, so it isn't retried.
+    pub fn permanent(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            error: error.into(),
+            transient: false,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        self.transient
+    }
+}
+
+impl Display for SinkError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "The event could not be delivered to the sink: {}",
+            self.error
+        )
+    }
+}
+
+impl std::error::Error for SinkError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&*self.error)
+    }
+
+    fn description(&self) -> &str {
+        "description() is deprecated; use Display"
+    }
+
+    fn cause(&self) -> Option<&dyn std::error::Error> {
+        self.source()
+    }
+}
+
+/// How often a transient [`SinkError`] is retried, see
+/// [`Mercurius::sink_retries`](crate::Mercurius::sink_retries).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct SinkRetries {
+    pub(crate) attempts: u32,
+    /// The wait before the first retry, which doubles with every further one.
+    pub(crate) backoff: Duration,
+}
+
+impl Default for SinkRetries {
+    fn default() -> Self {
+        Self {
+            attempts: 3,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// Feeds the events of a subscription into its sink until the subscription ends.
+pub(crate) struct Forwarder {
+    pub(crate) receiver: EventReceiver,
+    pub(crate) sink: Arc<dyn EventSink>,
+    pub(crate) handle: Handle,
+    pub(crate) retries: SinkRetries,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) on_error: Option<ErrorHandler>,
+    pub(crate) dead_letter: Option<mpsc::Sender<(Handle, Event)>>,
+}
+
+impl Forwarder {
+    pub(crate) async fn run(mut self) {
+        while let Some(event) = self.receiver.recv().await {
+            let Err((event, err)) = self.deliver(event).await else {
+                continue;
+            };
+
+            let err = EventError::Undeliverable(err);
+            match &self.on_error {
+                Some(handler) => handler(self.handle.collection_name(), &err),
+                None => eprintln!(
+                    "Skipped an event on collection {}: {}",
+                    self.handle.collection_name(),
+                    err
+                ),
+            }
+            // Best-effort, like the other undeliverable events
+            if let Some(dead_letter) = &self.dead_letter {
+                let _ = dead_letter.try_send((self.handle.clone(), event));
+            }
+        }
+    }
+
+    /// Returns the event if it couldn't be delivered, along with the last error.
+    async fn deliver(&self, event: Event) -> Result<(), (Event, SinkError)> {
+        let mut backoff = self.retries.backoff;
+        let mut retries = 0;
+
+        loop {
+            // A failed delivery needs the event again
+            let err = match self.sink.deliver(event.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(err) => err,
+            };
+            if !err.transient || retries == self.retries.attempts {
+                return Err((event, err));
+            }
+
+            self.clock.sleep(backoff).await;
+            backoff *= 2;
+            retries += 1;
+        }
+    }
+}
@@ -0,0 +1,75 @@
+//! How the background tasks of an instance are spawned, e.g. the change stream of every
+//! collection, see [`Mercurius::with_spawner`](crate::Mercurius::with_spawner).
+
+use std::{
+    future::{poll_fn, Future},
+    pin::Pin,
+    sync::{Arc, Mutex as StdMutex},
+};
+
+use futures_util::future::{abortable, AbortHandle};
+use tokio::task::{JoinError, JoinSet};
+
+/// A background task of an instance.
+pub type Task = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Runs the background tasks of an instance, e.g. on a dedicated runtime or with named tasks.
+/// Tasks are stopped by the instance itself once they aren't needed anymore, so the spawner
+/// only has to drive them to completion.
+pub trait Spawner: Send + Sync {
+    fn spawn(&self, task: Task);
+}
+
+impl<S: Spawner + ?Sized> Spawner for Arc<S> {
+    fn spawn(&self, task: Task) {
+        (**self).spawn(task);
+    }
+}
+
+/// The default [`Spawner`], which spawns the tasks onto the current tokio runtime and keeps
+/// them in a `JoinSet`, so that [`Mercurius::run`](crate::Mercurius::run) can wait for them.
+#[derive(Debug, Default)]
+pub struct TokioSpawner {
+    tasks: StdMutex<JoinSet<()>>,
+}
+
+impl TokioSpawner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Waits for the next task to finish; `None` once there are no tasks left. The tasks aren't
+    /// locked while waiting, so new ones can be spawned meanwhile.
+    pub(crate) async fn join_next(&self) -> Option<Result<(), JoinError>> {
+        poll_fn(|cx| self.tasks.lock().unwrap().poll_join_next(cx)).await
+    }
+
+    /// Aborts all tasks and waits for them to stop.
+    pub(crate) async fn shutdown(&self) {
+        self.abort_all();
+        while self.join_next().await.is_some() {}
+    }
+
+    /// Aborts all tasks without waiting for them to stop.
+    pub(crate) fn abort_all(&self) {
+        self.tasks.lock().unwrap().abort_all();
+    }
+}
+
+impl Spawner for TokioSpawner {
+    fn spawn(&self, task: Task) {
+        self.tasks.lock().unwrap().spawn(task);
+    }
+}
+
+/// Spawns `task` with `spawner`; it stops once the returned handle is aborted.
+pub(crate) fn spawn(
+    spawner: &dyn Spawner,
+    task: impl Future<Output = ()> + Send + 'static,
+) -> AbortHandle {
+    let (task, handle) = abortable(task);
+    spawner.spawn(Box::pin(async move {
+        let _ = task.await;
+    }));
+    handle
+}
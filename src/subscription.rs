@@ -1,147 +1,1348 @@
-use std::sync::Arc;
+use std::{
+    cell::OnceCell,
+    collections::HashSet,
+    fmt::Debug,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex as StdMutex,
+    },
+    time::Duration,
+};
 
 use mongodb::{
-    bson::{Bson, Document},
-    change_stream::event::UpdateDescription,
+    bson::{Bson, Document, Timestamp},
+    change_stream::event::{OperationType, ResumeToken, UpdateDescription},
+    options::Collation,
 };
 use serde_json::{json, Value};
-use serde_json_matcher::{from_json, ObjMatcher};
-use tokio::sync::mpsc::{error::SendError, UnboundedSender};
+use tokio_util::sync::DropGuard;
 
-#[derive(Debug)]
+#[cfg(feature = "bson-matcher")]
+use crate::bson_matcher::BsonMatcher;
+use crate::{
+    channel::{EventSender, SendFailure, Sequence},
+    interceptor::{self, Interceptor},
+    matcher::{Matcher, MatcherError},
+    projection::Projection,
+    receiver::EventReceiver,
+};
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Event {
-    Added(Arc<Document>),
+    /// Always the first event of a subscription. From this point onwards every change is delivered.
+    ///
+    /// `cluster_time` is the cluster time after which changes are delivered to this subscription; it
+    /// can be used to perform a snapshot read that neither misses nor double-counts a change. It is
+    /// only unavailable if the server doesn't report operation times.
+    Established {
+        cluster_time: Option<Timestamp>,
+        resume_token: Option<ResumeToken>,
+    },
+    Added(EventDocument),
+    /// The document with this key was deleted. Keys are the `_id` of the document, which has to
+    /// be a string or an ObjectId; ObjectIds are given in hex.
     Removed(Arc<String>),
     Updated((Arc<String>, Arc<UpdateDescription>)),
-    Replaced((Arc<String>, Arc<Document>)),
+    Replaced((Arc<String>, EventDocument)),
+    /// An update or replacement made the document match the filter, see
+    /// [`SubscriptionOptions::boundary_events`](crate::options::SubscriptionOptions::boundary_events).
+    /// Without that option, this is an [`Event::Added`].
+    Entered(EventDocument),
+    /// An update or replacement made the document with this key stop matching the filter, see
+    /// [`SubscriptionOptions::boundary_events`](crate::options::SubscriptionOptions::boundary_events).
+    /// Without that option, this is an [`Event::Removed`].
+    Left(Arc<String>),
     /// Something happend that requires the subscription to be removed.
     /// This can occur when the collection or database has been dropped or the collection has been renamed or the stream was invalidated.
     Drop,
+    /// The document `key` was updated, replaced or deleted, but its version from before the change
+    /// is not available, so it's unknown whether the change is relevant to the subscription.
+    /// `reason` tells whether the pre-image has expired or the collection records none. The
+    /// consumer should read the document itself.
+    PreImageUnavailable {
+        key: Arc<String>,
+        operation_type: OperationType,
+        reason: UnavailableReason,
+    },
+    /// The change stream has been reopened; documents received before may no longer exist, e.g.
+    /// because the collection was dropped.
+    Reset,
+    /// The change stream couldn't be resumed because its position is no longer in the oplog, so
+    /// it has been reopened at `cluster_time` and the changes in between are lost, see
+    /// [`SubscriptionOptions::on_token_expiry`](crate::options::SubscriptionOptions::on_token_expiry).
+    /// The consumer should consider what it received before as stale.
+    GapDetected {
+        cluster_time: Option<Timestamp>,
+    },
+    /// All changes of a batch that the change stream read from the server have been dispatched,
+    /// see [`SubscriptionOptions::batch_boundaries`](crate::options::SubscriptionOptions::batch_boundaries).
+    /// Resuming from `resume_token` continues with the next batch, so it's a good point to
+    /// checkpoint. Not sent while the changes of a grouped transaction are held back.
+    BatchBoundary {
+        resume_token: Option<ResumeToken>,
+    },
+    /// The pre- and post-images of the collection have been turned off by someone else, see
+    /// [`Mercurius::verify_pre_images`](crate::Mercurius::verify_pre_images). Since then, updates,
+    /// replacements and deletes may have been reported as [`Event::PreImageUnavailable`].
+    /// `re_enabled` tells whether they have been turned on again; if not, this is only sent once
+    /// until they are turned on.
+    ConfigurationChanged {
+        re_enabled: bool,
+    },
+    /// The number of documents that matched the subscription when it was established, see
+    /// [`SubscriptionOptions::initial_count`](crate::options::SubscriptionOptions::initial_count).
+    Count(u64),
+    /// A resumed change stream has delivered every change that happened before it was opened; all
+    /// following events are live.
+    CaughtUp,
+    /// The subscription has been closed with [`crate::Mercurius::drain_and_close`], or has
+    /// delivered the number of changes it was limited to with
+    /// [`SubscriptionOptions::take`](crate::options::SubscriptionOptions::take). No events follow.
+    Closed,
+    /// The events of a transaction that are relevant to the subscription, see
+    /// [`SubscriptionOptions::group_transactions`](crate::options::SubscriptionOptions::group_transactions).
+    Transaction(Box<Transaction>),
+    /// The collection itself has changed, e.g. an index was created.
+    Ddl(Box<DdlEvent>),
+    /// An update or replacement along with whether each version of the document matches, see
+    /// [`SubscriptionOptions::raw_transitions`](crate::options::SubscriptionOptions::raw_transitions).
+    Transition(Box<TransitionEvent>),
+    /// An [`Event::Added`], [`Event::Removed`], [`Event::Updated`], [`Event::Replaced`],
+    /// [`Event::Entered`] or [`Event::Left`] serialized to canonical extended JSON, in the shape of [`Event::to_json`], see
+    /// [`SubscriptionOptions::extended_json`](crate::options::SubscriptionOptions::extended_json).
+    Serialized(Arc<str>),
+    /// A change along with the change stream event it stems from, see
+    /// [`SubscriptionOptions::raw_events`](crate::options::SubscriptionOptions::raw_events).
+    Raw(Box<RawEvent>),
+}
+
+/// Reduces a change to whether the document joined or left the set of matches, see
+/// [`SubscriptionOptions::set_membership`](crate::options::SubscriptionOptions::set_membership);
+/// `None` for a change within the set.
+fn membership(event: Event) -> Option<Event> {
+    Some(match event {
+        Event::Entered(document) => Event::Added(document),
+        Event::Left(key) => Event::Removed(key),
+        Event::Updated(_) | Event::Replaced(_) => return None,
+        Event::Transition(transition) => match (transition.old_matched, transition.new_matched) {
+            (false, true) => Event::Added(transition.new),
+            (true, false) => Event::Removed(transition.key),
+            _ => return None,
+        },
+        event => event,
+    })
+}
+
+/// Metadata that accompanies every event, see [`EventReceiver::recv_with_meta`](crate::receiver::EventReceiver::recv_with_meta).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EventMeta {
+    /// The number of changes that the change stream of the collection had dispatched when the
+    /// event was sent. It increases with every change to the collection, including those that
+    /// are irrelevant to the subscription, so it orders the events of all subscriptions on the
+    /// collection; events that don't stem from a change, like [`Event::Established`], share the
+    /// number of the change before them. The sequence starts at 1 with every new change stream
+    /// of the collection.
+    pub seq: u64,
+    /// The cluster time of the change that was being dispatched when the event was sent, which
+    /// orders the changes of all collections, see [`merge_ordered`](crate::merge::merge_ordered).
+    /// Unset for events that aren't sent while dispatching a change.
+    pub cluster_time: Option<Timestamp>,
+    /// How long it took from the change at the server, according to its wall time, until the
+    /// event was sent to the channel of the subscription; zero if the server's clock is ahead.
+    /// Unset for events that aren't sent while dispatching a change, and for servers that don't
+    /// report the wall time (before MongoDB 6.0).
+    pub delivery_latency: Option<Duration>,
+    /// Whether the event reports that the document started or stopped matching the filter
+    /// rather than the operation of the change, i.e. an [`Event::Added`], [`Event::Removed`],
+    /// [`Event::Entered`] or [`Event::Left`] (or one of them serialized) that stems from an
+    /// update or replacement. Unset for the events within an [`Event::Transaction`].
+    pub synthesized: bool,
+}
+
+/// A change to a collection rather than to its documents.
+///
+/// The server only reports these when the change stream is opened with
+/// [`SubscriptionOptions::show_expanded_events`](crate::options::SubscriptionOptions::show_expanded_events).
+#[derive(Debug, Clone, PartialEq)]
+pub struct DdlEvent {
+    /// One of `create`, `createIndexes`, `dropIndexes`, `modify`, `shardCollection`,
+    /// `reshardCollection` and `refineCollectionShardKey`.
+    pub operation_type: String,
+    /// The `operationDescription` of the change event, whose shape depends on the operation
+    /// type, e.g. the created indexes.
+    pub description: Option<Document>,
+}
+
+/// An update or replacement of a document that matches the filter of a subscription before or
+/// after it, or both. Which versions match is up to the consumer to interpret; without
+/// [`SubscriptionOptions::raw_transitions`](crate::options::SubscriptionOptions::raw_transitions)
+/// it's an [`Event::Added`], [`Event::Removed`], [`Event::Updated`] or [`Event::Replaced`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TransitionEvent {
+    pub key: Arc<String>,
+    /// The version of the document before the change.
+    pub old: EventDocument,
+    /// The version of the document after the change.
+    pub new: EventDocument,
+    pub old_matched: bool,
+    pub new_matched: bool,
+}
+
+/// The event a subscription would have received for a change, and the change stream event it
+/// stems from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RawEvent {
+    pub event: Event,
+    /// The change event as the server reported it, with all of its fields, e.g. the resume token
+    /// in `_id`, `clusterTime` and `ns`; the fragments of a split event are joined. It can be
+    /// deserialized into a `ChangeStreamEvent<Document>`. Its documents are those of the change,
+    /// regardless of the
+    /// [`SubscriptionOptions::fields`](crate::options::SubscriptionOptions::fields).
+    pub change: Arc<Document>,
+}
+
+/// The changes of a single multi-document transaction, in the order they were made.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Transaction {
+    /// The id of the session that performed the transaction.
+    pub lsid: Document,
+    pub txn_number: i64,
+    pub events: Vec<Event>,
 }
 
 impl Event {
+    /// Whether the event is a change to the collection, rather than about the subscription.
+    pub(crate) fn is_change(&self) -> bool {
+        !matches!(
+            self,
+            Event::Established { .. }
+                | Event::Drop
+                | Event::Reset
+                | Event::GapDetected { .. }
+                | Event::BatchBoundary { .. }
+                | Event::ConfigurationChanged { .. }
+                | Event::Count(_)
+                | Event::CaughtUp
+                | Event::Closed
+        )
+    }
+
+    /// Whether the event is a change that has passed the filter of the subscription, unlike
+    /// [`Event::PreImageUnavailable`] and [`Event::Ddl`], which are sent regardless of it.
+    pub(crate) fn is_match(&self) -> bool {
+        self.is_change() && !matches!(self, Event::PreImageUnavailable { .. } | Event::Ddl(_))
+    }
+
     pub fn to_json(&self) -> Option<Value> {
         match self {
-            Event::Added(doc) => {
-                Some(json!({ "event": "added", "document": Subscription::document_to_value(doc) }))
+            Event::Added(doc) => Some(change_json(DocumentChange::Added(doc))),
+            Event::Removed(id) => Some(change_json(DocumentChange::Removed(id))),
+            Event::Updated((id, desc)) => Some(change_json(DocumentChange::Updated(id, desc))),
+            Event::Replaced((id, doc)) => Some(change_json(DocumentChange::Replaced(id, doc))),
+            Event::Entered(doc) => Some(change_json(DocumentChange::Entered(doc))),
+            Event::Left(id) => Some(change_json(DocumentChange::Left(id))),
+            Event::Transition(transition) => {
+                Some(change_json(DocumentChange::Transition(transition)))
             }
-            Event::Removed(id) => Some(json!({ "event": "removed", "id": id })),
-            Event::Updated((id, desc)) => {
-                Some(json!({ "event": "updated", "id": id, "description": desc }))
+            Event::PreImageUnavailable {
+                key,
+                operation_type,
+                reason,
+            } => Some(json!({
+                "event": "preImageUnavailable",
+                "id": key,
+                "operationType": operation_type,
+                "reason": match reason {
+                    UnavailableReason::Expired => "expired",
+                    UnavailableReason::Disabled => "disabled",
+                },
+            })),
+            Event::Transaction(transaction) => Some(json!({
+                "event": "transaction",
+                "txnNumber": transaction.txn_number,
+                "events": transaction.events.iter().filter_map(Event::to_json).collect::<Vec<_>>(),
+            })),
+            Event::Ddl(ddl) => Some(json!({
+                "event": "ddl",
+                "operationType": ddl.operation_type,
+                "description": ddl.description.as_ref().map(Subscription::document_to_value),
+            })),
+            Event::Count(count) => Some(json!({ "event": "count", "count": count })),
+            Event::Serialized(json) => serde_json::from_str(json).ok(),
+            Event::Raw(raw) => raw.event.to_json(),
+            Event::Established { .. }
+            | Event::Reset
+            | Event::GapDetected { .. }
+            | Event::BatchBoundary { .. }
+            | Event::ConfigurationChanged { .. }
+            | Event::CaughtUp
+            | Event::Drop
+            | Event::Closed => None,
+        }
+    }
+
+    /// Turns a change of a document into an [`Event::Serialized`]; other events are returned as
+    /// they are.
+    pub(crate) fn serialize(self) -> Event {
+        let change = match &self {
+            Event::Added(doc) => DocumentChange::Added(doc),
+            Event::Removed(id) => DocumentChange::Removed(id),
+            Event::Updated((id, desc)) => DocumentChange::Updated(id, desc),
+            Event::Replaced((id, doc)) => DocumentChange::Replaced(id, doc),
+            Event::Entered(doc) => DocumentChange::Entered(doc),
+            Event::Left(id) => DocumentChange::Left(id),
+            Event::Transition(transition) => DocumentChange::Transition(transition),
+            _ => return self,
+        };
+
+        Event::Serialized(change.serialize())
+    }
+}
+
+/// A change of a document that can be serialized, see [`Event::Serialized`].
+pub(crate) enum DocumentChange<'a> {
+    Added(&'a Document),
+    Removed(&'a str),
+    Updated(&'a str, &'a UpdateDescription),
+    Replaced(&'a str, &'a Document),
+    Entered(&'a Document),
+    Left(&'a str),
+    Transition(&'a TransitionEvent),
+}
+
+impl DocumentChange<'_> {
+    pub(crate) fn serialize(self) -> Arc<str> {
+        change_json(self).to_string().into()
+    }
+}
+
+fn change_json(change: DocumentChange) -> Value {
+    match change {
+        DocumentChange::Added(doc) => {
+            json!({ "event": "added", "document": Subscription::document_to_value(doc) })
+        }
+        DocumentChange::Removed(id) => json!({ "event": "removed", "id": id }),
+        DocumentChange::Updated(id, desc) => {
+            json!({ "event": "updated", "id": id, "description": desc })
+        }
+        DocumentChange::Replaced(id, doc) => {
+            json!({ "event": "replaced", "id": id, "document": Subscription::document_to_value(doc) })
+        }
+        DocumentChange::Entered(doc) => {
+            json!({ "event": "entered", "document": Subscription::document_to_value(doc) })
+        }
+        DocumentChange::Left(id) => json!({ "event": "left", "id": id }),
+        DocumentChange::Transition(transition) => json!({
+            "event": "transition",
+            "id": transition.key,
+            "old": Subscription::document_to_value(&transition.old),
+            "new": Subscription::document_to_value(&transition.new),
+            "oldMatched": transition.old_matched,
+            "newMatched": transition.new_matched,
+        }),
+    }
+}
+
+/// A document delivered with an event. Documents are shared between the subscriptions of a
+/// collection, unless the stream was opened with
+/// [`SubscriptionOptions::owned_documents`](crate::options::SubscriptionOptions::owned_documents).
+#[derive(Debug, Clone)]
+pub enum EventDocument {
+    Shared(Arc<Document>),
+    Owned(Document),
+}
+
+impl EventDocument {
+    /// Takes ownership of the document. Only clones a shared document that is still referenced
+    /// by another event.
+    pub fn into_owned(self) -> Document {
+        match self {
+            EventDocument::Shared(document) => {
+                Arc::try_unwrap(document).unwrap_or_else(|document| (*document).clone())
             }
-            Event::Replaced((id, doc)) => Some(
-                json!({ "event": "replaced", "id": id, "document": Subscription::document_to_value(doc) }),
-            ),
-            Event::Drop => None,
+            EventDocument::Owned(document) => document,
         }
     }
 }
 
+/// Documents are compared by value, whether or not they are shared.
+impl PartialEq for EventDocument {
+    fn eq(&self, other: &Self) -> bool {
+        **self == **other
+    }
+}
+
+impl Deref for EventDocument {
+    type Target = Document;
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            EventDocument::Shared(document) => document,
+            EventDocument::Owned(document) => document,
+        }
+    }
+}
+
+/// How a change to a document affects the set of documents matched by a subscription.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Transition {
+    /// Only the new version of the document matches.
+    Added,
+    /// Only the old version of the document matches.
+    Removed,
+    /// Both versions of the document match.
+    Changed,
+    /// Like [`Transition::Added`], for an update or replacement that is reported as
+    /// [`Event::Entered`].
+    Entered,
+    /// Like [`Transition::Removed`], for an update or replacement that is reported as
+    /// [`Event::Left`].
+    Left,
+    /// An update or replacement that is reported as an [`Event::Transition`].
+    Raw {
+        old_matched: bool,
+        new_matched: bool,
+    },
+}
+
+/// A client-side filter over the full document, for logic that can't be expressed as a query
+/// document. It runs on every change event of the collection, so keep it cheap.
+#[derive(Clone)]
+pub struct Predicate(Arc<dyn Fn(&Document) -> bool + Send + Sync>);
+
+impl Predicate {
+    pub fn new(predicate: impl Fn(&Document) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(predicate))
+    }
+}
+
+impl Debug for Predicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Predicate")
+    }
+}
+
+/// A client-side filter over the change of an update, e.g. whether a field is among its
+/// `updatedFields`. It complements the filter and the [`Predicate`], which only see documents.
+#[derive(Clone)]
+pub struct DeltaPredicate(Arc<dyn Fn(&UpdateDescription) -> bool + Send + Sync>);
+
+impl DeltaPredicate {
+    pub fn new(predicate: impl Fn(&UpdateDescription) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(predicate))
+    }
+}
+
+impl Debug for DeltaPredicate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("DeltaPredicate")
+    }
+}
+
+/// Why the pre-image of a change is unavailable, see [`Event::PreImageUnavailable`]. Told apart
+/// by whether the images of the collection are on as far as the instance knows when the change
+/// is dispatched, so a change from before they were turned on, e.g. in the backlog of a resumed
+/// stream, counts as expired.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnavailableReason {
+    /// The images are on, but this one has been removed after the retention period configured
+    /// with `changeStreamOptions.preAndPostImages.expireAfterSeconds`.
+    Expired,
+    /// The images of the collection are off, e.g. as it has been created after subscribing with
+    /// [`SubscriptionOptions::await_creation`](crate::options::SubscriptionOptions::await_creation)
+    /// and they haven't been turned on yet, or as they have been turned off since, see
+    /// [`Event::ConfigurationChanged`].
+    Disabled,
+}
+
+/// Why a subscription has been torn down, see
+/// [`SubscriptionOptions::on_teardown`](crate::options::SubscriptionOptions::on_teardown).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeardownReason {
+    /// It was removed, e.g. with [`crate::Mercurius::remove`] or by its cancellation token.
+    Removed,
+    /// Its receiver was dropped, which is noticed with the next event that is sent to it.
+    ReceiverDropped,
+    /// Its channel was full, see [`Overflow::Close`](crate::channel::Overflow::Close) and
+    /// [`Overflow::Block`](crate::channel::Overflow::Block).
+    Overflowed,
+    /// It delivered the number of changes it was limited to, see
+    /// [`SubscriptionOptions::take`](crate::options::SubscriptionOptions::take).
+    Completed,
+    /// It received [`Event::Drop`], as the collection was dropped or renamed or its change stream
+    /// was invalidated.
+    Dropped,
+    /// The instance was shut down or dropped.
+    Shutdown,
+    /// The change stream of its collection ended with an error, which is reported as
+    /// [`EventError::StreamFailed`](crate::error::EventError::StreamFailed).
+    StreamFailed,
+}
+
+/// Called once a subscription is torn down, see
+/// [`SubscriptionOptions::on_teardown`](crate::options::SubscriptionOptions::on_teardown).
+#[derive(Clone)]
+pub(crate) struct OnTeardown(Arc<StdMutex<TeardownState>>);
+
+struct TeardownState {
+    callback: Option<Box<dyn FnOnce(TeardownReason) + Send>>,
+    /// Set once the subscription has been added, so that the callback isn't called for one that
+    /// couldn't be.
+    armed: bool,
+    /// A teardown before the subscription was armed, e.g. a drop while it was being added.
+    pending: Option<TeardownReason>,
+}
+
+impl OnTeardown {
+    pub(crate) fn new(callback: impl FnOnce(TeardownReason) + Send + 'static) -> Self {
+        Self(Arc::new(StdMutex::new(TeardownState {
+            callback: Some(Box::new(callback)),
+            armed: false,
+            pending: None,
+        })))
+    }
+
+    pub(crate) fn arm(&self) {
+        let callback = {
+            let mut state = self.0.lock().unwrap();
+            state.armed = true;
+            match state.pending {
+                Some(reason) => state.callback.take().map(|callback| (callback, reason)),
+                None => None,
+            }
+        };
+        if let Some((callback, reason)) = callback {
+            callback(reason);
+        }
+    }
+
+    /// Calls the callback, unless it has been called already.
+    fn call(&self, reason: TeardownReason) {
+        let callback = {
+            let mut state = self.0.lock().unwrap();
+            if !state.armed {
+                state.pending.get_or_insert(reason);
+                return;
+            }
+            state.callback.take()
+        };
+        if let Some(callback) = callback {
+            callback(reason);
+        }
+    }
+}
+
+impl Debug for OnTeardown {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("OnTeardown")
+    }
+}
+
+/// Calls the [`OnTeardown`] of a subscription that is dropped without having been torn down
+/// otherwise, e.g. along with its collection on shutdown.
+#[derive(Debug)]
+struct TeardownGuard(OnTeardown);
+
+impl Drop for TeardownGuard {
+    fn drop(&mut self) {
+        self.0.call(TeardownReason::Shutdown);
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SubscriptionStats {
+    /// The number of events that were dropped because the channel of the subscription was full,
+    /// see [`Overflow::Drop`](crate::channel::Overflow::Drop), or because it was paused and had
+    /// held back as many events as it could, see [`crate::Mercurius::pause`].
+    pub dropped: u64,
+    /// How long the consumer hasn't received any of the events that are queued for it; `None`
+    /// if none are. A consumer that keeps up receives them shortly after they were sent, so a
+    /// long idle time means that it has stopped receiving, see
+    /// [`crate::Mercurius::stuck_subscriptions`].
+    pub idle: Option<Duration>,
+}
+
 // TODO: Share subscription matcher across multiple channels
 #[derive(Debug)]
 pub struct Subscription {
-    selector: Option<ObjMatcher>,
-    channel: UnboundedSender<Event>,
+    /// The filter that `selector` was compiled from, see [`crate::Mercurius::filter`].
+    filter: Option<Document>,
+    selector: Option<Selector>,
+    predicate: Option<Predicate>,
+    delta_predicate: Option<DeltaPredicate>,
+    /// The operations whose changes are delivered; all if unset.
+    operations: Option<Vec<OperationType>>,
+    projection: Option<Projection>,
+    /// Whether changes are delivered as [`Event::Serialized`].
+    extended_json: bool,
+    /// Whether documents that start or stop matching are reported as [`Event::Entered`] and
+    /// [`Event::Left`].
+    boundary_events: bool,
+    /// Whether updates and replacements are reported as [`Event::Transition`].
+    raw_transitions: bool,
+    /// Whether only documents entering and leaving the set of matches are reported.
+    set_membership: bool,
+    /// Whether the subscription receives [`Event::BatchBoundary`].
+    batch_boundaries: bool,
+    /// Whether changes are wrapped in [`Event::Raw`].
+    raw_events: bool,
+    interceptors: Arc<[Interceptor]>,
+    channel: EventSender,
+    /// Holds back events while the subscription is being primed, see [`Primer`].
+    backlog: Option<Backlog>,
+    /// The number of changes left to deliver, see
+    /// [`SubscriptionOptions::take`](crate::options::SubscriptionOptions::take).
+    remaining: Option<AtomicUsize>,
+    /// Set while the subscription is paused, see [`crate::Mercurius::pause`].
+    paused: StdMutex<Option<Paused>>,
+    _drop_guard: Option<DropGuard>,
+    on_teardown: Option<TeardownGuard>,
+}
+
+/// Held back events, along with whether each is [`EventMeta::synthesized`].
+type Backlog = Arc<StdMutex<Option<Vec<(Event, bool)>>>>;
+
+/// The events that have been held back while the subscription is paused.
+#[derive(Debug)]
+struct Paused {
+    events: Vec<(Event, bool)>,
+    /// Any further events are dropped.
+    capacity: usize,
+}
+
+/// Sends the documents that currently match a subscription as [`Event::Added`], before the
+/// events that the change stream delivered in the meantime.
+///
+/// A document that is inserted while priming may be read as well as be delivered by the stream,
+/// so held back [`Event::Added`]s and [`Event::Entered`]s are skipped for documents that have
+/// already been sent.
+#[derive(Debug)]
+pub(crate) struct Primer {
+    selector: Option<Selector>,
+    predicate: Option<Predicate>,
+    projection: Option<Projection>,
+    extended_json: bool,
+    interceptors: Arc<[Interceptor]>,
+    channel: EventSender,
+    backlog: Backlog,
+    seen: HashSet<String>,
+}
+
+impl Primer {
+    /// Whether the documents are matched by a predicate, which the server can't evaluate.
+    pub(crate) fn has_predicate(&self) -> bool {
+        self.predicate.is_some()
+    }
+
+    pub(crate) fn matches(&self, document: &Document) -> bool {
+        matches(&self.selector, &self.predicate, document)
+    }
+
+    /// Sends the number of documents that match, see [`Event::Count`].
+    pub(crate) fn count(&self, count: u64) {
+        // A dropped receiver is noticed by the change stream
+        if let Some(event) = interceptor::intercept(&self.interceptors, Event::Count(count)) {
+            let _ = self.channel.send(event);
+        }
+    }
+
+    pub(crate) fn add(&mut self, document: Document) {
+        if !self.matches(&document) {
+            return;
+        }
+
+        if let Some(id) = document.get("_id") {
+            self.seen.insert(id.to_string());
+        }
+
+        let document = match &self.projection {
+            Some(projection) => projection.document(&document),
+            None => document,
+        };
+
+        let event = match self.extended_json {
+            true => Event::Serialized(DocumentChange::Added(&document).serialize()),
+            false => Event::Added(EventDocument::Owned(document)),
+        };
+
+        // A dropped receiver is noticed by the change stream
+        if let Some(event) = interceptor::intercept(&self.interceptors, event) {
+            let _ = self.channel.send(event);
+        }
+    }
+
+    /// Sends the held back events and lets all following events through.
+    pub(crate) fn finish(self) {
+        let mut backlog = self.backlog.lock().unwrap();
+
+        for (event, synthesized) in backlog.take().into_iter().flatten() {
+            if matches!(added_id(&event), Some(id) if self.seen.contains(&id.to_string())) {
+                continue;
+            }
+
+            let _ = self.channel.send_with(event, synthesized);
+        }
+    }
+}
+
+/// The `_id` of the document that an [`Event::Added`] or [`Event::Entered`] reports, in whichever
+/// form the subscription receives it.
+fn added_id(event: &Event) -> Option<Bson> {
+    match event {
+        Event::Added(document) | Event::Entered(document) => document.get("_id").cloned(),
+        // The document is canonical extended JSON, which converts back without loss
+        Event::Serialized(json) => {
+            let mut change: Value = serde_json::from_str(json).ok()?;
+            match change.get("event")?.as_str()? {
+                "added" | "entered" => {
+                    Bson::try_from(change.get_mut("document")?.get_mut("_id")?.take()).ok()
+                }
+                _ => None,
+            }
+        }
+        Event::Raw(raw) => added_id(&raw.event),
+        _ => None,
+    }
 }
 
 impl Subscription {
-    pub fn new(selector: Option<Document>, channel: UnboundedSender<Event>) -> Self {
-        let selector = selector
-            .map(|e| from_json(Subscription::document_to_value(&e)).expect("is correct matcher"));
+    /// Fails if the filter can't be evaluated client-side.
+    pub(crate) fn new(
+        selector: Option<Document>,
+        channel: EventSender,
+    ) -> Result<Self, MatcherError> {
+        let filter = selector;
+        let selector = filter
+            .as_ref()
+            .map(|filter| Matcher::new(filter).map(Selector::Json))
+            .transpose()?;
 
-        Self { selector, channel }
+        Ok(Self::with_selector(filter, selector, channel))
     }
 
-    pub fn handle_insert(&self, document: &Arc<Document>) -> Result<(), SendError<Event>> {
-        if !self.matches(document) {
-            return Ok(());
+    /// Like [`Subscription::new`], evaluating the filter with a [`BsonMatcher`].
+    #[cfg(feature = "bson-matcher")]
+    pub(crate) fn with_bson_matcher(
+        selector: Option<Document>,
+        channel: EventSender,
+    ) -> Result<Self, MatcherError> {
+        let filter = selector;
+        let selector = filter
+            .as_ref()
+            .map(|filter| BsonMatcher::new(filter).map(Selector::Bson))
+            .transpose()?;
+
+        Ok(Self::with_selector(filter, selector, channel))
+    }
+
+    fn with_selector(
+        filter: Option<Document>,
+        selector: Option<Selector>,
+        channel: EventSender,
+    ) -> Self {
+        Self {
+            filter,
+            selector,
+            predicate: None,
+            delta_predicate: None,
+            operations: None,
+            projection: None,
+            extended_json: false,
+            boundary_events: false,
+            raw_transitions: false,
+            set_membership: false,
+            batch_boundaries: false,
+            raw_events: false,
+            interceptors: Arc::new([]),
+            channel,
+            backlog: None,
+            remaining: None,
+            paused: StdMutex::new(None),
+            _drop_guard: None,
+            on_teardown: None,
+        }
+    }
+
+    /// Only documents that match both the selector and the predicate are considered matching.
+    pub(crate) fn set_predicate(&mut self, predicate: Option<Predicate>) {
+        self.predicate = predicate;
+    }
+
+    /// Only updates for which the predicate returns `true` are delivered as [`Event::Updated`].
+    pub(crate) fn set_delta_predicate(&mut self, predicate: Option<DeltaPredicate>) {
+        self.delta_predicate = predicate;
+    }
+
+    pub(crate) fn set_operations(&mut self, operations: Option<Vec<OperationType>>) {
+        self.operations = operations;
+    }
+
+    /// Whether changes of the given operation are delivered to the subscription.
+    pub(crate) fn accepts(&self, operation_type: &OperationType) -> bool {
+        match &self.operations {
+            Some(operations) => operations.contains(operation_type),
+            None => true,
+        }
+    }
+
+    /// Whether an update of a matching document is relevant to the subscription.
+    pub(crate) fn matches_delta(&self, update: &UpdateDescription) -> bool {
+        match &self.delta_predicate {
+            Some(DeltaPredicate(predicate)) => predicate(update),
+            None => true,
+        }
+    }
+
+    /// Compares the strings of the filter according to `collation`, see [`Matcher::with_collation`].
+    pub(crate) fn set_collation(&mut self, collation: &Collation) {
+        if let Some(selector) = &mut self.selector {
+            selector.set_collation(collation);
+        }
+    }
+
+    /// Documents are matched before they are projected.
+    pub(crate) fn set_projection(&mut self, projection: Option<Projection>) {
+        self.projection = projection;
+    }
+
+    pub(crate) fn set_extended_json(&mut self, extended_json: bool) {
+        self.extended_json = extended_json;
+    }
+
+    pub(crate) fn set_boundary_events(&mut self, boundary_events: bool) {
+        self.boundary_events = boundary_events;
+    }
+
+    pub(crate) fn reports_boundaries(&self) -> bool {
+        self.boundary_events
+    }
+
+    pub(crate) fn reports_membership(&self) -> bool {
+        self.set_membership
+    }
+
+    pub(crate) fn set_raw_transitions(&mut self, raw_transitions: bool) {
+        self.raw_transitions = raw_transitions;
+    }
+
+    pub(crate) fn reports_raw_transitions(&self) -> bool {
+        self.raw_transitions
+    }
+
+    pub(crate) fn set_set_membership(&mut self, set_membership: bool) {
+        self.set_membership = set_membership;
+    }
+
+    pub(crate) fn set_batch_boundaries(&mut self, batch_boundaries: bool) {
+        self.batch_boundaries = batch_boundaries;
+    }
+
+    pub(crate) fn reports_batch_boundaries(&self) -> bool {
+        self.batch_boundaries
+    }
+
+    pub(crate) fn set_raw_events(&mut self, raw_events: bool) {
+        self.raw_events = raw_events;
+    }
+
+    pub(crate) fn reports_raw_events(&self) -> bool {
+        self.raw_events
+    }
+
+    /// Whether the subscription receives changes as [`Event::Serialized`] events that can be
+    /// shared with other subscriptions, i.e. serialized before it's intercepted.
+    pub(crate) fn shares_serialized(&self) -> bool {
+        self.extended_json && self.projection.is_none()
+    }
+
+    pub(crate) fn set_interceptors(&mut self, interceptors: Arc<[Interceptor]>) {
+        self.interceptors = interceptors;
+    }
+
+    /// Completes the subscription once it has delivered `limit` changes.
+    pub(crate) fn set_limit(&mut self, limit: Option<usize>) {
+        self.remaining = limit.map(AtomicUsize::new);
+    }
+
+    /// Whether the subscription has delivered all the changes it's limited to and has to be
+    /// removed.
+    pub(crate) fn is_complete(&self) -> bool {
+        self.remaining
+            .as_ref()
+            .is_some_and(|remaining| remaining.load(Ordering::Relaxed) == 0)
+    }
+
+    pub(crate) fn attach_sequence(&self, sequence: Sequence) {
+        self.channel.attach(sequence);
+    }
+
+    /// Cancels the guarded token once the subscription is dropped.
+    pub(crate) fn set_drop_guard(&mut self, guard: DropGuard) {
+        self._drop_guard = Some(guard);
+    }
+
+    /// Sets the callback that [`Subscription::tear_down`] calls.
+    pub(crate) fn set_on_teardown(&mut self, on_teardown: Option<OnTeardown>) {
+        self.on_teardown = on_teardown.map(TeardownGuard);
+    }
+
+    /// Calls the [`SubscriptionOptions::on_teardown`](crate::options::SubscriptionOptions::on_teardown)
+    /// callback, which is only called once.
+    pub(crate) fn tear_down(&self, reason: TeardownReason) {
+        if let Some(TeardownGuard(on_teardown)) = &self.on_teardown {
+            on_teardown.call(reason);
+        }
+    }
+
+    /// Whether the receiver has been dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.channel.is_closed()
+    }
+
+    /// Replaces the dropped receiver during the grace period, see
+    /// [`SubscriptionOptions::grace_period`](crate::options::SubscriptionOptions::grace_period).
+    pub(crate) fn reattach(&mut self) -> Option<EventReceiver> {
+        let (channel, receiver) = self.channel.reopen()?;
+        self.channel = channel;
+        Some(receiver)
+    }
+
+    /// The channel if its events wait for room, see
+    /// [`Overflow::Block`](crate::channel::Overflow::Block).
+    pub(crate) fn blocking_channel(&self) -> Option<EventSender> {
+        self.channel.blocks().then(|| self.channel.clone())
+    }
+
+    /// Holds back all events but [`Event::Established`] until the returned primer is finished.
+    pub(crate) fn start_priming(&mut self) -> Primer {
+        let backlog = Arc::new(StdMutex::new(Some(Vec::new())));
+        self.backlog = Some(backlog.clone());
+        self.primer(backlog)
+    }
+
+    /// A primer that sends the documents right away and holds back nothing, for when no events
+    /// can be dispatched to the subscription meanwhile.
+    pub(crate) fn snapshot(&self) -> Primer {
+        self.primer(Arc::new(StdMutex::new(None)))
+    }
+
+    pub(crate) fn is_priming(&self) -> bool {
+        self.backlog
+            .as_ref()
+            .is_some_and(|backlog| backlog.lock().unwrap().is_some())
+    }
+
+    fn primer(&self, backlog: Backlog) -> Primer {
+        Primer {
+            selector: self.selector.clone(),
+            predicate: self.predicate.clone(),
+            projection: self.projection.clone(),
+            extended_json: self.extended_json,
+            interceptors: self.interceptors.clone(),
+            channel: self.channel.clone(),
+            backlog,
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Sends [`Event::Established`], which always comes first, even before a priming.
+    pub(crate) fn establish(
+        &self,
+        cluster_time: Option<Timestamp>,
+        resume_token: Option<ResumeToken>,
+    ) -> Result<(), SendFailure> {
+        let event = Event::Established {
+            cluster_time,
+            resume_token,
         };
 
-        self.channel.send(Event::Added(document.clone()))?;
+        match interceptor::intercept(&self.interceptors, event) {
+            Some(event) => self.channel.send(event),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends [`Event::GapDetected`] right after [`Event::Established`], before a priming.
+    pub(crate) fn report_gap(&self, cluster_time: Option<Timestamp>) -> Result<(), SendFailure> {
+        match interceptor::intercept(&self.interceptors, Event::GapDetected { cluster_time }) {
+            Some(event) => self.channel.send(event),
+            None => Ok(()),
+        }
+    }
+
+    pub(crate) fn send(&self, event: Event) -> Result<(), SendFailure> {
+        self.send_with(event, false)
+    }
+
+    /// Sends a change, marked as [`EventMeta::synthesized`] if it is.
+    pub(crate) fn send_with(&self, event: Event, synthesized: bool) -> Result<(), SendFailure> {
+        match self.intercept(event) {
+            Some(event) => self.forward(event, synthesized),
+            None => Ok(()),
+        }
+    }
+
+    /// Projects the event and runs the interceptors; `None` if the event is discarded.
+    pub(crate) fn intercept(&self, event: Event) -> Option<Event> {
+        // The projection and serialization apply to the change, not the change stream event
+        let event = match event {
+            Event::Raw(mut raw) => {
+                raw.event = self.transform(raw.event)?;
+                Event::Raw(raw)
+            }
+            event => self.transform(event)?,
+        };
+
+        interceptor::intercept(&self.interceptors, event)
+    }
+
+    fn transform(&self, event: Event) -> Option<Event> {
+        let event = match self.set_membership {
+            true => membership(event)?,
+            false => event,
+        };
+        let event = match &self.projection {
+            Some(projection) => projection.event(event)?,
+            None => event,
+        };
+
+        Some(match self.extended_json {
+            true => event.serialize(),
+            false => event,
+        })
+    }
+
+    /// Sends an event that has already been intercepted. Once the subscription is complete, the
+    /// last change is followed by [`Event::Closed`] and all other events are discarded.
+    pub(crate) fn forward(&self, event: Event, synthesized: bool) -> Result<(), SendFailure> {
+        let remaining = match &self.remaining {
+            Some(remaining) => match remaining.load(Ordering::Relaxed) {
+                0 => return Ok(()),
+                _ if !event.is_match() => return self.push(event, synthesized),
+                _ => remaining,
+            },
+            None => return self.push(event, synthesized),
+        };
+
+        self.push(event, synthesized)?;
+
+        if remaining.fetch_sub(1, Ordering::Relaxed) == 1 {
+            if let Some(closed) = self.intercept(Event::Closed) {
+                return self.push(closed, false);
+            }
+        }
+
         Ok(())
     }
 
-    pub fn handle_delete(
-        &self,
-        key: &Arc<String>,
-        document: &Document,
-    ) -> Result<(), SendError<Event>> {
-        if !self.matches(document) {
+    fn push(&self, event: Event, synthesized: bool) -> Result<(), SendFailure> {
+        // Held for the next receiver, whether or not the subscription is paused
+        if self.channel.is_detached() {
+            return self.channel.send_with(event, synthesized);
+        }
+
+        if let Some(paused) = self.paused.lock().unwrap().as_mut() {
+            if self.channel.is_closed() {
+                return Err(SendFailure::Closed(event));
+            }
+            if paused.events.len() >= paused.capacity {
+                return Err(self.channel.drop_event(event));
+            }
+
+            paused.events.push((event, synthesized));
             return Ok(());
+        }
+
+        if let Some(backlog) = &self.backlog {
+            if let Some(backlog) = backlog.lock().unwrap().as_mut() {
+                if self.channel.is_closed() {
+                    return Err(SendFailure::Closed(event));
+                }
+
+                backlog.push((event, synthesized));
+                return Ok(());
+            }
+        }
+
+        self.channel.send_with(event, synthesized)
+    }
+
+    /// Holds back the events that follow, up to `capacity`, until the subscription is resumed.
+    /// Returns `false` if it's already paused.
+    pub(crate) fn pause(&self, capacity: usize) -> bool {
+        let mut paused = self.paused.lock().unwrap();
+        if paused.is_some() {
+            return false;
+        }
+
+        *paused = Some(Paused {
+            events: Vec::new(),
+            capacity,
+        });
+        true
+    }
+
+    /// Sends the events that were held back while paused and lets all following events through.
+    /// Returns `false` if it isn't paused.
+    pub(crate) fn resume(&self) -> bool {
+        let paused = match self.paused.lock().unwrap().take() {
+            Some(paused) => paused,
+            None => return false,
         };
 
-        self.channel.send(Event::Removed(key.clone()))?;
+        // A dropped receiver is noticed on the next event
+        for (event, synthesized) in paused.events {
+            let _ = self.push(event, synthesized);
+        }
+        true
+    }
 
-        Ok(())
+    pub(crate) fn filter(&self) -> Option<&Document> {
+        self.filter.as_ref()
     }
 
-    pub fn handle_update(
-        &self,
-        key: &Arc<String>,
-        update: &Arc<UpdateDescription>,
-        old_doc: &Document,
-        new_doc: &Arc<Document>,
-    ) -> Result<(), SendError<Event>> {
-        let old_doc_matches = self.matches(old_doc);
-        let new_doc_matches = self.matches(new_doc);
-
-        // If both documents match then just send the update along
-        if old_doc_matches && new_doc_matches {
-            self.channel
-                .send(Event::Updated((key.clone(), update.clone())))?;
-        // If only the old doc matches that means that, as far as the selector is concerned, it has been removed
-        } else if old_doc_matches {
-            self.channel.send(Event::Removed(key.clone()))?;
-        // If only the new doc matches that means that, as far as the selector is concerned, it has been added
-        } else if new_doc_matches {
-            self.channel.send(Event::Added(new_doc.clone()))?;
-        }
-        // If neither match, just skip
+    pub(crate) fn idle(&self) -> Option<Duration> {
+        self.channel.idle()
+    }
 
-        Ok(())
+    pub(crate) fn stats(&self) -> SubscriptionStats {
+        SubscriptionStats {
+            dropped: self.channel.dropped(),
+            idle: self.channel.idle(),
+        }
     }
 
-    pub fn handle_replace(
+    /// Determines what a change means to this subscription from the versions of the document
+    /// before and after it; `None` if neither matches.
+    pub(crate) fn transition(
         &self,
-        key: &Arc<String>,
-        old_doc: &Document,
-        new_doc: &Arc<Document>,
-    ) -> Result<(), SendError<Event>> {
-        let old_doc_matches = self.matches(old_doc);
-        let new_doc_matches = self.matches(new_doc);
-
-        // If both documents match then just send the replacement along
-        if old_doc_matches && new_doc_matches {
-            self.channel
-                .send(Event::Replaced((key.clone(), new_doc.clone())))?;
-        // If only the old doc matches that means that, as far as the selector is concerned, it has been removed
-        } else if old_doc_matches {
-            self.channel.send(Event::Removed(key.clone()))?;
-        // If only the new doc matches that means that, as far as the selector is concerned, it has been added
-        } else if new_doc_matches {
-            self.channel.send(Event::Added(new_doc.clone()))?;
-        }
-        // If neither match, just skip
+        old_doc: Option<&Candidate>,
+        new_doc: Option<&Candidate>,
+    ) -> Option<Transition> {
+        let old_doc_matches = old_doc.is_some_and(|doc| self.matches_candidate(doc));
+        let new_doc_matches = new_doc.is_some_and(|doc| self.matches_candidate(doc));
 
-        Ok(())
+        match (old_doc_matches, new_doc_matches) {
+            (true, true) => Some(Transition::Changed),
+            // If only the old doc matches that means that, as far as the selector is concerned, it has been removed
+            (true, false) => Some(Transition::Removed),
+            // If only the new doc matches that means that, as far as the selector is concerned, it has been added
+            (false, true) => Some(Transition::Added),
+            // If neither match, just skip
+            (false, false) => None,
+        }
     }
 
-    pub fn handle_drop(&self) -> Result<(), SendError<Event>> {
-        self.channel.send(Event::Drop)
+    pub(crate) fn handle_drop(&self) -> Result<(), SendFailure> {
+        let sent = self.send(Event::Drop);
+        self.tear_down(TeardownReason::Dropped);
+        sent
     }
 
-    fn matches(&self, document: &Document) -> bool {
-        // https://docs.rs/serde_json_matcher/0.1.5/serde_json_matcher/enum.ObjMatcher.html
-        if let Some(matcher) = &self.selector {
-            if !matcher.matches(&Subscription::document_to_value(document)) {
-                return false;
-            }
+    fn matches_candidate(&self, candidate: &Candidate) -> bool {
+        let matches = match &self.selector {
+            Some(Selector::Json(matcher)) => matcher.matches_value(candidate.value()),
+            #[cfg(feature = "bson-matcher")]
+            Some(Selector::Bson(matcher)) => matcher.matches(candidate.document),
+            None => true,
+        };
+        if !matches {
+            return false;
         }
 
-        true
+        match &self.predicate {
+            Some(Predicate(predicate)) => predicate(candidate.document),
+            None => true,
+        }
     }
 
     fn document_to_value(document: &Document) -> serde_json::Value {
         Bson::from(document).into_canonical_extjson()
     }
 }
+
+/// The compiled filter of a subscription.
+#[derive(Debug, Clone)]
+enum Selector {
+    Json(Matcher),
+    #[cfg(feature = "bson-matcher")]
+    Bson(BsonMatcher),
+}
+
+impl Selector {
+    fn set_collation(&mut self, collation: &Collation) {
+        match self {
+            Selector::Json(matcher) => matcher.set_collation(collation),
+            #[cfg(feature = "bson-matcher")]
+            Selector::Bson(matcher) => matcher.set_collation(collation),
+        }
+    }
+
+    fn matches(&self, document: &Document) -> bool {
+        match self {
+            Selector::Json(matcher) => matcher.matches(document),
+            #[cfg(feature = "bson-matcher")]
+            Selector::Bson(matcher) => matcher.matches(document),
+        }
+    }
+}
+
+/// A version of a document that is matched against all subscriptions of a collection. Its JSON
+/// form, on which the default matcher evaluates filters, is computed at most once, and only if a
+/// subscription needs it.
+pub(crate) struct Candidate<'a> {
+    document: &'a Document,
+    value: OnceCell<Value>,
+}
+
+impl<'a> Candidate<'a> {
+    pub(crate) fn new(document: &'a Document) -> Self {
+        Self {
+            document,
+            value: OnceCell::new(),
+        }
+    }
+
+    fn value(&self) -> &Value {
+        self.value
+            .get_or_init(|| Matcher::document_to_value(self.document))
+    }
+}
+
+fn matches(
+    selector: &Option<Selector>,
+    predicate: &Option<Predicate>,
+    document: &Document,
+) -> bool {
+    if let Some(matcher) = selector {
+        if !matcher.matches(document) {
+            return false;
+        }
+    }
+
+    if let Some(Predicate(predicate)) = predicate {
+        if !predicate(document) {
+            return false;
+        }
+    }
+
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use mongodb::{
+        bson::{doc, Document},
+        change_stream::event::OperationType,
+    };
+
+    use super::{DdlEvent, Event, EventDocument, RawEvent, Subscription, UnavailableReason};
+    use crate::{channel::test_channel, receiver::EventReceiver};
+
+    fn subscription(extended_json: bool) -> (Subscription, EventReceiver) {
+        let (sender, receiver) = test_channel();
+        let mut subscription = Subscription::new(None, sender).unwrap();
+        subscription.set_extended_json(extended_json);
+        (subscription, receiver)
+    }
+
+    fn added(document: Document) -> Event {
+        Event::Added(EventDocument::Owned(document))
+    }
+
+    /// Primes with the document `1` while `1` and `2` are added meanwhile, and returns the
+    /// events that are delivered.
+    fn prime(extended_json: bool, wrap: impl Fn(Event) -> Event) -> Vec<Event> {
+        let (mut subscription, mut receiver) = subscription(extended_json);
+        let mut primer = subscription.start_priming();
+
+        for id in [1, 2] {
+            subscription
+                .send(wrap(added(doc! { "_id": id, "n": id })))
+                .unwrap();
+        }
+        primer.add(doc! { "_id": 1, "n": 1 });
+        primer.finish();
+
+        receiver.drain_available()
+    }
+
+    #[test]
+    fn primer_skips_the_backlog_of_primed_documents() {
+        assert_eq!(
+            prime(false, |event| event),
+            [
+                added(doc! { "_id": 1, "n": 1 }),
+                added(doc! { "_id": 2, "n": 2 })
+            ]
+        );
+    }
+
+    #[test]
+    fn primer_skips_serialized_backlog() {
+        let events = prime(true, |event| event);
+        assert_eq!(
+            events,
+            [
+                added(doc! { "_id": 1, "n": 1 }).serialize(),
+                added(doc! { "_id": 2, "n": 2 }).serialize(),
+            ]
+        );
+    }
+
+    #[test]
+    fn primer_skips_raw_backlog() {
+        let raw = |event| {
+            Event::Raw(Box::new(RawEvent {
+                event,
+                change: Arc::new(Document::new()),
+            }))
+        };
+        let events = prime(false, raw);
+        assert_eq!(
+            events,
+            [
+                added(doc! { "_id": 1, "n": 1 }),
+                raw(added(doc! { "_id": 2, "n": 2 })),
+            ]
+        );
+
+        let events = prime(true, raw);
+        assert_eq!(
+            events,
+            [
+                added(doc! { "_id": 1, "n": 1 }).serialize(),
+                raw(added(doc! { "_id": 2, "n": 2 }).serialize()),
+            ]
+        );
+    }
+
+    #[test]
+    fn only_matching_changes_count_toward_the_limit() {
+        let (mut subscription, mut receiver) = subscription(false);
+        subscription.set_limit(Some(1));
+
+        let unavailable = Event::PreImageUnavailable {
+            key: Arc::new("1".to_string()),
+            operation_type: OperationType::Delete,
+            reason: UnavailableReason::Expired,
+        };
+        let ddl = Event::Ddl(Box::new(DdlEvent {
+            operation_type: "createIndexes".to_string(),
+            description: None,
+        }));
+        subscription.send(unavailable.clone()).unwrap();
+        subscription.send(ddl.clone()).unwrap();
+        assert!(!subscription.is_complete());
+
+        subscription.send(added(doc! { "_id": "2" })).unwrap();
+        assert!(subscription.is_complete());
+        assert_eq!(
+            receiver.drain_available(),
+            [unavailable, ddl, added(doc! { "_id": "2" }), Event::Closed]
+        );
+    }
+}
@@ -0,0 +1,301 @@
+//! Subscriptions whose documents and keys are deserialized into types of the caller's choosing,
+//! see [`Mercurius::add_typed`](crate::Mercurius::add_typed).
+
+use std::{
+    marker::PhantomData,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use futures_util::Stream;
+use mongodb::{
+    bson::{self, Bson, Document},
+    change_stream::event::{OperationType, UpdateDescription},
+};
+use serde::de::DeserializeOwned;
+
+use crate::{
+    error::TypedError,
+    receiver::EventReceiver,
+    subscription::{Event, EventDocument, TransitionEvent, UnavailableReason},
+};
+
+/// An [`Event`] whose document is deserialized into `T` and whose document key into `K`.
+#[derive(Debug)]
+pub enum TypedEvent<T, K = String> {
+    Added(T),
+    Removed(K),
+    Updated((K, Arc<UpdateDescription>)),
+    Replaced((K, T)),
+    /// See [`Event::Entered`].
+    Entered(T),
+    /// See [`Event::Left`].
+    Left(K),
+    /// See [`Event::Transition`].
+    Transition {
+        key: K,
+        old: T,
+        new: T,
+        old_matched: bool,
+        new_matched: bool,
+    },
+    /// See [`Event::PreImageUnavailable`].
+    PreImageUnavailable {
+        key: K,
+        operation_type: OperationType,
+        reason: UnavailableReason,
+    },
+    /// A document that matches the subscription but can't be deserialized into `T`, e.g. because
+    /// one of its fields has another type. `key` is unset for [`Event::Added`] and
+    /// [`Event::Entered`], whose key is the `_id` of `raw`.
+    DeserializeError {
+        key: Option<Arc<String>>,
+        error: bson::de::Error,
+        raw: Arc<Document>,
+    },
+    /// Any other event, which doesn't concern a single document, e.g. [`Event::Established`].
+    /// [`Event::Transaction`]s are passed on as they are.
+    Other(Event),
+}
+
+/// Why an event can't be converted into a [`TypedEvent`].
+enum Unexpected {
+    Document {
+        error: bson::de::Error,
+        raw: Arc<Document>,
+    },
+    Key(TypedError),
+}
+
+impl From<TypedError> for Unexpected {
+    fn from(err: TypedError) -> Self {
+        Unexpected::Key(err)
+    }
+}
+
+impl<T: DeserializeOwned, K: DeserializeOwned> TypedEvent<T, K> {
+    /// Fails if the key can't be deserialized into `K`; a document that can't be deserialized
+    /// into `T` is returned as [`TypedEvent::DeserializeError`].
+    pub fn from_event(event: Event) -> Result<Self, TypedError> {
+        let key = match &event {
+            Event::Removed(key)
+            | Event::Updated((key, _))
+            | Event::Replaced((key, _))
+            | Event::Left(key) => Some(key.clone()),
+            Event::Transition(transition) => Some(transition.key.clone()),
+            _ => None,
+        };
+
+        match Self::convert(event) {
+            Ok(event) => Ok(event),
+            Err(Unexpected::Document { error, raw }) => {
+                Ok(TypedEvent::DeserializeError { key, error, raw })
+            }
+            Err(Unexpected::Key(err)) => Err(err),
+        }
+    }
+
+    fn convert(event: Event) -> Result<Self, Unexpected> {
+        Ok(match event {
+            Event::Added(document) => TypedEvent::Added(document_into(document)?),
+            Event::Removed(key) => TypedEvent::Removed(key_into(key)?),
+            Event::Updated((key, update)) => TypedEvent::Updated((key_into(key)?, update)),
+            Event::Replaced((key, document)) => {
+                TypedEvent::Replaced((key_into(key)?, document_into(document)?))
+            }
+            Event::Entered(document) => TypedEvent::Entered(document_into(document)?),
+            Event::Left(key) => TypedEvent::Left(key_into(key)?),
+            Event::Transition(transition) => {
+                let TransitionEvent {
+                    key,
+                    old,
+                    new,
+                    old_matched,
+                    new_matched,
+                } = *transition;
+                TypedEvent::Transition {
+                    key: key_into(key)?,
+                    old: document_into(old)?,
+                    new: document_into(new)?,
+                    old_matched,
+                    new_matched,
+                }
+            }
+            Event::PreImageUnavailable {
+                key,
+                operation_type,
+                reason,
+            } => TypedEvent::PreImageUnavailable {
+                key: key_into(key)?,
+                operation_type,
+                reason,
+            },
+            event => TypedEvent::Other(event),
+        })
+    }
+}
+
+fn document_into<T: DeserializeOwned>(document: EventDocument) -> Result<T, Unexpected> {
+    let raw = match document {
+        EventDocument::Shared(document) => document,
+        EventDocument::Owned(document) => Arc::new(document),
+    };
+
+    bson::from_document((*raw).clone()).map_err(|error| Unexpected::Document { error, raw })
+}
+
+/// The change stream only keys documents by a string or ObjectId `_id`, which it stringifies, so a
+/// key type has to deserialize from a string; an ObjectId does so from its hex representation.
+/// The original type of the `_id` is gone by then: a string `_id` of 24 hex digits deserializes
+/// into an ObjectId as well, and an ObjectId `_id` into its hex string.
+fn key_into<K: DeserializeOwned>(key: Arc<String>) -> Result<K, TypedError> {
+    bson::from_bson(Bson::String(key.to_string())).map_err(|error| TypedError::Key { key, error })
+}
+
+/// Receives the events of a subscription as [`TypedEvent`]s, also as a [`Stream`] of the results
+/// of [`TypedReceiver::recv`]. Once it's dropped, the subscription is removed with its next
+/// event.
+#[derive(Debug)]
+pub struct TypedReceiver<T, K = String> {
+    receiver: EventReceiver,
+    _types: PhantomData<fn() -> (T, K)>,
+}
+
+impl<T: DeserializeOwned, K: DeserializeOwned> TypedReceiver<T, K> {
+    pub(crate) fn new(receiver: EventReceiver) -> Self {
+        Self {
+            receiver,
+            _types: PhantomData,
+        }
+    }
+
+    /// Waits for the next event, see [`EventReceiver::recv`]. An event whose key doesn't match
+    /// the key type is returned as an error, and one whose document doesn't match the document
+    /// type as [`TypedEvent::DeserializeError`]; the events that follow are unaffected.
+    pub async fn recv(&mut self) -> Option<Result<TypedEvent<T, K>, TypedError>> {
+        self.receiver.recv().await.map(TypedEvent::from_event)
+    }
+
+    /// Returns the next event if one is immediately available, without waiting.
+    pub fn try_recv(&mut self) -> Option<Result<TypedEvent<T, K>, TypedError>> {
+        self.receiver.try_recv().map(TypedEvent::from_event)
+    }
+
+    pub fn into_inner(self) -> EventReceiver {
+        self.receiver
+    }
+}
+
+impl<T: DeserializeOwned, K: DeserializeOwned> Stream for TypedReceiver<T, K> {
+    type Item = Result<TypedEvent<T, K>, TypedError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.receiver)
+            .poll_next(cx)
+            .map(|next| next.map(TypedEvent::from_event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_util::StreamExt;
+    use mongodb::bson::{doc, oid::ObjectId, Document};
+    use serde::Deserialize;
+
+    use super::{TypedEvent, TypedReceiver};
+    use crate::{
+        channel::test_channel,
+        error::TypedError,
+        subscription::{Event, EventDocument},
+    };
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Order {
+        _id: i32,
+        amount: i32,
+    }
+
+    fn added(document: Document) -> Event {
+        Event::Added(EventDocument::Owned(document))
+    }
+
+    #[test]
+    fn mismatched_field_is_a_deserialize_error() {
+        let document = doc! { "_id": 1, "amount": "ten" };
+        match TypedEvent::<Order>::from_event(added(document.clone())).unwrap() {
+            TypedEvent::DeserializeError { key, raw, .. } => {
+                assert_eq!(key, None);
+                assert_eq!(*raw, document);
+            }
+            event => panic!("expected a deserialize error, got {event:?}"),
+        }
+
+        let replaced = Event::Replaced((
+            Arc::new("1".to_string()),
+            EventDocument::Owned(document.clone()),
+        ));
+        match TypedEvent::<Order>::from_event(replaced).unwrap() {
+            TypedEvent::DeserializeError { key, raw, .. } => {
+                assert_eq!(key.as_deref().map(String::as_str), Some("1"));
+                assert_eq!(*raw, document);
+            }
+            event => panic!("expected a deserialize error, got {event:?}"),
+        }
+    }
+
+    #[test]
+    fn keys_deserialize_from_strings() {
+        let id = ObjectId::new();
+        let removed = |key: String| Event::Removed(Arc::new(key));
+
+        match TypedEvent::<Order, ObjectId>::from_event(removed(id.to_hex())).unwrap() {
+            TypedEvent::Removed(key) => assert_eq!(key, id),
+            event => panic!("expected a removal, got {event:?}"),
+        }
+        match TypedEvent::<Order>::from_event(removed(id.to_hex())).unwrap() {
+            TypedEvent::Removed(key) => assert_eq!(key, id.to_hex()),
+            event => panic!("expected a removal, got {event:?}"),
+        }
+
+        // Only types that deserialize from a string can be keys
+        assert!(matches!(
+            TypedEvent::<Order, i32>::from_event(removed("1".to_string())),
+            Err(TypedError::Key { key, .. }) if *key == "1"
+        ));
+        assert!(matches!(
+            TypedEvent::<Order, ObjectId>::from_event(removed("order-1".to_string())),
+            Err(TypedError::Key { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn typed_receiver_is_a_stream() {
+        let (sender, receiver) = test_channel();
+        let mut receiver = TypedReceiver::<Order>::new(receiver);
+
+        sender.send(added(doc! { "_id": 1, "amount": 10 })).unwrap();
+        sender
+            .send(added(doc! { "_id": 2, "amount": "ten" }))
+            .unwrap();
+        sender.send(Event::Reset).unwrap();
+        drop(sender);
+
+        assert!(matches!(
+            receiver.next().await,
+            Some(Ok(TypedEvent::Added(Order { _id: 1, amount: 10 })))
+        ));
+        // A document that doesn't match the type doesn't end the stream
+        assert!(matches!(
+            receiver.next().await,
+            Some(Ok(TypedEvent::DeserializeError { .. }))
+        ));
+        assert!(matches!(
+            receiver.next().await,
+            Some(Ok(TypedEvent::Other(Event::Reset)))
+        ));
+        assert!(receiver.next().await.is_none());
+    }
+}
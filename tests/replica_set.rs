@@ -0,0 +1,292 @@
+//! Tests against a live replica set, as change streams need one. They're ignored by default; run
+//! them with `cargo test --all-features -- --ignored` and the URI of the replica set in
+//! `MONGODB_URI`, e.g. `mongodb://localhost:27017/?directConnection=true`. Every test uses a
+//! database of its own, which it drops again.
+
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+
+use futures_util::{future::join_all, StreamExt};
+use mercurius::{
+    options::SubscriptionOptions, receiver::EventReceiver, subscription::Event, Mercurius,
+};
+use mongodb::{
+    bson::{doc, oid::ObjectId, Document},
+    change_stream::event::{OperationType, ResumeToken},
+    event::command::{CommandEventHandler, CommandStartedEvent},
+    options::ClientOptions,
+    Client, Database, IndexModel,
+};
+use tokio::time::{timeout, Duration};
+
+async fn database() -> Database {
+    database_with(None).await
+}
+
+/// A database whose client reports its commands to `handler`.
+async fn database_with(handler: Option<Arc<dyn CommandEventHandler>>) -> Database {
+    let uri =
+        std::env::var("MONGODB_URI").unwrap_or_else(|_| "mongodb://localhost:27017".to_string());
+    let mut options = ClientOptions::parse(uri).await.unwrap();
+    options.command_event_handler = handler;
+    let client = Client::with_options(options).unwrap();
+    client.database(&format!("mercurius_{}", ObjectId::new().to_hex()))
+}
+
+/// Counts the commands with the given name.
+#[derive(Debug)]
+struct CommandCount {
+    name: &'static str,
+    count: AtomicUsize,
+}
+
+impl CommandEventHandler for CommandCount {
+    fn handle_command_started_event(&self, event: CommandStartedEvent) {
+        if event.command_name == self.name {
+            self.count.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Creates the collection `name`, as subscribing turns on its pre- and post-images.
+async fn create(db: &Database, name: &str) {
+    db.create_collection(name, None).await.unwrap();
+}
+
+/// The next event other than [`Event::Established`].
+async fn next_change(receiver: &mut EventReceiver) -> Event {
+    loop {
+        let event = timeout(Duration::from_secs(10), receiver.recv())
+            .await
+            .expect("no event within 10 seconds")
+            .expect("the subscription ended");
+        if !matches!(event, Event::Established { .. }) {
+            return event;
+        }
+    }
+}
+
+fn added_name(event: &Event) -> Option<&str> {
+    match event {
+        Event::Added(document) => document.get_str("name").ok(),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a replica set at MONGODB_URI"]
+async fn subscribers_share_one_stream() {
+    let db = database().await;
+    create(&db, "orders").await;
+    let mercurius = Mercurius::new(db.clone());
+
+    let mut receivers = Vec::new();
+    for _ in 0..5 {
+        let (receiver, _) = mercurius.add("orders", None).await.unwrap();
+        receivers.push(receiver);
+    }
+    let concurrent = join_all((0..5).map(|_| mercurius.add("orders", None))).await;
+    receivers.extend(concurrent.into_iter().map(|added| added.unwrap().0));
+
+    assert_eq!(mercurius.stream_count_for("orders").await, 1);
+    assert_eq!(mercurius.total_subscription_count().await, 10);
+
+    db.collection::<Document>("orders")
+        .insert_one(doc! { "name": "first" }, None)
+        .await
+        .unwrap();
+    for receiver in &mut receivers {
+        assert_eq!(added_name(&next_change(receiver).await), Some("first"));
+    }
+    assert_eq!(mercurius.stream_count_for("orders").await, 1);
+
+    db.drop(None).await.unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+#[ignore = "needs a replica set at MONGODB_URI"]
+async fn concurrent_adds_run_one_coll_mod() {
+    let coll_mods = Arc::new(CommandCount {
+        name: "collMod",
+        count: AtomicUsize::new(0),
+    });
+    let db = database_with(Some(coll_mods.clone())).await;
+    create(&db, "orders").await;
+    let mercurius = Arc::new(Mercurius::new(db.clone()));
+
+    let tasks: Vec<_> = (0..20)
+        .map(|_| {
+            let mercurius = mercurius.clone();
+            tokio::spawn(async move {
+                mercurius
+                    .add("orders", None)
+                    .await
+                    .map(|(receiver, _)| receiver)
+                    .map_err(|err| err.to_string())
+            })
+        })
+        .collect();
+    let mut receivers = Vec::new();
+    for task in tasks {
+        receivers.push(task.await.unwrap().unwrap());
+    }
+    for _ in 0..5 {
+        receivers.push(mercurius.add("orders", None).await.unwrap().0);
+    }
+
+    assert_eq!(coll_mods.count.load(Ordering::Relaxed), 1);
+    assert_eq!(mercurius.stream_count_for("orders").await, 1);
+
+    db.drop(None).await.unwrap();
+}
+
+#[test]
+#[ignore = "needs a replica set at MONGODB_URI"]
+fn current_thread_runtime() {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let local = tokio::task::LocalSet::new();
+
+    local.block_on(&runtime, async {
+        let db = database().await;
+        create(&db, "orders").await;
+        let mercurius = Mercurius::new(db.clone());
+        let (mut receiver, _) = mercurius.add("orders", None).await.unwrap();
+
+        // `!Send` work on the same thread as the change stream
+        let inserted = std::rc::Rc::new(std::cell::Cell::new(false));
+        let insert = tokio::task::spawn_local({
+            let inserted = inserted.clone();
+            let orders = db.collection::<Document>("orders");
+            async move {
+                orders
+                    .insert_one(doc! { "name": "local" }, None)
+                    .await
+                    .unwrap();
+                inserted.set(true);
+            }
+        });
+
+        assert_eq!(added_name(&next_change(&mut receiver).await), Some("local"));
+        insert.await.unwrap();
+        assert!(inserted.get());
+
+        db.drop(None).await.unwrap();
+    });
+}
+
+#[tokio::test]
+#[ignore = "needs a replica set at MONGODB_URI"]
+async fn expanded_events() {
+    let db = database().await;
+    create(&db, "orders").await;
+    let mercurius = Mercurius::new(db.clone());
+    let options = SubscriptionOptions::new().show_expanded_events(true);
+    let (mut receiver, _) = mercurius
+        .add_with_options("orders", None, options)
+        .await
+        .unwrap();
+
+    let orders = db.collection::<Document>("orders");
+    let index = IndexModel::builder().keys(doc! { "name": 1 }).build();
+    orders.create_index(index, None).await.unwrap();
+    match next_change(&mut receiver).await {
+        Event::Ddl(ddl) => assert_eq!(ddl.operation_type, "createIndexes"),
+        event => panic!("expected a DDL event, got {event:?}"),
+    }
+
+    // Changes of documents still arrive as usual
+    orders
+        .insert_one(doc! { "name": "expanded" }, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        added_name(&next_change(&mut receiver).await),
+        Some("expanded")
+    );
+
+    db.drop(None).await.unwrap();
+}
+
+/// Drops and recreates the collection `name` and returns the resume token of the invalidation.
+async fn invalidate(db: &Database, name: &str) -> ResumeToken {
+    let collection = db.collection::<Document>(name);
+    let mut changes = collection.watch(None, None).await.unwrap();
+    collection.drop(None).await.unwrap();
+
+    let token = loop {
+        let event = timeout(Duration::from_secs(10), changes.next())
+            .await
+            .expect("no invalidation within 10 seconds")
+            .expect("the change stream ended")
+            .unwrap();
+        if event.operation_type == OperationType::Invalidate {
+            break event.id;
+        }
+    };
+
+    create(db, name).await;
+    token
+}
+
+#[tokio::test]
+#[ignore = "needs a replica set at MONGODB_URI"]
+async fn start_after_resumes_across_an_invalidation() {
+    let db = database().await;
+    create(&db, "orders").await;
+    let token = invalidate(&db, "orders").await;
+    db.collection::<Document>("orders")
+        .insert_one(doc! { "name": "recreated" }, None)
+        .await
+        .unwrap();
+
+    let options = SubscriptionOptions::new().resume_after(token.clone());
+    let resumed = Mercurius::new(db.clone())
+        .add_with_options("orders", None, options)
+        .await;
+    assert!(resumed.is_err(), "resumed after an invalidation");
+
+    let mercurius = Mercurius::new(db.clone());
+    let options = SubscriptionOptions::new().start_after(token);
+    let (mut receiver, _) = mercurius
+        .add_with_options("orders", None, options)
+        .await
+        .unwrap();
+    assert_eq!(
+        added_name(&next_change(&mut receiver).await),
+        Some("recreated")
+    );
+
+    db.drop(None).await.unwrap();
+}
+
+#[tokio::test]
+#[ignore = "needs a replica set at MONGODB_URI"]
+async fn restart_on_invalidate_continues_with_the_recreated_collection() {
+    let db = database().await;
+    create(&db, "orders").await;
+    let mercurius = Mercurius::new(db.clone());
+    let options = SubscriptionOptions::new().restart_on_invalidate(true);
+    let (mut receiver, _) = mercurius
+        .add_with_options("orders", None, options)
+        .await
+        .unwrap();
+
+    invalidate(&db, "orders").await;
+    assert_eq!(next_change(&mut receiver).await, Event::Reset);
+
+    db.collection::<Document>("orders")
+        .insert_one(doc! { "name": "recreated" }, None)
+        .await
+        .unwrap();
+    assert_eq!(
+        added_name(&next_change(&mut receiver).await),
+        Some("recreated")
+    );
+
+    db.drop(None).await.unwrap();
+}